@@ -125,9 +125,11 @@ IRQ 15
 .global init_ctx
 .global syscall_int
 .global idle_task
+.global kthread_start
 .type init_ctx, @function
 .type syscall_int, @function
 .type idle_task, @function
+.type kthread_start, @function
 
 int_common:
 STORE_REGS
@@ -172,3 +174,14 @@ idle_task:
     sti
     hlt
     jmp 0b
+
+kthread_start:
+    # Lazy cleanup
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+    # The entry point is passed in `ebx`
+    and esp, -16
+    sub esp, 12
+    push ebx
+    call kthread_main
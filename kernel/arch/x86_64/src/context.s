@@ -143,10 +143,12 @@ IRQ 15
 .global syscall_int
 .global syscall
 .global idle_task
+.global kthread_start
 .type init_ctx, @function
 .type syscall_int, @function
 .type syscall, @function
 .type idle_task, @function
+.type kthread_start, @function
 
 int_common:
 STORE_REGS
@@ -221,3 +223,13 @@ idle_task:
     sti
     hlt
     jmp 0b
+
+kthread_start:
+    # Lazy cleanup
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+    # The entry point is passed in `rbx`
+    mov rdi, rbx
+    and rsp, -16
+    call kthread_main
@@ -33,7 +33,7 @@ use crate::{
 	device::{
 		bar::{BARType, BAR},
 		manager,
-		manager::PhysicalDevice,
+		manager::{BusLocation, PhysicalDevice},
 		DeviceManager,
 	},
 	memory::{mmio::MMIO, PhysAddr},
@@ -50,6 +50,9 @@ const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
 /// The port used to retrieve the devices' information.
 const CONFIG_DATA_PORT: u16 = 0xcfc;
 
+/// Command register flag: The device can act as a bus master
+const COMMAND_BUS_MASTER: u32 = 0b100;
+
 /// Device class: Unclassified
 pub const CLASS_UNCLASSIFIED: u16 = 0x00;
 /// Device class: Mass Storage Controller
@@ -411,6 +414,14 @@ impl PCIDevice {
 }
 
 impl PhysicalDevice for PCIDevice {
+	fn get_location(&self) -> BusLocation {
+		BusLocation::Pci {
+			bus: self.bus,
+			device: self.device,
+			function: self.function,
+		}
+	}
+
 	fn get_device_id(&self) -> u16 {
 		self.device_id
 	}
@@ -464,6 +475,17 @@ impl PhysicalDevice for PCIDevice {
 			None
 		}
 	}
+
+	fn enable_bus_mastering(&self) {
+		let command = read_long(self.bus, self.device, self.function, 0x1);
+		write_long(
+			self.bus,
+			self.device,
+			self.function,
+			0x1,
+			command | COMMAND_BUS_MASTER,
+		);
+	}
 }
 
 /// This manager handles every devices connected to the PCI bus.
@@ -23,8 +23,27 @@ use crate::{device::bar::BAR, sync::mutex::Mutex};
 use core::any::{Any, TypeId};
 use utils::{collections::hashmap::HashMap, errno::EResult, ptr::arc::Arc};
 
+/// The location of a physical device, uniquely identifying it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BusLocation {
+	/// A function of a device on the PCI bus.
+	Pci {
+		/// The bus number.
+		bus: u8,
+		/// The offset of the device on the bus.
+		device: u8,
+		/// The function number.
+		function: u8,
+	},
+}
+
 /// Trait representing a physical device.
 pub trait PhysicalDevice {
+	/// Returns the location of the device.
+	///
+	/// This allows managers to find the resources associated with a device when it is unplugged.
+	fn get_location(&self) -> BusLocation;
+
 	/// Returns the device ID of the device.
 	fn get_device_id(&self) -> u16;
 	/// Returns the vendor ID of the device.
@@ -59,6 +78,12 @@ pub trait PhysicalDevice {
 	///
 	/// If the device doesn't use any, the function returns `None`.
 	fn get_interrupt_pin(&self) -> Option<u8>;
+
+	/// Allows the device to access the main memory by itself (bus mastering), which is required
+	/// for DMA.
+	///
+	/// If not applicable, the function does nothing.
+	fn enable_bus_mastering(&self);
 }
 
 /// Trait representing a structure managing the link between physical devices
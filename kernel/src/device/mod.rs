@@ -37,9 +37,11 @@ pub mod default;
 pub mod id;
 pub mod keyboard;
 pub mod manager;
+pub mod net;
 pub mod serial;
 pub mod storage;
 pub mod tty;
+pub mod virtio;
 
 use crate::{
 	device::manager::DeviceManager,
@@ -55,6 +57,7 @@ use crate::{
 };
 use core::{ffi::c_void, fmt, num::NonZeroU64};
 use keyboard::KeyboardManager;
+use net::NetManager;
 use storage::StorageManager;
 use utils::{
 	collections::{
@@ -182,6 +185,13 @@ pub trait DeviceIO {
 		Ok(buf_off)
 	}
 
+	/// Makes sure data written to the device reached the storage medium.
+	///
+	/// The default implementation does nothing.
+	fn flush(&self) -> EResult<()> {
+		Ok(())
+	}
+
 	/// Polls the device with the given mask.
 	fn poll(&self, mask: u32) -> EResult<u32> {
 		let _ = mask;
@@ -313,8 +323,12 @@ impl Device {
 
 	/// If exists, removes the device file.
 	///
-	/// If the file doesn't exist, the function does nothing.
+	/// If the file doesn't exist, the function does nothing. This is the case as long as files
+	/// management has not been initialized.
 	pub fn remove_file(&self) -> EResult<()> {
+		if !file::is_init() {
+			return Ok(());
+		}
 		vfs::unlink_from_path(&self.path, &ResolutionSettings::kernel_follow())
 	}
 }
@@ -343,7 +357,10 @@ pub fn register(device: Device) -> EResult<()> {
 	DEVICES.lock().insert(id, Arc::new(device)?)?;
 	// Create file if files management has been initialized
 	if file::is_init() {
-		Device::create_file(&id, &path, mode)?;
+		if let Err(e) = Device::create_file(&id, &path, mode) {
+			DEVICES.lock().remove(&id);
+			return Err(e);
+		}
 	}
 	Ok(())
 }
@@ -380,6 +397,9 @@ pub(crate) fn init() -> EResult<()> {
 	let storage_manager = StorageManager::new()?;
 	manager::register(storage_manager)?;
 
+	let net_manager = NetManager::new();
+	manager::register(net_manager)?;
+
 	bus::detect()?;
 
 	// Testing disk I/O (if enabled)
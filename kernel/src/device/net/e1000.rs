@@ -0,0 +1,610 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Intel 8254x (e1000) network controller driver.
+//!
+//! The controller uses rings of descriptors in main memory to receive and transmit frames. For
+//! each ring, the driver and the controller each own a contiguous range of descriptors, delimited
+//! by the head (owned by the controller) and tail (owned by the driver) registers.
+
+use crate::{
+	arch::x86::{idt::IntFrame, pic},
+	device::{bar::BAR, manager::PhysicalDevice},
+	event,
+	event::CallbackResult,
+	memory::dma::DmaBuffer,
+	net,
+	net::{buff::BuffList, Address, BindAddress, IfaceStats, Interface, LinkType, MAC},
+	sync::mutex::IntMutex,
+};
+use core::{
+	cmp::min,
+	hint,
+	mem::{size_of, ManuallyDrop},
+	ptr,
+};
+use utils::{
+	collections::{string::String, vec::Vec},
+	errno,
+	errno::{AllocResult, EResult},
+	limits::PAGE_SIZE,
+	ptr::arc::Arc,
+	TryClone,
+};
+
+/// The vendor ID of Intel.
+pub const VENDOR_ID: u16 = 0x8086;
+/// The device IDs of supported controllers.
+pub const DEVICE_IDS: &[u16] = &[
+	// 82540EM (emulated by QEMU, VirtualBox and VMware)
+	0x100e, // 82545EM
+	0x100f,
+];
+
+/// Register: Device control
+const REG_CTRL: usize = 0x0000;
+/// Register: Device status
+const REG_STATUS: usize = 0x0008;
+/// Register: EEPROM read
+const REG_EERD: usize = 0x0014;
+/// Register: Interrupt cause read. Reading it clears the pending interrupts
+const REG_ICR: usize = 0x00c0;
+/// Register: Interrupt mask set
+const REG_IMS: usize = 0x00d0;
+/// Register: Interrupt mask clear
+const REG_IMC: usize = 0x00d8;
+/// Register: Receive control
+const REG_RCTL: usize = 0x0100;
+/// Register: Transmit control
+const REG_TCTL: usize = 0x0400;
+/// Register: Transmit inter-packet gap
+const REG_TIPG: usize = 0x0410;
+/// Register: Receive descriptors base address, low
+const REG_RDBAL: usize = 0x2800;
+/// Register: Receive descriptors base address, high
+const REG_RDBAH: usize = 0x2804;
+/// Register: Receive descriptors ring length in bytes
+const REG_RDLEN: usize = 0x2808;
+/// Register: Receive descriptors head
+const REG_RDH: usize = 0x2810;
+/// Register: Receive descriptors tail
+const REG_RDT: usize = 0x2818;
+/// Register: Transmit descriptors base address, low
+const REG_TDBAL: usize = 0x3800;
+/// Register: Transmit descriptors base address, high
+const REG_TDBAH: usize = 0x3804;
+/// Register: Transmit descriptors ring length in bytes
+const REG_TDLEN: usize = 0x3808;
+/// Register: Transmit descriptors head
+const REG_TDH: usize = 0x3810;
+/// Register: Transmit descriptors tail
+const REG_TDT: usize = 0x3818;
+/// Register: Multicast table array (128 entries)
+const REG_MTA: usize = 0x5200;
+/// Register: Receive address, low (first entry)
+const REG_RAL: usize = 0x5400;
+/// Register: Receive address, high (first entry)
+const REG_RAH: usize = 0x5404;
+
+/// Control: Set link up
+const CTRL_SLU: u32 = 1 << 6;
+/// Control: Device reset
+const CTRL_RST: u32 = 1 << 26;
+/// Status: Link up
+const STATUS_LU: u32 = 1 << 1;
+/// EEPROM read: Start the read
+const EERD_START: u32 = 1 << 0;
+/// EEPROM read: The read is done
+const EERD_DONE: u32 = 1 << 4;
+/// Receive address high: The address is valid
+const RAH_AV: u32 = 1 << 31;
+
+/// Interrupt: Link status change
+const INT_LSC: u32 = 1 << 2;
+/// Interrupt: Receive descriptors minimum threshold reached
+const INT_RXDMT0: u32 = 1 << 4;
+/// Interrupt: Receiver overrun
+const INT_RXO: u32 = 1 << 6;
+/// Interrupt: Receiver timer
+const INT_RXT0: u32 = 1 << 7;
+
+/// Receive control: Enable
+const RCTL_EN: u32 = 1 << 1;
+/// Receive control: Accept broadcast
+const RCTL_BAM: u32 = 1 << 15;
+/// Receive control: Strip the CRC
+const RCTL_SECRC: u32 = 1 << 26;
+/// Transmit control: Enable
+const TCTL_EN: u32 = 1 << 1;
+/// Transmit control: Pad short packets
+const TCTL_PSP: u32 = 1 << 3;
+/// Transmit control: Collision threshold (recommended value)
+const TCTL_CT: u32 = 0x0f << 4;
+/// Transmit control: Collision distance (recommended value for full duplex)
+const TCTL_COLD: u32 = 0x40 << 12;
+/// Transmit inter-packet gap (recommended value for the 82540EM)
+const TIPG: u32 = 10 | (8 << 10) | (6 << 20);
+
+/// Descriptor status: The descriptor has been processed by the controller
+const DESC_DD: u8 = 1 << 0;
+/// Descriptor status: End of packet
+const DESC_EOP: u8 = 1 << 1;
+/// Transmit descriptor command: End of packet
+const CMD_EOP: u8 = 1 << 0;
+/// Transmit descriptor command: Insert the CRC
+const CMD_IFCS: u8 = 1 << 1;
+/// Transmit descriptor command: Report the status
+const CMD_RS: u8 = 1 << 3;
+
+/// The number of descriptors in each ring. The size of a ring must be a multiple of 128 bytes.
+const DESC_COUNT: usize = 32;
+/// The size of the buffer of a descriptor. It matches the default receive buffer size.
+const BUF_SIZE: usize = 2048;
+/// The maximum number of iterations when waiting for the controller.
+const POLL_ITER: usize = 100000;
+
+/// The interface's Maximum Transmission Unit.
+const MTU: usize = 1500;
+
+/// A receive descriptor.
+#[repr(C)]
+struct RxDesc {
+	/// The physical address of the buffer.
+	addr: u64,
+	/// The length of the received data.
+	length: u16,
+	/// The checksum of the packet.
+	checksum: u16,
+	/// Status.
+	status: u8,
+	/// Errors.
+	errors: u8,
+	/// VLAN information.
+	special: u16,
+}
+
+/// A transmit descriptor.
+#[repr(C)]
+struct TxDesc {
+	/// The physical address of the buffer.
+	addr: u64,
+	/// The length of the data to transmit.
+	length: u16,
+	/// Checksum offset.
+	cso: u8,
+	/// Command.
+	cmd: u8,
+	/// Status.
+	status: u8,
+	/// Checksum start.
+	css: u8,
+	/// VLAN information.
+	special: u16,
+}
+
+/// A ring of descriptors along with their buffers.
+#[derive(Debug)]
+struct Ring {
+	/// The descriptors.
+	descs: DmaBuffer,
+	/// The buffers.
+	bufs: DmaBuffer,
+	/// The index of the next descriptor to be processed by the driver.
+	cur: usize,
+}
+
+impl Ring {
+	/// Allocates a ring.
+	fn new() -> AllocResult<Self> {
+		Ok(Self {
+			descs: DmaBuffer::new((DESC_COUNT * size_of::<RxDesc>()).div_ceil(PAGE_SIZE))?,
+			bufs: DmaBuffer::new((DESC_COUNT * BUF_SIZE).div_ceil(PAGE_SIZE))?,
+			cur: 0,
+		})
+	}
+
+	/// Returns a pointer to the descriptor `i`.
+	#[inline]
+	fn desc<D>(&self, i: usize) -> *mut D {
+		self.descs.as_ptr(i * size_of::<D>()) as _
+	}
+
+	/// Returns the physical address of the buffer of the descriptor `i`.
+	#[inline]
+	fn buf_addr(&self, i: usize) -> u64 {
+		self.bufs.phys_addr(i * BUF_SIZE).0 as _
+	}
+
+	/// Returns the physical address of the descriptors.
+	#[inline]
+	fn descs_addr(&self) -> u64 {
+		self.descs.phys_addr(0).0 as _
+	}
+}
+
+/// An interrupt line used by an e1000 controller.
+struct Irq {
+	/// The IRQ number.
+	line: u8,
+	/// The BAR of the controller, to acknowledge interrupts.
+	bar: BAR,
+	/// The interface.
+	iface: Arc<IntMutex<dyn Interface>>,
+}
+
+/// The list of controllers waiting for interrupts.
+static IRQS: IntMutex<Vec<Irq>> = IntMutex::new(Vec::new());
+
+/// Stops handling interrupts for `iface`, if it is an e1000 controller.
+///
+/// This releases the reference held for interrupt handling, so that the driver can be freed once
+/// the interface is unregistered.
+pub(super) fn release(iface: &Arc<IntMutex<dyn Interface>>) {
+	IRQS.lock()
+		.retain(|irq| !ptr::addr_eq(Arc::as_ptr(&irq.iface), Arc::as_ptr(iface)));
+}
+
+/// Handles an interrupt for controllers on the line corresponding to the vector `id`.
+fn interrupt_handler(id: u32, _code: u32, _frame: &mut IntFrame, _ring: u8) -> CallbackResult {
+	let line = id - 0x20;
+	let irqs = IRQS.lock();
+	for irq in irqs.iter().filter(|irq| irq.line as u32 == line) {
+		// Reading the causes deasserts the interrupt
+		let cause = irq.bar.read::<u32>(REG_ICR) as u32;
+		if cause & INT_LSC != 0 && irq.bar.read::<u32>(REG_STATUS) as u32 & STATUS_LU != 0 {
+			let _ = net::link_up(&irq.iface);
+		}
+		if cause & (INT_RXT0 | INT_RXO | INT_RXDMT0) != 0 {
+			// Frames that cannot be processed are dropped
+			let _ = net::receive(&irq.iface);
+		}
+	}
+	CallbackResult::Continue
+}
+
+/// An e1000 network controller.
+pub struct E1000 {
+	/// The name of the interface.
+	name: String,
+	/// The BAR to access the controller's registers.
+	bar: BAR,
+	/// The MAC address of the controller.
+	mac: MAC,
+	/// The addresses bound to the interface.
+	addresses: Vec<BindAddress>,
+	/// Traffic counters.
+	stats: IfaceStats,
+	/// Tells whether the interface has been brought up by the administrator.
+	enabled: bool,
+
+	/// The receive ring.
+	rx: Ring,
+	/// The transmit ring.
+	tx: Ring,
+}
+
+impl E1000 {
+	/// Initializes the controller `dev` and registers it as the network interface `name`.
+	pub fn register(dev: &dyn PhysicalDevice, name: String) -> EResult<()> {
+		let line = dev.get_interrupt_line().ok_or_else(|| errno!(ENODEV))?;
+		let bar = dev.get_bars().first().cloned().flatten();
+		let Some(
+			bar @ BAR::MemorySpace {
+				..
+			},
+		) = bar
+		else {
+			return Err(errno!(ENODEV));
+		};
+		dev.enable_bus_mastering();
+		let iface = Self::new(bar.clone(), name.try_clone()?)?;
+		net::register_iface(name.try_clone()?, iface)?;
+		let iface = net::get_iface(&name).ok_or_else(|| errno!(ENODEV))?;
+		let mut irqs = IRQS.lock();
+		if !irqs.iter().any(|irq| irq.line == line) {
+			let hook = event::register_callback(0x20 + line as u32, interrupt_handler)?;
+			let _ = ManuallyDrop::new(hook);
+			if line >= 8 {
+				// Cascade
+				pic::enable_irq(2);
+			}
+			pic::enable_irq(line);
+		}
+		irqs.push(Irq {
+			line,
+			bar: bar.clone(),
+			iface,
+		})?;
+		// Enable interrupts only once the interface can be found by the handler
+		bar.write::<u32>(REG_IMS, (INT_LSC | INT_RXDMT0 | INT_RXO | INT_RXT0) as _);
+		Ok(())
+	}
+
+	/// Resets the controller and sets up its rings.
+	fn new(bar: BAR, name: String) -> EResult<Self> {
+		let read = |reg| bar.read::<u32>(reg) as u32;
+		let write = |reg, val: u32| bar.write::<u32>(reg, val as _);
+		// Reset
+		write(REG_IMC, u32::MAX);
+		write(REG_CTRL, read(REG_CTRL) | CTRL_RST);
+		wait(|| read(REG_CTRL) & CTRL_RST == 0)?;
+		write(REG_IMC, u32::MAX);
+		let _ = read(REG_ICR);
+		write(REG_CTRL, read(REG_CTRL) | CTRL_SLU);
+		let mac = read_mac(&bar)?;
+		write(
+			REG_RAL,
+			u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]),
+		);
+		write(
+			REG_RAH,
+			u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV,
+		);
+		for i in 0..128 {
+			write(REG_MTA + i * size_of::<u32>(), 0);
+		}
+		// Receive ring
+		let rx = Ring::new()?;
+		for i in 0..DESC_COUNT {
+			unsafe {
+				ptr::write_volatile(
+					rx.desc(i),
+					RxDesc {
+						addr: rx.buf_addr(i),
+						length: 0,
+						checksum: 0,
+						status: 0,
+						errors: 0,
+						special: 0,
+					},
+				);
+			}
+		}
+		write(REG_RDBAL, rx.descs_addr() as u32);
+		write(REG_RDBAH, (rx.descs_addr() >> 32) as u32);
+		write(REG_RDLEN, (DESC_COUNT * size_of::<RxDesc>()) as _);
+		write(REG_RDH, 0);
+		// All descriptors but one are given to the controller, since head equal to tail means
+		// the ring is empty
+		write(REG_RDT, (DESC_COUNT - 1) as _);
+		write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+		// Transmit ring
+		let tx = Ring::new()?;
+		for i in 0..DESC_COUNT {
+			unsafe {
+				ptr::write_volatile(
+					tx.desc(i),
+					TxDesc {
+						addr: tx.buf_addr(i),
+						length: 0,
+						cso: 0,
+						cmd: 0,
+						// Mark the descriptor as available
+						status: DESC_DD,
+						css: 0,
+						special: 0,
+					},
+				);
+			}
+		}
+		write(REG_TDBAL, tx.descs_addr() as u32);
+		write(REG_TDBAH, (tx.descs_addr() >> 32) as u32);
+		write(REG_TDLEN, (DESC_COUNT * size_of::<TxDesc>()) as _);
+		write(REG_TDH, 0);
+		write(REG_TDT, 0);
+		write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+		write(REG_TIPG, TIPG);
+		Ok(Self {
+			name,
+			bar,
+			mac,
+			addresses: Vec::new(),
+			stats: IfaceStats::default(),
+			enabled: true,
+
+			rx,
+			tx,
+		})
+	}
+}
+
+/// Busy-waits until `f` returns `true`.
+///
+/// If the controller does not respond in time, the function returns [`errno::EIO`].
+fn wait<F: FnMut() -> bool>(mut f: F) -> EResult<()> {
+	for _ in 0..POLL_ITER {
+		if f() {
+			return Ok(());
+		}
+		hint::spin_loop();
+	}
+	Err(errno!(EIO))
+}
+
+/// Reads the word at `addr` in the controller's EEPROM.
+fn read_eeprom(bar: &BAR, addr: u8) -> EResult<u16> {
+	bar.write::<u32>(REG_EERD, (((addr as u32) << 8) | EERD_START) as _);
+	let mut val = 0;
+	wait(|| {
+		val = bar.read::<u32>(REG_EERD) as u32;
+		val & EERD_DONE != 0
+	})?;
+	Ok((val >> 16) as u16)
+}
+
+/// Reads the MAC address of the controller from its EEPROM.
+///
+/// If the EEPROM cannot be read, the function falls back to the address the firmware has
+/// programmed in the receive address registers.
+fn read_mac(bar: &BAR) -> EResult<MAC> {
+	let mut mac = [0; 6];
+	for (i, chunk) in mac.chunks_exact_mut(2).enumerate() {
+		match read_eeprom(bar, i as _) {
+			Ok(word) => chunk.copy_from_slice(&word.to_le_bytes()),
+			Err(_) => break,
+		}
+		if i == 2 {
+			return Ok(mac);
+		}
+	}
+	let ral = bar.read::<u32>(REG_RAL) as u32;
+	let rah = bar.read::<u32>(REG_RAH) as u32;
+	if rah & RAH_AV == 0 {
+		return Err(errno!(EIO));
+	}
+	mac[..4].copy_from_slice(&ral.to_le_bytes());
+	mac[4..].copy_from_slice(&(rah as u16).to_le_bytes());
+	Ok(mac)
+}
+
+impl Interface for E1000 {
+	fn get_name(&self) -> &[u8] {
+		&self.name
+	}
+
+	fn get_link_type(&self) -> LinkType {
+		LinkType::Ethernet
+	}
+
+	fn is_up(&self) -> bool {
+		self.enabled && self.bar.read::<u32>(REG_STATUS) as u32 & STATUS_LU != 0
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	fn get_mtu(&self) -> usize {
+		MTU
+	}
+
+	fn get_mac(&self) -> &MAC {
+		&self.mac
+	}
+
+	fn get_addresses(&self) -> &[BindAddress] {
+		&self.addresses
+	}
+
+	fn get_stats(&self) -> &IfaceStats {
+		&self.stats
+	}
+
+	fn add_address(&mut self, addr: BindAddress) -> AllocResult<()> {
+		self.addresses.push(addr)
+	}
+
+	fn remove_address(&mut self, addr: &Address) -> bool {
+		let len = self.addresses.len();
+		self.addresses.retain(|a| a.addr != *addr);
+		self.addresses.len() != len
+	}
+
+	fn read(&mut self, buff: &mut [u8]) -> EResult<u64> {
+		loop {
+			let i = self.rx.cur;
+			let desc = self.rx.desc::<RxDesc>(i);
+			let d = unsafe { ptr::read_volatile(desc) };
+			if d.status & DESC_DD == 0 {
+				return Ok(0);
+			}
+			// Frames spanning several descriptors or with errors are dropped
+			let valid = d.status & DESC_EOP != 0 && d.errors == 0;
+			let len = min(d.length as usize, min(buff.len(), BUF_SIZE));
+			if valid {
+				buff[..len].copy_from_slice(self.rx.bufs.slice(i * BUF_SIZE, len));
+				self.stats.received(len);
+			} else {
+				self.stats.rx_errors += 1;
+			}
+			// Give the descriptor back to the controller
+			unsafe {
+				ptr::write_volatile(ptr::addr_of_mut!((*desc).status), 0);
+			}
+			self.bar.write::<u32>(REG_RDT, i as _);
+			self.rx.cur = (i + 1) % DESC_COUNT;
+			if valid {
+				return Ok(len as _);
+			}
+		}
+	}
+
+	fn write(&mut self, buff: &BuffList<'_>) -> EResult<u64> {
+		let len = buff.len();
+		if len > BUF_SIZE {
+			return Err(errno!(EMSGSIZE));
+		}
+		let i = self.tx.cur;
+		let desc = self.tx.desc::<TxDesc>(i);
+		// If the ring is full, drop the frame
+		if unsafe { ptr::read_volatile(ptr::addr_of!((*desc).status)) } & DESC_DD == 0 {
+			self.stats.tx_dropped += 1;
+			return Ok(len as _);
+		}
+		let mut off = i * BUF_SIZE;
+		for b in buff.iter() {
+			self.tx.bufs.slice_mut(off, b.len()).copy_from_slice(b);
+			off += b.len();
+		}
+		unsafe {
+			ptr::write_volatile(
+				desc,
+				TxDesc {
+					addr: self.tx.buf_addr(i),
+					length: len as _,
+					cso: 0,
+					cmd: CMD_EOP | CMD_IFCS | CMD_RS,
+					status: 0,
+					css: 0,
+					special: 0,
+				},
+			);
+		}
+		self.tx.cur = (i + 1) % DESC_COUNT;
+		self.bar.write::<u32>(REG_TDT, self.tx.cur as _);
+		self.stats.transmitted(len);
+		Ok(len as _)
+	}
+}
+
+impl Drop for E1000 {
+	fn drop(&mut self) {
+		// Make sure the controller does not access the rings after they are freed
+		self.bar.write::<u32>(REG_IMC, u32::MAX as _);
+		let ctrl = self.bar.read::<u32>(REG_CTRL) as u32;
+		self.bar.write::<u32>(REG_CTRL, (ctrl | CTRL_RST) as _);
+		let _ = wait(|| self.bar.read::<u32>(REG_CTRL) as u32 & CTRL_RST == 0);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn e1000_desc_layout() {
+		assert_eq!(size_of::<RxDesc>(), 16);
+		assert_eq!(size_of::<TxDesc>(), 16);
+		// The length of a ring must be a multiple of 128 bytes
+		assert_eq!((DESC_COUNT * size_of::<RxDesc>()) % 128, 0);
+	}
+}
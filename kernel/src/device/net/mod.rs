@@ -0,0 +1,101 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Network interface controllers.
+//!
+//! Detected controllers are registered to the network stack as Ethernet interfaces named `ethN`,
+//! where `N` is the number of interfaces registered before.
+
+pub mod e1000;
+pub mod virtio;
+
+use crate::{
+	device::{
+		bus::pci,
+		manager::{BusLocation, DeviceManager, PhysicalDevice},
+	},
+	net,
+};
+use utils::{
+	collections::{string::String, vec::Vec},
+	errno::EResult,
+	format, TryClone,
+};
+
+/// The manager of network interface controllers.
+///
+/// The manager has name `net`.
+#[derive(Default)]
+pub struct NetManager {
+	/// The number of registered interfaces, used to name the next one.
+	count: u32,
+	/// The names of the registered interfaces, with the location of their controller.
+	ifaces: Vec<(BusLocation, String)>,
+}
+
+impl NetManager {
+	/// Creates a new instance.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Returns the name of the next interface to be registered.
+	fn next_name(&self) -> EResult<String> {
+		Ok(format!("eth{}", self.count)?)
+	}
+}
+
+impl DeviceManager for NetManager {
+	fn on_plug(&mut self, dev: &dyn PhysicalDevice) -> EResult<()> {
+		// Ignore non-network devices
+		if dev.get_class() != pci::CLASS_NETWORK_CONTROLLER {
+			return Ok(());
+		}
+		let name = self.next_name()?;
+		let res = match (dev.get_vendor_id(), dev.get_device_id()) {
+			(virtio::VENDOR_ID, virtio::DEVICE_ID) => {
+				virtio::VirtioNet::register(dev, name.try_clone()?)
+			}
+			(e1000::VENDOR_ID, id) if e1000::DEVICE_IDS.contains(&id) => {
+				e1000::E1000::register(dev, name.try_clone()?)
+			}
+			// Unsupported controller
+			_ => return Ok(()),
+		};
+		res?;
+		self.count += 1;
+		self.ifaces.push((dev.get_location(), name))?;
+		Ok(())
+	}
+
+	fn on_unplug(&mut self, dev: &dyn PhysicalDevice) -> EResult<()> {
+		// Remove every interface of the controller
+		let location = dev.get_location();
+		self.ifaces.retain(|(loc, name)| {
+			if *loc != location {
+				return true;
+			}
+			if let Some(iface) = net::unregister_iface(name) {
+				e1000::release(&iface);
+				virtio::release(&iface);
+			}
+			false
+		});
+		Ok(())
+	}
+}
@@ -0,0 +1,395 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Virtio network device driver.
+//!
+//! Received frames are passed up the network stack from the interrupt handler. Transmitted
+//! frames are put on the transmit queue without waiting for the device to process them.
+
+pub use crate::device::virtio::{DEVICE_ID_NET as DEVICE_ID, VENDOR_ID};
+use crate::{
+	arch::x86::{idt::IntFrame, pic},
+	device::{
+		manager::PhysicalDevice,
+		virtio::{Transport, Virtqueue, DESC_NEXT, DESC_WRITE, ISR_QUEUE},
+	},
+	event,
+	event::CallbackResult,
+	memory::dma::DmaBuffer,
+	net,
+	net::{buff::BuffList, Address, BindAddress, IfaceStats, Interface, LinkType, MAC},
+	sync::mutex::IntMutex,
+};
+use core::{cmp::min, mem::ManuallyDrop, ptr};
+use utils::{
+	collections::{string::String, vec::Vec},
+	errno,
+	errno::{AllocResult, CollectResult, EResult},
+	limits::PAGE_SIZE,
+	ptr::arc::Arc,
+	TryClone,
+};
+
+/// Feature: The device has a MAC address in its configuration
+const FEATURE_MAC: u32 = 1 << 5;
+/// Feature: The device reports the link status in its configuration
+const FEATURE_STATUS: u32 = 1 << 16;
+
+/// Configuration: The offset of the MAC address
+const CONFIG_MAC: usize = 0x0;
+/// Configuration: The offset of the link status
+const CONFIG_STATUS: usize = 0x6;
+/// Link status: The link is up
+const STATUS_LINK_UP: u16 = 1;
+
+/// The index of the receive queue.
+const RX_QUEUE: u16 = 0;
+/// The index of the transmit queue.
+const TX_QUEUE: u16 = 1;
+
+/// The size of the header preceding each frame.
+const HDR_SIZE: usize = 10;
+/// The size of the buffer of a slot.
+const SLOT_SIZE: usize = 2048;
+/// The offset of the frame in the buffer of a slot.
+const FRAME_OFF: usize = 16;
+/// The maximum number of slots per queue.
+const MAX_SLOTS: u16 = 64;
+
+/// The interface's Maximum Transmission Unit.
+const MTU: usize = 1500;
+
+/// A queue along with the buffers of its slots.
+///
+/// Each slot uses two chained descriptors: one for the header and one for the frame. Thus, the
+/// head of the chain of slot `n` is the descriptor `2 * n`.
+#[derive(Debug)]
+struct Ring {
+	/// The virtqueue.
+	queue: Virtqueue,
+	/// The buffers of the slots.
+	bufs: DmaBuffer,
+	/// The number of slots.
+	slots: u16,
+}
+
+impl Ring {
+	/// Sets up the queue `index`.
+	///
+	/// `write` tells whether the buffers are written by the device.
+	fn new(transport: &Transport, index: u16, write: bool) -> EResult<Self> {
+		let mut queue = Virtqueue::new(transport, index)?;
+		let slots = min(queue.size() / 2, MAX_SLOTS);
+		if slots == 0 {
+			return Err(errno!(ENODEV));
+		}
+		let bufs = DmaBuffer::new((slots as usize * SLOT_SIZE).div_ceil(PAGE_SIZE))?;
+		let flags = if write { DESC_WRITE } else { 0 };
+		for slot in 0..slots {
+			let off = slot as usize * SLOT_SIZE;
+			let head = slot * 2;
+			queue.set_desc(
+				head,
+				bufs.phys_addr(off).0 as _,
+				HDR_SIZE as _,
+				flags | DESC_NEXT,
+				head + 1,
+			);
+			queue.set_desc(
+				head + 1,
+				bufs.phys_addr(off + FRAME_OFF).0 as _,
+				(SLOT_SIZE - FRAME_OFF) as _,
+				flags,
+				0,
+			);
+		}
+		Ok(Self {
+			queue,
+			bufs,
+			slots,
+		})
+	}
+}
+
+/// An interrupt line used by a virtio network device.
+struct Irq {
+	/// The IRQ number.
+	line: u8,
+	/// The transport of the device, to acknowledge interrupts.
+	transport: Transport,
+	/// The interface.
+	iface: Arc<IntMutex<dyn Interface>>,
+}
+
+/// The list of devices waiting for interrupts.
+static IRQS: IntMutex<Vec<Irq>> = IntMutex::new(Vec::new());
+
+/// Stops handling interrupts for `iface`, if it is a virtio network device.
+///
+/// This releases the reference held for interrupt handling, so that the driver can be freed once
+/// the interface is unregistered.
+pub(super) fn release(iface: &Arc<IntMutex<dyn Interface>>) {
+	IRQS.lock()
+		.retain(|irq| !ptr::addr_eq(Arc::as_ptr(&irq.iface), Arc::as_ptr(iface)));
+}
+
+/// Handles an interrupt for devices on the line corresponding to the vector `id`.
+fn interrupt_handler(id: u32, _code: u32, _frame: &mut IntFrame, _ring: u8) -> CallbackResult {
+	let line = id - 0x20;
+	let irqs = IRQS.lock();
+	for irq in irqs.iter().filter(|irq| irq.line as u32 == line) {
+		// Reading the status deasserts the interrupt
+		if irq.transport.interrupt_status() & ISR_QUEUE != 0 {
+			// Frames that cannot be processed are dropped
+			let _ = net::receive(&irq.iface);
+		}
+	}
+	CallbackResult::Continue
+}
+
+/// A virtio network device.
+pub struct VirtioNet {
+	/// The name of the interface.
+	name: String,
+	/// The transport of the device.
+	transport: Transport,
+	/// Tells whether the device reports the link status.
+	has_status: bool,
+	/// The MAC address of the device.
+	mac: MAC,
+	/// The addresses bound to the interface.
+	addresses: Vec<BindAddress>,
+	/// Traffic counters.
+	stats: IfaceStats,
+	/// Tells whether the interface has been brought up by the administrator.
+	enabled: bool,
+
+	/// The receive queue.
+	rx: Ring,
+	/// The transmit queue.
+	tx: Ring,
+	/// The slots of the transmit queue that are not in use by the device.
+	tx_free: Vec<u16>,
+}
+
+impl VirtioNet {
+	/// Initializes the device `dev` and registers it as the network interface `name`.
+	pub fn register(dev: &dyn PhysicalDevice, name: String) -> EResult<()> {
+		let line = dev.get_interrupt_line().ok_or_else(|| errno!(ENODEV))?;
+		let (transport, features) =
+			Transport::new(dev, FEATURE_MAC | FEATURE_STATUS).ok_or_else(|| errno!(ENODEV))?;
+		let iface = Self::new(dev, transport.clone(), features, name.try_clone()?)
+			.inspect_err(|_| transport.fail())?;
+		net::register_iface(name.try_clone()?, iface)?;
+		let iface = net::get_iface(&name).ok_or_else(|| errno!(ENODEV))?;
+		let mut irqs = IRQS.lock();
+		if !irqs.iter().any(|irq| irq.line == line) {
+			let hook = event::register_callback(0x20 + line as u32, interrupt_handler)?;
+			let _ = ManuallyDrop::new(hook);
+			if line >= 8 {
+				// Cascade
+				pic::enable_irq(2);
+			}
+			pic::enable_irq(line);
+		}
+		irqs.push(Irq {
+			line,
+			transport,
+			iface,
+		})?;
+		Ok(())
+	}
+
+	/// Sets up the queues of the device and tells it the driver is ready.
+	fn new(
+		dev: &dyn PhysicalDevice,
+		transport: Transport,
+		features: u32,
+		name: String,
+	) -> EResult<Self> {
+		dev.enable_bus_mastering();
+		let mac = if features & FEATURE_MAC != 0 {
+			let mut mac = [0; 6];
+			for (i, b) in mac.iter_mut().enumerate() {
+				*b = transport.read_config_u8(CONFIG_MAC + i);
+			}
+			mac
+		} else {
+			Self::fallback_mac(&name)
+		};
+		let mut rx = Ring::new(&transport, RX_QUEUE, true)?;
+		let tx = Ring::new(&transport, TX_QUEUE, false)?;
+		// Give all receive buffers to the device
+		for slot in 0..rx.slots {
+			rx.queue.push_avail(slot * 2);
+		}
+		let tx_free = (0..tx.slots).rev().collect::<CollectResult<_>>().0?;
+		transport.ready();
+		transport.notify(RX_QUEUE);
+		Ok(Self {
+			name,
+			transport,
+			has_status: features & FEATURE_STATUS != 0,
+			mac,
+			addresses: Vec::new(),
+			stats: IfaceStats::default(),
+			enabled: true,
+
+			rx,
+			tx,
+			tx_free,
+		})
+	}
+
+	/// Returns a locally administered MAC address for an interface that does not provide one.
+	///
+	/// The address is derived from the name of the interface, so that it is unique on the system.
+	fn fallback_mac(name: &[u8]) -> MAC {
+		let mut mac = [0x02, 0, 0, 0, 0, 0];
+		for (i, b) in name.iter().enumerate() {
+			mac[2 + i % 4] ^= *b;
+		}
+		mac
+	}
+}
+
+impl Interface for VirtioNet {
+	fn get_name(&self) -> &[u8] {
+		&self.name
+	}
+
+	fn get_link_type(&self) -> LinkType {
+		LinkType::Ethernet
+	}
+
+	fn is_up(&self) -> bool {
+		self.enabled
+			&& (!self.has_status
+				|| self.transport.read_config_u16(CONFIG_STATUS) & STATUS_LINK_UP != 0)
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	fn get_mtu(&self) -> usize {
+		MTU
+	}
+
+	fn get_mac(&self) -> &MAC {
+		&self.mac
+	}
+
+	fn get_addresses(&self) -> &[BindAddress] {
+		&self.addresses
+	}
+
+	fn get_stats(&self) -> &IfaceStats {
+		&self.stats
+	}
+
+	fn add_address(&mut self, addr: BindAddress) -> AllocResult<()> {
+		self.addresses.push(addr)
+	}
+
+	fn remove_address(&mut self, addr: &Address) -> bool {
+		let len = self.addresses.len();
+		self.addresses.retain(|a| a.addr != *addr);
+		self.addresses.len() != len
+	}
+
+	fn read(&mut self, buff: &mut [u8]) -> EResult<u64> {
+		let Some((head, len)) = self.rx.queue.pop_used() else {
+			return Ok(0);
+		};
+		let slot = (head / 2) as usize;
+		let len = (len as usize).saturating_sub(HDR_SIZE);
+		let len = min(len, min(buff.len(), SLOT_SIZE - FRAME_OFF));
+		let frame = self.rx.bufs.slice(slot * SLOT_SIZE + FRAME_OFF, len);
+		buff[..len].copy_from_slice(frame);
+		self.stats.received(len);
+		// Give the buffer back to the device
+		self.rx.queue.push_avail(head);
+		self.transport.notify(RX_QUEUE);
+		Ok(len as _)
+	}
+
+	fn write(&mut self, buff: &BuffList<'_>) -> EResult<u64> {
+		let len = buff.len();
+		if len > SLOT_SIZE - FRAME_OFF {
+			return Err(errno!(EMSGSIZE));
+		}
+		// Reclaim the slots the device is done with
+		while let Some((head, _)) = self.tx.queue.pop_used() {
+			self.tx_free.push(head / 2)?;
+		}
+		// If the queue is full, drop the frame
+		let Some(slot) = self.tx_free.pop() else {
+			self.stats.tx_dropped += 1;
+			return Ok(len as _);
+		};
+		let off = slot as usize * SLOT_SIZE;
+		// No offloading is used, so the header is zero
+		self.tx.bufs.slice_mut(off, HDR_SIZE).fill(0);
+		let mut frame_off = off + FRAME_OFF;
+		for b in buff.iter() {
+			self.tx
+				.bufs
+				.slice_mut(frame_off, b.len())
+				.copy_from_slice(b);
+			frame_off += b.len();
+		}
+		let head = slot * 2;
+		self.tx.queue.set_desc(
+			head + 1,
+			self.tx.bufs.phys_addr(off + FRAME_OFF).0 as _,
+			len as _,
+			0,
+			0,
+		);
+		self.tx.queue.push_avail(head);
+		self.transport.notify(TX_QUEUE);
+		self.stats.transmitted(len);
+		Ok(len as _)
+	}
+}
+
+impl Drop for VirtioNet {
+	fn drop(&mut self) {
+		// Make sure the device does not access the queues after they are freed
+		self.transport.reset();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn virtio_net_fallback_mac() {
+		let mac0 = VirtioNet::fallback_mac(b"eth0");
+		let mac1 = VirtioNet::fallback_mac(b"eth1");
+		assert_ne!(mac0, mac1);
+		// Locally administered, unicast
+		assert_eq!(mac0[0] & 0b11, 0b10);
+	}
+}
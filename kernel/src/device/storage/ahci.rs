@@ -0,0 +1,922 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The Advanced Host Controller Interface (AHCI) is the standard interface of SATA controllers.
+//!
+//! The controller, called HBA (Host Bus Adapter), exposes its registers through the memory
+//! space BAR 5 (ABAR). It has up to 32 ports, each of which may have a drive attached.
+//!
+//! Commands are sent to a port through a list of command slots in memory. Each slot points to a
+//! command table, containing the FIS (Frame Information Structure) to send to the drive and the
+//! list of physical memory regions to use for the transfer.
+//!
+//! When both the controller and the drive support it, Native Command Queuing (NCQ) allows
+//! several commands to be in flight at once on the same port.
+
+use crate::{
+	arch::x86::{idt::IntFrame, pic},
+	device::{bar::BAR, bus::pci, manager::PhysicalDevice, DeviceIO},
+	event,
+	event::CallbackResult,
+	file::wait_queue::WaitQueue,
+	memory::dma::DmaBuffer,
+	process::scheduler,
+	sync::mutex::IntMutex,
+	time::{
+		clock,
+		clock::CLOCK_MONOTONIC,
+		unit::{Timestamp, TimestampScale},
+	},
+};
+use core::{
+	cmp::min,
+	hint,
+	mem::{size_of, ManuallyDrop},
+	num::NonZeroU64,
+	ptr,
+	sync::atomic::{fence, Ordering::SeqCst},
+};
+use utils::{collections::vec::Vec, errno, errno::EResult, limits::PAGE_SIZE, ptr::arc::Arc};
+
+/// The PCI subclass of SATA controllers.
+const SUBCLASS_SATA: u16 = 0x06;
+/// The PCI programming interface of AHCI controllers.
+const PROG_IF_AHCI: u8 = 0x01;
+
+/// Register: Host Capabilities.
+const REG_CAP: usize = 0x00;
+/// Register: Global Host Control.
+const REG_GHC: usize = 0x04;
+/// Register: Interrupt Status.
+const REG_IS: usize = 0x08;
+/// Register: Ports Implemented.
+const REG_PI: usize = 0x0c;
+
+/// The offset of the registers of the first port.
+const PORT_BASE: usize = 0x100;
+/// The size of the registers of a port.
+const PORT_SIZE: usize = 0x80;
+
+/// Port register: Command List Base Address.
+const PORT_CLB: usize = 0x00;
+/// Port register: Command List Base Address Upper 32-bits.
+const PORT_CLBU: usize = 0x04;
+/// Port register: FIS Base Address.
+const PORT_FB: usize = 0x08;
+/// Port register: FIS Base Address Upper 32-bits.
+const PORT_FBU: usize = 0x0c;
+/// Port register: Interrupt Status.
+const PORT_IS: usize = 0x10;
+/// Port register: Interrupt Enable.
+const PORT_IE: usize = 0x14;
+/// Port register: Command and Status.
+const PORT_CMD: usize = 0x18;
+/// Port register: Task File Data.
+const PORT_TFD: usize = 0x20;
+/// Port register: Signature.
+const PORT_SIG: usize = 0x24;
+/// Port register: SATA Status.
+const PORT_SSTS: usize = 0x28;
+/// Port register: SATA Control.
+const PORT_SCTL: usize = 0x2c;
+/// Port register: SATA Error.
+const PORT_SERR: usize = 0x30;
+/// Port register: SATA Active, the set of queued commands in flight.
+const PORT_SACT: usize = 0x34;
+/// Port register: Command Issue.
+const PORT_CI: usize = 0x38;
+
+/// Capability: Supports Native Command Queuing.
+const CAP_SNCQ: u32 = 1 << 30;
+/// Global control: Interrupt Enable.
+const GHC_IE: u32 = 1 << 1;
+/// Global control: AHCI Enable.
+const GHC_AE: u32 = 1 << 31;
+
+/// Port command: Start.
+const CMD_ST: u32 = 1 << 0;
+/// Port command: Spin-Up Device.
+const CMD_SUD: u32 = 1 << 1;
+/// Port command: Power On Device.
+const CMD_POD: u32 = 1 << 2;
+/// Port command: FIS Receive Enable.
+const CMD_FRE: u32 = 1 << 4;
+/// Port command: FIS Receive Running.
+const CMD_FR: u32 = 1 << 14;
+/// Port command: Command List Running.
+const CMD_CR: u32 = 1 << 15;
+
+/// Port interrupt: Device to Host Register FIS received.
+const INT_DHRS: u32 = 1 << 0;
+/// Port interrupt: PIO Setup FIS received.
+const INT_PSS: u32 = 1 << 1;
+/// Port interrupt: DMA Setup FIS received.
+const INT_DSS: u32 = 1 << 2;
+/// Port interrupt: Set Device Bits FIS received, on completion of queued commands.
+const INT_SDBS: u32 = 1 << 3;
+/// Port interrupt: Interface Fatal Error.
+const INT_IFS: u32 = 1 << 27;
+/// Port interrupt: Host Bus Data Error.
+const INT_HBDS: u32 = 1 << 28;
+/// Port interrupt: Host Bus Fatal Error.
+const INT_HBFS: u32 = 1 << 29;
+/// Port interrupt: Task File Error.
+const INT_TFES: u32 = 1 << 30;
+/// The set of port interrupts reporting an error.
+const INT_ERRORS: u32 = INT_IFS | INT_HBDS | INT_HBFS | INT_TFES;
+
+/// Task file status: the drive is busy.
+const TFD_BSY: u32 = 1 << 7;
+/// Task file status: the drive is waiting for a data transfer.
+const TFD_DRQ: u32 = 1 << 3;
+
+/// Mask of the device detection field of the SATA status and control registers.
+const DET_MASK: u32 = 0xf;
+/// SATA status: a drive is present and communication is established.
+const SSTS_DET_PRESENT: u32 = 3;
+/// SATA control: perform interface initialization (COMRESET).
+const SCTL_DET_INIT: u32 = 1;
+
+/// The signature of ATA drives.
+const SIG_ATA: u32 = 0x00000101;
+
+/// FIS type: Register, Host to Device.
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// FIS flag: the FIS is a command.
+const FIS_COMMAND: u8 = 0x80;
+/// Device register: the address is an LBA.
+const DEVICE_LBA: u8 = 0x40;
+
+/// Command header flag: the command writes to the drive.
+const HEADER_WRITE: u16 = 1 << 6;
+/// Physical region descriptor flag: interrupt on completion.
+const PRD_INTERRUPT: u32 = 1 << 31;
+
+/// Reads sectors with LBA28, with DMA.
+const COMMAND_READ_DMA: u8 = 0xc8;
+/// Reads sectors with LBA48, with DMA.
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+/// Reads sectors with NCQ.
+const COMMAND_READ_FPDMA_QUEUED: u8 = 0x60;
+/// Writes sectors with LBA28, with DMA.
+const COMMAND_WRITE_DMA: u8 = 0xca;
+/// Writes sectors with LBA48, with DMA.
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+/// Writes sectors with NCQ.
+const COMMAND_WRITE_FPDMA_QUEUED: u8 = 0x61;
+/// Flushes the drive's write cache, with LBA28.
+const COMMAND_FLUSH_CACHE: u8 = 0xe7;
+/// Flushes the drive's write cache, with LBA48.
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xea;
+/// Identifies the drive.
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// The maximum number of command slots used on a port.
+const MAX_SLOTS: usize = 8;
+/// The number of pages of the bounce buffer of a command slot.
+const SLOT_BUF_PAGES: usize = 4;
+/// The size of the bounce buffer of a command slot, in bytes. This is the maximum size of a
+/// transfer with a single command.
+const SLOT_BUF_SIZE: usize = SLOT_BUF_PAGES * PAGE_SIZE;
+
+/// The offset of the command list in the memory of a port.
+const CMD_LIST_OFF: usize = 0;
+/// The offset of the received FIS area in the memory of a port.
+const FIS_OFF: usize = 1024;
+/// The offset of the command tables in the memory of a port.
+const TABLES_OFF: usize = PAGE_SIZE;
+/// The size of a command table, holding a single physical region descriptor.
+const TABLE_SIZE: usize = 256;
+/// The offset of the physical region descriptor table in a command table.
+const PRDT_OFF: usize = 0x80;
+/// The number of pages of the memory of a port.
+const MEM_PAGES: usize = 1 + (MAX_SLOTS * TABLE_SIZE).div_ceil(PAGE_SIZE);
+
+/// The maximum number of iterations when busy-waiting for the controller.
+const POLL_ITER: usize = 100000;
+/// The maximum number of iterations when busy-waiting for the completion of a command.
+const COMMAND_POLL_ITER: usize = POLL_ITER * 100;
+/// The timeout for the completion of a command when sleeping, in milliseconds.
+const COMMAND_TIMEOUT: Timestamp = 10000;
+/// The maximum duration of a single sleep while waiting for a command, in milliseconds.
+///
+/// This bounds the delay in case a wakeup is missed.
+const SLEEP_SLICE: Timestamp = 10;
+
+/// Busy-waits until `f` returns `true`.
+///
+/// If the controller does not respond in time, the function returns [`errno::EIO`].
+fn wait<F: FnMut() -> bool>(mut f: F) -> EResult<()> {
+	for _ in 0..POLL_ITER {
+		if f() {
+			return Ok(());
+		}
+		hint::spin_loop();
+	}
+	Err(errno!(EIO))
+}
+
+/// A command header, in the command list of a port.
+#[repr(C)]
+struct CommandHeader {
+	/// The length of the command FIS in dwords, along with flags.
+	flags: u16,
+	/// The number of entries in the physical region descriptor table.
+	prdtl: u16,
+	/// The number of bytes transferred, updated by the controller.
+	prdbc: u32,
+	/// The physical address of the command table.
+	ctba: u64,
+	/// Reserved.
+	_reserved: [u32; 4],
+}
+
+/// A physical region descriptor, describing a buffer used for a transfer.
+#[repr(C)]
+struct Prd {
+	/// The physical address of the buffer.
+	dba: u64,
+	/// Reserved.
+	_reserved: u32,
+	/// The size of the buffer minus one, along with flags.
+	dbc: u32,
+}
+
+/// A Register FIS, sent by the host to issue a command to the drive.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct FisRegH2D {
+	/// The type of the FIS.
+	fis_type: u8,
+	/// Port multiplier and command flag.
+	flags: u8,
+	/// The command.
+	command: u8,
+	/// Features register, low byte.
+	feature_lo: u8,
+
+	/// LBA, bits 0 to 23.
+	lba_lo: [u8; 3],
+	/// Device register.
+	device: u8,
+
+	/// LBA, bits 24 to 47.
+	lba_hi: [u8; 3],
+	/// Features register, high byte.
+	feature_hi: u8,
+
+	/// Sectors count.
+	count: [u8; 2],
+	/// Isochronous command completion.
+	icc: u8,
+	/// Control register.
+	control: u8,
+
+	/// Reserved.
+	_reserved: [u8; 4],
+}
+
+impl FisRegH2D {
+	/// Creates a FIS for the command `command`, on `count` sectors at `lba`.
+	fn new(command: u8, lba: u64, count: u16) -> Self {
+		let lba = lba.to_le_bytes();
+		Self {
+			fis_type: FIS_TYPE_REG_H2D,
+			flags: FIS_COMMAND,
+			command,
+			lba_lo: [lba[0], lba[1], lba[2]],
+			device: DEVICE_LBA,
+			lba_hi: [lba[3], lba[4], lba[5]],
+			count: count.to_le_bytes(),
+			..Default::default()
+		}
+	}
+}
+
+/// The data buffer of a command.
+enum Data<'a> {
+	/// The command does not transfer data.
+	None,
+	/// Data is read from the drive to the buffer.
+	In(&'a mut [u8]),
+	/// Data is written from the buffer to the drive.
+	Out(&'a [u8]),
+}
+
+/// The state of the command slots of a port. Each field is a bitmap of slots.
+#[derive(Default)]
+struct Slots {
+	/// The slots the driver may use.
+	usable: u32,
+	/// The slots reserved by a command.
+	allocated: u32,
+	/// The slots issued to the controller, whose command has not completed yet.
+	issued: u32,
+	/// The slots whose command completed successfully.
+	done: u32,
+	/// The slots whose command failed.
+	failed: u32,
+}
+
+/// Returns the offset of the register `reg` of the port `index`.
+#[inline]
+fn port_reg(index: usize, reg: usize) -> usize {
+	PORT_BASE + index * PORT_SIZE + reg
+}
+
+/// A port of an AHCI controller, with an ATA drive attached.
+pub struct Port {
+	/// The BAR of the controller.
+	bar: BAR,
+	/// The index of the port on the controller.
+	index: usize,
+	/// The command list, the received FIS area and the command tables.
+	mem: DmaBuffer,
+	/// The bounce buffers used for transfers, one for each slot.
+	bufs: DmaBuffer,
+	/// The state of command slots.
+	slots: IntMutex<Slots>,
+	/// The queue of processes waiting for a slot or for the completion of a command.
+	queue: WaitQueue,
+
+	/// The size of a sector in bytes.
+	sector_size: u64,
+	/// The number of sectors on the drive.
+	sectors_count: u64,
+	/// Tells whether the drive supports LBA48.
+	lba48: bool,
+	/// Tells whether commands are sent with NCQ.
+	ncq: bool,
+}
+
+impl Port {
+	/// Initializes the port `index` of the controller.
+	///
+	/// `ncq_slots` is the number of command slots the controller supports for NCQ.
+	///
+	/// If no ATA drive is attached to the port, the function returns `None`.
+	fn new(bar: BAR, index: usize, ncq_slots: usize) -> EResult<Option<Self>> {
+		let ssts = bar.read::<u32>(port_reg(index, PORT_SSTS)) as u32;
+		if ssts & DET_MASK != SSTS_DET_PRESENT {
+			return Ok(None);
+		}
+		let mut port = Self {
+			bar,
+			index,
+			mem: DmaBuffer::new(MEM_PAGES)?,
+			bufs: DmaBuffer::new(ncq_slots * SLOT_BUF_PAGES)?,
+			slots: IntMutex::new(Slots {
+				usable: 1,
+				..Default::default()
+			}),
+			queue: WaitQueue::new(),
+
+			sector_size: 512,
+			sectors_count: 0,
+			lba48: false,
+			ncq: false,
+		};
+		port.stop()?;
+		let clb = port.mem.phys_addr(CMD_LIST_OFF).0 as u64;
+		port.write(PORT_CLB, clb as _);
+		port.write(PORT_CLBU, (clb >> 32) as _);
+		let fb = port.mem.phys_addr(FIS_OFF).0 as u64;
+		port.write(PORT_FB, fb as _);
+		port.write(PORT_FBU, (fb >> 32) as _);
+		port.write(PORT_SERR, u32::MAX);
+		port.write(PORT_IS, u32::MAX);
+		port.write(PORT_CMD, port.read(PORT_CMD) | CMD_SUD | CMD_POD);
+		port.start()?;
+		if port.read(PORT_SIG) != SIG_ATA {
+			return Ok(None);
+		}
+		port.identify(ncq_slots)?;
+		Ok(Some(port))
+	}
+
+	/// Reads the port register `reg`.
+	#[inline]
+	fn read(&self, reg: usize) -> u32 {
+		self.bar.read::<u32>(port_reg(self.index, reg)) as u32
+	}
+
+	/// Writes the port register `reg`.
+	#[inline]
+	fn write(&self, reg: usize, val: u32) {
+		self.bar.write::<u32>(port_reg(self.index, reg), val as _);
+	}
+
+	/// Stops the command engine and FIS reception on the port.
+	///
+	/// This clears the set of issued commands.
+	fn stop(&self) -> EResult<()> {
+		self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_ST);
+		wait(|| self.read(PORT_CMD) & CMD_CR == 0)?;
+		self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_FRE);
+		wait(|| self.read(PORT_CMD) & CMD_FR == 0)
+	}
+
+	/// Starts FIS reception and the command engine on the port.
+	fn start(&self) -> EResult<()> {
+		self.write(PORT_CMD, self.read(PORT_CMD) | CMD_FRE);
+		// The command engine must not be started while the drive is busy
+		wait(|| self.read(PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+		self.write(PORT_CMD, self.read(PORT_CMD) | CMD_ST);
+		Ok(())
+	}
+
+	/// Resets the link with the drive (COMRESET), while the port is stopped.
+	fn reset(&self) -> EResult<()> {
+		let sctl = self.read(PORT_SCTL) & !DET_MASK;
+		self.write(PORT_SCTL, sctl | SCTL_DET_INIT);
+		// The reset must be held for at least 1 millisecond. An access to a register takes more
+		// than 100 nanoseconds
+		for _ in 0..10000 {
+			self.read(PORT_SSTS);
+		}
+		self.write(PORT_SCTL, sctl);
+		wait(|| self.read(PORT_SSTS) & DET_MASK == SSTS_DET_PRESENT)?;
+		self.write(PORT_SERR, u32::MAX);
+		Ok(())
+	}
+
+	/// Enables interrupts on the port.
+	fn enable_interrupts(&self) {
+		self.write(
+			PORT_IE,
+			INT_DHRS | INT_PSS | INT_DSS | INT_SDBS | INT_ERRORS,
+		);
+	}
+
+	/// Restarts the port after an error, failing all issued commands.
+	fn recover(&self, slots: &mut Slots) {
+		// If the port cannot be restarted, subsequent commands time out
+		let _ = self.stop();
+		self.write(PORT_SERR, u32::MAX);
+		self.write(PORT_IS, u32::MAX);
+		if self.read(PORT_TFD) & (TFD_BSY | TFD_DRQ) != 0 {
+			let _ = self.reset();
+		}
+		let _ = self.start();
+		slots.failed |= slots.issued;
+		slots.issued = 0;
+	}
+
+	/// Acknowledges interrupts on the port and updates the state of slots.
+	fn update(&self, slots: &mut Slots) {
+		let is = self.read(PORT_IS);
+		self.write(PORT_IS, is);
+		self.bar.write::<u32>(REG_IS, 1 << self.index);
+		if is & INT_ERRORS != 0 {
+			self.recover(slots);
+			return;
+		}
+		let active = self.read(PORT_CI) | self.read(PORT_SACT);
+		let done = slots.issued & !active;
+		slots.issued &= !done;
+		slots.done |= done;
+	}
+
+	/// Waits until `f` returns `Some`, updating the state of slots before each call.
+	///
+	/// The current process sleeps if possible. Completions are signaled by interrupts, but the
+	/// state is also checked periodically in case the controller has no interrupt line.
+	///
+	/// If the drive does not respond in time, the port is restarted, which fails all issued
+	/// commands. Then, if `f` still returns `None`, the function returns [`errno::EIO`].
+	fn wait_for<F: FnMut(&mut Slots) -> Option<T>, T>(&self, mut f: F) -> EResult<T> {
+		let mut check = || {
+			let mut slots = self.slots.lock();
+			self.update(&mut slots);
+			f(&mut slots)
+		};
+		if scheduler::can_sleep() {
+			let deadline = clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond)?
+				+ COMMAND_TIMEOUT;
+			loop {
+				let now = clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond)?;
+				if now >= deadline {
+					break;
+				}
+				let timeout = min(deadline - now, SLEEP_SLICE);
+				// Signals are ignored since the command is being executed anyway
+				if let Ok(val) = self.queue.wait_until_timeout(Some(timeout), &mut check) {
+					return Ok(val);
+				}
+			}
+		} else {
+			for _ in 0..COMMAND_POLL_ITER {
+				if let Some(val) = check() {
+					return Ok(val);
+				}
+				hint::spin_loop();
+			}
+		}
+		let mut slots = self.slots.lock();
+		self.recover(&mut slots);
+		f(&mut slots).ok_or_else(|| errno!(EIO))
+	}
+
+	/// Executes the command described by `fis`, transferring `data`.
+	///
+	/// If `queued` is set, the command is sent with NCQ and the function sets its tag in `fis`.
+	/// Else, the command waits for all other commands to complete, since the drive does not
+	/// accept other commands while queued commands are in flight.
+	fn exec(&self, mut fis: FisRegH2D, data: Data, queued: bool) -> EResult<()> {
+		let slot = self.wait_for(|slots| {
+			if queued {
+				let free = slots.usable & !slots.allocated;
+				(free != 0).then(|| {
+					let slot = free.trailing_zeros() as usize;
+					slots.allocated |= 1 << slot;
+					slot
+				})
+			} else {
+				(slots.allocated == 0).then(|| {
+					slots.allocated = slots.usable;
+					0
+				})
+			}
+		})?;
+		if queued {
+			// The tag is given in the count field
+			fis.count[0] = (slot as u8) << 3;
+		}
+		let res = self.exec_slot(slot, fis, data, queued);
+		{
+			let mut slots = self.slots.lock();
+			if queued {
+				slots.allocated &= !(1 << slot);
+			} else {
+				slots.allocated = 0;
+			}
+		}
+		self.queue.wake_all();
+		res
+	}
+
+	/// Executes the command described by `fis` on the reserved slot `slot`.
+	fn exec_slot(&self, slot: usize, fis: FisRegH2D, data: Data, queued: bool) -> EResult<()> {
+		let (len, write) = match &data {
+			Data::None => (0, false),
+			Data::In(buf) => (buf.len(), false),
+			Data::Out(buf) => (buf.len(), true),
+		};
+		debug_assert!(len <= SLOT_BUF_SIZE);
+		let buf_off = slot * SLOT_BUF_SIZE;
+		let table_off = TABLES_OFF + slot * TABLE_SIZE;
+		let mut flags = (size_of::<FisRegH2D>() / size_of::<u32>()) as u16;
+		if write {
+			flags |= HEADER_WRITE;
+		}
+		unsafe {
+			if let Data::Out(buf) = &data {
+				ptr::copy_nonoverlapping(buf.as_ptr(), self.bufs.as_ptr(buf_off), len);
+			}
+			ptr::write_volatile(self.mem.as_ptr(table_off) as *mut FisRegH2D, fis);
+			ptr::write_volatile(
+				self.mem.as_ptr(table_off + PRDT_OFF) as *mut Prd,
+				Prd {
+					dba: self.bufs.phys_addr(buf_off).0 as _,
+					_reserved: 0,
+					dbc: len.saturating_sub(1) as u32 | PRD_INTERRUPT,
+				},
+			);
+			ptr::write_volatile(
+				self.mem
+					.as_ptr(CMD_LIST_OFF + slot * size_of::<CommandHeader>())
+					as *mut CommandHeader,
+				CommandHeader {
+					flags,
+					prdtl: (len > 0) as u16,
+					prdbc: 0,
+					ctba: self.mem.phys_addr(table_off).0 as _,
+					_reserved: [0; 4],
+				},
+			);
+		}
+		// Make sure the command is in memory before issuing it
+		fence(SeqCst);
+		{
+			let mut slots = self.slots.lock();
+			if queued {
+				self.write(PORT_SACT, 1 << slot);
+			}
+			self.write(PORT_CI, 1 << slot);
+			slots.issued |= 1 << slot;
+		}
+		let success = self.wait_for(|slots| {
+			let bit = 1 << slot;
+			if slots.done & bit != 0 {
+				slots.done &= !bit;
+				Some(true)
+			} else if slots.failed & bit != 0 {
+				slots.failed &= !bit;
+				Some(false)
+			} else {
+				None
+			}
+		})?;
+		if !success {
+			return Err(errno!(EIO));
+		}
+		fence(SeqCst);
+		if let Data::In(buf) = data {
+			unsafe {
+				ptr::copy_nonoverlapping(self.bufs.as_ptr(buf_off), buf.as_mut_ptr(), len);
+			}
+		}
+		Ok(())
+	}
+
+	/// Identifies the drive to retrieve its properties.
+	///
+	/// `ncq_slots` is the number of command slots the controller supports for NCQ.
+	fn identify(&mut self, ncq_slots: usize) -> EResult<()> {
+		let mut buf = [0u8; 512];
+		let mut fis = FisRegH2D::new(COMMAND_IDENTIFY, 0, 0);
+		fis.device = 0;
+		self.exec(fis, Data::In(&mut buf), false)?;
+		let word = |i: usize| u16::from_le_bytes([buf[i * 2], buf[i * 2 + 1]]) as u64;
+		self.lba48 = word(83) & (1 << 10) != 0;
+		self.sectors_count = if self.lba48 {
+			word(100) | (word(101) << 16) | (word(102) << 32) | (word(103) << 48)
+		} else {
+			word(60) | (word(61) << 16)
+		};
+		// Logical sectors larger than 512 bytes
+		if word(106) & 0xc000 == 0x4000 && word(106) & (1 << 12) != 0 {
+			// The size is given in words
+			self.sector_size = (word(117) | (word(118) << 16)) * 2;
+		}
+		if !self.sector_size.is_power_of_two() || self.sector_size > SLOT_BUF_SIZE as u64 {
+			return Err(errno!(EIO));
+		}
+		// NCQ requires LBA48 addressing
+		if ncq_slots > 1 && self.lba48 && word(76) & (1 << 8) != 0 {
+			let depth = (word(75) & 0x1f) as usize + 1;
+			let count = min(ncq_slots, depth);
+			if count > 1 {
+				self.ncq = true;
+				self.slots.lock().usable = (1 << count) - 1;
+			}
+		}
+		Ok(())
+	}
+
+	/// Reads from or writes to the drive, starting at the sector `lba`.
+	///
+	/// The length of the buffer must be a multiple of the sector size, and fit in a bounce
+	/// buffer.
+	fn transfer(&self, lba: u64, data: Data) -> EResult<()> {
+		let (len, write) = match &data {
+			Data::None => (0, false),
+			Data::In(buf) => (buf.len(), false),
+			Data::Out(buf) => (buf.len(), true),
+		};
+		let count = (len as u64 / self.sector_size) as u16;
+		let fis = if self.ncq {
+			let command = if write {
+				COMMAND_WRITE_FPDMA_QUEUED
+			} else {
+				COMMAND_READ_FPDMA_QUEUED
+			};
+			// With NCQ, the count is given in the features field
+			let mut fis = FisRegH2D::new(command, lba, 0);
+			[fis.feature_lo, fis.feature_hi] = count.to_le_bytes();
+			fis
+		} else if self.lba48 {
+			let command = if write {
+				COMMAND_WRITE_DMA_EXT
+			} else {
+				COMMAND_READ_DMA_EXT
+			};
+			FisRegH2D::new(command, lba, count)
+		} else {
+			let command = if write {
+				COMMAND_WRITE_DMA
+			} else {
+				COMMAND_READ_DMA
+			};
+			// With LBA28, the highest bits are given in the device register
+			let mut fis = FisRegH2D::new(command, lba & 0xffffff, count);
+			fis.device |= ((lba >> 24) & 0xf) as u8;
+			fis
+		};
+		self.exec(fis, data, self.ncq)
+	}
+
+	/// Checks that `len` bytes starting at sector `off` are in bounds of the drive.
+	///
+	/// On success, the function returns the number of bytes to transfer, and the maximum number
+	/// of bytes to transfer with a single command.
+	fn check_bounds(&self, off: u64, len: usize) -> EResult<(usize, usize)> {
+		let count = len as u64 / self.sector_size;
+		if off
+			.checked_add(count)
+			.is_none_or(|end| end > self.sectors_count)
+		{
+			return Err(errno!(EINVAL));
+		}
+		let chunk = SLOT_BUF_SIZE / self.sector_size as usize * self.sector_size as usize;
+		Ok(((count * self.sector_size) as usize, chunk))
+	}
+}
+
+impl DeviceIO for Port {
+	fn block_size(&self) -> NonZeroU64 {
+		self.sector_size.try_into().unwrap()
+	}
+
+	fn blocks_count(&self) -> u64 {
+		self.sectors_count
+	}
+
+	fn read(&self, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		let (len, chunk) = self.check_bounds(off, buf.len())?;
+		for (i, buf) in buf[..len].chunks_mut(chunk).enumerate() {
+			let lba = off + (i * chunk) as u64 / self.sector_size;
+			self.transfer(lba, Data::In(buf))?;
+		}
+		Ok(len)
+	}
+
+	fn write(&self, off: u64, buf: &[u8]) -> EResult<usize> {
+		let (len, chunk) = self.check_bounds(off, buf.len())?;
+		for (i, buf) in buf[..len].chunks(chunk).enumerate() {
+			let lba = off + (i * chunk) as u64 / self.sector_size;
+			self.transfer(lba, Data::Out(buf))?;
+		}
+		Ok(len)
+	}
+
+	fn flush(&self) -> EResult<()> {
+		let command = if self.lba48 {
+			COMMAND_FLUSH_CACHE_EXT
+		} else {
+			COMMAND_FLUSH_CACHE
+		};
+		self.exec(FisRegH2D::new(command, 0, 0), Data::None, false)
+	}
+}
+
+impl Drop for Port {
+	fn drop(&mut self) {
+		// Make sure the controller does not access the memory after it is freed
+		let _ = self.stop();
+	}
+}
+
+/// A port waiting for interrupts.
+struct Irq {
+	/// The IRQ number.
+	line: u8,
+	/// The port.
+	port: Arc<Port>,
+}
+
+/// The list of ports waiting for interrupts.
+static IRQS: IntMutex<Vec<Irq>> = IntMutex::new(Vec::new());
+
+/// Stops handling interrupts for `io`, if it is a AHCI port.
+///
+/// This releases the reference held for interrupt handling, so that the device can be freed once
+/// it is removed.
+pub(super) fn release(io: &Arc<dyn DeviceIO>) {
+	IRQS.lock()
+		.retain(|irq| !ptr::addr_eq(Arc::as_ptr(&irq.port), Arc::as_ptr(io)));
+}
+
+/// Handles an interrupt for ports on the line corresponding to the vector `id`.
+fn interrupt_handler(id: u32, _code: u32, _frame: &mut IntFrame, _ring: u8) -> CallbackResult {
+	let line = id - 0x20;
+	let irqs = IRQS.lock();
+	for irq in irqs.iter().filter(|irq| irq.line as u32 == line) {
+		let port = &irq.port;
+		// The line may be shared
+		if port.read(PORT_IS) == 0 {
+			continue;
+		}
+		port.update(&mut port.slots.lock());
+		port.queue.wake_all();
+	}
+	CallbackResult::Continue
+}
+
+/// An AHCI controller.
+#[derive(Debug)]
+pub struct Controller {
+	/// The BAR to access the controller's registers.
+	bar: BAR,
+	/// The interrupt line of the controller, if any.
+	line: Option<u8>,
+}
+
+impl Controller {
+	/// Creates a new instance from the given `PhysicalDevice`, and enables bus mastering on it.
+	///
+	/// If the given device is not an AHCI controller, the function returns `None`.
+	pub fn new(dev: &dyn PhysicalDevice) -> Option<Self> {
+		if dev.get_class() != pci::CLASS_MASS_STORAGE_CONTROLLER
+			|| dev.get_subclass() != SUBCLASS_SATA
+			|| dev.get_prog_if() != PROG_IF_AHCI
+		{
+			return None;
+		}
+		let bar = dev.get_bars().get(5).cloned().flatten();
+		let Some(
+			bar @ BAR::MemorySpace {
+				..
+			},
+		) = bar
+		else {
+			return None;
+		};
+		dev.enable_bus_mastering();
+		Some(Self {
+			bar,
+			line: dev.get_interrupt_line(),
+		})
+	}
+
+	/// Enables the controller and detects drives attached to its ports.
+	pub(super) fn detect(&self) -> impl '_ + Iterator<Item = EResult<Arc<dyn DeviceIO>>> {
+		let read = |reg| self.bar.read::<u32>(reg) as u32;
+		let write = |reg, val: u32| self.bar.write::<u32>(reg, val as _);
+		write(REG_GHC, read(REG_GHC) | GHC_AE);
+		let cap = read(REG_CAP);
+		let ncq_slots = if cap & CAP_SNCQ != 0 {
+			min(((cap >> 8) & 0x1f) as usize + 1, MAX_SLOTS)
+		} else {
+			1
+		};
+		write(REG_IS, u32::MAX);
+		write(REG_GHC, read(REG_GHC) | GHC_IE);
+		let pi = read(REG_PI);
+		(0..32)
+			.filter(move |i| pi & (1 << i) != 0)
+			.filter_map(move |i| Port::new(self.bar.clone(), i, ncq_slots).transpose())
+			.map(move |port| {
+				let port = Arc::new(port?)?;
+				if let Some(line) = self.line {
+					let mut irqs = IRQS.lock();
+					if !irqs.iter().any(|irq| irq.line == line) {
+						let hook =
+							event::register_callback(0x20 + line as u32, interrupt_handler)?;
+						let _ = ManuallyDrop::new(hook);
+						if line >= 8 {
+							// Cascade
+							pic::enable_irq(2);
+						}
+						pic::enable_irq(line);
+					}
+					irqs.push(Irq {
+						line,
+						port: port.clone(),
+					})?;
+					// Enable interrupts only once the port can be found by the handler
+					port.enable_interrupts();
+				}
+				Ok(port as Arc<dyn DeviceIO>)
+			})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn ahci_layout() {
+		assert_eq!(size_of::<CommandHeader>(), 32);
+		assert_eq!(size_of::<Prd>(), 16);
+		assert_eq!(size_of::<FisRegH2D>(), 20);
+		// Command tables must be aligned on 128 bytes and hold the FIS and one descriptor
+		assert_eq!(TABLE_SIZE % 128, 0);
+		assert!(PRDT_OFF + size_of::<Prd>() <= TABLE_SIZE);
+	}
+
+	#[test_case]
+	fn ahci_fis_lba() {
+		let fis = FisRegH2D::new(COMMAND_READ_DMA_EXT, 0x0605_0403_0201, 0x1234);
+		assert_eq!(fis.lba_lo, [0x01, 0x02, 0x03]);
+		assert_eq!(fis.lba_hi, [0x04, 0x05, 0x06]);
+		assert_eq!(fis.count, [0x34, 0x12]);
+		assert_eq!(fis.device, DEVICE_LBA);
+	}
+}
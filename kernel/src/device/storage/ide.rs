@@ -22,7 +22,10 @@
 use crate::device::{
 	bar::BAR,
 	bus::pci,
-	storage::{pata::PATAInterface, PhysicalDevice},
+	storage::{
+		pata::{Bus, PATAInterface},
+		PhysicalDevice,
+	},
 	DeviceIO,
 };
 use utils::{errno::AllocResult, ptr::arc::Arc};
@@ -47,6 +50,22 @@ const SECONDARY_DEVICE_CONTROL_PORT: u16 = 0x376;
 /// mode).
 const SECONDARY_ALTERNATE_STATUS_PORT: u16 = 0x376;
 
+/// The beginning of the port range for the third ATA bus (compatibility mode).
+const TERTIARY_ATA_BUS_PORT_BEGIN: u16 = 0x1e8;
+/// The port for the third disk's device control register (compatibility mode).
+const TERTIARY_DEVICE_CONTROL_PORT: u16 = 0x3ee;
+
+/// The beginning of the port range for the fourth ATA bus (compatibility mode).
+const QUATERNARY_ATA_BUS_PORT_BEGIN: u16 = 0x168;
+/// The port for the fourth disk's device control register (compatibility mode).
+const QUATERNARY_DEVICE_CONTROL_PORT: u16 = 0x36e;
+
+/// The IRQs of the ATA buses in compatibility mode.
+const COMPATIBILITY_IRQS: [u8; 4] = [14, 15, 11, 10];
+
+/// The size of the bus master registers of a channel.
+const BUS_MASTER_SIZE: usize = 8;
+
 /// Structure representing a channel on an IDE controller. It contains the BARs
 /// used to access a drive.
 #[derive(Debug)]
@@ -55,39 +74,39 @@ pub struct Channel {
 	pub ata_bar: BAR,
 	/// The BAR for control port.
 	pub control_bar: BAR,
+	/// The BAR for bus master registers, if the channel supports DMA.
+	pub bus_master_bar: Option<BAR>,
+	/// The IRQ raised by drives on the channel, if known.
+	pub irq: Option<u8>,
 }
 
 impl Channel {
 	/// Returns a new instance representing the channel in compatibility mode.
 	///
-	/// `secondary` tells whether the primary or secondary channel is picked.
-	pub fn new_compatibility(secondary: bool) -> Self {
-		if secondary {
-			Self {
-				ata_bar: BAR::IOSpace {
-					address: SECONDARY_ATA_BUS_PORT_BEGIN as _,
-
-					size: 8,
-				},
-				control_bar: BAR::IOSpace {
-					address: SECONDARY_DEVICE_CONTROL_PORT as _,
-
-					size: 4,
-				},
-			}
-		} else {
-			Self {
-				ata_bar: BAR::IOSpace {
-					address: PRIMARY_ATA_BUS_PORT_BEGIN as _,
-
-					size: 8,
-				},
-				control_bar: BAR::IOSpace {
-					address: PRIMARY_DEVICE_CONTROL_PORT as _,
-
-					size: 4,
-				},
-			}
+	/// `bus` is the index of the bus, from `0` (primary) to `3` (fourth).
+	pub fn new_compatibility(bus: usize) -> Self {
+		let (ata, control) = match bus {
+			0 => (PRIMARY_ATA_BUS_PORT_BEGIN, PRIMARY_DEVICE_CONTROL_PORT),
+			1 => (SECONDARY_ATA_BUS_PORT_BEGIN, SECONDARY_DEVICE_CONTROL_PORT),
+			2 => (TERTIARY_ATA_BUS_PORT_BEGIN, TERTIARY_DEVICE_CONTROL_PORT),
+			_ => (
+				QUATERNARY_ATA_BUS_PORT_BEGIN,
+				QUATERNARY_DEVICE_CONTROL_PORT,
+			),
+		};
+		Self {
+			ata_bar: BAR::IOSpace {
+				address: ata as _,
+
+				size: 8,
+			},
+			control_bar: BAR::IOSpace {
+				address: control as _,
+
+				size: 4,
+			},
+			bus_master_bar: None,
+			irq: Some(COMPATIBILITY_IRQS[bus]),
 		}
 	}
 }
@@ -100,20 +119,29 @@ pub struct Controller {
 
 	/// IDE controller's BARs.
 	bars: [Option<BAR>; 5],
+	/// The interrupt line of the controller, used by channels in PCI mode.
+	line: Option<u8>,
 }
 
 impl Controller {
 	/// Creates a new instance from the given `PhysicalDevice`.
 	///
 	/// If the given device is not an IDE controller, the function returns `None`.
+	///
+	/// If the controller supports DMA, bus mastering is enabled.
 	pub fn new(dev: &dyn PhysicalDevice) -> Option<Self> {
 		if dev.get_class() != pci::CLASS_MASS_STORAGE_CONTROLLER || dev.get_subclass() != 0x01 {
 			return None;
 		}
 
 		let bars = dev.get_bars();
+		let prog_if = dev.get_prog_if();
+		if prog_if & 0b10000000 != 0 {
+			dev.enable_bus_mastering();
+		}
 		Some(Self {
-			prog_if: dev.get_prog_if(),
+			prog_if,
+			line: dev.get_interrupt_line(),
 
 			bars: [
 				bars[0].clone(),
@@ -143,39 +171,65 @@ impl Controller {
 		self.prog_if & 0b10000000 != 0
 	}
 
-	/// Detects all disks on the controller. For each disk, the function calls
-	/// the given closure `f`.
+	/// Returns the channel of the bus `bus`, from `0` (primary) to `3` (fourth).
 	///
-	/// If an error is returned from a call to the closure, the function returns
-	/// with the same error.
+	/// The third and fourth buses are always in compatibility mode.
+	fn channel(&self, bus: usize) -> Channel {
+		let secondary = bus == 1;
+		let pci_mode = (bus == 0 && self.is_primary_pci_mode())
+			|| (secondary && self.is_secondary_pci_mode());
+		let mut channel = if !pci_mode {
+			Channel::new_compatibility(bus)
+		} else if !secondary {
+			// Primary channel
+			Channel {
+				ata_bar: self.bars[0].clone().unwrap(),
+				control_bar: self.bars[1].clone().unwrap(),
+				bus_master_bar: None,
+				irq: self.line,
+			}
+		} else {
+			// Secondary channel
+			Channel {
+				ata_bar: self.bars[2].clone().unwrap(),
+				control_bar: self.bars[3].clone().unwrap(),
+				bus_master_bar: None,
+				irq: self.line,
+			}
+		};
+		// The bus master registers of the secondary channel follow the primary's
+		if let (
+			true,
+			Some(BAR::IOSpace {
+				address, ..
+			}),
+		) = (self.is_dma() && bus < 2, &self.bars[4])
+		{
+			channel.bus_master_bar = Some(BAR::IOSpace {
+				address: address + (bus * BUS_MASTER_SIZE) as u32,
+				size: BUS_MASTER_SIZE,
+			});
+		}
+		channel
+	}
+
+	/// Detects all disks on the controller.
+	///
+	/// If the primary channel is in compatibility mode, the third and fourth legacy buses are
+	/// probed as well.
 	pub(super) fn detect(&self) -> impl '_ + Iterator<Item = AllocResult<Arc<dyn DeviceIO>>> {
-		(0..4)
-			.map(|i| {
-				let secondary = (i & 0b10) != 0;
-				let slave = (i & 0b01) != 0;
-				let pci_mode = (!secondary && self.is_primary_pci_mode())
-					|| (secondary && self.is_secondary_pci_mode());
-				if !pci_mode {
-					// Compatibility mode
-					return (Channel::new_compatibility(secondary), slave);
-				}
-				let channel = if !secondary {
-					// Primary channel
-					Channel {
-						ata_bar: self.bars[0].clone().unwrap(),
-						control_bar: self.bars[1].clone().unwrap(),
-					}
-				} else {
-					// Secondary channel
-					Channel {
-						ata_bar: self.bars[2].clone().unwrap(),
-						control_bar: self.bars[3].clone().unwrap(),
-					}
+		let buses = if self.is_primary_pci_mode() { 2 } else { 4 };
+		(0..buses).flat_map(move |bus| {
+			let bus = Bus::new(self.channel(bus)).and_then(Arc::new);
+			[false, true].into_iter().filter_map(move |slave| {
+				let bus = match &bus {
+					Ok(bus) => bus.clone(),
+					Err(e) => return Some(Err(*e)),
 				};
-				(channel, slave)
+				// TODO log errors?
+				let iface = PATAInterface::new(bus, slave).ok()?;
+				Some(Arc::new(iface).map(|a| a as Arc<dyn DeviceIO>))
 			})
-			// TODO log errors?
-			.filter_map(|(channel, slave)| PATAInterface::new(channel, slave).ok())
-			.map(|i| Arc::new(i).map(|a| a as Arc<dyn DeviceIO>))
+		})
 	}
 }
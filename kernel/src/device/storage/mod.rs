@@ -18,10 +18,13 @@
 
 //! Storage management implementation.
 
+pub mod ahci;
 pub mod ide;
 pub mod partition;
 pub mod pata;
+pub mod queue;
 pub mod ramdisk;
+pub mod virtio;
 
 use crate::{
 	device,
@@ -29,10 +32,10 @@ use crate::{
 		bus::pci,
 		id,
 		id::MajorBlock,
-		manager::{DeviceManager, PhysicalDevice},
+		manager::{BusLocation, DeviceManager, PhysicalDevice},
 		Device, DeviceID, DeviceIO, DeviceType,
 	},
-	file::Mode,
+	file::{page_cache::CachedDevice, vfs::mountpoint, Mode},
 	process::mem_space::copy::SyscallPtr,
 	syscall::{ioctl, FromSyscallArg},
 };
@@ -41,7 +44,9 @@ use core::{
 	num::NonZeroU64,
 };
 use partition::Partition;
+use queue::{deadline::Deadline, noop::Noop, RequestQueue, Scheduler};
 use utils::{
+	boxed::Box,
 	collections::{
 		path::{Path, PathBuf},
 		vec::Vec,
@@ -50,10 +55,9 @@ use utils::{
 	errno::EResult,
 	format,
 	ptr::arc::Arc,
-	TryClone,
 };
 
-/// The major number for storage devices.
+/// The major number for SCSI disks, which include SATA and PATA drives.
 const STORAGE_MAJOR: u32 = 8;
 /// The mode of the device file for a storage device.
 const STORAGE_MODE: Mode = 0o660;
@@ -74,6 +78,37 @@ struct HdGeometry {
 	start: c_ulong,
 }
 
+/// A type of storage device.
+///
+/// Each type has its own major number and prefix for the names of device files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiskType {
+	/// A SCSI disk, including SATA and PATA drives (`/dev/sdX`).
+	Scsi,
+	/// A virtio block device (`/dev/vdX`).
+	Virtio,
+}
+
+impl DiskType {
+	/// Returns the prefix of the names of device files.
+	fn prefix(self) -> &'static str {
+		match self {
+			Self::Scsi => "sd",
+			Self::Virtio => "vd",
+		}
+	}
+
+	/// Returns a new instance of the default I/O scheduler for this type of disk.
+	///
+	/// Virtual disks use the noop scheduler since the host does its own scheduling.
+	fn scheduler(self) -> EResult<Box<dyn Scheduler>> {
+		Ok(match self {
+			Self::Scsi => Box::new(Deadline::default())?,
+			Self::Virtio => Box::new(Noop::default())?,
+		})
+	}
+}
+
 /// Handle for the device file of a whole storage device or a partition.
 pub struct StorageDeviceHandle {
 	/// Device I/O.
@@ -126,6 +161,10 @@ impl DeviceIO for StorageDeviceHandle {
 		self.io.write(start + off, buf)
 	}
 
+	fn flush(&self) -> EResult<()> {
+		self.io.flush()
+	}
+
 	fn ioctl(&self, request: ioctl::Request, argp: *const c_void) -> EResult<u32> {
 		match request.get_old_format() {
 			ioctl::HDIO_GETGEO => {
@@ -149,7 +188,7 @@ impl DeviceIO for StorageDeviceHandle {
 				Ok(0)
 			}
 			ioctl::BLKRRPART => {
-				StorageManager::clear_partitions(self.major)?;
+				StorageManager::clear_partitions(self.major, self.storage_id)?;
 				StorageManager::read_partitions(
 					self.io.clone(),
 					self.major,
@@ -175,29 +214,109 @@ impl DeviceIO for StorageDeviceHandle {
 	}
 }
 
+/// Releases the references held by the driver of `io` for interrupt handling, so that the driver
+/// can be freed once the disk is removed.
+fn release_driver(io: &Arc<dyn DeviceIO>) {
+	ahci::release(io);
+	virtio::release(io);
+}
+
+/// A storage device registered in the manager.
+struct Disk {
+	/// The type of the disk.
+	disk_type: DiskType,
+	/// The major number of the device files.
+	major: u32,
+	/// The ID of the disk among disks of the same type.
+	storage_id: u32,
+	/// The location of the controller the disk is attached to.
+	location: BusLocation,
+
+	/// The driver.
+	driver: Arc<dyn DeviceIO>,
+	/// The request queue.
+	queue: Arc<RequestQueue>,
+	/// The cache, through which device files access the disk.
+	cache: Arc<CachedDevice>,
+}
+
+impl Disk {
+	/// Returns the ID of the device file with number `part_nbr`.
+	///
+	/// Number zero is the whole disk, other numbers are partitions.
+	fn device_id(&self, part_nbr: u32) -> DeviceID {
+		DeviceID {
+			dev_type: DeviceType::Block,
+			major: self.major,
+			minor: self.storage_id * MAX_PARTITIONS as u32 + part_nbr,
+		}
+	}
+
+	/// Creates the device files for the disk and its partitions.
+	///
+	/// `path` is the path to the device file of the whole disk.
+	fn register_files(&self, path: &Path) -> EResult<()> {
+		let io: Arc<dyn DeviceIO> = self.cache.clone();
+		let handle = StorageDeviceHandle {
+			io: io.clone(),
+			partition: None,
+
+			major: self.major,
+			storage_id: self.storage_id,
+			path_prefix: path.to_path_buf()?,
+		};
+		let device = Device::new(self.device_id(0), path.to_path_buf()?, STORAGE_MODE, handle)?;
+		device::register(device)?;
+		StorageManager::read_partitions(io, self.major, self.storage_id, path)
+	}
+
+	/// Removes the disk from the system.
+	///
+	/// In-flight I/O fails, mountpoints using the disk are detached and device files are
+	/// removed. If a step fails, the following ones are still performed and the first error is
+	/// returned.
+	fn remove(&self) -> EResult<()> {
+		self.queue.shutdown();
+		self.cache.remove();
+		release_driver(&self.driver);
+		let mut res = Ok(());
+		for part_nbr in 0..MAX_PARTITIONS as u32 {
+			let id = self.device_id(part_nbr);
+			res = res
+				.and(mountpoint::detach_device(&id))
+				.and(device::unregister(&id));
+		}
+		res
+	}
+}
+
 /// An instance of StorageManager manages devices on a whole major number.
 ///
 /// The manager has name `storage`.
 pub struct StorageManager {
-	/// The allocated device major number for storage devices.
-	major_block: MajorBlock,
-	/// The list of detected interfaces.
-	interfaces: Vec<Arc<dyn DeviceIO>>,
+	/// The allocated device major number for SCSI disks.
+	scsi_major: MajorBlock,
+	/// The allocated device major number for virtio block devices.
+	virtio_major: MajorBlock,
+	/// The list of registered disks.
+	disks: Vec<Disk>,
 }
 
 impl StorageManager {
 	/// Creates a new instance.
 	pub fn new() -> EResult<Self> {
 		Ok(Self {
-			major_block: id::alloc_major(DeviceType::Block, Some(STORAGE_MAJOR))?,
-			interfaces: Vec::new(),
+			scsi_major: id::alloc_major(DeviceType::Block, Some(STORAGE_MAJOR))?,
+			virtio_major: id::alloc_major(DeviceType::Block, None)?,
+			disks: Vec::new(),
 		})
 	}
 
-	// TODO When failing, remove previously registered devices
 	/// Creates device files for every partitions on the storage device, within the limit of
 	/// `MAX_PARTITIONS`.
 	///
+	/// On failure, the device files of the partitions that have been created are removed.
+	///
 	/// Arguments:
 	/// - `io` is the I/O interface.
 	/// - `major` is the major number of the device.
@@ -208,6 +327,20 @@ impl StorageManager {
 		major: u32,
 		storage_id: u32,
 		path_prefix: &Path,
+	) -> EResult<()> {
+		let res = Self::add_partitions(io, major, storage_id, path_prefix);
+		if res.is_err() {
+			let _ = Self::clear_partitions(major, storage_id);
+		}
+		res
+	}
+
+	/// Implementation of [`Self::read_partitions`], without cleanup on failure.
+	fn add_partitions(
+		io: Arc<dyn DeviceIO>,
+		major: u32,
+		storage_id: u32,
+		path_prefix: &Path,
 	) -> EResult<()> {
 		let Some(partitions_table) = partition::read(&*io)? else {
 			return Ok(());
@@ -231,8 +364,7 @@ impl StorageManager {
 			let device = Device::new(
 				DeviceID {
 					dev_type: DeviceType::Block,
-					// TODO use a different major for different storage device types
-					major: STORAGE_MAJOR,
+					major,
 					minor: storage_id * MAX_PARTITIONS as u32 + part_nbr,
 				},
 				path,
@@ -245,65 +377,117 @@ impl StorageManager {
 		Ok(())
 	}
 
-	/// Clears device files for every partition.
+	/// Clears device files for every partition of a storage device.
 	///
-	/// `major` is the major number of the devices to be removed.
-	pub fn clear_partitions(major: u32) -> EResult<()> {
+	/// Arguments:
+	/// - `major` is the major number of the devices to be removed.
+	/// - `storage_id` is the ID of the storage device in the manager.
+	pub fn clear_partitions(major: u32, storage_id: u32) -> EResult<()> {
 		for i in 1..MAX_PARTITIONS {
 			device::unregister(&DeviceID {
 				dev_type: DeviceType::Block,
 				major,
-				minor: i as _,
+				minor: storage_id * MAX_PARTITIONS as u32 + i as u32,
 			})?;
 		}
 
 		Ok(())
 	}
 
+	/// Adds the given storage device of type `disk_type` to the manager.
+	///
+	/// Arguments:
+	/// - `location` is the location of the controller the device is attached to
+	/// - `io` is the driver of the device
+	///
+	/// On failure, everything that has been registered for the device is removed and the driver
+	/// is released.
+	fn add(
+		&mut self,
+		disk_type: DiskType,
+		location: BusLocation,
+		io: Arc<dyn DeviceIO>,
+	) -> EResult<()> {
+		let (disk, main_path) = match self.new_disk(disk_type, location, io.clone()) {
+			Ok(res) => res,
+			Err(e) => {
+				release_driver(&io);
+				return Err(e);
+			}
+		};
+		if let Err(e) = disk.register_files(&main_path) {
+			// This releases the driver as well
+			let _ = disk.remove();
+			return Err(e);
+		}
+		self.disks.push(disk)?;
+		Ok(())
+	}
+
 	// TODO Handle the case where there is more devices that the number of devices
 	// that can be handled in the range of minor numbers
-	// TODO When failing, remove previously registered devices
-	/// Adds the given storage device to the manager.
-	fn add(&mut self, io: Arc<dyn DeviceIO>) -> EResult<()> {
+	/// Creates the disk for the storage device of type `disk_type`, along with its request queue
+	/// and cache, without registering its device files.
+	///
+	/// Arguments are the same as for [`Self::add`].
+	///
+	/// On success, the function returns the disk and the path to its device file.
+	fn new_disk(
+		&mut self,
+		disk_type: DiskType,
+		location: BusLocation,
+		io: Arc<dyn DeviceIO>,
+	) -> EResult<(Disk, PathBuf)> {
+		// Make sure the disk can be inserted once registered
+		self.disks.reserve(1)?;
 		// The device files' major number
-		let major = self.major_block.get_major();
-		// The id of the storage interface in the manager's list
-		let storage_id = self.interfaces.len() as u32;
+		let major = match disk_type {
+			DiskType::Scsi => self.scsi_major.get_major(),
+			DiskType::Virtio => self.virtio_major.get_major(),
+		};
+		// The lowest id that is not used by another disk of the same type
+		let storage_id = (0..)
+			.find(|id| {
+				!self
+					.disks
+					.iter()
+					.any(|d| d.disk_type == disk_type && d.storage_id == *id)
+			})
+			.unwrap();
 
 		// Prefix is the path of the main device file
 		// TODO Handle if out of the alphabet
 		let letter = (b'a' + (storage_id as u8)) as char;
-		let main_path = PathBuf::try_from(format!("/dev/sd{letter}")?)?;
-
-		// Create the main device file
-		let main_handle = StorageDeviceHandle {
-			io: io.clone(),
-			partition: None,
-
+		let name = format!("{}{letter}", disk_type.prefix())?;
+		let main_path = PathBuf::try_from(format!("/dev/{name}")?)?;
+		let main_id = DeviceID {
+			dev_type: DeviceType::Block,
 			major,
-			storage_id,
-			path_prefix: main_path.try_clone()?,
+			minor: storage_id * MAX_PARTITIONS as u32,
 		};
-		let main_device = Device::new(
-			DeviceID {
-				dev_type: DeviceType::Block,
-				major,
-				minor: storage_id * MAX_PARTITIONS as u32,
-			},
-			main_path.try_clone()?,
-			STORAGE_MODE,
-			main_handle,
-		)?;
-		device::register(main_device)?;
-
-		Self::read_partitions(io.clone(), major, storage_id, &main_path)?;
+		// Accesses to the device and its partitions go through the page cache, then the request
+		// queue
+		let queue = RequestQueue::new(main_id, name, io.clone(), disk_type.scheduler()?)?;
+		let cache = match CachedDevice::new(main_id, queue.clone()) {
+			Ok(cache) => cache,
+			Err(e) => {
+				queue.shutdown();
+				return Err(e.into());
+			}
+		};
+		let disk = Disk {
+			disk_type,
+			major,
+			storage_id,
+			location,
 
-		self.interfaces.push(io)?;
-		Ok(())
+			driver: io,
+			queue,
+			cache,
+		};
+		Ok((disk, main_path))
 	}
 
-	// TODO Function to remove a device
-
 	/// Fills a random buffer `buff` of size `size` with seed `seed`.
 	///
 	/// The function returns the seed for the next block.
@@ -367,10 +551,10 @@ impl StorageManager {
 		let mut seed = 42;
 		let iterations_count = 10;
 		for i in 0..iterations_count {
-			let interfaces_count = self.interfaces.len();
+			let interfaces_count = self.disks.len();
 
 			for j in 0..interfaces_count {
-				let mut interface = self.interfaces[j].lock();
+				let mut interface = self.disks[j].driver.lock();
 
 				crate::print!(
 					"Processing iteration: {}/{iterations_count}; device: {}/{iterations_count}...",
@@ -401,7 +585,7 @@ impl StorageManager {
 	/// writable disks, so it must be used carefully.
 	#[cfg(config_debug_storage_test)]
 	pub fn test(&mut self) {
-		crate::println!("Running disks tests... ({} devices)", self.interfaces.len());
+		crate::println!("Running disks tests... ({} devices)", self.disks.len());
 
 		if self.perform_test() {
 			crate::println!("Done!");
@@ -419,26 +603,118 @@ impl DeviceManager for StorageManager {
 			return Ok(());
 		}
 
-		let mut register_iface = |res: EResult<_>| {
-			let res = res.and_then(|iface| self.add(iface));
+		let location = dev.get_location();
+		let mut register_iface = |disk_type, res: EResult<Arc<dyn DeviceIO>>| {
+			let res = res.and_then(|iface| self.add(disk_type, location, iface));
 			if let Err(e) = res {
 				crate::println!("Could not register storage device: {e}");
 			}
 		};
 
-		// TODO use device class as a hint
-		// TODO handle other controller types
-		if let Some(ide) = ide::Controller::new(dev) {
+		if (dev.get_vendor_id(), dev.get_device_id()) == (virtio::VENDOR_ID, virtio::DEVICE_ID) {
+			let iface = virtio::VirtioBlk::new(dev).map(|blk| blk as Arc<dyn DeviceIO>);
+			register_iface(DiskType::Virtio, iface);
+		} else if let Some(ide) = ide::Controller::new(dev) {
 			for iface in ide.detect() {
-				register_iface(iface.map_err(Into::into));
+				register_iface(DiskType::Scsi, iface.map_err(Into::into));
+			}
+		} else if let Some(ahci) = ahci::Controller::new(dev) {
+			for iface in ahci.detect() {
+				register_iface(DiskType::Scsi, iface);
 			}
 		}
 
 		Ok(())
 	}
 
-	fn on_unplug(&mut self, _dev: &dyn PhysicalDevice) -> EResult<()> {
-		// TODO remove device
-		todo!();
+	fn on_unplug(&mut self, dev: &dyn PhysicalDevice) -> EResult<()> {
+		// Remove every disk attached to the device
+		let location = dev.get_location();
+		self.disks.retain(|disk| {
+			if disk.location != location {
+				return true;
+			}
+			if let Err(e) = disk.remove() {
+				crate::println!("Could not cleanly remove storage device: {e}");
+			}
+			false
+		});
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::device::manager;
+	use core::any::Any;
+
+	/// A disk on which every access fails.
+	struct FaultyDisk;
+
+	impl DeviceIO for FaultyDisk {
+		fn block_size(&self) -> NonZeroU64 {
+			NonZeroU64::new(512).unwrap()
+		}
+
+		fn blocks_count(&self) -> u64 {
+			64
+		}
+
+		fn read(&self, _off: u64, _buf: &mut [u8]) -> EResult<usize> {
+			Err(errno!(EIO))
+		}
+
+		fn write(&self, _off: u64, _buf: &[u8]) -> EResult<usize> {
+			Err(errno!(EIO))
+		}
+	}
+
+	/// Checks that a failure to add a disk to `manager` leaves nothing behind.
+	fn add_rollback(manager: &mut StorageManager) {
+		let io: Arc<dyn DeviceIO> = Arc::new(FaultyDisk).unwrap();
+		let location = BusLocation::Pci {
+			bus: 0xff,
+			device: 0x1f,
+			function: 7,
+		};
+		let disks = manager.disks.len();
+		let storage_id = (0..)
+			.find(|id| {
+				!manager
+					.disks
+					.iter()
+					.any(|d| d.disk_type == DiskType::Virtio && d.storage_id == *id)
+			})
+			.unwrap();
+		// The device file of the disk is registered, then reading the partition table fails
+		let res = manager.add(DiskType::Virtio, location, io.clone());
+		assert_eq!(res, Err(errno!(EIO)));
+		assert_eq!(manager.disks.len(), disks);
+		let id = DeviceID {
+			dev_type: DeviceType::Block,
+			major: manager.virtio_major.get_major(),
+			minor: storage_id * MAX_PARTITIONS as u32,
+		};
+		assert!(device::get(&id).is_none());
+		assert!(!queue::queues().unwrap().iter().any(|q| q.id() == &id));
+		// Nothing holds the driver anymore
+		assert_eq!(Arc::strong_count(&io), 1);
+	}
+
+	#[test_case]
+	fn storage_add_rollback() {
+		// The major numbers of the registered manager cannot be allocated twice
+		match manager::get::<StorageManager>() {
+			Some(manager) => {
+				let mut manager = manager.lock();
+				let manager = (&mut *manager as &mut dyn Any)
+					.downcast_mut::<StorageManager>()
+					.unwrap();
+				add_rollback(manager);
+			}
+			// The major numbers of a temporary manager are released when it is dropped
+			None => add_rollback(&mut StorageManager::new().unwrap()),
+		}
 	}
 }
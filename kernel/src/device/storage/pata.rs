@@ -33,17 +33,43 @@
 //! - Select the drive (with the dedicated command)
 //! - Identify it to retrieve information, such as whether the drives support LBA48
 //!
-//! TODO
-
-// TODO Add support for third and fourth bus
+//! If the IDE controller supports bus mastering, transfers are done with DMA: the controller
+//! copies data between the drive and memory, then raises an interrupt on completion. Otherwise,
+//! data is transferred through I/O ports (PIO).
 
 use crate::{
-	arch::x86::io::inb,
-	device::{storage::ide, DeviceIO},
-	sync::mutex::Mutex,
+	arch::x86::{idt::IntFrame, io::inb, pic},
+	device::{bar::BAR, storage::ide, DeviceIO},
+	event,
+	event::CallbackResult,
+	file::wait_queue::WaitQueue,
+	memory::dma::DmaBuffer,
+	process::scheduler,
+	sync::mutex::IntMutex,
+	time::{
+		clock,
+		clock::CLOCK_MONOTONIC,
+		unit::{Timestamp, TimestampScale},
+	},
+};
+use core::{
+	cmp::min,
+	hint,
+	mem::ManuallyDrop,
+	num::NonZeroU64,
+	ptr,
+	sync::atomic::{
+		fence, AtomicBool,
+		Ordering::{Acquire, Relaxed, Release, SeqCst},
+	},
+};
+use utils::{
+	collections::vec::Vec,
+	errno,
+	errno::{AllocResult, EResult},
+	limits::PAGE_SIZE,
+	ptr::arc::Arc,
 };
-use core::{cmp::min, num::NonZeroU64};
-use utils::{errno, errno::EResult};
 
 /// Offset to the data register.
 const DATA_REGISTER_OFFSET: u16 = 0;
@@ -79,10 +105,27 @@ const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
 const COMMAND_WRITE_SECTORS: u8 = 0x30;
 /// Writes sectors on the disk with LBA48.
 const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+/// Reads sectors from the disk with LBA28, with DMA.
+const COMMAND_READ_DMA: u8 = 0xc8;
+/// Reads sectors from the disk with LBA48, with DMA.
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+/// Writes sectors on the disk with LBA28, with DMA.
+const COMMAND_WRITE_DMA: u8 = 0xca;
+/// Writes sectors on the disk with LBA48, with DMA.
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
 /// Flush cache command.
 const COMMAND_CACHE_FLUSH: u8 = 0xe7;
 /// Identifies the selected drive.
 const COMMAND_IDENTIFY: u8 = 0xec;
+/// Sets a feature of the selected drive, given in the features register.
+const COMMAND_SET_FEATURES: u8 = 0xef;
+
+/// Feature: sets the transfer mode, given in the sectors count register.
+const FEATURE_TRANSFER_MODE: u8 = 0x03;
+/// Transfer mode: Multiword DMA. The mode number is OR'ed with this value.
+const TRANSFER_MODE_MWDMA: u8 = 0x20;
+/// Transfer mode: Ultra DMA. The mode number is OR'ed with this value.
+const TRANSFER_MODE_UDMA: u8 = 0x40;
 
 /// Address mark not found.
 const ERROR_AMNF: u8 = 0b00000001;
@@ -114,12 +157,50 @@ const STATUS_RDY: u8 = 0b01000000;
 /// Indicates the drive is preparing to send/receive data.
 const STATUS_BSY: u8 = 0b10000000;
 
+/// Offset to the bus master command register.
+const BM_COMMAND_OFFSET: usize = 0;
+/// Offset to the bus master status register.
+const BM_STATUS_OFFSET: usize = 2;
+/// Offset to the bus master register holding the physical address of the PRD table.
+const BM_PRDT_OFFSET: usize = 4;
+
+/// Bus master command: starts the transfer.
+const BM_COMMAND_START: u8 = 0b00000001;
+/// Bus master command: the transfer writes to memory, which means it reads from the drive.
+const BM_COMMAND_READ: u8 = 0b00001000;
+
+/// Bus master status: the transfer failed.
+const BM_STATUS_ERR: u8 = 0b00000010;
+/// Bus master status: the drive raised an interrupt.
+const BM_STATUS_IRQ: u8 = 0b00000100;
+/// Bus master status: the master drive is capable of DMA.
+const BM_STATUS_MASTER_DMA: u8 = 0b00100000;
+/// Bus master status: the slave drive is capable of DMA.
+const BM_STATUS_SLAVE_DMA: u8 = 0b01000000;
+
+/// PRD flag: the entry is the last of the table.
+const PRD_EOT: u16 = 1 << 15;
+
+/// The number of pages of the bounce buffer for DMA transfers.
+///
+/// The buffer is aligned on its size, so it never crosses a 64 KiB boundary, as required by the
+/// controller.
+const DMA_BUF_PAGES: usize = 16;
+/// The size of the bounce buffer for DMA transfers, in bytes.
+const DMA_BUF_SIZE: usize = DMA_BUF_PAGES * PAGE_SIZE;
+
+/// The maximum number of iterations when busy-waiting for a DMA transfer.
+const POLL_ITER: usize = 10000000;
+/// The timeout for a DMA transfer when sleeping, in milliseconds.
+const TIMEOUT: Timestamp = 10000;
+/// The maximum duration of a single sleep while waiting for the drive, in milliseconds.
+///
+/// This bounds the delay in case a wakeup is missed.
+const SLEEP_SLICE: Timestamp = 10;
+
 /// The size of a sector in bytes.
 const SECTOR_SIZE: u64 = 512;
 
-// TODO Synchronize both master and slave disks so that another thread cannot
-// trigger a select while operating on a drive
-
 /// Applies a delay. `n` determines the amount to wait.
 ///
 /// This function is a dirty hack and the actual delay is approximate but
@@ -133,6 +214,198 @@ fn delay(n: u32) {
 	}
 }
 
+/// Waits until `f` returns `Some`.
+///
+/// The current process sleeps on `queue` if possible. Else, the function busy-waits.
+///
+/// If the drive does not respond in time, the function returns `None`.
+fn wait_for<F: FnMut() -> Option<T>, T>(queue: &WaitQueue, mut f: F) -> Option<T> {
+	if scheduler::can_sleep() {
+		let now = || clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond).ok();
+		let deadline = now()? + TIMEOUT;
+		loop {
+			// Signals are ignored since the command is being executed anyway
+			if let Ok(val) = queue.wait_until_timeout(Some(SLEEP_SLICE), &mut f) {
+				return Some(val);
+			}
+			if now()? >= deadline {
+				return None;
+			}
+		}
+	}
+	for _ in 0..POLL_ITER {
+		if let Some(val) = f() {
+			return Some(val);
+		}
+		hint::spin_loop();
+	}
+	None
+}
+
+/// An entry of the PRD (Physical Region Descriptor) table, describing a buffer for a DMA
+/// transfer.
+#[repr(C)]
+struct Prd {
+	/// The physical address of the buffer.
+	addr: u32,
+	/// The size of the buffer in bytes. Zero stands for 64 KiB.
+	size: u16,
+	/// Flags.
+	flags: u16,
+}
+
+impl Prd {
+	/// Returns the last entry of a table, describing the `len` first bytes of `buf`.
+	///
+	/// `len` must not exceed 64 KiB.
+	fn new(buf: &DmaBuffer, len: usize) -> Self {
+		debug_assert!(len <= 0x10000);
+		Self {
+			addr: buf.phys_addr(0).0 as _,
+			// 64 KiB wraps around to zero, as expected by the controller
+			size: len as _,
+			flags: PRD_EOT,
+		}
+	}
+}
+
+/// The resources of a bus for DMA transfers.
+struct Dma {
+	/// The BAR for bus master registers.
+	bar: BAR,
+	/// The PRD table.
+	prdt: DmaBuffer,
+	/// The bounce buffer for transfers.
+	buf: DmaBuffer,
+	/// Set by the interrupt handler when the drive raised an interrupt.
+	done: AtomicBool,
+	/// Tells whether the interrupt handler has been registered for the bus.
+	irq_registered: AtomicBool,
+}
+
+/// An ATA bus, shared by its master and slave drives.
+pub struct Bus {
+	/// The channel of the bus.
+	channel: ide::Channel,
+	/// Tells whether a drive is using the bus.
+	busy: AtomicBool,
+	/// The queue of processes waiting for the bus or for the completion of a transfer.
+	queue: WaitQueue,
+	/// The resources for DMA transfers, if the bus supports it.
+	dma: Option<Dma>,
+}
+
+/// Guard giving exclusive access to a bus. The bus is released when dropped.
+struct BusGuard<'b>(&'b Bus);
+
+impl Drop for BusGuard<'_> {
+	fn drop(&mut self) {
+		self.0.busy.store(false, Release);
+		self.0.queue.wake_all();
+	}
+}
+
+impl Bus {
+	/// Creates a bus on the given channel.
+	pub fn new(channel: ide::Channel) -> AllocResult<Self> {
+		let dma = channel
+			.bus_master_bar
+			.clone()
+			.map(|bar| {
+				Ok(Dma {
+					bar,
+					prdt: DmaBuffer::new(1)?,
+					buf: DmaBuffer::new(DMA_BUF_PAGES)?,
+					done: AtomicBool::new(false),
+					irq_registered: AtomicBool::new(false),
+				})
+			})
+			.transpose()?;
+		Ok(Self {
+			channel,
+			busy: AtomicBool::new(false),
+			queue: WaitQueue::new(),
+			dma,
+		})
+	}
+
+	/// Takes exclusive access to the bus, waiting for the command in progress to complete.
+	fn acquire(&self) -> BusGuard<'_> {
+		let mut take = || {
+			self.busy
+				.compare_exchange(false, true, Acquire, Relaxed)
+				.ok()
+				.map(|_| ())
+		};
+		while wait_for(&self.queue, &mut take).is_none() {}
+		BusGuard(self)
+	}
+
+	/// Registers the interrupt handler for DMA transfers on `bus`, if not already done.
+	///
+	/// If the IRQ of the bus is unknown, completions are detected by polling.
+	fn register_irq(bus: &Arc<Self>) -> AllocResult<()> {
+		let (Some(dma), Some(line)) = (&bus.dma, bus.channel.irq) else {
+			return Ok(());
+		};
+		if dma.irq_registered.swap(true, Relaxed) {
+			return Ok(());
+		}
+		let mut irqs = IRQS.lock();
+		if !irqs.iter().any(|irq| irq.line == line) {
+			let hook = event::register_callback(0x20 + line as u32, interrupt_handler)?;
+			let _ = ManuallyDrop::new(hook);
+			if line >= 8 {
+				// Cascade
+				pic::enable_irq(2);
+			}
+			pic::enable_irq(line);
+		}
+		irqs.push(Irq {
+			line,
+			bus: bus.clone(),
+		})
+	}
+}
+
+/// An interrupt line used by a bus.
+struct Irq {
+	/// The IRQ number.
+	line: u8,
+	/// The bus.
+	bus: Arc<Bus>,
+}
+
+/// The list of buses waiting for interrupts.
+static IRQS: IntMutex<Vec<Irq>> = IntMutex::new(Vec::new());
+
+/// Handles an interrupt for buses on the line corresponding to the vector `id`.
+fn interrupt_handler(id: u32, _code: u32, _frame: &mut IntFrame, _ring: u8) -> CallbackResult {
+	let line = id - 0x20;
+	let irqs = IRQS.lock();
+	for irq in irqs.iter().filter(|irq| irq.line as u32 == line) {
+		let Some(dma) = &irq.bus.dma else {
+			continue;
+		};
+		// The line may be shared
+		let status = dma.bar.read::<u8>(BM_STATUS_OFFSET) as u8;
+		if status & BM_STATUS_IRQ == 0 {
+			continue;
+		}
+		// Keep the error bit for the waiting process
+		dma.bar
+			.write::<u8>(BM_STATUS_OFFSET, (status & !BM_STATUS_ERR) as _);
+		// Reading the status register acknowledges the interrupt on the drive's side
+		irq.bus
+			.channel
+			.ata_bar
+			.read::<u8>(STATUS_REGISTER_OFFSET as _);
+		dma.done.store(true, Release);
+		irq.bus.queue.wake_all();
+	}
+	CallbackResult::Continue
+}
+
 /// An enumeration representing port offset types for ATA.
 enum PortOffset {
 	/// Port offset on general register ports.
@@ -142,10 +415,9 @@ enum PortOffset {
 }
 
 /// A PATA interface with a unique disk.
-#[derive(Debug)]
 pub struct PATAInterface {
-	/// The channel on which the disk is located.
-	channel: ide::Channel,
+	/// The bus on which the disk is located.
+	bus: Arc<Bus>,
 	/// Tells whether the disk is slave or master.
 	slave: bool,
 
@@ -153,9 +425,8 @@ pub struct PATAInterface {
 	lba48: bool,
 	/// The number of sectors on the disk.
 	sectors_count: u64,
-
-	/// Mutex preventing data race on read/write operations.
-	lock: Mutex<()>,
+	/// Tells whether transfers are done with DMA.
+	dma: bool,
 }
 
 impl PATAInterface {
@@ -164,19 +435,21 @@ impl PATAInterface {
 	/// On error, the function returns a string telling the cause.
 	///
 	/// Arguments:
-	/// - `channel` is the IDE channel of the disk.
+	/// - `bus` is the bus of the disk.
 	/// - `slave` tells whether the disk is the slave disk.
-	pub fn new(channel: ide::Channel, slave: bool) -> Result<Self, &'static str> {
+	pub fn new(bus: Arc<Bus>, slave: bool) -> Result<Self, &'static str> {
 		let mut s = Self {
-			channel,
+			bus,
 			slave,
 
 			lba48: false,
 			sectors_count: 0,
-
-			lock: Default::default(),
+			dma: false,
 		};
 		s.identify()?;
+		if s.dma {
+			Bus::register_irq(&s.bus).map_err(|_| "Out of memory")?;
+		}
 		Ok(s)
 	}
 
@@ -184,8 +457,8 @@ impl PATAInterface {
 	#[inline(always)]
 	fn inb(&self, port_off: PortOffset) -> u8 {
 		let (bar, off) = match port_off {
-			PortOffset::Ata(off) => (&self.channel.ata_bar, off),
-			PortOffset::Control(off) => (&self.channel.control_bar, off),
+			PortOffset::Ata(off) => (&self.bus.channel.ata_bar, off),
+			PortOffset::Control(off) => (&self.bus.channel.control_bar, off),
 		};
 		bar.read::<u8>(off as _) as _
 	}
@@ -194,8 +467,8 @@ impl PATAInterface {
 	#[inline(always)]
 	fn inw(&self, port_off: PortOffset) -> u16 {
 		let (bar, off) = match port_off {
-			PortOffset::Ata(off) => (&self.channel.ata_bar, off),
-			PortOffset::Control(off) => (&self.channel.control_bar, off),
+			PortOffset::Ata(off) => (&self.bus.channel.ata_bar, off),
+			PortOffset::Control(off) => (&self.bus.channel.control_bar, off),
 		};
 		bar.read::<u16>(off as _) as _
 	}
@@ -204,8 +477,8 @@ impl PATAInterface {
 	#[inline(always)]
 	fn outb(&self, port_off: PortOffset, value: u8) {
 		let (bar, off) = match port_off {
-			PortOffset::Ata(off) => (&self.channel.ata_bar, off),
-			PortOffset::Control(off) => (&self.channel.control_bar, off),
+			PortOffset::Ata(off) => (&self.bus.channel.ata_bar, off),
+			PortOffset::Control(off) => (&self.bus.channel.control_bar, off),
 		};
 		bar.write::<u8>(off as _, value as _) as _
 	}
@@ -214,8 +487,8 @@ impl PATAInterface {
 	#[inline(always)]
 	fn outw(&self, port_off: PortOffset, value: u16) {
 		let (bar, off) = match port_off {
-			PortOffset::Ata(off) => (&self.channel.ata_bar, off),
-			PortOffset::Control(off) => (&self.channel.control_bar, off),
+			PortOffset::Ata(off) => (&self.bus.channel.ata_bar, off),
+			PortOffset::Control(off) => (&self.bus.channel.control_bar, off),
 		};
 		bar.write::<u16>(off as _, value as _) as _
 	}
@@ -365,11 +638,187 @@ impl PATAInterface {
 		} else {
 			lba28_size as _
 		};
+		self.dma = self.enable_dma(&data);
 
 		delay(420);
 		Ok(())
 	}
 
+	/// Selects the fastest DMA mode supported by the drive, according to the IDENTIFY data
+	/// `data`. The device is assumed to be selected.
+	///
+	/// If either the drive or the bus does not support DMA, the function returns `false`.
+	fn enable_dma(&self, data: &[u16; 256]) -> bool {
+		let Some(dma) = &self.bus.dma else {
+			return false;
+		};
+		if data[49] & (1 << 8) == 0 {
+			return false;
+		}
+		let udma = data[88] & 0x7f;
+		let mwdma = data[63] & 0x7;
+		let mode = if udma != 0 {
+			TRANSFER_MODE_UDMA | (15 - udma.leading_zeros()) as u8
+		} else if mwdma != 0 {
+			TRANSFER_MODE_MWDMA | (15 - mwdma.leading_zeros()) as u8
+		} else {
+			return false;
+		};
+		self.outb(
+			PortOffset::Ata(FEATURES_REGISTER_OFFSET),
+			FEATURE_TRANSFER_MODE,
+		);
+		self.outb(PortOffset::Ata(SECTORS_COUNT_REGISTER_OFFSET), mode);
+		self.send_command(COMMAND_SET_FEATURES);
+		delay(420);
+		self.wait_busy();
+		if self.get_status() & (STATUS_ERR | STATUS_DF) != 0 {
+			return false;
+		}
+		// Tell the controller the drive is capable of DMA
+		let capable = if self.slave {
+			BM_STATUS_SLAVE_DMA
+		} else {
+			BM_STATUS_MASTER_DMA
+		};
+		let status = dma.bar.read::<u8>(BM_STATUS_OFFSET) as u8;
+		dma.bar.write::<u8>(
+			BM_STATUS_OFFSET,
+			((status & !(BM_STATUS_ERR | BM_STATUS_IRQ)) | capable) as _,
+		);
+		true
+	}
+
+	/// Selects the drive and writes the address of a command on `count` sectors at `off`.
+	///
+	/// A `count` of zero stands for the maximum number of sectors for a command.
+	fn setup_lba(&self, off: u64, count: u64, lba48: bool) {
+		let mut drive = if lba48 {
+			// LBA48
+			0x40
+		} else {
+			// LBA28
+			0xe0
+		};
+		if self.slave {
+			// Setting slave bit
+			drive |= 1 << 4;
+		}
+
+		// If LBA28, add the end of the sector offset
+		if !lba48 {
+			drive |= ((off >> 24) & 0x0f) as u8;
+		}
+
+		self.outb(PortOffset::Ata(DRIVE_REGISTER_OFFSET), drive);
+
+		// If LBA48, write high bytes first
+		if lba48 {
+			let count = ((count >> 8) & 0xff) as u8;
+			let lo_lba = ((off >> 24) & 0xff) as u8;
+			let mid_lba = ((off >> 32) & 0xff) as u8;
+			let hi_lba = ((off >> 40) & 0xff) as u8;
+
+			self.outb(PortOffset::Ata(SECTORS_COUNT_REGISTER_OFFSET), count);
+			self.outb(PortOffset::Ata(LBA_LO_REGISTER_OFFSET), lo_lba);
+			self.outb(PortOffset::Ata(LBA_MID_REGISTER_OFFSET), mid_lba);
+			self.outb(PortOffset::Ata(LBA_HI_REGISTER_OFFSET), hi_lba);
+		}
+
+		let lo_lba = (off & 0xff) as u8;
+		let mid_lba = ((off >> 8) & 0xff) as u8;
+		let hi_lba = ((off >> 16) & 0xff) as u8;
+
+		self.outb(
+			PortOffset::Ata(SECTORS_COUNT_REGISTER_OFFSET),
+			(count & 0xff) as u8,
+		);
+		self.outb(PortOffset::Ata(LBA_LO_REGISTER_OFFSET), lo_lba);
+		self.outb(PortOffset::Ata(LBA_MID_REGISTER_OFFSET), mid_lba);
+		self.outb(PortOffset::Ata(LBA_HI_REGISTER_OFFSET), hi_lba);
+	}
+
+	/// Transfers `len` bytes starting at sector `off` with DMA, between the drive and the
+	/// bounce buffer. `write` tells the direction of the transfer.
+	///
+	/// The bus must be acquired and `len` must not exceed the size of the bounce buffer.
+	fn dma_command(&self, off: u64, len: usize, lba48: bool, write: bool) -> EResult<()> {
+		let dma = self.bus.dma.as_ref().unwrap();
+		debug_assert!(len <= DMA_BUF_SIZE);
+		unsafe {
+			ptr::write_volatile(dma.prdt.as_ptr(0) as *mut Prd, Prd::new(&dma.buf, len));
+		}
+		// Make sure the table is in memory before starting the transfer
+		fence(SeqCst);
+		let bar = &dma.bar;
+		let direction = if write { 0 } else { BM_COMMAND_READ };
+		bar.write::<u8>(BM_COMMAND_OFFSET, direction as _);
+		bar.write::<u32>(BM_PRDT_OFFSET, dma.prdt.phys_addr(0).0 as _);
+		let status = bar.read::<u8>(BM_STATUS_OFFSET) as u8;
+		bar.write::<u8>(
+			BM_STATUS_OFFSET,
+			(status | BM_STATUS_ERR | BM_STATUS_IRQ) as _,
+		);
+		dma.done.store(false, Release);
+		let count = len as u64 / SECTOR_SIZE;
+		self.setup_lba(off, count, lba48);
+		let command = match (write, lba48) {
+			(false, false) => COMMAND_READ_DMA,
+			(false, true) => COMMAND_READ_DMA_EXT,
+			(true, false) => COMMAND_WRITE_DMA,
+			(true, true) => COMMAND_WRITE_DMA_EXT,
+		};
+		self.send_command(command);
+		bar.write::<u8>(BM_COMMAND_OFFSET, (direction | BM_COMMAND_START) as _);
+		let completed = wait_for(&self.bus.queue, || {
+			let irq = bar.read::<u8>(BM_STATUS_OFFSET) as u8 & BM_STATUS_IRQ != 0;
+			(dma.done.load(Acquire) || irq).then_some(())
+		});
+		bar.write::<u8>(BM_COMMAND_OFFSET, direction as _);
+		let bm_status = bar.read::<u8>(BM_STATUS_OFFSET) as u8;
+		bar.write::<u8>(
+			BM_STATUS_OFFSET,
+			(bm_status | BM_STATUS_ERR | BM_STATUS_IRQ) as _,
+		);
+		// Reading the status register acknowledges the interrupt
+		let status = self.get_status();
+		if completed.is_none() {
+			// Abort the command
+			self.reset();
+			return Err(errno!(EIO));
+		}
+		if bm_status & BM_STATUS_ERR != 0 || status & (STATUS_ERR | STATUS_DF) != 0 {
+			return Err(errno!(EIO));
+		}
+		fence(SeqCst);
+		Ok(())
+	}
+
+	/// Reads sectors starting at `off` with DMA into `buf`. The bus must be acquired.
+	fn read_dma(&self, off: u64, buf: &mut [u8], lba48: bool) -> EResult<()> {
+		let dma = self.bus.dma.as_ref().unwrap();
+		for (i, chunk) in buf.chunks_mut(DMA_BUF_SIZE).enumerate() {
+			let off = off + (i * DMA_BUF_SIZE) as u64 / SECTOR_SIZE;
+			self.dma_command(off, chunk.len(), lba48, false)?;
+			chunk.copy_from_slice(dma.buf.slice(0, chunk.len()));
+		}
+		Ok(())
+	}
+
+	/// Writes sectors starting at `off` with DMA from `buf`. The bus must be acquired.
+	fn write_dma(&self, off: u64, buf: &[u8], lba48: bool) -> EResult<()> {
+		let dma = self.bus.dma.as_ref().unwrap();
+		for (i, chunk) in buf.chunks(DMA_BUF_SIZE).enumerate() {
+			let off = off + (i * DMA_BUF_SIZE) as u64 / SECTOR_SIZE;
+			unsafe {
+				ptr::copy_nonoverlapping(chunk.as_ptr(), dma.buf.as_ptr(0), chunk.len());
+			}
+			self.dma_command(off, chunk.len(), lba48, true)?;
+		}
+		self.cache_flush();
+		Ok(())
+	}
+
 	/// Waits for the drive to be ready for IO operation.
 	///
 	/// The device is assumed to be selected.
@@ -419,7 +868,12 @@ impl DeviceIO for PATAInterface {
 		};
 
 		// Avoid data race
-		let _guard = self.lock.lock();
+		let _guard = self.bus.acquire();
+		if self.dma {
+			let len = (size * SECTOR_SIZE) as usize;
+			self.read_dma(off, &mut buf[..len], lba48)?;
+			return Ok(len);
+		}
 		// Select disk
 		self.select(false);
 
@@ -433,49 +887,7 @@ impl DeviceIO for PATAInterface {
 				count = 0;
 			}
 
-			let mut drive = if lba48 {
-				// LBA48
-				0x40
-			} else {
-				// LBA28
-				0xe0
-			};
-			if self.slave {
-				// Setting slave bit
-				drive |= 1 << 4;
-			}
-
-			// If LBA28, add the end of the sector offset
-			if !lba48 {
-				drive |= ((off >> 24) & 0x0f) as u8;
-			}
-
-			self.outb(PortOffset::Ata(DRIVE_REGISTER_OFFSET), drive);
-
-			// If LBA48, write high bytes first
-			if lba48 {
-				let count = ((count >> 8) & 0xff) as u8;
-				let lo_lba = ((off >> 24) & 0xff) as u8;
-				let mid_lba = ((off >> 32) & 0xff) as u8;
-				let hi_lba = ((off >> 40) & 0xff) as u8;
-
-				self.outb(PortOffset::Ata(SECTORS_COUNT_REGISTER_OFFSET), count);
-				self.outb(PortOffset::Ata(LBA_LO_REGISTER_OFFSET), lo_lba);
-				self.outb(PortOffset::Ata(LBA_MID_REGISTER_OFFSET), mid_lba);
-				self.outb(PortOffset::Ata(LBA_HI_REGISTER_OFFSET), hi_lba);
-			}
-
-			let lo_lba = (off & 0xff) as u8;
-			let mid_lba = ((off >> 8) & 0xff) as u8;
-			let hi_lba = ((off >> 16) & 0xff) as u8;
-
-			self.outb(
-				PortOffset::Ata(SECTORS_COUNT_REGISTER_OFFSET),
-				(count & 0xff) as u8,
-			);
-			self.outb(PortOffset::Ata(LBA_LO_REGISTER_OFFSET), lo_lba);
-			self.outb(PortOffset::Ata(LBA_MID_REGISTER_OFFSET), mid_lba);
-			self.outb(PortOffset::Ata(LBA_HI_REGISTER_OFFSET), hi_lba);
+			self.setup_lba(off, count, lba48);
 
 			if lba48 {
 				self.send_command(COMMAND_READ_SECTORS_EXT);
@@ -530,7 +942,12 @@ impl DeviceIO for PATAInterface {
 		};
 
 		// Avoid data race
-		let _guard = self.lock.lock();
+		let _guard = self.bus.acquire();
+		if self.dma {
+			let len = (size * SECTOR_SIZE) as usize;
+			self.write_dma(off, &buf[..len], lba48)?;
+			return Ok(len);
+		}
 		// Select disk
 		self.select(false);
 
@@ -544,49 +961,7 @@ impl DeviceIO for PATAInterface {
 				count = 0;
 			}
 
-			let mut drive = if lba48 {
-				// LBA48
-				0x40
-			} else {
-				// LBA28
-				0xe0
-			};
-			if self.slave {
-				// Setting slave bit
-				drive |= 1 << 4;
-			}
-
-			// If LBA28, add the end of the sector offset
-			if !lba48 {
-				drive |= ((off >> 24) & 0x0f) as u8;
-			}
-
-			self.outb(PortOffset::Ata(DRIVE_REGISTER_OFFSET), drive);
-
-			// If LBA48, write high bytes first
-			if lba48 {
-				let count = ((count >> 8) & 0xff) as u8;
-				let lo_lba = ((off >> 24) & 0xff) as u8;
-				let mid_lba = ((off >> 32) & 0xff) as u8;
-				let hi_lba = ((off >> 40) & 0xff) as u8;
-
-				self.outb(PortOffset::Ata(SECTORS_COUNT_REGISTER_OFFSET), count);
-				self.outb(PortOffset::Ata(LBA_LO_REGISTER_OFFSET), lo_lba);
-				self.outb(PortOffset::Ata(LBA_MID_REGISTER_OFFSET), mid_lba);
-				self.outb(PortOffset::Ata(LBA_HI_REGISTER_OFFSET), hi_lba);
-			}
-
-			let lo_lba = (off & 0xff) as u8;
-			let mid_lba = ((off >> 8) & 0xff) as u8;
-			let hi_lba = ((off >> 16) & 0xff) as u8;
-
-			self.outb(
-				PortOffset::Ata(SECTORS_COUNT_REGISTER_OFFSET),
-				(count & 0xff) as u8,
-			);
-			self.outb(PortOffset::Ata(LBA_LO_REGISTER_OFFSET), lo_lba);
-			self.outb(PortOffset::Ata(LBA_MID_REGISTER_OFFSET), mid_lba);
-			self.outb(PortOffset::Ata(LBA_HI_REGISTER_OFFSET), hi_lba);
+			self.setup_lba(off, count, lba48);
 
 			if lba48 {
 				self.send_command(COMMAND_WRITE_SECTORS_EXT);
@@ -617,3 +992,28 @@ impl DeviceIO for PATAInterface {
 		Ok((size * SECTOR_SIZE) as _)
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn pata_prd() {
+		assert_eq!(size_of::<Prd>(), 8);
+		let buf = DmaBuffer::new(DMA_BUF_PAGES).unwrap();
+		assert!(buf.size() >= DMA_BUF_SIZE);
+		let phys = buf.phys_addr(0).0;
+		// The controller only addresses 32 bits and the buffer must not cross 64 KiB
+		assert!(phys < u32::MAX as usize);
+		assert_eq!(phys & 0xffff, 0);
+		let prd = Prd::new(&buf, SECTOR_SIZE as _);
+		assert_eq!(prd.addr as usize, phys);
+		assert_eq!(prd.size as u64, SECTOR_SIZE);
+		assert_eq!(prd.flags, PRD_EOT);
+		// A full 64 KiB buffer is encoded as zero
+		let prd = Prd::new(&buf, DMA_BUF_SIZE);
+		assert_eq!(DMA_BUF_SIZE, 0x10000);
+		assert_eq!(prd.size, 0);
+		assert_eq!(prd.flags, PRD_EOT);
+	}
+}
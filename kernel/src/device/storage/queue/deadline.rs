@@ -0,0 +1,274 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The deadline scheduler serves requests in order of their position on the device, to reduce
+//! seeking, while guaranteeing that each request is executed before a deadline.
+//!
+//! Requests are dispatched in batches going in one direction. Within a batch, requests are
+//! served in ascending order of block, starting from the end of the last request. A new batch
+//! starts with the oldest request if its deadline has expired.
+//!
+//! Reads are preferred over writes since processes usually wait for them, within the limit of
+//! [`WRITES_STARVED`] batches.
+
+use super::{Op, Request, Scheduler};
+use crate::time::unit::Timestamp;
+use utils::{collections::btreemap::BTreeMap, errno::AllocResult};
+
+/// The duration after which a read request expires, in milliseconds.
+const READ_EXPIRE: Timestamp = 500;
+/// The duration after which a write request expires, in milliseconds.
+const WRITE_EXPIRE: Timestamp = 5000;
+/// The maximum number of requests in a batch.
+const FIFO_BATCH: usize = 16;
+/// The number of read batches after which a write batch is started, if writes are pending.
+const WRITES_STARVED: usize = 2;
+
+/// Pending requests going in one direction.
+struct Direction {
+	/// Pending requests, by arrival order.
+	requests: BTreeMap<u64, Request>,
+	/// The arrival numbers of pending requests, sorted by block.
+	sorted: BTreeMap<(u64, u64), ()>,
+	/// The arrival number of the last request that was added or merged into.
+	last: Option<u64>,
+	/// The duration after which a request expires, in milliseconds.
+	expire: Timestamp,
+}
+
+impl Direction {
+	/// Creates an empty instance with the given expire duration.
+	fn new(expire: Timestamp) -> Self {
+		Self {
+			requests: BTreeMap::new(),
+			sorted: BTreeMap::new(),
+			last: None,
+			expire,
+		}
+	}
+
+	/// Tries to merge `req` into the pending request with arrival number `seq`.
+	///
+	/// On failure, the request is given back.
+	fn merge(&mut self, seq: u64, req: Request) -> Result<(), Request> {
+		let Some(pending) = self.requests.get_mut(&seq) else {
+			return Err(req);
+		};
+		let old = pending.sector();
+		pending.merge(req)?;
+		let new = pending.sector();
+		if new != old {
+			self.sorted.remove(&(old, seq));
+			// On failure, the request remains reachable by arrival order
+			let _ = self.sorted.insert((new, seq), ());
+		}
+		self.last = Some(seq);
+		Ok(())
+	}
+
+	/// Adds the request `req` with arrival number `seq`, merging it if possible.
+	///
+	/// The function returns `true` if the request has been merged.
+	fn add(&mut self, seq: u64, req: Request) -> AllocResult<bool> {
+		// Try the last request first, which catches sequential I/O
+		let req = match self.last {
+			Some(last) => match self.merge(last, req) {
+				Ok(()) => return Ok(true),
+				Err(req) => req,
+			},
+			None => req,
+		};
+		// Try the request starting right after the new one
+		let next = self
+			.sorted
+			.range((req.end(), 0)..)
+			.next()
+			.filter(|((sector, _), _)| *sector == req.end())
+			.map(|((_, seq), _)| *seq);
+		let req = match next {
+			Some(next) => match self.merge(next, req) {
+				Ok(()) => return Ok(true),
+				Err(req) => req,
+			},
+			None => req,
+		};
+		let sector = req.sector();
+		self.requests.insert(seq, req)?;
+		// On failure, the request remains reachable by arrival order
+		let _ = self.sorted.insert((sector, seq), ());
+		self.last = Some(seq);
+		Ok(false)
+	}
+
+	/// Returns the arrival number of the first request starting at or after block `sector`.
+	fn next_from(&self, sector: u64) -> Option<u64> {
+		self.sorted
+			.range((sector, 0)..)
+			.next()
+			.map(|((_, seq), _)| *seq)
+	}
+
+	/// Returns the arrival number of the oldest request.
+	fn oldest(&self) -> Option<u64> {
+		self.requests.first_key_value().map(|(seq, _)| *seq)
+	}
+
+	/// Returns the arrival number of the oldest request if its deadline has expired at `now`.
+	fn expired(&self, now: Timestamp) -> Option<u64> {
+		let (seq, req) = self.requests.first_key_value()?;
+		(req.submitted() + self.expire <= now).then_some(*seq)
+	}
+
+	/// Removes the request with arrival number `seq`.
+	fn remove(&mut self, seq: u64) -> Option<Request> {
+		let req = self.requests.remove(&seq)?;
+		self.sorted.remove(&(req.sector(), seq));
+		if self.last == Some(seq) {
+			self.last = None;
+		}
+		Some(req)
+	}
+}
+
+/// The deadline scheduler.
+pub struct Deadline {
+	/// Pending requests, by direction.
+	dirs: [Direction; 2],
+	/// The arrival number of the last request.
+	seq: u64,
+
+	/// The direction of the current batch.
+	current: Option<Op>,
+	/// The number of requests dispatched in the current batch.
+	batch: usize,
+	/// The block right after the end of the last dispatched request.
+	position: u64,
+	/// The number of read batches started while writes were pending.
+	starved: usize,
+}
+
+impl Default for Deadline {
+	fn default() -> Self {
+		Self {
+			dirs: [Direction::new(READ_EXPIRE), Direction::new(WRITE_EXPIRE)],
+			seq: 0,
+
+			current: None,
+			batch: 0,
+			position: 0,
+			starved: 0,
+		}
+	}
+}
+
+impl Deadline {
+	/// Removes the request with arrival number `seq` in direction `op` and makes it the last
+	/// dispatched request.
+	fn take(&mut self, op: Op, seq: u64) -> Option<Request> {
+		let req = self.dirs[op as usize].remove(seq)?;
+		self.position = req.end();
+		self.batch += 1;
+		Some(req)
+	}
+}
+
+impl Scheduler for Deadline {
+	fn name(&self) -> &'static str {
+		"deadline"
+	}
+
+	fn add(&mut self, req: Request) -> AllocResult<bool> {
+		self.seq += 1;
+		self.dirs[req.op() as usize].add(self.seq, req)
+	}
+
+	fn dispatch(&mut self, now: Timestamp) -> Option<Request> {
+		// Continue the current batch
+		if let Some(op) = self.current {
+			if self.batch < FIFO_BATCH {
+				if let Some(seq) = self.dirs[op as usize].next_from(self.position) {
+					return self.take(op, seq);
+				}
+			}
+		}
+		// Start a new batch
+		let reads = !self.dirs[Op::Read as usize].requests.is_empty();
+		let writes = !self.dirs[Op::Write as usize].requests.is_empty();
+		let op = if reads && (!writes || self.starved < WRITES_STARVED) {
+			if writes {
+				self.starved += 1;
+			}
+			Op::Read
+		} else if writes {
+			self.starved = 0;
+			Op::Write
+		} else {
+			return None;
+		};
+		let dir = &self.dirs[op as usize];
+		// Serve the oldest request if expired, else keep going forward on the device
+		let seq = dir
+			.expired(now)
+			.or_else(|| {
+				(self.current == Some(op))
+					.then(|| dir.next_from(self.position))
+					.flatten()
+			})
+			.or_else(|| dir.oldest())?;
+		self.current = Some(op);
+		self.batch = 0;
+		self.take(op, seq)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use utils::{boxed::Box, collections::vec::Vec};
+
+	fn request(op: Op, sector: u64) -> Request {
+		let mut buf = Vec::new();
+		buf.resize(512, 0).unwrap();
+		Request::new(op, sector, 1, buf, Box::new(|_| {}).unwrap()).unwrap()
+	}
+
+	#[test_case]
+	fn deadline_order() {
+		let mut sched = Deadline::default();
+		for sector in [10, 11, 0, 20, 5] {
+			sched.add(request(Op::Read, sector)).unwrap();
+		}
+		sched.add(request(Op::Write, 30)).unwrap();
+		// The request at 11 is merged into the last request, at 10
+		let order: [_; 5] = core::array::from_fn(|_| {
+			let req = sched.dispatch(0).unwrap();
+			(req.op(), req.sector())
+		});
+		assert_eq!(
+			order,
+			[
+				(Op::Read, 10),
+				(Op::Read, 20),
+				(Op::Read, 0),
+				(Op::Read, 5),
+				(Op::Write, 30)
+			]
+		);
+		assert!(sched.dispatch(0).is_none());
+	}
+}
@@ -0,0 +1,721 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The block layer sits between filesystems and storage drivers.
+//!
+//! Each storage device has a [`RequestQueue`] to which I/O is submitted. Requests on adjacent
+//! blocks are merged, and a [`Scheduler`] decides the order in which they are executed.
+//!
+//! Submission is asynchronous: a callback is called when the request completes. Since drivers
+//! perform I/O synchronously, requests are executed by the context running the queue. While a
+//! context is running the queue, other contexts only queue their requests, which gives the
+//! scheduler the opportunity to merge and reorder them.
+//!
+//! I/O statistics of each queue are exposed in `/proc/diskstats`.
+//!
+//! When a device is removed, its queue is shut down and all of its requests fail.
+
+pub mod deadline;
+pub mod noop;
+
+use crate::{
+	device::{DeviceID, DeviceIO},
+	file::wait_queue::WaitQueue,
+	process::scheduler,
+	sync::mutex::{IntMutex, Mutex},
+	syscall::ioctl,
+	time::{
+		clock,
+		clock::CLOCK_MONOTONIC,
+		unit::{Timestamp, TimestampScale},
+	},
+};
+use core::{
+	ffi::c_void,
+	hint, mem,
+	num::NonZeroU64,
+	ptr,
+	sync::atomic::{
+		AtomicBool,
+		Ordering::{Acquire, Release},
+	},
+};
+use utils::{
+	boxed::Box,
+	collections::{string::String, vec::Vec},
+	errno,
+	errno::{AllocResult, CollectResult, EResult},
+	ptr::arc::Arc,
+	vec,
+};
+
+/// The maximum size of a request after merging, in bytes.
+const MAX_REQUEST_SIZE: usize = 128 * 1024;
+/// The size of a sector in statistics, in bytes.
+const STATS_SECTOR_SIZE: u64 = 512;
+/// The maximum duration of a single sleep while waiting for a request, in milliseconds.
+///
+/// This bounds the delay in case a wakeup is missed.
+const SLEEP_SLICE: Timestamp = 10;
+
+/// Returns the current timestamp, in milliseconds.
+fn now() -> Timestamp {
+	clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond).unwrap_or(0)
+}
+
+/// The direction of a request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Op {
+	/// Data is read from the device.
+	Read = 0,
+	/// Data is written to the device.
+	Write = 1,
+}
+
+/// Function called when a request completes.
+///
+/// On success, the function is given the request's buffer back. It is called exactly once, by the
+/// context running the queue, so it must not block.
+pub type Callback = Box<dyn FnMut(EResult<Vec<u8>>)>;
+
+/// A buffer submitted to a queue, along with its completion callback.
+struct Segment {
+	/// The data buffer.
+	buf: Vec<u8>,
+	/// The completion callback. `None` if already called.
+	callback: Option<Callback>,
+}
+
+impl Segment {
+	/// Calls the completion callback with the result `res`.
+	fn complete(mut self, res: EResult<()>) {
+		if let Some(mut callback) = self.callback.take() {
+			callback(res.map(|_| mem::take(&mut self.buf)));
+		}
+	}
+}
+
+impl Drop for Segment {
+	fn drop(&mut self) {
+		// A segment dropped without being executed fails
+		if let Some(mut callback) = self.callback.take() {
+			callback(Err(errno!(EIO)));
+		}
+	}
+}
+
+/// A request on a contiguous range of blocks, made of one or several merged segments.
+pub struct Request {
+	/// The direction of the request.
+	op: Op,
+	/// The offset of the first block.
+	sector: u64,
+	/// The number of blocks.
+	count: u64,
+	/// The size of the request, in bytes.
+	size: usize,
+	/// The segments, in order on the device.
+	segments: Vec<Segment>,
+	/// The timestamp at which the oldest segment was submitted, in milliseconds.
+	submitted: Timestamp,
+}
+
+impl Request {
+	/// Creates a request with a single segment.
+	///
+	/// Arguments:
+	/// - `op` is the direction of the request
+	/// - `sector` is the offset of the first block
+	/// - `count` is the number of blocks covered by `buf`
+	/// - `buf` is the data buffer
+	/// - `callback` is the completion callback
+	///
+	/// On failure, the callback is called with an error.
+	fn new(
+		op: Op,
+		sector: u64,
+		count: u64,
+		buf: Vec<u8>,
+		callback: Callback,
+	) -> AllocResult<Self> {
+		let size = buf.len();
+		let mut segments = Vec::new();
+		segments.push(Segment {
+			buf,
+			callback: Some(callback),
+		})?;
+		Ok(Self {
+			op,
+			sector,
+			count,
+			size,
+			segments,
+			submitted: now(),
+		})
+	}
+
+	/// Returns the direction of the request.
+	#[inline]
+	pub fn op(&self) -> Op {
+		self.op
+	}
+
+	/// Returns the offset of the first block of the request.
+	#[inline]
+	pub fn sector(&self) -> u64 {
+		self.sector
+	}
+
+	/// Returns the offset of the block right after the end of the request.
+	#[inline]
+	pub fn end(&self) -> u64 {
+		self.sector + self.count
+	}
+
+	/// Returns the timestamp at which the oldest part of the request was submitted, in
+	/// milliseconds.
+	#[inline]
+	pub fn submitted(&self) -> Timestamp {
+		self.submitted
+	}
+
+	/// Tries to merge `other` at the back or at the front of `self`.
+	///
+	/// Requests can be merged if they go in the same direction, are adjacent and the resulting
+	/// request is not too large. On failure, `other` is given back.
+	pub fn merge(&mut self, mut other: Request) -> Result<(), Request> {
+		if self.op != other.op || self.size + other.size > MAX_REQUEST_SIZE {
+			return Err(other);
+		}
+		if self.end() == other.sector {
+			if self.segments.append(&mut other.segments).is_err() {
+				return Err(other);
+			}
+		} else if other.end() == self.sector {
+			if other.segments.append(&mut self.segments).is_err() {
+				return Err(other);
+			}
+			self.segments = mem::take(&mut other.segments);
+			self.sector = other.sector;
+		} else {
+			return Err(other);
+		}
+		self.count += other.count;
+		self.size += other.size;
+		self.submitted = self.submitted.min(other.submitted);
+		Ok(())
+	}
+}
+
+/// An I/O scheduler, deciding the order in which requests are executed.
+pub trait Scheduler {
+	/// Returns the name of the scheduler.
+	fn name(&self) -> &'static str;
+
+	/// Adds `req` to the pending requests, merging it with a pending request if possible.
+	///
+	/// The function returns `true` if the request has been merged.
+	///
+	/// On failure, the request is dropped, which fails it.
+	fn add(&mut self, req: Request) -> AllocResult<bool>;
+
+	/// Removes and returns the next request to execute, if any.
+	///
+	/// `now` is the current timestamp, in milliseconds.
+	fn dispatch(&mut self, now: Timestamp) -> Option<Request>;
+}
+
+/// I/O statistics of a queue, in the format of `/proc/diskstats`.
+///
+/// Durations are in milliseconds and sectors are 512 bytes long.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+	/// The number of completed requests, by direction.
+	pub ios: [u64; 2],
+	/// The number of submissions merged into another request, by direction.
+	pub merges: [u64; 2],
+	/// The number of transferred sectors, by direction.
+	pub sectors: [u64; 2],
+	/// The time spent by requests in the queue and being executed, by direction.
+	pub ticks: [u64; 2],
+	/// The number of completed flushes.
+	pub flushes: u64,
+	/// The time spent flushing.
+	pub flush_ticks: u64,
+	/// The number of submissions that did not complete yet.
+	pub in_flight: u64,
+	/// The time during which at least one submission was in flight.
+	pub io_ticks: u64,
+	/// The time submissions spent in flight, weighted by their number.
+	pub time_in_queue: u64,
+	/// The timestamp at which `io_ticks` and `time_in_queue` were last updated.
+	last_update: Timestamp,
+}
+
+impl Stats {
+	/// Accounts for the time elapsed since the last update, with the current timestamp `now`.
+	fn update(&mut self, now: Timestamp) {
+		let delta = now.saturating_sub(self.last_update);
+		if self.in_flight > 0 {
+			self.io_ticks += delta;
+			self.time_in_queue += self.in_flight * delta;
+		}
+		self.last_update = now;
+	}
+}
+
+/// The state of a queue.
+struct State {
+	/// The I/O scheduler.
+	scheduler: Box<dyn Scheduler>,
+	/// Tells whether a context is running the queue.
+	running: bool,
+	/// I/O statistics.
+	stats: Stats,
+}
+
+/// The queue of I/O requests of a storage device.
+pub struct RequestQueue {
+	/// The ID of the device.
+	id: DeviceID,
+	/// The name of the device.
+	name: String,
+	/// The underlying driver.
+	io: Arc<dyn DeviceIO>,
+
+	/// The state of the queue.
+	state: IntMutex<State>,
+	/// Tells whether the device has been removed.
+	dead: AtomicBool,
+	/// The queue of processes waiting for requests to complete.
+	wait_queue: WaitQueue,
+}
+
+/// The list of request queues.
+static QUEUES: Mutex<Vec<Arc<RequestQueue>>> = Mutex::new(Vec::new());
+
+/// Returns the list of request queues.
+pub fn queues() -> AllocResult<Vec<Arc<RequestQueue>>> {
+	QUEUES
+		.lock()
+		.iter()
+		.cloned()
+		.collect::<CollectResult<_>>()
+		.0
+}
+
+impl RequestQueue {
+	/// Creates a new instance and registers it, so that its statistics are listed.
+	///
+	/// Arguments:
+	/// - `id` is the ID of the device
+	/// - `name` is the name of the device
+	/// - `io` is the underlying driver
+	/// - `scheduler` is the I/O scheduler
+	pub fn new(
+		id: DeviceID,
+		name: String,
+		io: Arc<dyn DeviceIO>,
+		scheduler: Box<dyn Scheduler>,
+	) -> AllocResult<Arc<Self>> {
+		let queue = Arc::new(Self {
+			id,
+			name,
+			io,
+
+			state: IntMutex::new(State {
+				scheduler,
+				running: false,
+				stats: Stats {
+					last_update: now(),
+					..Default::default()
+				},
+			}),
+			dead: AtomicBool::new(false),
+			wait_queue: WaitQueue::new(),
+		})?;
+		QUEUES.lock().push(queue.clone())?;
+		Ok(queue)
+	}
+
+	/// Returns the ID of the device.
+	#[inline]
+	pub fn id(&self) -> &DeviceID {
+		&self.id
+	}
+
+	/// Returns the name of the device.
+	#[inline]
+	pub fn name(&self) -> &String {
+		&self.name
+	}
+
+	/// Returns the name of the I/O scheduler.
+	pub fn scheduler_name(&self) -> &'static str {
+		self.state.lock().scheduler.name()
+	}
+
+	/// Returns the I/O statistics.
+	pub fn stats(&self) -> Stats {
+		let mut state = self.state.lock();
+		state.stats.update(now());
+		state.stats
+	}
+
+	/// Submits a request.
+	///
+	/// Arguments:
+	/// - `op` is the direction of the request
+	/// - `off` is the offset on the device, in blocks
+	/// - `buf` is the data buffer. Its size has to be a multiple of the block size
+	/// - `callback` is called when the request completes
+	///
+	/// The request is executed once the queue is run with [`Self::run`]. Submitting several
+	/// requests before running the queue allows merging them.
+	pub fn submit(&self, op: Op, off: u64, buf: Vec<u8>, mut callback: Callback) {
+		let blk_size = self.io.block_size().get();
+		let len = buf.len() as u64;
+		let count = len / blk_size;
+		if len % blk_size != 0 || off.saturating_add(count) > self.io.blocks_count() {
+			callback(Err(errno!(EINVAL)));
+			return;
+		}
+		let Ok(req) = Request::new(op, off, count, buf, callback) else {
+			return;
+		};
+		let mut state = self.state.lock();
+		// Checked under the lock, so that the request cannot be missed by `shutdown`
+		if self.dead.load(Acquire) {
+			drop(state);
+			// Dropping the request fails it
+			drop(req);
+			return;
+		}
+		state.stats.update(req.submitted());
+		state.stats.in_flight += 1;
+		match state.scheduler.add(req) {
+			Ok(true) => state.stats.merges[op as usize] += 1,
+			Ok(false) => {}
+			// The request has been failed
+			Err(_) => state.stats.in_flight -= 1,
+		}
+	}
+
+	/// Executes pending requests until there is none left.
+	///
+	/// If another context is already running the queue, the function returns immediately since
+	/// this context executes the requests.
+	pub fn run(&self) {
+		{
+			let mut state = self.state.lock();
+			if state.running {
+				return;
+			}
+			state.running = true;
+		}
+		loop {
+			let req = {
+				let mut state = self.state.lock();
+				match state.scheduler.dispatch(now()) {
+					Some(req) => req,
+					None => {
+						// Checked and cleared under the lock, so that no request is left behind
+						state.running = false;
+						break;
+					}
+				}
+			};
+			self.execute(req);
+		}
+	}
+
+	/// Performs the I/O for the request `req`, then completes it.
+	fn execute(&self, mut req: Request) {
+		let mut res = if !self.dead.load(Acquire) {
+			self.transfer(&mut req)
+		} else {
+			Err(errno!(EIO))
+		};
+		// The device may have been removed during the transfer
+		if self.dead.load(Acquire) {
+			res = Err(errno!(EIO));
+		}
+		{
+			let now = now();
+			let mut state = self.state.lock();
+			let stats = &mut state.stats;
+			stats.update(now);
+			stats.in_flight -= req.segments.len() as u64;
+			if res.is_ok() {
+				let op = req.op as usize;
+				stats.ios[op] += 1;
+				stats.sectors[op] += req.size as u64 / STATS_SECTOR_SIZE;
+				stats.ticks[op] += now.saturating_sub(req.submitted);
+			}
+		}
+		for seg in req.segments {
+			seg.complete(res);
+		}
+		self.wait_queue.wake_all();
+	}
+
+	/// Shuts the queue down after the device has been removed, and removes it from the list of
+	/// queues.
+	///
+	/// Pending requests fail with [`errno::EIO`], as well as requests being executed and requests
+	/// submitted afterwards.
+	pub fn shutdown(&self) {
+		QUEUES.lock().retain(|q| !ptr::eq(Arc::as_ptr(q), self));
+		loop {
+			let req = {
+				let mut state = self.state.lock();
+				self.dead.store(true, Release);
+				let Some(req) = state.scheduler.dispatch(Timestamp::MAX) else {
+					break;
+				};
+				state.stats.in_flight -= req.segments.len() as u64;
+				req
+			};
+			for seg in req.segments {
+				seg.complete(Err(errno!(EIO)));
+			}
+		}
+		self.wait_queue.wake_all();
+	}
+
+	/// Transfers the data of the request `req` with the driver.
+	fn transfer(&self, req: &mut Request) -> EResult<()> {
+		if let [seg] = req.segments.as_mut_slice() {
+			return self.transfer_segment(req.op, req.sector, seg);
+		}
+		// Gather segments in a single buffer, to issue a single command to the device
+		let Ok(mut buf) = vec![0u8; req.size] else {
+			// Not enough memory: transfer segments one by one
+			let blk_size = self.io.block_size().get();
+			let mut sector = req.sector;
+			for seg in req.segments.iter_mut() {
+				self.transfer_segment(req.op, sector, seg)?;
+				sector += seg.buf.len() as u64 / blk_size;
+			}
+			return Ok(());
+		};
+		match req.op {
+			Op::Read => {
+				self.io.read(req.sector, &mut buf)?;
+				let mut off = 0;
+				for seg in req.segments.iter_mut() {
+					let len = seg.buf.len();
+					seg.buf.copy_from_slice(&buf[off..(off + len)]);
+					off += len;
+				}
+			}
+			Op::Write => {
+				let mut off = 0;
+				for seg in req.segments.iter() {
+					let len = seg.buf.len();
+					buf[off..(off + len)].copy_from_slice(&seg.buf);
+					off += len;
+				}
+				self.io.write(req.sector, &buf)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Transfers the data of a single segment `seg` at offset `sector`, in blocks.
+	fn transfer_segment(&self, op: Op, sector: u64, seg: &mut Segment) -> EResult<()> {
+		match op {
+			Op::Read => self.io.read(sector, &mut seg.buf)?,
+			Op::Write => self.io.write(sector, &seg.buf)?,
+		};
+		Ok(())
+	}
+
+	/// Runs the queue and waits until `f` returns `Some`.
+	///
+	/// `f` is called each time a request completes.
+	pub fn wait<F: FnMut() -> Option<T>, T>(&self, mut f: F) -> T {
+		loop {
+			self.run();
+			if scheduler::can_sleep() {
+				// Signals are ignored since requests are being executed anyway
+				if let Ok(val) = self
+					.wait_queue
+					.wait_until_timeout(Some(SLEEP_SLICE), &mut f)
+				{
+					return val;
+				}
+			} else if let Some(val) = f() {
+				return val;
+			} else {
+				hint::spin_loop();
+			}
+		}
+	}
+
+	/// Submits a request, then waits for its completion.
+	///
+	/// Arguments are the same as for [`Self::submit`]. On success, the function returns the
+	/// buffer.
+	pub fn submit_wait(&self, op: Op, off: u64, buf: Vec<u8>) -> EResult<Vec<u8>> {
+		let result: Arc<Mutex<Option<EResult<Vec<u8>>>>> = Arc::new(Mutex::new(None))?;
+		let res = result.clone();
+		let callback = Box::new(move |r| *res.lock() = Some(r))?;
+		self.submit(op, off, buf, callback);
+		self.wait(|| result.lock().take())
+	}
+}
+
+impl DeviceIO for RequestQueue {
+	fn block_size(&self) -> NonZeroU64 {
+		self.io.block_size()
+	}
+
+	fn blocks_count(&self) -> u64 {
+		self.io.blocks_count()
+	}
+
+	fn read(&self, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		let data = self.submit_wait(Op::Read, off, vec![0u8; buf.len()]?)?;
+		buf.copy_from_slice(&data);
+		Ok(buf.len())
+	}
+
+	fn write(&self, off: u64, buf: &[u8]) -> EResult<usize> {
+		let mut data = Vec::new();
+		data.extend_from_slice(buf)?;
+		self.submit_wait(Op::Write, off, data)?;
+		Ok(buf.len())
+	}
+
+	fn flush(&self) -> EResult<()> {
+		// Wait for submitted requests, so that the flush covers them
+		self.wait(|| (self.state.lock().stats.in_flight == 0).then_some(()));
+		if self.dead.load(Acquire) {
+			return Err(errno!(EIO));
+		}
+		let start = now();
+		let res = self.io.flush();
+		let end = now();
+		let mut state = self.state.lock();
+		state.stats.flushes += 1;
+		state.stats.flush_ticks += end.saturating_sub(start);
+		res
+	}
+
+	fn poll(&self, mask: u32) -> EResult<u32> {
+		self.io.poll(mask)
+	}
+
+	fn ioctl(&self, request: ioctl::Request, argp: *const c_void) -> EResult<u32> {
+		self.io.ioctl(request, argp)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::device::DeviceType;
+	use noop::Noop;
+
+	/// A device of 64 blocks, reading zeros and discarding writes.
+	struct NullDisk;
+
+	impl DeviceIO for NullDisk {
+		fn block_size(&self) -> NonZeroU64 {
+			NonZeroU64::new(512).unwrap()
+		}
+
+		fn blocks_count(&self) -> u64 {
+			64
+		}
+
+		fn read(&self, _off: u64, buf: &mut [u8]) -> EResult<usize> {
+			buf.fill(0);
+			Ok(buf.len())
+		}
+
+		fn write(&self, _off: u64, buf: &[u8]) -> EResult<usize> {
+			Ok(buf.len())
+		}
+	}
+
+	fn request(op: Op, sector: u64, count: u64) -> Request {
+		let mut buf = Vec::new();
+		buf.resize(count as usize * 512, 0).unwrap();
+		Request::new(op, sector, count, buf, Box::new(|_| {}).unwrap()).unwrap()
+	}
+
+	#[test_case]
+	fn request_merge() {
+		let mut req = request(Op::Write, 8, 8);
+		// Back merge
+		assert!(req.merge(request(Op::Write, 16, 8)).is_ok());
+		assert_eq!((req.sector(), req.end()), (8, 24));
+		// Front merge
+		assert!(req.merge(request(Op::Write, 0, 8)).is_ok());
+		assert_eq!((req.sector(), req.end()), (0, 24));
+		assert_eq!(req.segments.len(), 3);
+		// Not adjacent
+		assert!(req.merge(request(Op::Write, 32, 8)).is_err());
+		// Different direction
+		assert!(req.merge(request(Op::Read, 24, 8)).is_err());
+		// Too large
+		assert!(req.merge(request(Op::Write, 24, 256)).is_err());
+		assert_eq!((req.sector(), req.end()), (0, 24));
+	}
+
+	#[test_case]
+	fn queue_shutdown() {
+		let id = DeviceID {
+			dev_type: DeviceType::Block,
+			major: 0,
+			minor: 0,
+		};
+		let queue = RequestQueue::new(
+			id,
+			String::try_from(b"null").unwrap(),
+			Arc::new(NullDisk).unwrap(),
+			Box::new(Noop::default()).unwrap(),
+		)
+		.unwrap();
+		let results: Arc<Mutex<Vec<EResult<()>>>> = Arc::new(Mutex::new(Vec::new())).unwrap();
+		let submit = |op, off| {
+			let res = results.clone();
+			let callback = Box::new(move |r: EResult<Vec<u8>>| {
+				res.lock().push(r.map(|_| ())).unwrap();
+			});
+			let mut buf = Vec::new();
+			buf.resize(512, 0).unwrap();
+			queue.submit(op, off, buf, callback.unwrap());
+		};
+		// Requests are pending until the queue is run
+		submit(Op::Write, 0);
+		submit(Op::Read, 8);
+		assert!(results.lock().is_empty());
+		queue.shutdown();
+		assert_eq!(results.lock().len(), 2);
+		assert!(!queues().unwrap().iter().any(|q| q.id() == &id));
+		// Requests submitted afterwards fail as well
+		submit(Op::Write, 16);
+		let results = results.lock();
+		assert_eq!(results.len(), 3);
+		assert!(results.iter().all(|r| *r == Err(errno!(EIO))));
+		assert_eq!(queue.flush(), Err(errno!(EIO)));
+	}
+}
@@ -0,0 +1,59 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The noop scheduler executes requests in the order they are submitted.
+//!
+//! It is suited to devices for which the order of requests does not matter, such as virtual
+//! devices whose host does its own scheduling.
+
+use super::{Request, Scheduler};
+use crate::time::unit::Timestamp;
+use utils::{collections::btreemap::BTreeMap, errno::AllocResult};
+
+/// The noop scheduler.
+#[derive(Default)]
+pub struct Noop {
+	/// Pending requests, by arrival order.
+	requests: BTreeMap<u64, Request>,
+	/// The arrival number of the last request.
+	last: u64,
+}
+
+impl Scheduler for Noop {
+	fn name(&self) -> &'static str {
+		"noop"
+	}
+
+	fn add(&mut self, req: Request) -> AllocResult<bool> {
+		// Only the last request is considered for merging, which catches sequential I/O
+		let req = match self.requests.get_mut(&self.last) {
+			Some(last) => match last.merge(req) {
+				Ok(()) => return Ok(true),
+				Err(req) => req,
+			},
+			None => req,
+		};
+		self.last += 1;
+		self.requests.insert(self.last, req)?;
+		Ok(false)
+	}
+
+	fn dispatch(&mut self, _now: Timestamp) -> Option<Request> {
+		self.requests.pop_first().map(|(_, req)| req)
+	}
+}
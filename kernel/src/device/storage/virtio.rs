@@ -0,0 +1,488 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Virtio block device driver.
+//!
+//! Each request is made of a chain of three descriptors: a header describing the operation, the
+//! data buffer and a status byte written by the device. Several requests may be in flight at
+//! once, each using its own slot.
+
+pub use crate::device::virtio::{DEVICE_ID_BLOCK as DEVICE_ID, VENDOR_ID};
+use crate::{
+	arch::x86::{idt::IntFrame, pic},
+	device::{
+		manager::PhysicalDevice,
+		virtio::{Transport, Virtqueue, DESC_NEXT, DESC_WRITE, ISR_QUEUE},
+		DeviceIO,
+	},
+	event,
+	event::CallbackResult,
+	file::wait_queue::WaitQueue,
+	memory::dma::DmaBuffer,
+	process::scheduler,
+	sync::mutex::IntMutex,
+	time::unit::Timestamp,
+};
+use core::{
+	cmp::min,
+	hint,
+	mem::{size_of, ManuallyDrop},
+	num::NonZeroU64,
+	ptr,
+	sync::atomic::{fence, Ordering::SeqCst},
+};
+use utils::{collections::vec::Vec, errno, errno::EResult, limits::PAGE_SIZE, ptr::arc::Arc};
+
+/// Feature: The device is read-only
+const FEATURE_RO: u32 = 1 << 5;
+/// Feature: The device reports its logical block size in its configuration
+const FEATURE_BLK_SIZE: u32 = 1 << 6;
+/// Feature: The device supports the flush command
+const FEATURE_FLUSH: u32 = 1 << 9;
+
+/// Configuration: The offset of the capacity, in sectors of 512 bytes (64 bits)
+const CONFIG_CAPACITY: usize = 0x0;
+/// Configuration: The offset of the logical block size (32 bits)
+const CONFIG_BLK_SIZE: usize = 0x14;
+
+/// Request type: Read
+const REQ_IN: u32 = 0;
+/// Request type: Write
+const REQ_OUT: u32 = 1;
+/// Request type: Flush the device's write cache
+const REQ_FLUSH: u32 = 4;
+
+/// Request status: Success
+const STATUS_OK: u8 = 0;
+
+/// The size of the sectors used to address the device, regardless of its block size.
+const SECTOR_SIZE: u64 = 512;
+
+/// The index of the request queue.
+const REQUEST_QUEUE: u16 = 0;
+/// The maximum number of slots.
+const MAX_SLOTS: u16 = 16;
+/// The number of pages of the bounce buffer of a slot.
+const SLOT_BUF_PAGES: usize = 4;
+/// The size of the bounce buffer of a slot, in bytes. This is the maximum size of a single
+/// request.
+const SLOT_BUF_SIZE: usize = SLOT_BUF_PAGES * PAGE_SIZE;
+/// The space reserved for the header and the status of a slot.
+const SLOT_HDR_SIZE: usize = 32;
+/// The offset of the status byte in the header space of a slot.
+const STATUS_OFF: usize = size_of::<RequestHeader>();
+
+/// The maximum number of iterations when busy-waiting for the completion of a request.
+const POLL_ITER: usize = 10000000;
+/// The maximum duration of a single sleep while waiting for a request, in milliseconds.
+///
+/// This bounds the delay in case a wakeup is missed.
+const SLEEP_SLICE: Timestamp = 10;
+
+/// The header of a request.
+#[repr(C)]
+struct RequestHeader {
+	/// The type of the request.
+	type_: u32,
+	/// Reserved.
+	_reserved: u32,
+	/// The sector at which the request starts.
+	sector: u64,
+}
+
+/// The data buffer of a request.
+enum Data<'a> {
+	/// The request does not transfer data.
+	None,
+	/// Data is read from the device to the buffer.
+	In(&'a mut [u8]),
+	/// Data is written from the buffer to the device.
+	Out(&'a [u8]),
+}
+
+/// The request queue along with the state of slots.
+struct State {
+	/// The request queue.
+	queue: Virtqueue,
+	/// The bitmap of slots that are in use.
+	allocated: u32,
+	/// The bitmap of slots whose request has been completed by the device.
+	done: u32,
+}
+
+impl State {
+	/// Collects the requests the device is done with.
+	fn update(&mut self) {
+		while let Some((head, _)) = self.queue.pop_used() {
+			self.done |= 1 << (head / 3);
+		}
+	}
+}
+
+/// A virtio block device.
+pub struct VirtioBlk {
+	/// The transport of the device.
+	transport: Transport,
+	/// The request queue along with the state of slots.
+	state: IntMutex<State>,
+	/// The number of slots.
+	slots: u16,
+	/// The headers and status bytes of requests, one for each slot.
+	hdrs: DmaBuffer,
+	/// The bounce buffers of requests, one for each slot.
+	bufs: DmaBuffer,
+	/// The queue of processes waiting for a slot or for the completion of a request.
+	wait_queue: WaitQueue,
+
+	/// The size of a block in bytes.
+	block_size: u64,
+	/// The number of blocks on the device.
+	blocks_count: u64,
+	/// Tells whether the device is read-only.
+	read_only: bool,
+	/// Tells whether the device supports the flush command.
+	flush: bool,
+}
+
+/// An interrupt line used by a virtio block device.
+struct Irq {
+	/// The IRQ number.
+	line: u8,
+	/// The device.
+	dev: Arc<VirtioBlk>,
+}
+
+/// The list of devices waiting for interrupts.
+static IRQS: IntMutex<Vec<Irq>> = IntMutex::new(Vec::new());
+
+/// Stops handling interrupts for `io`, if it is a virtio block device.
+///
+/// This releases the reference held for interrupt handling, so that the device can be freed once
+/// it is removed.
+pub(super) fn release(io: &Arc<dyn DeviceIO>) {
+	IRQS.lock()
+		.retain(|irq| !ptr::addr_eq(Arc::as_ptr(&irq.dev), Arc::as_ptr(io)));
+}
+
+/// Handles an interrupt for devices on the line corresponding to the vector `id`.
+fn interrupt_handler(id: u32, _code: u32, _frame: &mut IntFrame, _ring: u8) -> CallbackResult {
+	let line = id - 0x20;
+	let irqs = IRQS.lock();
+	for irq in irqs.iter().filter(|irq| irq.line as u32 == line) {
+		// Reading the status deasserts the interrupt
+		if irq.dev.transport.interrupt_status() & ISR_QUEUE != 0 {
+			irq.dev.state.lock().update();
+			irq.dev.wait_queue.wake_all();
+		}
+	}
+	CallbackResult::Continue
+}
+
+impl VirtioBlk {
+	/// Initializes the device `dev`.
+	pub fn new(dev: &dyn PhysicalDevice) -> EResult<Arc<Self>> {
+		let (transport, features) =
+			Transport::new(dev, FEATURE_RO | FEATURE_BLK_SIZE | FEATURE_FLUSH)
+				.ok_or_else(|| errno!(ENODEV))?;
+		let blk =
+			Self::init(dev, transport.clone(), features).inspect_err(|_| transport.fail())?;
+		let blk = Arc::new(blk)?;
+		if let Some(line) = dev.get_interrupt_line() {
+			let mut irqs = IRQS.lock();
+			if !irqs.iter().any(|irq| irq.line == line) {
+				let hook = event::register_callback(0x20 + line as u32, interrupt_handler)?;
+				let _ = ManuallyDrop::new(hook);
+				if line >= 8 {
+					// Cascade
+					pic::enable_irq(2);
+				}
+				pic::enable_irq(line);
+			}
+			irqs.push(Irq {
+				line,
+				dev: blk.clone(),
+			})?;
+		}
+		Ok(blk)
+	}
+
+	/// Reads the configuration of the device, sets up its queue and tells it the driver is
+	/// ready.
+	fn init(dev: &dyn PhysicalDevice, transport: Transport, features: u32) -> EResult<Self> {
+		dev.enable_bus_mastering();
+		let capacity = transport.read_config_u32(CONFIG_CAPACITY) as u64
+			| ((transport.read_config_u32(CONFIG_CAPACITY + 4) as u64) << 32);
+		let block_size = if features & FEATURE_BLK_SIZE != 0 {
+			transport.read_config_u32(CONFIG_BLK_SIZE) as u64
+		} else {
+			SECTOR_SIZE
+		};
+		if !block_size.is_power_of_two()
+			|| block_size < SECTOR_SIZE
+			|| block_size > SLOT_BUF_SIZE as u64
+		{
+			return Err(errno!(EIO));
+		}
+		let mut queue = Virtqueue::new(&transport, REQUEST_QUEUE)?;
+		let slots = min(queue.size() / 3, MAX_SLOTS);
+		if slots == 0 {
+			return Err(errno!(ENODEV));
+		}
+		let hdrs = DmaBuffer::new((slots as usize * SLOT_HDR_SIZE).div_ceil(PAGE_SIZE))?;
+		let bufs = DmaBuffer::new(slots as usize * SLOT_BUF_PAGES)?;
+		// The header and status descriptors never change
+		for slot in 0..slots {
+			let head = slot * 3;
+			let off = slot as usize * SLOT_HDR_SIZE;
+			queue.set_desc(
+				head,
+				hdrs.phys_addr(off).0 as _,
+				size_of::<RequestHeader>() as _,
+				DESC_NEXT,
+				head + 1,
+			);
+			queue.set_desc(
+				head + 2,
+				hdrs.phys_addr(off + STATUS_OFF).0 as _,
+				1,
+				DESC_WRITE,
+				0,
+			);
+		}
+		transport.ready();
+		Ok(Self {
+			transport,
+			state: IntMutex::new(State {
+				queue,
+				allocated: 0,
+				done: 0,
+			}),
+			slots,
+			hdrs,
+			bufs,
+			wait_queue: WaitQueue::new(),
+
+			block_size,
+			blocks_count: capacity * SECTOR_SIZE / block_size,
+			read_only: features & FEATURE_RO != 0,
+			flush: features & FEATURE_FLUSH != 0,
+		})
+	}
+
+	/// Waits until `f` returns `Some`, collecting completed requests before each call.
+	///
+	/// The current process sleeps if possible. Completions are signaled by interrupts, but the
+	/// queue is also checked periodically in case the device has no interrupt line.
+	///
+	/// If busy-waiting and the device does not respond in time, the function returns `None`.
+	fn wait_for<F: FnMut(&mut State) -> Option<T>, T>(&self, mut f: F) -> Option<T> {
+		let mut check = || {
+			let mut state = self.state.lock();
+			state.update();
+			f(&mut state)
+		};
+		if scheduler::can_sleep() {
+			loop {
+				// Signals are ignored since the request is being executed anyway
+				if let Ok(val) = self
+					.wait_queue
+					.wait_until_timeout(Some(SLEEP_SLICE), &mut check)
+				{
+					return Some(val);
+				}
+			}
+		}
+		for _ in 0..POLL_ITER {
+			if let Some(val) = check() {
+				return Some(val);
+			}
+			hint::spin_loop();
+		}
+		None
+	}
+
+	/// Executes a request of type `type_` starting at sector `sector`, transferring `data`.
+	///
+	/// The length of the buffer must fit in a bounce buffer.
+	fn request(&self, type_: u32, sector: u64, data: Data) -> EResult<()> {
+		let slot = self
+			.wait_for(|state| {
+				let free = !state.allocated & ((1 << self.slots) - 1);
+				(free != 0).then(|| {
+					let slot = free.trailing_zeros() as u16;
+					state.allocated |= 1 << slot;
+					slot
+				})
+			})
+			.ok_or_else(|| errno!(EIO))?;
+		let (len, flags) = match &data {
+			Data::None => (0, 0),
+			Data::In(buf) => (buf.len(), DESC_WRITE),
+			Data::Out(buf) => (buf.len(), 0),
+		};
+		debug_assert!(len <= SLOT_BUF_SIZE);
+		let head = slot * 3;
+		let hdr_off = slot as usize * SLOT_HDR_SIZE;
+		let buf_off = slot as usize * SLOT_BUF_SIZE;
+		unsafe {
+			ptr::write_volatile(
+				self.hdrs.as_ptr(hdr_off) as *mut RequestHeader,
+				RequestHeader {
+					type_,
+					_reserved: 0,
+					sector,
+				},
+			);
+			ptr::write_volatile(self.hdrs.as_ptr(hdr_off + STATUS_OFF), u8::MAX);
+			if let Data::Out(buf) = &data {
+				ptr::copy_nonoverlapping(buf.as_ptr(), self.bufs.as_ptr(buf_off), len);
+			}
+		}
+		{
+			let mut state = self.state.lock();
+			if len > 0 {
+				state.queue.set_desc(
+					head,
+					self.hdrs.phys_addr(hdr_off).0 as _,
+					size_of::<RequestHeader>() as _,
+					DESC_NEXT,
+					head + 1,
+				);
+				state.queue.set_desc(
+					head + 1,
+					self.bufs.phys_addr(buf_off).0 as _,
+					len as _,
+					flags | DESC_NEXT,
+					head + 2,
+				);
+			} else {
+				// Skip the data descriptor
+				state.queue.set_desc(
+					head,
+					self.hdrs.phys_addr(hdr_off).0 as _,
+					size_of::<RequestHeader>() as _,
+					DESC_NEXT,
+					head + 2,
+				);
+			}
+			// The request must be in memory before it is made available
+			fence(SeqCst);
+			state.queue.push_avail(head);
+			self.transport.notify(REQUEST_QUEUE);
+		}
+		let bit = 1 << slot;
+		let done = self.wait_for(|state| {
+			(state.done & bit != 0).then(|| {
+				state.done &= !bit;
+			})
+		});
+		// If the device did not respond, the slot cannot be reused since the device may still
+		// access it
+		done.ok_or_else(|| errno!(EIO))?;
+		fence(SeqCst);
+		let status = unsafe { ptr::read_volatile(self.hdrs.as_ptr(hdr_off + STATUS_OFF)) };
+		if status == STATUS_OK {
+			if let Data::In(buf) = data {
+				unsafe {
+					ptr::copy_nonoverlapping(self.bufs.as_ptr(buf_off), buf.as_mut_ptr(), len);
+				}
+			}
+		}
+		self.state.lock().allocated &= !bit;
+		self.wait_queue.wake_all();
+		if status != STATUS_OK {
+			return Err(errno!(EIO));
+		}
+		Ok(())
+	}
+
+	/// Checks that `len` bytes starting at block `off` are in bounds of the device.
+	///
+	/// On success, the function returns the number of bytes to transfer.
+	fn check_bounds(&self, off: u64, len: usize) -> EResult<usize> {
+		let count = len as u64 / self.block_size;
+		if off
+			.checked_add(count)
+			.is_none_or(|end| end > self.blocks_count)
+		{
+			return Err(errno!(EINVAL));
+		}
+		Ok((count * self.block_size) as usize)
+	}
+}
+
+impl DeviceIO for VirtioBlk {
+	fn block_size(&self) -> NonZeroU64 {
+		self.block_size.try_into().unwrap()
+	}
+
+	fn blocks_count(&self) -> u64 {
+		self.blocks_count
+	}
+
+	fn read(&self, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		let len = self.check_bounds(off, buf.len())?;
+		let sector = off * (self.block_size / SECTOR_SIZE);
+		for (i, buf) in buf[..len].chunks_mut(SLOT_BUF_SIZE).enumerate() {
+			let sector = sector + (i * SLOT_BUF_SIZE) as u64 / SECTOR_SIZE;
+			self.request(REQ_IN, sector, Data::In(buf))?;
+		}
+		Ok(len)
+	}
+
+	fn write(&self, off: u64, buf: &[u8]) -> EResult<usize> {
+		if self.read_only {
+			return Err(errno!(EROFS));
+		}
+		let len = self.check_bounds(off, buf.len())?;
+		let sector = off * (self.block_size / SECTOR_SIZE);
+		for (i, buf) in buf[..len].chunks(SLOT_BUF_SIZE).enumerate() {
+			let sector = sector + (i * SLOT_BUF_SIZE) as u64 / SECTOR_SIZE;
+			self.request(REQ_OUT, sector, Data::Out(buf))?;
+		}
+		Ok(len)
+	}
+
+	fn flush(&self) -> EResult<()> {
+		// Without the feature, the device does not cache writes
+		if !self.flush {
+			return Ok(());
+		}
+		self.request(REQ_FLUSH, 0, Data::None)
+	}
+}
+
+impl Drop for VirtioBlk {
+	fn drop(&mut self) {
+		// Make sure the device does not access the memory after it is freed
+		self.transport.reset();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn virtio_blk_layout() {
+		assert_eq!(size_of::<RequestHeader>(), 16);
+		assert!(size_of::<RequestHeader>() < SLOT_HDR_SIZE);
+		// A bounce buffer must hold whole blocks
+		assert_eq!(SLOT_BUF_SIZE as u64 % SECTOR_SIZE, 0);
+	}
+}
@@ -0,0 +1,333 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Virtio is a standard for paravirtualized devices, exposed by hypervisors such as QEMU.
+//!
+//! This module implements the legacy PCI transport, in which the registers of the device are
+//! accessed through its first BAR, along with split virtqueues.
+//!
+//! A virtqueue is made of three areas shared with the device:
+//! - the descriptor table, describing buffers in memory
+//! - the available ring, in which the driver puts buffers to be used by the device
+//! - the used ring, in which the device puts buffers it is done with
+
+use crate::{
+	device::{bar::BAR, manager::PhysicalDevice},
+	memory::dma::DmaBuffer,
+};
+use core::{
+	mem::size_of,
+	ptr,
+	sync::atomic::{fence, Ordering},
+};
+use utils::{errno, errno::EResult, limits::PAGE_SIZE};
+
+/// The vendor ID of virtio devices.
+pub const VENDOR_ID: u16 = 0x1af4;
+/// The device ID of transitional network devices.
+pub const DEVICE_ID_NET: u16 = 0x1000;
+/// The device ID of transitional block devices.
+pub const DEVICE_ID_BLOCK: u16 = 0x1001;
+
+/// Register: Features offered by the device (32 bits)
+const REG_DEVICE_FEATURES: usize = 0x00;
+/// Register: Features accepted by the driver (32 bits)
+const REG_DRIVER_FEATURES: usize = 0x04;
+/// Register: Page frame number of the selected queue (32 bits)
+const REG_QUEUE_ADDRESS: usize = 0x08;
+/// Register: Size of the selected queue (16 bits)
+const REG_QUEUE_SIZE: usize = 0x0c;
+/// Register: Index of the selected queue (16 bits)
+const REG_QUEUE_SELECT: usize = 0x0e;
+/// Register: Notifies the device that a queue has new available buffers (16 bits)
+const REG_QUEUE_NOTIFY: usize = 0x10;
+/// Register: Device status (8 bits)
+const REG_DEVICE_STATUS: usize = 0x12;
+/// Register: Interrupt status. Reading it acknowledges the interrupt (8 bits)
+const REG_ISR_STATUS: usize = 0x13;
+/// The offset of the device-specific configuration, when MSI-X is disabled.
+const REG_CONFIG: usize = 0x14;
+
+/// Status: The guest has noticed the device
+const STATUS_ACKNOWLEDGE: u8 = 1;
+/// Status: The guest knows how to drive the device
+const STATUS_DRIVER: u8 = 2;
+/// Status: The driver is ready
+const STATUS_DRIVER_OK: u8 = 4;
+/// Status: The driver gave up on the device
+const STATUS_FAILED: u8 = 128;
+
+/// Interrupt status: A queue has been updated
+pub const ISR_QUEUE: u8 = 1;
+/// Interrupt status: The configuration of the device has changed
+pub const ISR_CONFIG: u8 = 2;
+
+/// Descriptor flag: The buffer continues in the descriptor given by the `next` field
+pub const DESC_NEXT: u16 = 1;
+/// Descriptor flag: The buffer is written by the device (otherwise, it is read)
+pub const DESC_WRITE: u16 = 2;
+
+/// The alignment of the used ring for the legacy interface.
+const QUEUE_ALIGN: usize = PAGE_SIZE;
+
+/// A descriptor of a buffer, in the descriptor table of a virtqueue.
+#[repr(C)]
+struct Descriptor {
+	/// The physical address of the buffer.
+	addr: u64,
+	/// The length of the buffer in bytes.
+	len: u32,
+	/// Flags.
+	flags: u16,
+	/// The index of the next descriptor, if the [`DESC_NEXT`] flag is set.
+	next: u16,
+}
+
+/// An element of the used ring.
+#[repr(C)]
+struct UsedElem {
+	/// The index of the head of the descriptor chain.
+	id: u32,
+	/// The number of bytes written by the device to the buffer.
+	len: u32,
+}
+
+/// The legacy PCI transport of a virtio device.
+#[derive(Clone, Debug)]
+pub struct Transport {
+	/// The BAR to access the device's registers.
+	bar: BAR,
+}
+
+impl Transport {
+	/// Resets the device `dev` and starts its initialization.
+	///
+	/// `features` is the set of features supported by the driver. The function returns the
+	/// transport along with the features that are supported by both the driver and the device.
+	///
+	/// If the device does not have a legacy interface, the function returns `None`.
+	pub fn new(dev: &dyn PhysicalDevice, features: u32) -> Option<(Self, u32)> {
+		let bar = dev.get_bars().first()?.clone()?;
+		if !matches!(bar, BAR::IOSpace { .. }) {
+			return None;
+		}
+		let transport = Self {
+			bar,
+		};
+		// Reset
+		transport.set_status(0);
+		transport.set_status(STATUS_ACKNOWLEDGE);
+		transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+		let features = transport.bar.read::<u32>(REG_DEVICE_FEATURES) as u32 & features;
+		transport
+			.bar
+			.write::<u32>(REG_DRIVER_FEATURES, features as _);
+		Some((transport, features))
+	}
+
+	/// Writes the status register.
+	fn set_status(&self, status: u8) {
+		self.bar.write::<u8>(REG_DEVICE_STATUS, status as _);
+	}
+
+	/// Tells the device the driver is ready, ending the initialization.
+	pub fn ready(&self) {
+		self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+	}
+
+	/// Resets the device, which stops it from accessing memory.
+	pub fn reset(&self) {
+		self.set_status(0);
+	}
+
+	/// Tells the device the driver gave up on it.
+	pub fn fail(&self) {
+		self.set_status(STATUS_FAILED);
+	}
+
+	/// Reads and acknowledges the interrupt status.
+	pub fn interrupt_status(&self) -> u8 {
+		self.bar.read::<u8>(REG_ISR_STATUS) as _
+	}
+
+	/// Reads a byte of the device-specific configuration at offset `off`.
+	pub fn read_config_u8(&self, off: usize) -> u8 {
+		self.bar.read::<u8>(REG_CONFIG + off) as _
+	}
+
+	/// Reads a 16 bits value of the device-specific configuration at offset `off`.
+	pub fn read_config_u16(&self, off: usize) -> u16 {
+		self.bar.read::<u16>(REG_CONFIG + off) as _
+	}
+
+	/// Reads a 32 bits value of the device-specific configuration at offset `off`.
+	pub fn read_config_u32(&self, off: usize) -> u32 {
+		self.bar.read::<u32>(REG_CONFIG + off) as _
+	}
+
+	/// Notifies the device that new buffers are available on the queue `index`.
+	pub fn notify(&self, index: u16) {
+		self.bar.write::<u16>(REG_QUEUE_NOTIFY, index as _);
+	}
+}
+
+/// A split virtqueue.
+#[derive(Debug)]
+pub struct Virtqueue {
+	/// The index of the queue on the device.
+	index: u16,
+	/// The number of descriptors in the queue.
+	size: u16,
+	/// The memory shared with the device.
+	mem: DmaBuffer,
+	/// The offset of the available ring in `mem`.
+	avail_off: usize,
+	/// The offset of the used ring in `mem`.
+	used_off: usize,
+	/// The index of the next element of the used ring to be consumed.
+	last_used: u16,
+}
+
+impl Virtqueue {
+	/// Sets up the queue `index` of the device.
+	///
+	/// If the queue does not exist, the function returns [`errno::ENODEV`].
+	pub fn new(transport: &Transport, index: u16) -> EResult<Self> {
+		let bar = &transport.bar;
+		bar.write::<u16>(REG_QUEUE_SELECT, index as _);
+		let size = bar.read::<u16>(REG_QUEUE_SIZE) as u16;
+		if size == 0 {
+			return Err(errno!(ENODEV));
+		}
+		let (avail_off, used_off, len) = Self::layout(size);
+		let mem = DmaBuffer::new(len.div_ceil(PAGE_SIZE))?;
+		let pfn = mem.phys_addr(0).0 / PAGE_SIZE;
+		bar.write::<u32>(REG_QUEUE_ADDRESS, pfn as _);
+		Ok(Self {
+			index,
+			size,
+			mem,
+			avail_off,
+			used_off,
+			last_used: 0,
+		})
+	}
+
+	/// Returns the offsets of the available and used rings for a queue of `size` descriptors,
+	/// along with the total size of the queue in bytes.
+	fn layout(size: u16) -> (usize, usize, usize) {
+		let size = size as usize;
+		let avail_off = size * size_of::<Descriptor>();
+		// Flags, index, ring and used event
+		let avail_len = (3 + size) * size_of::<u16>();
+		let used_off = (avail_off + avail_len).next_multiple_of(QUEUE_ALIGN);
+		// Flags, index, ring and available event
+		let used_len = 3 * size_of::<u16>() + size * size_of::<UsedElem>();
+		(avail_off, used_off, used_off + used_len)
+	}
+
+	/// Returns the index of the queue on the device.
+	#[inline]
+	pub fn index(&self) -> u16 {
+		self.index
+	}
+
+	/// Returns the number of descriptors in the queue.
+	#[inline]
+	pub fn size(&self) -> u16 {
+		self.size
+	}
+
+	/// Writes the descriptor `i`.
+	///
+	/// Arguments:
+	/// - `addr` is the physical address of the buffer
+	/// - `len` is the length of the buffer in bytes
+	/// - `flags` is the set of flags of the descriptor
+	/// - `next` is the index of the next descriptor in the chain, if [`DESC_NEXT`] is set
+	pub fn set_desc(&mut self, i: u16, addr: u64, len: u32, flags: u16, next: u16) {
+		assert!(i < self.size);
+		let desc = self.mem.as_ptr(i as usize * size_of::<Descriptor>()) as *mut Descriptor;
+		unsafe {
+			ptr::write_volatile(
+				desc,
+				Descriptor {
+					addr,
+					len,
+					flags,
+					next,
+				},
+			);
+		}
+	}
+
+	/// Returns a pointer to the 16 bits field at offset `off`.
+	#[inline]
+	fn field(&self, off: usize) -> *mut u16 {
+		self.mem.as_ptr(off) as _
+	}
+
+	/// Makes the descriptor chain starting at `head` available to the device.
+	///
+	/// The device must then be notified with [`Transport::notify`].
+	pub fn push_avail(&mut self, head: u16) {
+		let idx_ptr = self.field(self.avail_off + size_of::<u16>());
+		unsafe {
+			let idx = ptr::read_volatile(idx_ptr);
+			let slot = 2 + (idx % self.size) as usize;
+			ptr::write_volatile(self.field(self.avail_off + slot * size_of::<u16>()), head);
+			// The entry must be visible to the device before the index
+			fence(Ordering::SeqCst);
+			ptr::write_volatile(idx_ptr, idx.wrapping_add(1));
+		}
+	}
+
+	/// Returns the next descriptor chain the device is done with, if any.
+	///
+	/// The function returns the index of the head of the chain along with the number of bytes
+	/// written by the device.
+	pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+		let idx = unsafe { ptr::read_volatile(self.field(self.used_off + size_of::<u16>())) };
+		if idx == self.last_used {
+			return None;
+		}
+		// Read the entry only after the index
+		fence(Ordering::SeqCst);
+		let slot = (self.last_used % self.size) as usize;
+		let off = self.used_off + 2 * size_of::<u16>() + slot * size_of::<UsedElem>();
+		let elem = unsafe { ptr::read_volatile(self.mem.as_ptr(off) as *const UsedElem) };
+		self.last_used = self.last_used.wrapping_add(1);
+		Some((elem.id as _, elem.len))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn virtqueue_layout() {
+		// Values from the legacy interface of the virtio specification
+		let (avail_off, used_off, len) = Virtqueue::layout(256);
+		assert_eq!(avail_off, 4096);
+		assert_eq!(used_off, 8192);
+		assert_eq!(len, 8192 + 6 + 256 * 8);
+		let (_, used_off, _) = Virtqueue::layout(128);
+		assert_eq!(used_off, 4096);
+	}
+}
@@ -22,7 +22,7 @@ use crate::{
 	arch::x86::{idt, idt::IntFrame, pic},
 	crypto::rand,
 	process,
-	sync::mutex::IntMutex,
+	sync::{mutex, mutex::IntMutex},
 };
 use core::ptr;
 use utils::{collections::vec::Vec, errno::AllocResult};
@@ -159,6 +159,11 @@ extern "C" fn interrupt_handler(frame: &mut IntFrame) {
 	let id = frame.int as u32;
 	let ring = (frame.cs & 0b11) as u8;
 	let code = frame.code as u32;
+	// Contrary to exceptions, hardware interrupt handlers must not sleep
+	let irq = id.checked_sub(ERROR_MESSAGES.len() as u32);
+	if irq.is_some() {
+		mutex::enter_atomic();
+	}
 	// Call corresponding callbacks
 	let callbacks = &CALLBACKS[id as usize];
 	let mut i = 0;
@@ -178,7 +183,8 @@ extern "C" fn interrupt_handler(frame: &mut IntFrame) {
 		}
 	}
 	// If not a hardware exception, send EOI
-	if let Some(irq) = id.checked_sub(ERROR_MESSAGES.len() as u32) {
+	if let Some(irq) = irq {
+		mutex::leave_atomic();
 		pic::end_of_interrupt(irq as _);
 	}
 	process::yield_current(ring, frame);
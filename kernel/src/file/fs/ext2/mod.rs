@@ -149,8 +149,6 @@ const WRITE_REQUIRED_DIRECTORY_BINARY_TREE: u32 = 0x4;
 /// The maximum length of a name in the filesystem.
 const MAX_NAME_LEN: usize = 255;
 
-// TODO page cache on read_block and write_block
-
 /// Reads the `off`th block on the given device and writes the data onto the
 /// given buffer.
 ///
@@ -0,0 +1,73 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Implementation of the `diskstats` file, which gives I/O statistics of storage devices.
+
+use crate::{
+	device::storage::queue,
+	file::{fs::NodeOps, FileLocation, FileType, Stat},
+	format_content,
+};
+use core::{fmt, fmt::Formatter};
+use utils::errno::EResult;
+
+/// The `diskstats` file.
+#[derive(Debug, Default)]
+pub struct DiskStats;
+
+impl NodeOps for DiskStats {
+	fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+		Ok(Stat {
+			mode: FileType::Regular.to_mode() | 0o444,
+			..Default::default()
+		})
+	}
+
+	fn read_content(&self, _loc: &FileLocation, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		format_content!(off, buf, "{}", self)
+	}
+}
+
+impl fmt::Display for DiskStats {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let mut queues = queue::queues().map_err(|_| fmt::Error)?;
+		queues.sort_unstable_by_key(|q| (q.id().major, q.id().minor));
+		// Partitions are not tracked, since the queue is shared by the whole disk
+		for q in queues {
+			let s = q.stats();
+			let id = q.id();
+			write!(f, "{:4} {:7} {} ", id.major, id.minor, q.name())?;
+			write!(
+				f,
+				"{} {} {} {} {} {} {} {} ",
+				s.ios[0],
+				s.merges[0],
+				s.sectors[0],
+				s.ticks[0],
+				s.ios[1],
+				s.merges[1],
+				s.sectors[1],
+				s.ticks[1]
+			)?;
+			write!(f, "{} {} {} ", s.in_flight, s.io_ticks, s.time_in_queue)?;
+			// Discards are not supported
+			writeln!(f, "0 0 0 0 {} {}", s.flushes, s.flush_ticks)?;
+		}
+		Ok(())
+	}
+}
@@ -19,7 +19,9 @@
 //! The `procfs` is a virtual filesystem which provides information about
 //! processes.
 
+mod disk_stats;
 mod mem_info;
+mod net_dir;
 mod proc_dir;
 mod self_link;
 mod sys_dir;
@@ -39,16 +41,25 @@ use crate::{
 			Statfs,
 		},
 		perm::{Gid, Uid},
-		DirEntry, FileLocation, FileType, INode, Stat,
+		writeback, DirEntry, FileLocation, FileType, INode, Stat,
 	},
 	process::{pid::Pid, scheduler::SCHEDULER, Process},
 };
+use disk_stats::DiskStats;
 use mem_info::MemInfo;
+use net_dir::{
+	arp::Arp,
+	dev::Dev,
+	if_inet6::IfInet6,
+	inet::{Tcp, Tcp6, Udp, Udp6},
+	route::Route,
+	unix::Unix,
+};
 use proc_dir::{
 	cmdline::Cmdline, cwd::Cwd, exe::Exe, mounts::Mounts, stat::StatNode, status::Status,
 };
 use self_link::SelfNode;
-use sys_dir::OsRelease;
+use sys_dir::{IntParam, OsRelease};
 use uptime::Uptime;
 use utils::{
 	boxed::Box,
@@ -83,6 +94,11 @@ impl RootDir {
 	/// processes.
 	const STATIC: StaticDir = StaticDir {
 		entries: &[
+			StaticEntryBuilder {
+				name: b"diskstats",
+				entry_type: FileType::Regular,
+				init: entry_init_default::<DiskStats>,
+			},
 			StaticEntryBuilder {
 				name: b"meminfo",
 				entry_type: FileType::Regular,
@@ -93,6 +109,62 @@ impl RootDir {
 				entry_type: FileType::Link,
 				init: |_| box_wrap(StaticLink(b"self/mounts")),
 			},
+			StaticEntryBuilder {
+				name: b"net",
+				entry_type: FileType::Directory,
+				init: |_| {
+					box_wrap(StaticDir {
+						entries: &[
+							StaticEntryBuilder {
+								name: b"arp",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<Arp>,
+							},
+							StaticEntryBuilder {
+								name: b"dev",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<Dev>,
+							},
+							StaticEntryBuilder {
+								name: b"if_inet6",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<IfInet6>,
+							},
+							StaticEntryBuilder {
+								name: b"route",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<Route>,
+							},
+							StaticEntryBuilder {
+								name: b"tcp",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<Tcp>,
+							},
+							StaticEntryBuilder {
+								name: b"tcp6",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<Tcp6>,
+							},
+							StaticEntryBuilder {
+								name: b"udp",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<Udp>,
+							},
+							StaticEntryBuilder {
+								name: b"udp6",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<Udp6>,
+							},
+							StaticEntryBuilder {
+								name: b"unix",
+								entry_type: FileType::Regular,
+								init: entry_init_default::<Unix>,
+							},
+						],
+						data: (),
+					})
+				},
+			},
 			StaticEntryBuilder {
 				name: b"self",
 				entry_type: FileType::Link,
@@ -103,20 +175,51 @@ impl RootDir {
 				entry_type: FileType::Directory,
 				init: |_| {
 					box_wrap(StaticDir {
-						entries: &[(StaticEntryBuilder {
-							name: b"kernel",
-							entry_type: FileType::Directory,
-							init: |_| {
-								box_wrap(StaticDir {
-									entries: &[StaticEntryBuilder {
-										name: b"osrelease",
-										entry_type: FileType::Regular,
-										init: entry_init_default::<OsRelease>,
-									}],
-									data: (),
-								})
+						entries: &[
+							StaticEntryBuilder {
+								name: b"kernel",
+								entry_type: FileType::Directory,
+								init: |_| {
+									box_wrap(StaticDir {
+										entries: &[StaticEntryBuilder {
+											name: b"osrelease",
+											entry_type: FileType::Regular,
+											init: entry_init_default::<OsRelease>,
+										}],
+										data: (),
+									})
+								},
+							},
+							StaticEntryBuilder {
+								name: b"vm",
+								entry_type: FileType::Directory,
+								init: |_| {
+									box_wrap(StaticDir {
+										entries: &[
+											StaticEntryBuilder {
+												name: b"dirty_expire_centisecs",
+												entry_type: FileType::Regular,
+												init: |_| {
+													box_wrap(IntParam(
+														&writeback::DIRTY_EXPIRE_CENTISECS,
+													))
+												},
+											},
+											StaticEntryBuilder {
+												name: b"dirty_writeback_centisecs",
+												entry_type: FileType::Regular,
+												init: |_| {
+													box_wrap(IntParam(
+														&writeback::DIRTY_WRITEBACK_CENTISECS,
+													))
+												},
+											},
+										],
+										data: (),
+									})
+								},
 							},
-						})],
+						],
 						data: (),
 					})
 				},
@@ -153,8 +256,8 @@ impl NodeOps for RootDir {
 		let Some(pid) = pid else {
 			return Self::STATIC.entry_by_name_inner(name);
 		};
-		// Check the process exists
-		if Process::get_by_pid(pid).is_none() {
+		// Check the process exists. Kernel threads are not exposed
+		if Process::get_by_pid(pid).is_none_or(|proc| proc.is_kernel_thread()) {
 			return Ok(None);
 		}
 		// Return the entry for the process
@@ -220,6 +323,7 @@ impl NodeOps for RootDir {
 			// TODO start iterating at `off`
 			let pid = sched
 				.iter_process()
+				.filter(|(_, proc)| !proc.is_kernel_thread())
 				.map(|(pid, _)| pid)
 				.find(|pid| **pid >= off as Pid);
 			if let Some(pid) = pid {
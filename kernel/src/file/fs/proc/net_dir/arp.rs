@@ -0,0 +1,81 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Implementation of the `arp` file, which lists the entries of the IPv4 neighbor cache.
+
+use crate::{
+	file::{fs::NodeOps, FileLocation, FileType, Stat},
+	format_content,
+	net::{neighbor::NEIGHBORS, Address},
+};
+use core::{fmt, fmt::Formatter};
+use utils::errno::EResult;
+
+/// Returns the number of characters of the decimal representation of `n`.
+fn digits(n: u8) -> usize {
+	match n {
+		0..10 => 1,
+		10..100 => 2,
+		_ => 3,
+	}
+}
+
+/// The `arp` file.
+#[derive(Debug, Default)]
+pub struct Arp;
+
+impl NodeOps for Arp {
+	fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+		Ok(Stat {
+			mode: FileType::Regular.to_mode() | 0o444,
+			..Default::default()
+		})
+	}
+
+	fn read_content(&self, _loc: &FileLocation, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		format_content!(off, buf, "{}", self)
+	}
+}
+
+impl fmt::Display for Arp {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		writeln!(
+			f,
+			"IP address       HW type     Flags       HW address            Mask     Device"
+		)?;
+		let neighbors = NEIGHBORS.lock();
+		for (addr, n) in neighbors.iter() {
+			let Address::IPv4(addr) = addr else {
+				continue;
+			};
+			let len = addr.iter().map(|b| digits(*b)).sum::<usize>() + 3;
+			let [a, b, c, d] = addr;
+			write!(f, "{a}.{b}.{c}.{d}{:1$}", "", 17 - len)?;
+			// Incomplete entries have no flag
+			let flags = if n.mac.is_some() { 0x2 } else { 0 };
+			let [m0, m1, m2, m3, m4, m5] = n.mac.unwrap_or_default();
+			writeln!(
+				f,
+				"0x1         {flags:<#12x}{m0:02x}:{m1:02x}:{m2:02x}:{m3:02x}:{m4:02x}:{m5:02x}     \
+				 *        {}",
+				n.iface
+			)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,75 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Implementation of the `dev` file, which gives the traffic counters of network interfaces.
+
+use crate::{
+	file::{fs::NodeOps, FileLocation, FileType, Stat},
+	format_content, net,
+};
+use core::{fmt, fmt::Formatter};
+use utils::errno::EResult;
+
+/// The `dev` file.
+#[derive(Debug, Default)]
+pub struct Dev;
+
+impl NodeOps for Dev {
+	fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+		Ok(Stat {
+			mode: FileType::Regular.to_mode() | 0o444,
+			..Default::default()
+		})
+	}
+
+	fn read_content(&self, _loc: &FileLocation, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		format_content!(off, buf, "{}", self)
+	}
+}
+
+impl fmt::Display for Dev {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		writeln!(
+			f,
+			"Inter-|   Receive                                                |  Transmit"
+		)?;
+		writeln!(
+			f,
+			" face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets \
+			 errs drop fifo colls carrier compressed"
+		)?;
+		let ifaces = net::interfaces().map_err(|_| fmt::Error)?;
+		for (_, name, iface) in ifaces {
+			let s = *iface.lock().get_stats();
+			write!(f, "{:1$}{name}:", "", 6usize.saturating_sub(name.len()))?;
+			// FIFO, frame, compressed and multicast counters are not tracked
+			write!(
+				f,
+				"{:>8} {:>7} {:>4} {:>4}    0     0          0         0 ",
+				s.rx_bytes, s.rx_packets, s.rx_errors, s.rx_dropped
+			)?;
+			// FIFO, collisions, carrier and compressed counters are not tracked
+			writeln!(
+				f,
+				"{:>8} {:>7} {:>4} {:>4}    0     0       0          0",
+				s.tx_bytes, s.tx_packets, s.tx_errors, s.tx_dropped
+			)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Implementation of the `if_inet6` file, which lists the IPv6 addresses of network interfaces.
+
+use crate::{
+	file::{fs::NodeOps, FileLocation, FileType, Stat},
+	format_content, net,
+	net::{Address, LinkType},
+};
+use core::{fmt, fmt::Formatter};
+use utils::{errno::EResult, DisplayableStr};
+
+/// Address scope: host-local
+const IFA_HOST: u8 = 0x10;
+/// Address scope: link-local
+const IFA_LINK: u8 = 0x20;
+/// Address scope: site-local
+const IFA_SITE: u8 = 0x40;
+
+/// Address flag: permanent address
+const IFA_F_PERMANENT: u8 = 0x80;
+
+/// Returns the scope of the IPv6 address `addr`.
+fn scope(addr: &[u8; 16]) -> u8 {
+	if *addr == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] {
+		IFA_HOST
+	} else if addr[0] == 0xfe && addr[1] & 0xc0 == 0x80 {
+		IFA_LINK
+	} else if addr[0] == 0xfe && addr[1] & 0xc0 == 0xc0 {
+		IFA_SITE
+	} else {
+		0
+	}
+}
+
+/// The `if_inet6` file.
+#[derive(Debug, Default)]
+pub struct IfInet6;
+
+impl NodeOps for IfInet6 {
+	fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+		Ok(Stat {
+			mode: FileType::Regular.to_mode() | 0o444,
+			..Default::default()
+		})
+	}
+
+	fn read_content(&self, _loc: &FileLocation, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		format_content!(off, buf, "{}", self)
+	}
+}
+
+impl fmt::Display for IfInet6 {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let ifaces = net::interfaces().map_err(|_| fmt::Error)?;
+		for (index, name, iface) in ifaces {
+			let iface = iface.lock();
+			let addrs = iface.get_addresses().iter().filter_map(|a| match a.addr {
+				Address::IPv6(addr) => Some((addr, a.subnet_mask)),
+				Address::IPv4(_) => None,
+			});
+			for (addr, prefix) in addrs {
+				for b in addr {
+					write!(f, "{b:02x}")?;
+				}
+				let mut scope = scope(&addr);
+				if iface.get_link_type() == LinkType::Loopback {
+					scope = IFA_HOST;
+				}
+				writeln!(
+					f,
+					" {index:02x} {prefix:02x} {scope:02x} {IFA_F_PERMANENT:02x} {:>8}",
+					DisplayableStr(&name)
+				)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn proc_if_inet6_scope() {
+		let mut addr = [0; 16];
+		addr[15] = 1;
+		assert_eq!(scope(&addr), IFA_HOST);
+		addr = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+		assert_eq!(scope(&addr), IFA_LINK);
+		addr = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+		assert_eq!(scope(&addr), 0);
+	}
+}
@@ -0,0 +1,189 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Implementation of the `tcp`, `tcp6`, `udp` and `udp6` files, which list the sockets of the
+//! Internet protocols.
+
+use crate::{
+	file::{
+		fs::NodeOps,
+		socket::{Socket, SocketState},
+		FileLocation, FileType, Stat,
+	},
+	format_content,
+	net::{sockaddr::SockAddr, tcp, udp, Address, SocketDomain},
+};
+use core::{fmt, fmt::Formatter};
+use utils::{
+	collections::vec::Vec,
+	errno::{AllocResult, EResult},
+	ptr::arc::Arc,
+};
+
+/// Socket state code: closed
+const ST_CLOSE: u8 = 0x07;
+/// Socket state code: listening
+const ST_LISTEN: u8 = 0x0a;
+
+/// Returns the state code displayed for the given TCP state.
+fn tcp_state_code(state: tcp::State) -> u8 {
+	match state {
+		tcp::State::Established => 0x01,
+		tcp::State::SynSent => 0x02,
+		tcp::State::SynReceived => 0x03,
+		tcp::State::FinWait1 => 0x04,
+		tcp::State::FinWait2 => 0x05,
+		tcp::State::TimeWait => 0x06,
+		tcp::State::Closed => ST_CLOSE,
+		tcp::State::CloseWait => 0x08,
+		tcp::State::LastAck => 0x09,
+		tcp::State::Listen => ST_LISTEN,
+		tcp::State::Closing => 0x0b,
+	}
+}
+
+/// Writes the endpoint `addr` the way Linux does: the address as one or four native-endian
+/// integers of bytes in network order, then the port in host order.
+fn write_endpoint(
+	f: &mut Formatter<'_>,
+	addr: Option<&SockAddr>,
+	domain: SocketDomain,
+) -> fmt::Result {
+	let (addr, port) = match addr {
+		Some(addr) => (Some(addr.addr), addr.port),
+		None => (None, 0),
+	};
+	match (domain, addr) {
+		(SocketDomain::AfInet6, Some(Address::IPv6(addr))) => {
+			for c in addr.chunks_exact(4) {
+				write!(f, "{:08X}", u32::from_ne_bytes(c.try_into().unwrap()))?;
+			}
+		}
+		(SocketDomain::AfInet6, _) => write!(f, "{:032X}", 0)?,
+		(_, Some(Address::IPv4(addr))) => write!(f, "{:08X}", u32::from_ne_bytes(addr))?,
+		_ => write!(f, "{:08X}", 0)?,
+	}
+	write!(f, ":{port:04X}")
+}
+
+/// Writes the line of the socket `sock`, at slot `sl`.
+fn write_socket(f: &mut Formatter<'_>, sl: usize, sock: &Socket) -> fmt::Result {
+	let domain = sock.desc().domain;
+	let (local, remote, st) = match &*sock.state().lock() {
+		SocketState::Tcp(s) => {
+			let st = match s.tcb() {
+				Some(tcb) => tcp_state_code(tcb.state()),
+				None if sock.is_listening() => ST_LISTEN,
+				None => ST_CLOSE,
+			};
+			(s.local().copied(), s.remote().copied(), st)
+		}
+		SocketState::Udp(s) => {
+			// Like Linux, connected UDP sockets are shown as established
+			let st = if s.remote().is_some() { 0x01 } else { ST_CLOSE };
+			(s.local().copied(), s.remote().copied(), st)
+		}
+		_ => return Ok(()),
+	};
+	let tx_queue = sock
+		.tx_buff()
+		.lock()
+		.as_ref()
+		.map(|b| b.get_data_len())
+		.unwrap_or(0);
+	let rx_queue = sock
+		.rx_buff()
+		.lock()
+		.as_ref()
+		.map(|b| b.get_data_len())
+		.unwrap_or(0);
+	write!(f, "{sl:4}: ")?;
+	write_endpoint(f, local.as_ref(), domain)?;
+	write!(f, " ")?;
+	write_endpoint(f, remote.as_ref(), domain)?;
+	// Timers, retransmissions, UID and inode are not tracked
+	writeln!(
+		f,
+		" {st:02X} {tx_queue:08X}:{rx_queue:08X} 00:00000000 00000000 {:5} {:8} {}",
+		0, 0, 0
+	)
+}
+
+/// Writes the content of a file listing `socks` which belong to the given `domain`.
+fn write_sockets(
+	f: &mut Formatter<'_>,
+	socks: AllocResult<Vec<Arc<Socket>>>,
+	domain: SocketDomain,
+) -> fmt::Result {
+	if domain == SocketDomain::AfInet6 {
+		writeln!(
+			f,
+			"  sl  local_address                         remote_address                        st \
+			 tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode"
+		)?;
+	} else {
+		writeln!(
+			f,
+			"  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  \
+			 timeout inode"
+		)?;
+	}
+	let socks = socks.map_err(|_| fmt::Error)?;
+	socks
+		.iter()
+		.filter(|sock| sock.desc().domain == domain)
+		.enumerate()
+		.try_for_each(|(sl, sock)| write_socket(f, sl, sock))
+}
+
+macro_rules! inet_file {
+	($name:ident, $file:literal, $proto:ident, $domain:ident) => {
+		#[doc = concat!("The `", $file, "` file.")]
+		#[derive(Debug, Default)]
+		pub struct $name;
+
+		impl NodeOps for $name {
+			fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+				Ok(Stat {
+					mode: FileType::Regular.to_mode() | 0o444,
+					..Default::default()
+				})
+			}
+
+			fn read_content(
+				&self,
+				_loc: &FileLocation,
+				off: u64,
+				buf: &mut [u8],
+			) -> EResult<usize> {
+				format_content!(off, buf, "{}", self)
+			}
+		}
+
+		impl fmt::Display for $name {
+			fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+				write_sockets(f, $proto::sockets(), SocketDomain::$domain)
+			}
+		}
+	};
+}
+
+inet_file!(Tcp, "tcp", tcp, AfInet);
+inet_file!(Tcp6, "tcp6", tcp, AfInet6);
+inet_file!(Udp, "udp", udp, AfInet);
+inet_file!(Udp6, "udp6", udp, AfInet6);
@@ -0,0 +1,113 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Implementation of the `route` file, which lists the IPv4 routes of the routing table.
+
+use crate::{
+	file::{fs::NodeOps, FileLocation, FileType, Stat},
+	format_content,
+	net::{Address, ROUTING_TABLE},
+};
+use core::{fmt, fmt::Formatter};
+use utils::{errno::EResult, DisplayableStr};
+
+/// Route flag: The route is usable
+const RTF_UP: u16 = 0x1;
+/// Route flag: The destination is reached through a gateway
+const RTF_GATEWAY: u16 = 0x2;
+
+/// Returns the value of the IPv4 address `addr` as displayed in the file. Like Linux, the bytes
+/// are in network order, interpreted as a native-endian integer.
+fn addr_value(addr: &[u8; 4]) -> u32 {
+	u32::from_ne_bytes(*addr)
+}
+
+/// Returns the IPv4 netmask for a prefix of `len` bits.
+fn netmask(len: u8) -> [u8; 4] {
+	u32::MAX
+		.checked_shl(32 - len.min(32) as u32)
+		.unwrap_or(0)
+		.to_be_bytes()
+}
+
+/// The `route` file.
+#[derive(Debug, Default)]
+pub struct Route;
+
+impl NodeOps for Route {
+	fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+		Ok(Stat {
+			mode: FileType::Regular.to_mode() | 0o444,
+			..Default::default()
+		})
+	}
+
+	fn read_content(&self, _loc: &FileLocation, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		format_content!(off, buf, "{}", self)
+	}
+}
+
+impl fmt::Display for Route {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		writeln!(
+			f,
+			"Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT"
+		)?;
+		let routing_table = ROUTING_TABLE.lock();
+		for route in routing_table.iter() {
+			let Address::IPv4(gateway) = route.gateway() else {
+				continue;
+			};
+			let (dst, mask) = match route.dst() {
+				Some(dst) => match dst.addr {
+					Address::IPv4(addr) => (addr, netmask(dst.subnet_mask)),
+					Address::IPv6(_) => continue,
+				},
+				// Default route
+				None => ([0; 4], [0; 4]),
+			};
+			let mut flags = RTF_UP;
+			if *gateway != [0; 4] {
+				flags |= RTF_GATEWAY;
+			}
+			writeln!(
+				f,
+				"{}\t{:08X}\t{:08X}\t{flags:04X}\t0\t0\t{}\t{:08X}\t0\t0\t0",
+				DisplayableStr(route.iface()),
+				addr_value(&dst),
+				addr_value(gateway),
+				route.metric(),
+				addr_value(&mask),
+			)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn proc_route_netmask() {
+		assert_eq!(netmask(0), [0, 0, 0, 0]);
+		assert_eq!(netmask(8), [255, 0, 0, 0]);
+		assert_eq!(netmask(24), [255, 255, 255, 0]);
+		assert_eq!(netmask(32), [255; 4]);
+	}
+}
@@ -0,0 +1,101 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Implementation of the `unix` file, which lists local sockets.
+
+use crate::{
+	file::{
+		fs::NodeOps,
+		socket::{Socket, SocketState},
+		FileLocation, FileType, Stat,
+	},
+	format_content, net,
+};
+use core::{fmt, fmt::Formatter};
+use utils::{errno::EResult, DisplayableStr};
+
+/// Socket flag: the socket is listening for connections
+const SO_ACCEPTCON: u32 = 0x10000;
+
+/// Socket state: not connected
+const SS_UNCONNECTED: u8 = 1;
+/// Socket state: connected
+const SS_CONNECTED: u8 = 3;
+
+/// Writes the path of the bound `sockaddr_un` structure `sockname`, if any.
+///
+/// Like Linux, the name of abstract sockets is prefixed with `@`.
+fn write_path(f: &mut Formatter<'_>, sockname: &[u8]) -> fmt::Result {
+	let path = sockname.get(2..).unwrap_or_default();
+	match path.split_first() {
+		Some((0, name)) => write!(f, " @{}", DisplayableStr(name)),
+		Some(_) => {
+			let len = path.iter().position(|b| *b == 0).unwrap_or(path.len());
+			write!(f, " {}", DisplayableStr(&path[..len]))
+		}
+		None => Ok(()),
+	}
+}
+
+/// Writes the line of the socket `sock`.
+fn write_socket(f: &mut Formatter<'_>, sock: &Socket) -> fmt::Result {
+	let SocketState::Unix(state) = &*sock.state().lock() else {
+		return Ok(());
+	};
+	let listening = sock.is_listening();
+	let flags = if listening { SO_ACCEPTCON } else { 0 };
+	let st = if state.is_connected() {
+		SS_CONNECTED
+	} else {
+		SS_UNCONNECTED
+	};
+	// Kernel addresses, reference counts and inodes are not exposed
+	write!(
+		f,
+		"0000000000000000: 00000002 00000000 {flags:08X} {:04X} {st:02X} {:5}",
+		sock.desc().type_.get_id(),
+		0
+	)?;
+	write_path(f, &sock.get_sockname().lock())?;
+	writeln!(f)
+}
+
+/// The `unix` file.
+#[derive(Debug, Default)]
+pub struct Unix;
+
+impl NodeOps for Unix {
+	fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+		Ok(Stat {
+			mode: FileType::Regular.to_mode() | 0o444,
+			..Default::default()
+		})
+	}
+
+	fn read_content(&self, _loc: &FileLocation, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		format_content!(off, buf, "{}", self)
+	}
+}
+
+impl fmt::Display for Unix {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		writeln!(f, "Num       RefCount Protocol Flags    Type St Inode Path")?;
+		let socks = net::unix::sockets().map_err(|_| fmt::Error)?;
+		socks.iter().try_for_each(|sock| write_socket(f, sock))
+	}
+}
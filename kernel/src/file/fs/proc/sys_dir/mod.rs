@@ -22,7 +22,8 @@ use crate::{
 	file::{fs::NodeOps, FileLocation, FileType, Stat},
 	format_content,
 };
-use utils::errno::EResult;
+use core::sync::atomic::{AtomicU32, Ordering::Relaxed};
+use utils::{errno, errno::EResult};
 
 /// The `osrelease` file.
 #[derive(Debug, Default)]
@@ -40,3 +41,33 @@ impl NodeOps for OsRelease {
 		format_content!(off, buf, "{}\n", crate::VERSION)
 	}
 }
+
+/// A file exposing an integer kernel parameter, which can be read and written.
+#[derive(Debug)]
+pub struct IntParam(pub &'static AtomicU32);
+
+impl NodeOps for IntParam {
+	fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+		Ok(Stat {
+			mode: FileType::Regular.to_mode() | 0o644,
+			..Default::default()
+		})
+	}
+
+	fn read_content(&self, _loc: &FileLocation, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		format_content!(off, buf, "{}\n", self.0.load(Relaxed))
+	}
+
+	fn write_content(&self, _loc: &FileLocation, _off: u64, buf: &[u8]) -> EResult<usize> {
+		let val = core::str::from_utf8(buf)
+			.ok()
+			.and_then(|s| s.trim().parse().ok())
+			.ok_or_else(|| errno!(EINVAL))?;
+		self.0.store(val, Relaxed);
+		Ok(buf.len())
+	}
+
+	fn truncate_content(&self, _loc: &FileLocation, _size: u64) -> EResult<()> {
+		Ok(())
+	}
+}
@@ -26,14 +26,17 @@
 
 pub mod fd;
 pub mod fs;
+pub mod page_cache;
 pub mod perm;
 pub mod pipe;
 pub mod socket;
 pub mod util;
 pub mod vfs;
 pub mod wait_queue;
+pub mod writeback;
 
 use crate::{
+	device,
 	device::{DeviceID, DeviceType},
 	file::{
 		fs::Filesystem,
@@ -458,6 +461,17 @@ impl File {
 		(self.ops.deref() as &dyn Any).downcast_ref::<B>()
 	}
 
+	/// Same as [`Self::get_buffer`], but returns a new reference to the buffer.
+	///
+	/// If the buffer is not reference counted, the function returns `None`.
+	pub fn get_buffer_arc<B: FileOps>(&self) -> Option<Arc<B>> {
+		let CounterOption::Some(ops) = &self.ops else {
+			return None;
+		};
+		let ops: Arc<dyn Any> = ops.clone();
+		ops.downcast().ok()
+	}
+
 	/// Returns the open file description's flags.
 	pub fn get_flags(&self) -> i32 {
 		*self.flags.lock()
@@ -511,7 +525,36 @@ impl File {
 			.as_ref()
 			.ok_or_else(|| errno!(EINVAL))?
 			.node();
-		node.ops.truncate_content(&node.location, size)
+		node.ops.truncate_content(&node.location, size)?;
+		page_cache::truncate_file(&node.location, size);
+		Ok(())
+	}
+
+	/// Makes the data of the file durable on its storage device, blocking until done.
+	///
+	/// If the file is a block device, the device itself is flushed.
+	///
+	/// If the file is not on a filesystem (pipes, sockets, etc...), the function returns
+	/// [`errno::EINVAL`].
+	pub fn sync(&self) -> EResult<()> {
+		let node = self
+			.vfs_entry
+			.as_ref()
+			.ok_or_else(|| errno!(EINVAL))?
+			.node();
+		let stat = node.ops.get_stat(&node.location)?;
+		match stat.get_type() {
+			Some(FileType::BlockDevice) => {
+				let dev = device::get(&DeviceID {
+					dev_type: DeviceType::Block,
+					major: stat.dev_major,
+					minor: stat.dev_minor,
+				})
+				.ok_or_else(|| errno!(ENODEV))?;
+				dev.get_io().flush()
+			}
+			_ => page_cache::fsync(node),
+		}
 	}
 
 	/// Closes the file, removing it the underlying node if no link remain and this was the last
@@ -657,6 +700,7 @@ impl AccessProfile {
 ///
 /// `root` is the set of major and minor numbers of the root device. If `None`, a tmpfs is used.
 pub(crate) fn init(root: Option<(u32, u32)>) -> EResult<()> {
+	page_cache::init()?;
 	fs::register_defaults()?;
 	// Create the root mountpoint
 	let source = match root {
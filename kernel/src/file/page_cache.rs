@@ -0,0 +1,1198 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The page cache keeps the content of files and storage devices in memory, so that repeated
+//! accesses do not require I/O.
+//!
+//! Pages are identified by their owner and their index inside of it. Modified pages are marked
+//! dirty and are written back when their owner is synchronized, when they expire (see
+//! [`super::writeback`]), or when too many dirty pages accumulate.
+//!
+//! The same physical pages are used to back file mappings, so that every process mapping a file
+//! sees the same data.
+//!
+//! Under memory pressure, clean pages that are not mapped anywhere are reclaimed.
+
+use crate::{
+	device,
+	device::{
+		storage::queue::{Op, RequestQueue},
+		DeviceID, DeviceIO,
+	},
+	file::{
+		fs::NodeOps,
+		vfs::{
+			mountpoint,
+			mountpoint::{MountPoint, MountSource},
+			node::Node,
+		},
+		wait_queue::WaitQueue,
+		writeback, FileLocation,
+	},
+	memory::buddy,
+	process::mem_space::residence::ResidencePage,
+	sync::{atomic::AtomicU64, mutex::Mutex},
+	syscall::ioctl,
+	time::{
+		clock,
+		clock::CLOCK_MONOTONIC,
+		unit::{Timestamp, TimestampScale},
+	},
+};
+use core::{
+	cmp::min,
+	ffi::c_void,
+	num::NonZeroU64,
+	ptr,
+	sync::atomic::{
+		AtomicBool, AtomicU8, AtomicUsize,
+		Ordering::{Acquire, Relaxed, Release},
+	},
+};
+use utils::{
+	boxed::Box,
+	collections::{hashmap::HashMap, vec::Vec},
+	errno,
+	errno::{AllocResult, CollectResult, EResult, Errno},
+	limits::PAGE_SIZE,
+	ptr::arc::Arc,
+};
+
+/// The number of dirty pages above which the writeback thread is woken up.
+const DIRTY_BACKGROUND_LIMIT: usize = 512;
+/// The number of dirty pages above which writers have to write back pages themselves.
+const DIRTY_LIMIT: usize = 1024;
+
+/// Page state: the content of the page is being read.
+const STATE_FILLING: u8 = 0;
+/// Page state: the content of the page is valid.
+const STATE_READY: u8 = 1;
+/// Page state: reading the content of the page failed. The page has been removed from the cache.
+const STATE_FAILED: u8 = 2;
+
+/// The entity a cached page belongs to.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum PageOwner {
+	/// The content of a regular file.
+	File(FileLocation),
+	/// The content of a storage device.
+	Device(DeviceID),
+}
+
+/// A page of data in the cache.
+#[derive(Debug)]
+pub struct CachedPage {
+	/// The physical page holding the data.
+	page: Arc<ResidencePage>,
+	/// The state of the content of the page.
+	state: AtomicU8,
+	/// Tells whether the page has been modified since it was last written back.
+	dirty: AtomicBool,
+	/// The timestamp, in milliseconds, at which the page became dirty.
+	dirtied: AtomicU64,
+	/// Tells whether the page has been accessed since the last reclaim pass.
+	accessed: AtomicBool,
+}
+
+impl CachedPage {
+	/// Allocates a new zeroed page, waiting to be filled.
+	fn new() -> AllocResult<Self> {
+		let page = buddy::alloc(0, buddy::FLAG_ZONE_TYPE_KERNEL)?;
+		let page = Self {
+			page: Arc::new(ResidencePage::new(page))?,
+			state: AtomicU8::new(STATE_FILLING),
+			dirty: AtomicBool::new(false),
+			dirtied: AtomicU64::new(0),
+			accessed: AtomicBool::new(false),
+		};
+		unsafe {
+			page.as_ptr().write_bytes(0, PAGE_SIZE);
+		}
+		Ok(page)
+	}
+
+	/// Returns a pointer to the content of the page.
+	fn as_ptr(&self) -> *mut u8 {
+		// Cached pages are allocated in the kernel zone, so they are always mapped
+		self.page.get().kernel_to_virtual().unwrap().as_ptr()
+	}
+
+	/// Returns the content of the page.
+	fn as_slice(&self) -> &[u8] {
+		unsafe { &*ptr::slice_from_raw_parts(self.as_ptr(), PAGE_SIZE) }
+	}
+
+	/// Returns the content of the page, mutably.
+	///
+	/// # Safety
+	///
+	/// The content of the page must not be accessed concurrently, which is the case while it is
+	/// being filled.
+	#[allow(clippy::mut_from_ref)]
+	unsafe fn as_mut_slice(&self) -> &mut [u8] {
+		&mut *ptr::slice_from_raw_parts_mut(self.as_ptr(), PAGE_SIZE)
+	}
+
+	/// Copies data from offset `off` in the page to `buf`.
+	///
+	/// The function returns the number of bytes copied.
+	fn read(&self, off: usize, buf: &mut [u8]) -> usize {
+		let len = min(PAGE_SIZE - off, buf.len());
+		unsafe {
+			ptr::copy_nonoverlapping(self.as_ptr().add(off), buf.as_mut_ptr(), len);
+		}
+		self.accessed.store(true, Relaxed);
+		len
+	}
+
+	/// Copies data from `buf` to offset `off` in the page.
+	///
+	/// The function returns the number of bytes copied.
+	fn write(&self, off: usize, buf: &[u8]) -> usize {
+		let len = min(PAGE_SIZE - off, buf.len());
+		unsafe {
+			ptr::copy_nonoverlapping(buf.as_ptr(), self.as_ptr().add(off), len);
+		}
+		self.accessed.store(true, Relaxed);
+		len
+	}
+
+	/// Tells whether the content of the page is valid.
+	fn is_ready(&self) -> bool {
+		self.state.load(Acquire) == STATE_READY
+	}
+
+	/// Waits until the page has been filled.
+	///
+	/// If filling the page failed, the function returns `false`.
+	fn wait_filled(&self) -> EResult<bool> {
+		FILL_QUEUE.wait_until(|| match self.state.load(Acquire) {
+			STATE_FILLING => None,
+			state => Some(state == STATE_READY),
+		})
+	}
+
+	/// Tells whether the page is dirty.
+	fn is_dirty(&self) -> bool {
+		self.dirty.load(Acquire)
+	}
+
+	/// Tells whether the page is dirty and has to be written back.
+	///
+	/// If `older_than` is not `None`, only pages that became dirty before this timestamp, in
+	/// milliseconds, have to be written back.
+	fn needs_write_back(&self, older_than: Option<Timestamp>) -> bool {
+		self.is_dirty() && older_than.is_none_or(|ts| self.dirtied.load(Relaxed) <= ts)
+	}
+
+	/// Marks the page as dirty.
+	///
+	/// This function must be called *after* modifying the page, so that a concurrent writeback
+	/// cannot miss the modification.
+	fn mark_dirty(&self) {
+		if !self.dirty.swap(true, Release) {
+			let ts = clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond);
+			self.dirtied.store(ts.unwrap_or(0), Relaxed);
+			DIRTY_PAGES.fetch_add(1, Relaxed);
+		}
+	}
+
+	/// Marks the page as clean.
+	///
+	/// This function must be called *before* writing back the page. It returns `true` if the page
+	/// was dirty.
+	fn mark_clean(&self) -> bool {
+		let dirty = self.dirty.swap(false, Acquire);
+		if dirty {
+			DIRTY_PAGES.fetch_sub(1, Relaxed);
+		}
+		dirty
+	}
+}
+
+/// The cached pages, by owner and index.
+static CACHE: Mutex<HashMap<(PageOwner, u64), Arc<CachedPage>>> = Mutex::new(HashMap::new());
+/// The number of dirty pages in the cache.
+static DIRTY_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// The queue of processes waiting for a page to be filled.
+static FILL_QUEUE: WaitQueue = WaitQueue::new();
+/// The cached storage devices, by ID.
+static DEVICES: Mutex<HashMap<DeviceID, Arc<CachedDevice>>> = Mutex::new(HashMap::new());
+
+/// Returns the page at `index` in `owner`.
+///
+/// If the page is not in cache, it is allocated and `fill` is called to read its content. If the
+/// page is being read by another thread, the function waits for it.
+fn get_page<F: FnOnce(&mut [u8]) -> EResult<()>>(
+	owner: &PageOwner,
+	index: u64,
+	fill: F,
+) -> EResult<Arc<CachedPage>> {
+	let key = (owner.clone(), index);
+	let page = loop {
+		let page = {
+			let mut cache = CACHE.lock();
+			match cache.get(&key) {
+				Some(page) => page.clone(),
+				None => {
+					// Insert the page before reading it, so that it cannot be read twice and
+					// writes to it wait for its content
+					let page = Arc::new(CachedPage::new()?)?;
+					cache.insert(key.clone(), page.clone())?;
+					break page;
+				}
+			}
+		};
+		// If another thread failed to read the page, try again
+		if page.wait_filled()? {
+			page.accessed.store(true, Relaxed);
+			return Ok(page);
+		}
+	};
+	// Read the page without holding the lock, since this requires I/O
+	let res = fill(unsafe { page.as_mut_slice() });
+	if res.is_err() {
+		let mut cache = CACHE.lock();
+		if cache
+			.get(&key)
+			.is_some_and(|p| ptr::eq(Arc::as_ptr(p), Arc::as_ptr(&page)))
+		{
+			cache.remove(&key);
+		}
+	}
+	let state = if res.is_ok() {
+		STATE_READY
+	} else {
+		STATE_FAILED
+	};
+	page.state.store(state, Release);
+	FILL_QUEUE.wake_all();
+	res?;
+	Ok(page)
+}
+
+/// Returns the number of dirty pages in the cache.
+pub fn dirty_count() -> usize {
+	DIRTY_PAGES.load(Relaxed)
+}
+
+/// Returns the pages of `owner` that have to be written back, sorted by index.
+///
+/// `older_than` is the timestamp before which pages must have become dirty to be written back. If
+/// `None`, all dirty pages are returned.
+fn dirty_pages(
+	owner: &PageOwner,
+	older_than: Option<Timestamp>,
+) -> AllocResult<Vec<(u64, Arc<CachedPage>)>> {
+	// Collect pages first, to avoid holding the lock during I/O
+	let mut pages = CACHE
+		.lock()
+		.iter()
+		.filter(|((o, _), page)| o == owner && page.needs_write_back(older_than))
+		.map(|((_, index), page)| (*index, page.clone()))
+		.collect::<CollectResult<Vec<_>>>()
+		.0?;
+	pages.sort_unstable_by_key(|(index, _)| *index);
+	Ok(pages)
+}
+
+/// Writes back the dirty pages of `owner`, in order, using `write`.
+///
+/// Arguments:
+/// - `older_than` has the same meaning as for [`dirty_pages`]
+/// - `write` is called with the index of the page and its content
+fn write_back<F: FnMut(u64, &[u8]) -> EResult<()>>(
+	owner: &PageOwner,
+	older_than: Option<Timestamp>,
+	mut write: F,
+) -> EResult<()> {
+	for (index, page) in dirty_pages(owner, older_than)? {
+		if !page.mark_clean() {
+			continue;
+		}
+		if let Err(e) = write(index, page.as_slice()) {
+			page.mark_dirty();
+			return Err(e);
+		}
+	}
+	Ok(())
+}
+
+/// Removes the pages of `owner` from the cache, discarding modifications.
+///
+/// Pages that are still mapped remain valid for the mappings using them.
+pub fn invalidate(owner: &PageOwner) {
+	CACHE.lock().retain(|(o, _), page| {
+		if o != owner {
+			return true;
+		}
+		page.mark_clean();
+		false
+	});
+}
+
+/// Removes the pages of every file on the mountpoint with ID `mountpoint_id` from the cache,
+/// discarding modifications.
+pub fn invalidate_mountpoint(mountpoint_id: u32) {
+	CACHE.lock().retain(|(o, _), page| {
+		if !matches!(o, PageOwner::File(loc) if loc.mountpoint_id == mountpoint_id) {
+			return true;
+		}
+		page.mark_clean();
+		false
+	});
+}
+
+/// Reclaims up to `count` pages that are neither dirty nor in use.
+///
+/// Pages that have been accessed since the last pass are given a second chance.
+///
+/// If the cache is currently in use, the function does nothing to avoid a deadlock, since it is
+/// called as a [`buddy::Shrinker`] while allocating memory.
+///
+/// The function returns the number of reclaimed pages.
+pub fn shrink(count: usize) -> usize {
+	let Some(mut cache) = CACHE.try_lock() else {
+		return 0;
+	};
+	let mut reclaimed = 0;
+	cache.retain(|_, page| {
+		if reclaimed >= count
+			|| page.is_dirty()
+			|| Arc::strong_count(page) > 1
+			|| Arc::strong_count(&page.page) > 1
+		{
+			return true;
+		}
+		if page.accessed.swap(false, Relaxed) {
+			return true;
+		}
+		reclaimed += 1;
+		false
+	});
+	reclaimed
+}
+
+/// Initializes the page cache, so that its pages are reclaimed when memory runs out.
+pub(crate) fn init() -> EResult<()> {
+	buddy::register_shrinker(shrink)?;
+	Ok(())
+}
+
+/// Reads a whole page of the file at `node`, at index `index`.
+///
+/// Bytes past the end of the file are left untouched.
+fn read_file_page(node: &Node, index: u64, buf: &mut [u8]) -> EResult<()> {
+	let off = index * PAGE_SIZE as u64;
+	let mut i = 0;
+	while i < buf.len() {
+		let len = node
+			.ops
+			.read_content(&node.location, off + i as u64, &mut buf[i..])?;
+		if len == 0 {
+			break;
+		}
+		i += len;
+	}
+	Ok(())
+}
+
+/// Writes back a page of the file at `loc`, at index `index`.
+///
+/// Arguments:
+/// - `ops` is the handle to perform operations on the file
+/// - `size` is the size of the file. Data past it is not written
+fn write_file_page(
+	loc: &FileLocation,
+	ops: &dyn NodeOps,
+	size: u64,
+	index: u64,
+	buf: &[u8],
+) -> EResult<()> {
+	let off = index * PAGE_SIZE as u64;
+	let len = min(size.saturating_sub(off), buf.len() as u64) as usize;
+	let mut i = 0;
+	while i < len {
+		let l = ops.write_content(loc, off + i as u64, &buf[i..len])?;
+		if l == 0 {
+			break;
+		}
+		i += l;
+	}
+	Ok(())
+}
+
+/// Reads from the file at `node`, through the cache.
+///
+/// Arguments:
+/// - `off` is the offset in the file
+/// - `buf` is the buffer to write the data to
+///
+/// The function returns the number of bytes read.
+pub fn read_file(node: &Node, off: u64, buf: &mut [u8]) -> EResult<usize> {
+	let owner = PageOwner::File(node.location.clone());
+	let size = node.ops.get_stat(&node.location)?.size;
+	let len = min(size.saturating_sub(off), buf.len() as u64) as usize;
+	let mut i = 0;
+	while i < len {
+		let pos = off + i as u64;
+		let index = pos / PAGE_SIZE as u64;
+		let page = get_page(&owner, index, |b| read_file_page(node, index, b))?;
+		i += page.read((pos % PAGE_SIZE as u64) as usize, &mut buf[i..len]);
+	}
+	Ok(len)
+}
+
+/// Writes to the file at `node`, through the cache.
+///
+/// Arguments:
+/// - `off` is the offset in the file
+/// - `buf` is the data to write
+///
+/// Data inside of the file is written to cached pages, which are marked dirty to be written back
+/// later. Data past the end of the file is written to the file directly, since the filesystem has
+/// to allocate space for it.
+///
+/// The function returns the number of bytes written.
+pub fn write_file(node: &Node, off: u64, buf: &[u8]) -> EResult<usize> {
+	let owner = PageOwner::File(node.location.clone());
+	let size = node.ops.get_stat(&node.location)?.size;
+	let len = min(size.saturating_sub(off), buf.len() as u64) as usize;
+	let mut i = 0;
+	while i < len {
+		let pos = off + i as u64;
+		let index = pos / PAGE_SIZE as u64;
+		let page = get_page(&owner, index, |b| read_file_page(node, index, b))?;
+		i += page.write((pos % PAGE_SIZE as u64) as usize, &buf[i..len]);
+		page.mark_dirty();
+	}
+	if len < buf.len() {
+		let pos = off + len as u64;
+		i += node.ops.write_content(&node.location, pos, &buf[len..])?;
+		update_file(&node.location, pos, &buf[len..i])?;
+		// A concurrent writeback may have written the previous content of the pages over the data
+		let start = pos / PAGE_SIZE as u64;
+		let end = (off + i as u64).div_ceil(PAGE_SIZE as u64);
+		mark_file_dirty(&node.location, start, end - start);
+	}
+	// Throttle writers when too much data is waiting to be written back
+	let dirty = dirty_count();
+	if dirty > DIRTY_LIMIT {
+		sync_file(node)?;
+	} else if dirty > DIRTY_BACKGROUND_LIMIT {
+		writeback::wake();
+	}
+	Ok(i)
+}
+
+/// Updates the cached pages of the file at `location` after data has been written to the file
+/// directly.
+///
+/// Arguments:
+/// - `off` is the offset in the file at which the data has been written
+/// - `buf` is the data that has been written
+pub fn update_file(location: &FileLocation, off: u64, buf: &[u8]) -> EResult<()> {
+	let owner = PageOwner::File(location.clone());
+	let mut i = 0;
+	while i < buf.len() {
+		let pos = off + i as u64;
+		let inner_off = (pos % PAGE_SIZE as u64) as usize;
+		let len = min(PAGE_SIZE - inner_off, buf.len() - i);
+		let page = CACHE
+			.lock()
+			.get(&(owner.clone(), pos / PAGE_SIZE as u64))
+			.cloned();
+		// A page being read may have been read before the data was written, so wait for it
+		if let Some(page) = page {
+			if page.wait_filled()? {
+				page.write(inner_off, &buf[i..(i + len)]);
+			}
+		}
+		i += len;
+	}
+	Ok(())
+}
+
+/// Updates the cached pages of the file at `location` after it has been truncated to `size`.
+pub fn truncate_file(location: &FileLocation, size: u64) {
+	let owner = PageOwner::File(location.clone());
+	let mut cache = CACHE.lock();
+	cache.retain(|(o, index), page| {
+		if *o != owner || *index * (PAGE_SIZE as u64) < size {
+			return true;
+		}
+		page.mark_clean();
+		false
+	});
+	// Clear the end of the last page, so that it does not reappear if the file grows again
+	let inner_off = (size % PAGE_SIZE as u64) as usize;
+	if inner_off != 0 {
+		let page = cache.get(&(owner, size / PAGE_SIZE as u64));
+		// A page being read is read after the truncation
+		if let Some(page) = page.filter(|page| page.is_ready()) {
+			unsafe {
+				page.as_ptr()
+					.add(inner_off)
+					.write_bytes(0, PAGE_SIZE - inner_off);
+			}
+		}
+	}
+}
+
+/// Returns the physical page at `index` in the file at `node`, to be mapped in memory.
+pub fn map_file_page(node: &Node, index: u64) -> EResult<Arc<ResidencePage>> {
+	let owner = PageOwner::File(node.location.clone());
+	let page = get_page(&owner, index, |b| read_file_page(node, index, b))?;
+	Ok(page.page.clone())
+}
+
+/// Returns the physical page at `index` in the file at `location` if it is in the cache.
+///
+/// Contrary to [`map_file_page`], this function never performs I/O.
+pub fn cached_file_page(location: &FileLocation, index: u64) -> Option<Arc<ResidencePage>> {
+	let key = (PageOwner::File(location.clone()), index);
+	let cache = CACHE.lock();
+	let page = cache.get(&key).filter(|page| page.is_ready())?;
+	page.accessed.store(true, Relaxed);
+	Some(page.page.clone())
+}
+
+/// Marks the cached pages in the given range of the file at `location` as dirty.
+///
+/// This is used when a shared mapping of the file may have modified them.
+///
+/// Arguments:
+/// - `start` is the index of the first page
+/// - `count` is the number of pages
+pub fn mark_file_dirty(location: &FileLocation, start: u64, count: u64) {
+	let owner = PageOwner::File(location.clone());
+	let cache = CACHE.lock();
+	for index in start..start.saturating_add(count) {
+		if let Some(page) = cache.get(&(owner.clone(), index)) {
+			page.mark_dirty();
+		}
+	}
+}
+
+/// Writes back the dirty pages of the file at `loc`, using `ops` to perform operations on it.
+///
+/// `older_than` has the same meaning as for [`write_back`].
+fn sync_file_impl(
+	loc: &FileLocation,
+	ops: &dyn NodeOps,
+	older_than: Option<Timestamp>,
+) -> EResult<()> {
+	let owner = PageOwner::File(loc.clone());
+	let size = ops.get_stat(loc)?.size;
+	write_back(&owner, older_than, |index, buf| {
+		write_file_page(loc, ops, size, index, buf)
+	})
+}
+
+/// Writes back the dirty pages of the file at `node`.
+pub fn sync_file(node: &Node) -> EResult<()> {
+	sync_file_impl(&node.location, &*node.ops, None)
+}
+
+/// Writes back the dirty pages of files.
+///
+/// Arguments:
+/// - `mountpoint_id` is the ID of the mountpoint whose files are to be written back. If `None`,
+///   files of every mountpoint are written back
+/// - `older_than` has the same meaning as for [`write_back`]
+///
+/// Files that cannot be written back are skipped, and the first error is returned after the
+/// other files have been processed.
+fn write_back_files(mountpoint_id: Option<u32>, older_than: Option<Timestamp>) -> EResult<()> {
+	let mut files = CACHE
+		.lock()
+		.iter()
+		.filter(|(_, page)| page.needs_write_back(older_than))
+		.filter_map(|((owner, _), _)| match owner {
+			PageOwner::File(loc) if mountpoint_id.is_none_or(|id| loc.mountpoint_id == id) => {
+				Some((loc.mountpoint_id, loc.inode))
+			}
+			_ => None,
+		})
+		.collect::<CollectResult<Vec<_>>>()
+		.0?;
+	files.sort_unstable();
+	let mut res = Ok(());
+	let mut prev = None;
+	for (mountpoint_id, inode) in files {
+		if prev == Some((mountpoint_id, inode)) {
+			continue;
+		}
+		prev = Some((mountpoint_id, inode));
+		let Some(mp) = mountpoint::from_id(mountpoint_id) else {
+			continue;
+		};
+		let loc = FileLocation {
+			mountpoint_id,
+			inode,
+		};
+		let r = mp
+			.fs
+			.node_from_inode(inode)
+			.and_then(|ops| sync_file_impl(&loc, &*ops, older_than));
+		if res.is_ok() {
+			res = r;
+		}
+	}
+	res
+}
+
+/// Makes the data of the file at `node` durable on its storage device.
+pub fn fsync(node: &Node) -> EResult<()> {
+	sync_file(node)?;
+	// Data and metadata written by the filesystem are cached by the device
+	if let Some(mp) = node.location.get_mountpoint() {
+		flush_source(&mp)?;
+	}
+	Ok(())
+}
+
+/// Flushes the storage device the mountpoint `mp` is associated with, if any.
+fn flush_source(mp: &MountPoint) -> EResult<()> {
+	if let MountSource::Device(dev_id) = &mp.source {
+		if let Some(dev) = device::get(dev_id) {
+			dev.get_io().flush()?;
+		}
+	}
+	Ok(())
+}
+
+/// Writes back the dirty pages of every file on the mountpoint `mp`, then flushes the
+/// associated storage device, if any.
+pub fn sync_mountpoint(mp: &MountPoint) -> EResult<()> {
+	write_back_files(Some(mp.id), None)?;
+	flush_source(mp)
+}
+
+/// Returns the list of cached devices.
+fn cached_devices() -> AllocResult<Vec<Arc<CachedDevice>>> {
+	DEVICES
+		.lock()
+		.iter()
+		.map(|(_, dev)| dev.clone())
+		.collect::<CollectResult<_>>()
+		.0
+}
+
+/// Writes back pages that became dirty before `older_than`, in milliseconds.
+///
+/// Data is written to storage devices, but the function does not wait for it to be durable.
+pub fn write_back_expired(older_than: Timestamp) -> EResult<()> {
+	let res = write_back_files(None, Some(older_than));
+	for dev in cached_devices()? {
+		dev.write_back(Some(older_than))?;
+	}
+	res
+}
+
+/// Writes back every dirty page, then flushes every storage device.
+pub fn sync_all() -> EResult<()> {
+	let res = write_back_files(None, None);
+	for dev in cached_devices()? {
+		dev.flush()?;
+	}
+	res
+}
+
+/// The state of a batch of pages being written back asynchronously.
+#[derive(Default)]
+struct Batch {
+	/// The number of pages whose write did not complete yet.
+	pending: usize,
+	/// The first error that occurred, if any.
+	error: Option<Errno>,
+}
+
+/// A [`DeviceIO`] wrapper caching the content of a storage device.
+///
+/// If the device's block size does not divide the size of a page, I/O bypasses the cache.
+pub struct CachedDevice {
+	/// The ID of the device, identifying its pages.
+	id: DeviceID,
+	/// The request queue of the underlying device.
+	io: Arc<RequestQueue>,
+}
+
+impl CachedDevice {
+	/// Creates a new instance and registers it, so that its dirty pages get written back.
+	///
+	/// Arguments:
+	/// - `id` is the ID of the device
+	/// - `io` is the request queue of the underlying device
+	pub fn new(id: DeviceID, io: Arc<RequestQueue>) -> AllocResult<Arc<Self>> {
+		let dev = Arc::new(Self {
+			id,
+			io,
+		})?;
+		DEVICES.lock().insert(id, dev.clone())?;
+		Ok(dev)
+	}
+
+	/// Unregisters the device after it has been removed, discarding its cached pages.
+	///
+	/// Modifications that have not been written back are lost, since the device is gone.
+	pub fn remove(&self) {
+		DEVICES.lock().remove(&self.id);
+		invalidate(&PageOwner::Device(self.id));
+	}
+
+	/// Writes back the dirty pages of the device.
+	///
+	/// `older_than` has the same meaning as for [`dirty_pages`].
+	///
+	/// Pages are submitted all at once so that writes on adjacent pages are merged, then the
+	/// function waits for all of them to complete.
+	fn write_back(&self, older_than: Option<Timestamp>) -> EResult<()> {
+		let owner = PageOwner::Device(self.id);
+		let batch = Arc::new(Mutex::new(Batch::default()))?;
+		let mut res = Ok(());
+		for (index, page) in dirty_pages(&owner, older_than)? {
+			if !page.mark_clean() {
+				continue;
+			}
+			res = self.submit_page(index, page, &batch);
+			if res.is_err() {
+				break;
+			}
+		}
+		let error = self.io.wait(|| {
+			let batch = batch.lock();
+			(batch.pending == 0).then_some(batch.error)
+		});
+		res?;
+		match error {
+			Some(e) => Err(e),
+			None => Ok(()),
+		}
+	}
+
+	/// Submits the write of the page `page` at `index`, which has been marked clean.
+	///
+	/// On completion, `batch` is updated and the page is marked dirty again if the write failed.
+	fn submit_page(
+		&self,
+		index: u64,
+		page: Arc<CachedPage>,
+		batch: &Arc<Mutex<Batch>>,
+	) -> EResult<()> {
+		let (start, len) = self.page_blocks(index);
+		let mut buf = Vec::new();
+		if let Err(e) = buf.extend_from_slice(&page.as_slice()[..len]) {
+			page.mark_dirty();
+			return Err(e.into());
+		}
+		let b = batch.clone();
+		let p = page.clone();
+		let callback = Box::new(move |res: EResult<_>| {
+			let mut batch = b.lock();
+			if let Err(e) = res {
+				p.mark_dirty();
+				batch.error.get_or_insert(e);
+			}
+			batch.pending -= 1;
+		});
+		let callback = match callback {
+			Ok(callback) => callback,
+			Err(e) => {
+				page.mark_dirty();
+				return Err(e.into());
+			}
+		};
+		batch.lock().pending += 1;
+		self.io.submit(Op::Write, start, buf, callback);
+		Ok(())
+	}
+
+	/// Tells whether I/O can go through the cache.
+	fn is_cacheable(&self) -> bool {
+		PAGE_SIZE as u64 % self.io.block_size().get() == 0
+	}
+
+	/// Returns the range of blocks covered by the page at `index`, clamped to the size of the
+	/// device.
+	fn page_blocks(&self, index: u64) -> (u64, usize) {
+		let blk_size = self.io.block_size().get();
+		let start = index * (PAGE_SIZE as u64 / blk_size);
+		let count = min(
+			PAGE_SIZE as u64 / blk_size,
+			self.io.blocks_count().saturating_sub(start),
+		);
+		(start, (count * blk_size) as usize)
+	}
+
+	/// Reads the page at `index` from the device.
+	fn read_page(&self, index: u64, buf: &mut [u8]) -> EResult<()> {
+		let (start, len) = self.page_blocks(index);
+		self.io.read(start, &mut buf[..len])?;
+		Ok(())
+	}
+
+	/// Checks the request at block offset `off` with buffer size `len` is within bounds, and
+	/// returns the offset in bytes.
+	fn check_bounds(&self, off: u64, len: usize) -> EResult<u64> {
+		let blk_size = self.io.block_size().get();
+		let blks = (len as u64).div_ceil(blk_size);
+		if off.saturating_add(blks) > self.io.blocks_count() {
+			return Err(errno!(EINVAL));
+		}
+		Ok(off * blk_size)
+	}
+}
+
+impl DeviceIO for CachedDevice {
+	fn block_size(&self) -> NonZeroU64 {
+		self.io.block_size()
+	}
+
+	fn blocks_count(&self) -> u64 {
+		self.io.blocks_count()
+	}
+
+	fn read(&self, off: u64, buf: &mut [u8]) -> EResult<usize> {
+		if !self.is_cacheable() {
+			return self.io.read(off, buf);
+		}
+		let owner = PageOwner::Device(self.id);
+		let start = self.check_bounds(off, buf.len())?;
+		let mut i = 0;
+		while i < buf.len() {
+			let pos = start + i as u64;
+			let index = pos / PAGE_SIZE as u64;
+			let page = get_page(&owner, index, |b| self.read_page(index, b))?;
+			i += page.read((pos % PAGE_SIZE as u64) as usize, &mut buf[i..]);
+		}
+		Ok(buf.len())
+	}
+
+	fn write(&self, off: u64, buf: &[u8]) -> EResult<usize> {
+		if !self.is_cacheable() {
+			return self.io.write(off, buf);
+		}
+		let owner = PageOwner::Device(self.id);
+		let start = self.check_bounds(off, buf.len())?;
+		let mut i = 0;
+		while i < buf.len() {
+			let pos = start + i as u64;
+			let index = pos / PAGE_SIZE as u64;
+			let page = get_page(&owner, index, |b| self.read_page(index, b))?;
+			i += page.write((pos % PAGE_SIZE as u64) as usize, &buf[i..]);
+			page.mark_dirty();
+		}
+		// Throttle writers when too much data is waiting to be written back
+		let dirty = dirty_count();
+		if dirty > DIRTY_LIMIT {
+			self.write_back(None)?;
+		} else if dirty > DIRTY_BACKGROUND_LIMIT {
+			writeback::wake();
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&self) -> EResult<()> {
+		self.write_back(None)?;
+		self.io.flush()
+	}
+
+	fn poll(&self, mask: u32) -> EResult<u32> {
+		self.io.poll(mask)
+	}
+
+	fn ioctl(&self, request: ioctl::Request, argp: *const c_void) -> EResult<u32> {
+		self.io.ioctl(request, argp)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{
+		device::{storage::queue::noop::Noop, DeviceType},
+		file::{INode, Stat},
+	};
+	use utils::collections::string::String;
+
+	/// The block size of [`MemDisk`].
+	const BLK_SIZE: usize = 512;
+
+	/// A storage device in memory.
+	struct MemDisk(Mutex<Vec<u8>>);
+
+	impl DeviceIO for MemDisk {
+		fn block_size(&self) -> NonZeroU64 {
+			NonZeroU64::new(BLK_SIZE as _).unwrap()
+		}
+
+		fn blocks_count(&self) -> u64 {
+			(self.0.lock().len() / BLK_SIZE) as _
+		}
+
+		fn read(&self, off: u64, buf: &mut [u8]) -> EResult<usize> {
+			let off = off as usize * BLK_SIZE;
+			buf.copy_from_slice(&self.0.lock()[off..(off + buf.len())]);
+			Ok(buf.len())
+		}
+
+		fn write(&self, off: u64, buf: &[u8]) -> EResult<usize> {
+			let off = off as usize * BLK_SIZE;
+			self.0.lock()[off..(off + buf.len())].copy_from_slice(buf);
+			Ok(buf.len())
+		}
+	}
+
+	/// A regular file in memory.
+	#[derive(Debug)]
+	struct MemFile(Mutex<Vec<u8>>);
+
+	impl NodeOps for MemFile {
+		fn get_stat(&self, _loc: &FileLocation) -> EResult<Stat> {
+			Ok(Stat {
+				size: self.0.lock().len() as _,
+				..Default::default()
+			})
+		}
+
+		fn read_content(&self, _loc: &FileLocation, off: u64, buf: &mut [u8]) -> EResult<usize> {
+			let data = self.0.lock();
+			let off = min(off as usize, data.len());
+			let len = min(buf.len(), data.len() - off);
+			buf[..len].copy_from_slice(&data[off..(off + len)]);
+			Ok(len)
+		}
+
+		fn write_content(&self, _loc: &FileLocation, off: u64, buf: &[u8]) -> EResult<usize> {
+			let mut data = self.0.lock();
+			let end = off as usize + buf.len();
+			if end > data.len() {
+				data.resize(end, 0)?;
+			}
+			data[(off as usize)..end].copy_from_slice(buf);
+			Ok(buf.len())
+		}
+	}
+
+	/// Creates a cached device of `pages` pages in memory, with the minor number `minor`.
+	fn mem_disk(minor: u32, pages: usize) -> (Arc<MemDisk>, Arc<RequestQueue>, Arc<CachedDevice>) {
+		let mut data = Vec::new();
+		data.resize(pages * PAGE_SIZE, 0).unwrap();
+		let disk = Arc::new(MemDisk(Mutex::new(data))).unwrap();
+		let id = DeviceID {
+			dev_type: DeviceType::Block,
+			major: 0,
+			minor,
+		};
+		let queue = RequestQueue::new(
+			id,
+			String::try_from(b"memdisk").unwrap(),
+			disk.clone(),
+			Box::new(Noop::default()).unwrap(),
+		)
+		.unwrap();
+		let dev = CachedDevice::new(id, queue.clone()).unwrap();
+		(disk, queue, dev)
+	}
+
+	/// Tells whether the page at `index` in `owner` is in cache and dirty.
+	fn is_dirty(owner: &PageOwner, index: u64) -> bool {
+		CACHE
+			.lock()
+			.get(&(owner.clone(), index))
+			.is_some_and(|page| page.is_dirty())
+	}
+
+	/// Returns an owner for the file with inode `inode` on a mountpoint that does not exist.
+	fn file_owner(inode: INode) -> (FileLocation, PageOwner) {
+		let loc = FileLocation {
+			mountpoint_id: u32::MAX,
+			inode,
+		};
+		(loc.clone(), PageOwner::File(loc))
+	}
+
+	/// Tells whether the page at `index` in `owner` is in cache.
+	fn is_cached(owner: &PageOwner, index: u64) -> bool {
+		CACHE.lock().get(&(owner.clone(), index)).is_some()
+	}
+
+	#[test_case]
+	fn page_cache_get() {
+		let (loc, owner) = file_owner(0);
+		let page = get_page(&owner, 0, |buf| {
+			buf[..5].copy_from_slice(b"hello");
+			Ok(())
+		})
+		.unwrap();
+		assert_eq!(&page.as_slice()[..5], b"hello");
+		assert!(page.as_slice()[5..].iter().all(|b| *b == 0));
+		// The page is not read again once in cache
+		let page2 = get_page(&owner, 0, |_| panic!()).unwrap();
+		assert_eq!(Arc::as_ptr(&page), Arc::as_ptr(&page2));
+		// A page that cannot be read is not inserted
+		let res = get_page(&owner, 1, |_| Err(errno!(EIO)));
+		assert_eq!(res.unwrap_err(), errno!(EIO));
+		assert!(!is_cached(&owner, 1));
+		// The page is in cache while being read, but cannot be mapped before
+		get_page(&owner, 2, |_| {
+			assert!(is_cached(&owner, 2));
+			assert!(cached_file_page(&loc, 2).is_none());
+			Ok(())
+		})
+		.unwrap();
+		assert!(cached_file_page(&loc, 2).is_some());
+		invalidate(&owner);
+		assert!(!is_cached(&owner, 0));
+	}
+
+	#[test_case]
+	fn page_cache_invalidate() {
+		let (_, owner) = file_owner(1);
+		let (_, other) = file_owner(2);
+		let dirty = dirty_count();
+		get_page(&owner, 0, |_| Ok(())).unwrap().mark_dirty();
+		get_page(&other, 0, |_| Ok(())).unwrap();
+		assert_eq!(dirty_count(), dirty + 1);
+		// Modifications are discarded and other owners are left untouched
+		invalidate(&owner);
+		assert!(!is_cached(&owner, 0));
+		assert!(is_cached(&other, 0));
+		assert_eq!(dirty_count(), dirty);
+		invalidate(&other);
+	}
+
+	#[test_case]
+	fn page_cache_shrink() {
+		let (_, owner) = file_owner(3);
+		get_page(&owner, 0, |_| Ok(())).unwrap();
+		get_page(&owner, 1, |_| Ok(())).unwrap().mark_dirty();
+		let used = get_page(&owner, 2, |_| Ok(())).unwrap();
+		let mapped = get_page(&owner, 3, |_| Ok(())).unwrap().page.clone();
+		get_page(&owner, 4, |_| Ok(())).unwrap();
+		// Accessed pages are given a second chance
+		get_page(&owner, 4, |_| Ok(())).unwrap();
+		shrink(usize::MAX);
+		assert!(!is_cached(&owner, 0));
+		assert!(is_cached(&owner, 4));
+		shrink(usize::MAX);
+		assert!(!is_cached(&owner, 4));
+		// Dirty and used pages are kept
+		assert!(is_cached(&owner, 1));
+		assert!(is_cached(&owner, 2));
+		assert!(is_cached(&owner, 3));
+		drop(used);
+		drop(mapped);
+		shrink(usize::MAX);
+		assert!(is_cached(&owner, 1));
+		assert!(!is_cached(&owner, 2));
+		assert!(!is_cached(&owner, 3));
+		invalidate(&owner);
+	}
+
+	#[test_case]
+	fn page_cache_truncate() {
+		let (loc, owner) = file_owner(4);
+		let fill = |buf: &mut [u8]| -> EResult<()> {
+			buf.fill(0xff);
+			Ok(())
+		};
+		let page0 = get_page(&owner, 0, fill).unwrap();
+		let page1 = get_page(&owner, 1, fill).unwrap();
+		// Pages before the new size are kept, with the end of the last one cleared
+		truncate_file(&loc, (PAGE_SIZE + 100) as _);
+		assert!(is_cached(&owner, 1));
+		assert!(page0.as_slice().iter().all(|b| *b == 0xff));
+		assert!(page1.as_slice()[..100].iter().all(|b| *b == 0xff));
+		assert!(page1.as_slice()[100..].iter().all(|b| *b == 0));
+		// Pages past the new size are removed
+		truncate_file(&loc, 50);
+		assert!(!is_cached(&owner, 1));
+		assert!(page0.as_slice()[..50].iter().all(|b| *b == 0xff));
+		assert!(page0.as_slice()[50..].iter().all(|b| *b == 0));
+		// Truncating on a page boundary leaves the last page untouched
+		page0.write(0, &[0xff; 100]);
+		truncate_file(&loc, PAGE_SIZE as _);
+		assert!(page0.as_slice()[..100].iter().all(|b| *b == 0xff));
+		truncate_file(&loc, 0);
+		assert!(!is_cached(&owner, 0));
+	}
+
+	#[test_case]
+	fn page_cache_write_file() {
+		let (location, owner) = file_owner(5);
+		let node = Node {
+			location,
+			ops: Box::new(MemFile(Mutex::new(Vec::new()))).unwrap(),
+		};
+		// Returns the content of the file, bypassing the cache
+		let content = || {
+			let mut buf = [0; 8];
+			let len = node.ops.read_content(&node.location, 0, &mut buf).unwrap();
+			Vec::try_from(&buf[..len]).unwrap()
+		};
+		// Data past the end of the file is written directly
+		assert_eq!(write_file(&node, 0, b"hello").unwrap(), 5);
+		assert_eq!(content().as_slice(), b"hello");
+		// Data inside of the file remains in cache until written back
+		assert_eq!(write_file(&node, 0, b"HE").unwrap(), 2);
+		assert!(is_dirty(&owner, 0));
+		assert_eq!(content().as_slice(), b"hello");
+		// A write across the end of the file does both
+		assert_eq!(write_file(&node, 3, b"LOW").unwrap(), 3);
+		assert_eq!(content().as_slice(), b"helLOW");
+		let mut buf = [0; 8];
+		assert_eq!(read_file(&node, 0, &mut buf).unwrap(), 6);
+		assert_eq!(&buf[..6], b"HELLOW");
+		sync_file(&node).unwrap();
+		assert!(!is_dirty(&owner, 0));
+		assert_eq!(content().as_slice(), b"HELLOW");
+		invalidate(&owner);
+	}
+
+	#[test_case]
+	fn page_cache_device_writeback() {
+		let (disk, queue, dev) = mem_disk(0, 2);
+		let owner = PageOwner::Device(dev.id);
+		let off = PAGE_SIZE / BLK_SIZE;
+		dev.write(off as _, b"hello").unwrap();
+		assert!(is_dirty(&owner, 1));
+		assert_eq!(&disk.0.lock()[PAGE_SIZE..(PAGE_SIZE + 5)], &[0; 5]);
+		// Only pages that became dirty before the given timestamp are written back
+		CACHE
+			.lock()
+			.get(&(owner.clone(), 1))
+			.unwrap()
+			.dirtied
+			.store(1000, Relaxed);
+		write_back_expired(999).unwrap();
+		assert!(is_dirty(&owner, 1));
+		write_back_expired(1000).unwrap();
+		assert!(!is_dirty(&owner, 1));
+		assert_eq!(&disk.0.lock()[PAGE_SIZE..(PAGE_SIZE + 5)], b"hello");
+		// Synchronization writes back every page
+		dev.write(0, b"world").unwrap();
+		assert!(is_dirty(&owner, 0));
+		sync_all().unwrap();
+		assert!(!is_dirty(&owner, 0));
+		assert_eq!(&disk.0.lock()[..5], b"world");
+		dev.remove();
+		queue.shutdown();
+	}
+}
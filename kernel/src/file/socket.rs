@@ -19,68 +19,252 @@
 //! This file implements sockets.
 
 use crate::{
-	file::{wait_queue::WaitQueue, File, FileOps, FileType, Stat},
-	net::{osi, SocketDesc},
-	sync::mutex::Mutex,
-	syscall::ioctl::Request,
+	file::{
+		perm::AccessProfile, vfs::ResolutionSettings, wait_queue::WaitQueue, File, FileOps,
+		FileType, Mode, Stat, O_NONBLOCK,
+	},
+	net::{
+		icmp,
+		icmp::IcmpState,
+		ip,
+		ip::TxOptions,
+		netlink,
+		netlink::NetlinkState,
+		packet,
+		packet::PacketState,
+		sioc, sockopt,
+		sockopt::SocketOptions,
+		tcp,
+		tcp::TcpState,
+		udp,
+		udp::UdpState,
+		unix,
+		unix::{Ancillary, AncillaryQueue, UnixState},
+		SocketDesc, SocketDomain, SocketType,
+	},
+	process::{mem_space::copy::SyscallPtr, Process},
+	sync::mutex::IntMutex,
+	syscall::{
+		ioctl,
+		ioctl::Request,
+		poll::{POLLERR, POLLHUP, POLLIN, POLLOUT, POLLRDHUP},
+		FromSyscallArg,
+	},
+	time::unit::Timestamp,
 };
 use core::{
+	cmp::{max, min},
 	ffi::{c_int, c_void},
+	intrinsics::unlikely,
+	mem,
 	sync::{atomic, atomic::AtomicUsize},
 };
 use utils::{
 	collections::{ring_buffer::RingBuffer, vec::Vec},
 	errno,
 	errno::{AllocResult, EResult},
+	ptr::arc::Arc,
 	vec,
 };
 
-/// The maximum size of a socket's buffers.
+/// The default size of a socket's buffers.
 const BUFFER_SIZE: usize = 65536;
 
-/// Socket option level: Socket
-const SOL_SOCKET: c_int = 1;
+/// The size of the header of a message stored in a receive buffer.
+///
+/// The header is made of two native-endian `u32`: the length of the sender's address, then the
+/// length of the message's data. The header is followed by the address, then by the data.
+pub const MSG_HDR_SIZE: usize = 8;
 
-/// A UNIX socket.
+/// Writes a message in the receive buffer `buf`, for message-oriented sockets.
+///
+/// Arguments:
+/// - `addr` is the address of the sender
+/// - `data` is the message's data
+///
+/// If the buffer does not have enough room for the whole message, the function does nothing and
+/// returns `false`.
+pub fn push_msg(buf: &mut RingBuffer<u8, Vec<u8>>, addr: &[u8], data: &[u8]) -> bool {
+	let len = MSG_HDR_SIZE + addr.len() + data.len();
+	if buf.get_available_len() < len {
+		return false;
+	}
+	buf.write(&(addr.len() as u32).to_ne_bytes());
+	buf.write(&(data.len() as u32).to_ne_bytes());
+	buf.write(addr);
+	buf.write(data);
+	true
+}
+
+/// Reads the next message from the receive buffer `buf`, for message-oriented sockets.
+///
+/// The data of the message is written to `data`. If `data` is too small, the rest of the message
+/// is discarded.
+///
+/// If `addr` is not `None`, the address of the sender is written into it.
+///
+/// If no message is available, the function returns `None`. Else, it returns the length of the
+/// message's data, which might be greater than the length of `data`.
+pub fn pop_msg(
+	buf: &mut RingBuffer<u8, Vec<u8>>,
+	addr: Option<&mut Vec<u8>>,
+	data: &mut [u8],
+) -> EResult<Option<usize>> {
+	let mut hdr = [0u8; MSG_HDR_SIZE];
+	if buf.peek(&mut hdr) < MSG_HDR_SIZE {
+		return Ok(None);
+	}
+	buf.read(&mut hdr);
+	let addr_len = u32::from_ne_bytes(hdr[..4].try_into().unwrap()) as usize;
+	let data_len = u32::from_ne_bytes(hdr[4..].try_into().unwrap()) as usize;
+	// Read address
+	let mut addr_buf = vec![0u8; addr_len]?;
+	buf.read(&mut addr_buf);
+	if let Some(addr) = addr {
+		*addr = addr_buf;
+	}
+	// Read data
+	let len = min(data.len(), data_len);
+	buf.read(&mut data[..len]);
+	// Discard the remaining data
+	let mut remain = data_len - len;
+	let mut discard = [0u8; 128];
+	while remain > 0 {
+		let l = min(remain, discard.len());
+		buf.read(&mut discard[..l]);
+		remain -= l;
+	}
+	Ok(Some(data_len))
+}
+
+/// Resizes the buffer `buf` to `size` bytes, keeping the data it contains.
+///
+/// If the data does not fit, the buffer is made just large enough to hold it.
+fn resize_buffer(buf: &mut RingBuffer<u8, Vec<u8>>, size: usize) -> AllocResult<()> {
+	let size = max(size, buf.get_data_len() + 1);
+	let mut new = RingBuffer::new(vec![0; size]?);
+	let mut chunk = [0u8; 512];
+	while !buf.is_empty() {
+		let len = buf.read(&mut chunk);
+		new.write(&chunk[..len]);
+	}
+	*buf = new;
+	Ok(())
+}
+
+/// Returns the length of the data of the next message in the receive buffer `buf`, for
+/// message-oriented sockets.
+///
+/// If no message is available, the function returns zero.
+fn peek_msg_len(buf: &mut RingBuffer<u8, Vec<u8>>) -> usize {
+	let mut hdr = [0u8; MSG_HDR_SIZE];
+	if buf.peek(&mut hdr) < MSG_HDR_SIZE {
+		return 0;
+	}
+	u32::from_ne_bytes(hdr[4..].try_into().unwrap()) as usize
+}
+
+/// Protocol-specific state of a socket.
+#[derive(Debug)]
+pub enum SocketState {
+	/// The socket has no protocol-specific state.
+	None,
+	/// Local socket.
+	Unix(UnixState),
+	/// ICMP socket.
+	Icmp(IcmpState),
+	/// TCP socket.
+	Tcp(TcpState),
+	/// UDP socket.
+	Udp(UdpState),
+	/// Netlink socket.
+	Netlink(NetlinkState),
+	/// Packet socket.
+	Packet(PacketState),
+}
+
+/// Queue of connections waiting to be accepted on a listening socket.
+#[derive(Debug)]
+struct Backlog {
+	/// The maximum number of pending connections.
+	max: usize,
+	/// The pending connections.
+	queue: Vec<Arc<Socket>>,
+}
+
+/// A socket, of any domain.
 #[derive(Debug)]
 pub struct Socket {
 	/// The socket's stack descriptor.
 	desc: SocketDesc,
-	/// The socket's network stack corresponding to the descriptor.
-	stack: Option<osi::Stack>,
 	/// The number of entities owning a reference to the socket. When this count reaches zero, the
 	/// socket is closed.
 	open_count: AtomicUsize,
 
+	// The network stack runs in interrupt context, hence the interrupt-safe locks below
 	/// The address the socket is bound to.
-	sockname: Mutex<Vec<u8>>,
+	sockname: IntMutex<Vec<u8>>,
+	/// Protocol-specific state.
+	state: IntMutex<SocketState>,
+	/// If the socket is listening, the queue of pending connections.
+	backlog: IntMutex<Option<Backlog>>,
 
 	/// The buffer containing received data. If `None`, reception has been shutdown.
-	rx_buff: Mutex<Option<RingBuffer<u8, Vec<u8>>>>,
+	rx_buff: IntMutex<Option<RingBuffer<u8, Vec<u8>>>>,
 	/// The buffer containing data to be transmitted. If `None`, transmission has been shutdown.
-	tx_buff: Mutex<Option<RingBuffer<u8, Vec<u8>>>>,
+	tx_buff: IntMutex<Option<RingBuffer<u8, Vec<u8>>>>,
 
 	/// Receive wait queue.
 	rx_queue: WaitQueue,
 	/// Transmit wait queue.
 	tx_queue: WaitQueue,
+
+	/// For local sockets, the ancillary data attached to the data in the receive buffer.
+	ancillary: IntMutex<AncillaryQueue>,
+
+	/// Options set by the user.
+	opts: IntMutex<SocketOptions>,
+	/// Transmission parameters of the network layer.
+	ip_opts: IntMutex<TxOptions>,
 }
 
 impl Socket {
 	/// Creates a new instance.
 	pub fn new(desc: SocketDesc) -> AllocResult<Self> {
+		let icmp = matches!(desc.protocol as u8, ip::PROTO_ICMP | ip::PROTO_ICMPV6);
+		let state = match (desc.domain, desc.type_) {
+			(SocketDomain::AfUnix, _) => SocketState::Unix(UnixState::default()),
+			(SocketDomain::AfNetlink, _) => SocketState::Netlink(NetlinkState::default()),
+			(SocketDomain::AfPacket, _) => SocketState::Packet(PacketState::new(desc.protocol)),
+			(SocketDomain::AfInet | SocketDomain::AfInet6, _) if icmp => {
+				SocketState::Icmp(IcmpState::default())
+			}
+			(SocketDomain::AfInet | SocketDomain::AfInet6, SocketType::SockStream) => {
+				SocketState::Tcp(TcpState::default())
+			}
+			(SocketDomain::AfInet | SocketDomain::AfInet6, SocketType::SockDgram) => {
+				SocketState::Udp(UdpState::default())
+			}
+			_ => SocketState::None,
+		};
 		Ok(Self {
 			desc,
-			stack: None,
 			open_count: AtomicUsize::new(0),
 
 			sockname: Default::default(),
+			state: IntMutex::new(state),
+			backlog: IntMutex::new(None),
 
-			rx_buff: Mutex::new(Some(RingBuffer::new(vec![0; BUFFER_SIZE]?))),
-			tx_buff: Mutex::new(Some(RingBuffer::new(vec![0; BUFFER_SIZE]?))),
+			rx_buff: IntMutex::new(Some(RingBuffer::new(vec![0; BUFFER_SIZE]?))),
+			tx_buff: IntMutex::new(Some(RingBuffer::new(vec![0; BUFFER_SIZE]?))),
 
 			rx_queue: WaitQueue::new(),
 			tx_queue: WaitQueue::new(),
+
+			ancillary: Default::default(),
+
+			opts: Default::default(),
+			ip_opts: Default::default(),
 		})
 	}
 
@@ -90,20 +274,155 @@ impl Socket {
 		&self.desc
 	}
 
-	/// Returns the socket's network stack.
+	/// Returns the protocol-specific state of the socket.
+	#[inline(always)]
+	pub fn state(&self) -> &IntMutex<SocketState> {
+		&self.state
+	}
+
+	/// Tells whether the socket uses the TCP protocol.
+	#[inline(always)]
+	fn is_tcp(&self) -> bool {
+		matches!(
+			(self.desc.domain, self.desc.type_),
+			(
+				SocketDomain::AfInet | SocketDomain::AfInet6,
+				SocketType::SockStream
+			)
+		)
+	}
+
+	/// Tells whether the socket uses the UDP protocol.
+	#[inline(always)]
+	fn is_udp(&self) -> bool {
+		matches!(
+			(self.desc.domain, self.desc.type_),
+			(
+				SocketDomain::AfInet | SocketDomain::AfInet6,
+				SocketType::SockDgram
+			)
+		) && !self.is_icmp()
+	}
+
+	/// Tells whether the socket uses the ICMP protocol.
+	#[inline(always)]
+	fn is_icmp(&self) -> bool {
+		matches!(
+			self.desc.domain,
+			SocketDomain::AfInet | SocketDomain::AfInet6
+		) && matches!(self.desc.protocol as u8, ip::PROTO_ICMP | ip::PROTO_ICMPV6)
+	}
+
+	/// Finishes the creation of the socket, registering it to its protocol if necessary.
+	///
+	/// `ap` is the access profile of the process creating the socket.
+	pub fn open(this: &Arc<Self>, ap: &AccessProfile) -> EResult<()> {
+		if this.is_icmp() {
+			icmp::open(this)?;
+		}
+		match this.desc.domain {
+			SocketDomain::AfUnix => unix::open(this)?,
+			SocketDomain::AfNetlink => netlink::open(this, ap),
+			SocketDomain::AfPacket => packet::open(this)?,
+			_ => {}
+		}
+		Ok(())
+	}
+
+	/// Returns the buffer containing received data.
+	#[inline(always)]
+	pub fn rx_buff(&self) -> &IntMutex<Option<RingBuffer<u8, Vec<u8>>>> {
+		&self.rx_buff
+	}
+
+	/// Returns the buffer containing data to be transmitted.
+	#[inline(always)]
+	pub fn tx_buff(&self) -> &IntMutex<Option<RingBuffer<u8, Vec<u8>>>> {
+		&self.tx_buff
+	}
+
+	/// Returns the receive wait queue.
+	#[inline(always)]
+	pub fn rx_queue(&self) -> &WaitQueue {
+		&self.rx_queue
+	}
+
+	/// Returns the transmit wait queue.
+	#[inline(always)]
+	pub fn tx_queue(&self) -> &WaitQueue {
+		&self.tx_queue
+	}
+
+	/// Returns the ancillary data attached to the data in the receive buffer, for local sockets.
+	#[inline(always)]
+	pub fn ancillary(&self) -> &IntMutex<AncillaryQueue> {
+		&self.ancillary
+	}
+
+	/// Returns the options set by the user.
+	#[inline(always)]
+	pub fn opts(&self) -> &IntMutex<SocketOptions> {
+		&self.opts
+	}
+
+	/// Returns the transmission parameters of the network layer.
 	#[inline(always)]
-	pub fn stack(&self) -> Option<&osi::Stack> {
-		self.stack.as_ref()
+	pub fn ip_opts(&self) -> &IntMutex<TxOptions> {
+		&self.ip_opts
+	}
+
+	/// Returns the timeout for receive operations, in milliseconds.
+	///
+	/// If `nonblock` is set, the timeout is zero. If `None`, operations wait indefinitely.
+	pub fn rcv_timeout(&self, nonblock: bool) -> Option<Timestamp> {
+		if nonblock {
+			Some(0)
+		} else {
+			self.opts.lock().rcv_timeout
+		}
+	}
+
+	/// Returns the timeout for send operations, in milliseconds.
+	///
+	/// If `nonblock` is set, the timeout is zero. If `None`, operations wait indefinitely.
+	pub fn snd_timeout(&self, nonblock: bool) -> Option<Timestamp> {
+		if nonblock {
+			Some(0)
+		} else {
+			self.opts.lock().snd_timeout
+		}
+	}
+
+	/// Resizes the receive buffer to `size` bytes, keeping the data it contains.
+	pub fn resize_rx_buff(&self, size: usize) -> EResult<()> {
+		if let Some(rx_buff) = self.rx_buff.lock().as_mut() {
+			resize_buffer(rx_buff, size)?;
+		}
+		// The window may have been opened
+		if self.is_tcp() {
+			tcp::on_recv(self);
+		}
+		// Wake processes waiting for room in the buffer
+		self.tx_queue.wake_all();
+		Ok(())
+	}
+
+	/// Resizes the transmit buffer to `size` bytes, keeping the data it contains.
+	pub fn resize_tx_buff(&self, size: usize) -> EResult<()> {
+		if let Some(tx_buff) = self.tx_buff.lock().as_mut() {
+			resize_buffer(tx_buff, size)?;
+		}
+		self.tx_queue.wake_all();
+		Ok(())
 	}
 
-	/// Reads the given socket option.
+	/// Reads the given socket option, returning its value.
 	///
 	/// Arguments:
 	/// - `level` is the level (protocol) at which the option is located.
 	/// - `optname` is the name of the option.
-	pub fn get_opt(&self, _level: c_int, _optname: c_int) -> EResult<&[u8]> {
-		// TODO
-		todo!()
+	pub fn get_opt(&self, level: c_int, optname: c_int) -> EResult<Vec<u8>> {
+		sockopt::get(self, level, optname)
 	}
 
 	/// Writes the given socket option.
@@ -112,45 +431,449 @@ impl Socket {
 	/// - `level` is the level (protocol) at which the option is located.
 	/// - `optname` is the name of the option.
 	/// - `optval` is the value of the option.
-	///
-	/// The function returns a value to be returned by the syscall on success.
-	pub fn set_opt(&self, _level: c_int, _optname: c_int, _optval: &[u8]) -> EResult<c_int> {
-		// TODO
-		Ok(0)
+	pub fn set_opt(&self, level: c_int, optname: c_int, optval: &[u8]) -> EResult<()> {
+		sockopt::set(self, level, optname, optval)
 	}
 
 	/// Returns the name of the socket.
-	pub fn get_sockname(&self) -> &Mutex<Vec<u8>> {
+	pub fn get_sockname(&self) -> &IntMutex<Vec<u8>> {
 		&self.sockname
 	}
 
 	/// Binds the socket to the given address.
 	///
-	/// `sockaddr` is the new socket name.
+	/// Arguments:
+	/// - `sockaddr` is the new socket name
+	/// - `rs` is the resolution settings used to create a socket file, if necessary
+	/// - `umask` is the mask to apply to the permissions of the socket file, if created
 	///
 	/// If the socket is already bound, or if the address is invalid, or if the address is already
-	/// in used, the function returns an error.
-	pub fn bind(&self, sockaddr: &[u8]) -> EResult<()> {
-		let mut sockname = self.sockname.lock();
-		if !sockname.is_empty() {
-			return Err(errno!(EINVAL));
+	/// in use, the function returns an error.
+	pub fn bind(
+		this: &Arc<Self>,
+		sockaddr: &[u8],
+		rs: &ResolutionSettings,
+		umask: Mode,
+	) -> EResult<()> {
+		match this.desc.domain {
+			SocketDomain::AfUnix => return unix::bind(this, sockaddr, rs, umask),
+			SocketDomain::AfNetlink => return netlink::bind(this, sockaddr),
+			SocketDomain::AfPacket => return packet::bind(this, sockaddr),
+			_ => {}
+		}
+		if this.is_tcp() {
+			return tcp::bind(this, sockaddr, &rs.access_profile);
+		}
+		if this.is_udp() {
+			return udp::bind(this, sockaddr, &rs.access_profile);
+		}
+		if this.is_icmp() {
+			return icmp::bind(this, sockaddr, &rs.access_profile);
+		}
+		// Every supported kind of socket has been handled above
+		Err(errno!(EOPNOTSUPP))
+	}
+
+	/// Connects the socket to the given address.
+	///
+	/// Arguments:
+	/// - `sockaddr` is the address to connect to
+	/// - `rs` is the resolution settings used to find a socket file, if necessary
+	/// - `nonblock` tells whether the function returns [`errno::EINPROGRESS`] instead of waiting
+	///   for a connection to be established
+	pub fn connect(
+		this: &Arc<Self>,
+		sockaddr: &[u8],
+		rs: &ResolutionSettings,
+		nonblock: bool,
+	) -> EResult<()> {
+		match this.desc.domain {
+			SocketDomain::AfUnix => unix::connect(this, sockaddr, rs),
+			SocketDomain::AfPacket => Err(errno!(EOPNOTSUPP)),
+			_ if this.is_tcp() => tcp::connect(this, sockaddr, nonblock),
+			_ if this.is_udp() => udp::connect(this, sockaddr),
+			_ if this.is_icmp() => icmp::connect(this, sockaddr),
+			// Netlink sockets only talk to the kernel, which needs no connection
+			_ => Err(errno!(EOPNOTSUPP)),
+		}
+	}
+
+	/// Marks the socket as accepting connections.
+	///
+	/// `backlog` is the maximum number of pending connections.
+	///
+	/// If the socket type does not support connections, the function returns
+	/// [`errno::EOPNOTSUPP`].
+	pub fn listen(this: &Arc<Self>, backlog: usize) -> EResult<()> {
+		if !this.desc.type_.is_stream() {
+			return Err(errno!(EOPNOTSUPP));
+		}
+		if this.desc.domain == SocketDomain::AfUnix {
+			unix::listen(this)?;
+		} else if this.is_tcp() {
+			tcp::listen(this)?;
+		}
+		let mut b = this.backlog.lock();
+		match &mut *b {
+			Some(b) => b.max = backlog,
+			None => {
+				*b = Some(Backlog {
+					max: backlog,
+					queue: Vec::new(),
+				})
+			}
 		}
-		// TODO check if address is already in used (EADDRINUSE)
-		// TODO check the requested network interface exists (EADDRNOTAVAIL)
-		// TODO check address against stack's domain
+		Ok(())
+	}
+
+	/// Tells whether the socket is listening for connections.
+	pub fn is_listening(&self) -> bool {
+		self.backlog.lock().is_some()
+	}
 
-		*sockname = Vec::try_from(sockaddr)?;
+	/// Inserts the connection `sock` in the queue of pending connections of the listening socket,
+	/// waking up a process waiting to accept it.
+	///
+	/// If the socket is not listening, the function returns [`errno::ECONNREFUSED`].
+	///
+	/// If the queue is full, the function returns [`errno::EAGAIN`].
+	pub fn enqueue_connection(&self, sock: Arc<Socket>) -> EResult<()> {
+		{
+			let mut backlog = self.backlog.lock();
+			let backlog = backlog.as_mut().ok_or_else(|| errno!(ECONNREFUSED))?;
+			// Like Linux, allow one more connection than the requested length
+			if backlog.queue.len() > backlog.max {
+				return Err(errno!(EAGAIN));
+			}
+			backlog.queue.push(sock)?;
+		}
+		self.rx_queue.wake_all();
 		Ok(())
 	}
 
+	/// Waits for a pending connection on the listening socket, then returns the socket
+	/// associated with it.
+	///
+	/// If the socket is not listening, the function returns [`errno::EINVAL`].
+	///
+	/// If `nonblock` is set and no connection is pending, or if the receive timeout expires, the
+	/// function returns [`errno::EAGAIN`].
+	pub fn accept(&self, nonblock: bool) -> EResult<Arc<Socket>> {
+		let sock = self
+			.rx_queue
+			.wait_until_timeout(self.rcv_timeout(nonblock), || {
+				let mut backlog = self.backlog.lock();
+				let Some(backlog) = backlog.as_mut() else {
+					return Some(Err(errno!(EINVAL)));
+				};
+				if backlog.queue.is_empty() {
+					return None;
+				}
+				Some(Ok(backlog.queue.remove(0)))
+			})??;
+		// Wake processes waiting for room in the queue
+		self.tx_queue.wake_all();
+		Ok(sock)
+	}
+
+	/// Sends the data in `buf` on the connected socket.
+	///
+	/// If `nonblock` is set, the function returns [`errno::EAGAIN`] instead of waiting for room to
+	/// send the data. The same happens if the send timeout expires. For streams, the data that
+	/// could be sent before is sent.
+	///
+	/// On success, the function returns the number of bytes sent.
+	pub fn send(this: &Arc<Self>, buf: &[u8], nonblock: bool) -> EResult<usize> {
+		match this.desc.domain {
+			SocketDomain::AfUnix => unix::send(this, buf, None, Ancillary::default(), nonblock),
+			SocketDomain::AfNetlink => netlink::send(this, buf, None),
+			SocketDomain::AfPacket => packet::send(this, buf, None),
+			_ if this.is_tcp() => tcp::send(this, buf, nonblock),
+			_ if this.is_udp() => udp::send(this, buf, None),
+			_ if this.is_icmp() => icmp::send(this, buf, None),
+			// No other kind of socket supports transmission
+			_ => Err(errno!(EOPNOTSUPP)),
+		}
+	}
+
+	/// Sends the data in `buf` to the given address.
+	///
+	/// Arguments:
+	/// - `buf` is the data to send
+	/// - `sockaddr` is the destination address
+	/// - `rs` is the resolution settings used to find a socket file, if necessary
+	/// - `nonblock` has the same meaning as for [`Self::send`]
+	///
+	/// On success, the function returns the number of bytes sent.
+	pub fn send_to(
+		this: &Arc<Self>,
+		buf: &[u8],
+		sockaddr: &[u8],
+		rs: &ResolutionSettings,
+		nonblock: bool,
+	) -> EResult<usize> {
+		match this.desc.domain {
+			SocketDomain::AfUnix => {
+				let dest = unix::lookup(sockaddr, rs)?;
+				unix::send(this, buf, Some(dest), Ancillary::default(), nonblock)
+			}
+			SocketDomain::AfNetlink => netlink::send(this, buf, Some(sockaddr)),
+			SocketDomain::AfPacket => packet::send(this, buf, Some(sockaddr)),
+			// The destination of a connection-mode socket is ignored
+			_ if this.is_tcp() => tcp::send(this, buf, nonblock),
+			_ if this.is_udp() => udp::send(this, buf, Some(sockaddr)),
+			_ if this.is_icmp() => icmp::send(this, buf, Some(sockaddr)),
+			// No other kind of socket supports transmission
+			_ => Err(errno!(EOPNOTSUPP)),
+		}
+	}
+
+	/// Sends the data in `buf` along with the ancillary data `anc`.
+	///
+	/// Arguments:
+	/// - `buf` is the data to send
+	/// - `sockaddr` is the destination address. If `None`, the connected peer is used
+	/// - `anc` is the ancillary data
+	/// - `rs` is the resolution settings used to find a socket file, if necessary
+	/// - `nonblock` has the same meaning as for [`Self::send`]
+	///
+	/// Only local sockets support ancillary data. If `anc` is not empty for another domain, the
+	/// function returns [`errno::EINVAL`].
+	///
+	/// On success, the function returns the number of bytes sent.
+	pub fn send_msg(
+		this: &Arc<Self>,
+		buf: &[u8],
+		sockaddr: Option<&[u8]>,
+		anc: Ancillary,
+		rs: &ResolutionSettings,
+		nonblock: bool,
+	) -> EResult<usize> {
+		if this.desc.domain == SocketDomain::AfUnix {
+			let dest = sockaddr.map(|a| unix::lookup(a, rs)).transpose()?;
+			return unix::send(this, buf, dest, anc, nonblock);
+		}
+		if !anc.is_empty() {
+			return Err(errno!(EINVAL));
+		}
+		match sockaddr {
+			Some(sockaddr) => Self::send_to(this, buf, sockaddr, rs, nonblock),
+			None => Self::send(this, buf, nonblock),
+		}
+	}
+
+	/// Returns the address of the peer the socket is connected to.
+	///
+	/// If the socket is not connected, the function returns [`errno::ENOTCONN`].
+	pub fn get_peername(&self) -> EResult<Vec<u8>> {
+		let remote = match &*self.state.lock() {
+			SocketState::Unix(state) => return unix::peername(state),
+			SocketState::Netlink(_) => return Ok(netlink::peername()?),
+			SocketState::Tcp(state) => state.remote().cloned(),
+			SocketState::Udp(state) => state.remote().cloned(),
+			SocketState::Icmp(state) => state.remote().cloned(),
+			SocketState::Packet(_) | SocketState::None => None,
+		};
+		let remote = remote.ok_or_else(|| errno!(ENOTCONN))?;
+		Ok(remote.to_bytes()?)
+	}
+
+	/// Tells whether the end of the stream has been reached, meaning no more data can be received.
+	///
+	/// If so, the function returns the value to be returned to the user, which is an error if the
+	/// connection has been dropped.
+	fn eof(&self) -> Option<EResult<usize>> {
+		match &*self.state.lock() {
+			SocketState::Unix(state) => state.is_eof().then_some(Ok(0)),
+			SocketState::Tcp(state) => state.eof(),
+			SocketState::Udp(_)
+			| SocketState::Icmp(_)
+			| SocketState::Netlink(_)
+			| SocketState::Packet(_)
+			| SocketState::None => None,
+		}
+	}
+
+	/// Receives data from the socket and writes it into `buf`.
+	///
+	/// For message-oriented sockets, a single message is received and the part of the message
+	/// that does not fit in `buf` is discarded.
+	///
+	/// If `nonblock` is set and no data is available, the function returns [`errno::EAGAIN`]
+	/// instead of waiting. The same happens if the receive timeout expires.
+	///
+	/// On success, the function returns the number of bytes written to `buf`.
+	pub fn recv(&self, buf: &mut [u8], nonblock: bool) -> EResult<usize> {
+		self.recv_msg(buf, None, None, nonblock).map(|(len, _)| len)
+	}
+
+	/// Same as [`Self::recv`], with additional information:
+	/// - for message-oriented sockets, the address of the sender is written to `addr` if not
+	///   `None`
+	/// - for local sockets, the ancillary data attached to the received data is written to `anc`
+	///   if not `None`. Else, it is discarded
+	///
+	/// On success, the function returns the number of bytes written to `buf`, along with the
+	/// length of the received data, which is greater for a truncated message.
+	pub fn recv_msg(
+		&self,
+		buf: &mut [u8],
+		mut addr: Option<&mut Vec<u8>>,
+		anc: Option<&mut Ancillary>,
+		nonblock: bool,
+	) -> EResult<(usize, usize)> {
+		let stream = self.desc.type_ == SocketType::SockStream;
+		if unlikely(stream && buf.is_empty()) {
+			return Ok((0, 0));
+		}
+		let timeout = self.rcv_timeout(nonblock);
+		let (len, data_len, ancillary) = self.rx_queue.wait_until_timeout(timeout, || {
+			// Checked before reading since the data preceding the end of stream is already in the
+			// buffer
+			let eof = self.eof();
+			let mut rx_buff = self.rx_buff.lock();
+			let Some(rx_buff) = rx_buff.as_mut() else {
+				// Reception has been shutdown
+				return Some(Ok((0, 0, None)));
+			};
+			let mut ancillary = self.ancillary.lock();
+			let prev_len = rx_buff.get_data_len();
+			let len = if stream {
+				// Do not receive data attached to different ancillary data at once
+				let max = ancillary.read_limit().unwrap_or(usize::MAX);
+				let max = min(buf.len(), max);
+				let len = rx_buff.read(&mut buf[..max]);
+				(len > 0).then_some((len, len))
+			} else {
+				match pop_msg(rx_buff, addr.as_deref_mut(), buf) {
+					Ok(len) => len.map(|len| (min(len, buf.len()), len)),
+					Err(e) => return Some(Err(e)),
+				}
+			};
+			let ancillary = ancillary.consume(prev_len - rx_buff.get_data_len());
+			match len {
+				Some((len, data_len)) => Some(Ok((len, data_len, ancillary))),
+				None => eof.map(|r| r.map(|len| (len, len, None))),
+			}
+		})??;
+		// Dropping the ancillary data may close files, hence it is done without holding locks
+		if let (Some(anc), Some(ancillary)) = (anc, ancillary) {
+			*anc = ancillary;
+		}
+		if stream && self.desc.domain == SocketDomain::AfUnix {
+			if let Some(addr) = addr {
+				*addr = self.get_peername().unwrap_or_default();
+			}
+		}
+		if self.is_tcp() {
+			tcp::on_recv(self);
+		}
+		// Wake processes waiting for room in the buffer
+		self.tx_queue.wake_all();
+		Ok((len, data_len))
+	}
+
+	/// Tells whether data can be sent without waiting, for the `poll` operation.
+	///
+	/// The function also returns whether transmission has been closed, in which case sending
+	/// fails without waiting.
+	fn poll_tx(&self) -> (bool, bool) {
+		let state = self.state.lock();
+		let room = self
+			.tx_buff
+			.lock()
+			.as_ref()
+			.map(|b| b.get_available_len() > 0);
+		let Some(room) = room else {
+			return (true, true);
+		};
+		match &*state {
+			// Local sockets write directly to the receive buffer of the peer
+			SocketState::Unix(state) => match state.peer() {
+				Some(peer) => match peer.rx_buff().lock().as_ref() {
+					Some(rx_buff) => (rx_buff.get_available_len() > 0, false),
+					None => (true, true),
+				},
+				// The peer has been closed
+				None if state.is_connected() => (true, true),
+				// Datagrams can still be sent to an explicit destination
+				None => (self.desc.type_ == SocketType::SockDgram, false),
+			},
+			SocketState::Tcp(state) => match state.tcb() {
+				Some(tcb) if tcb.error().is_some() => (true, true),
+				Some(tcb) if tcb.is_synchronized() => {
+					if tcb.can_send() {
+						(room, false)
+					} else {
+						(true, true)
+					}
+				}
+				_ => (false, false),
+			},
+			_ => (true, false),
+		}
+	}
+
 	/// Shuts down the reception side of the socket.
 	pub fn shutdown_reception(&self) {
 		*self.rx_buff.lock() = None;
+		self.rx_queue.wake_all();
+		self.tx_queue.wake_all();
 	}
 
 	/// Shuts down the transmit side of the socket.
 	pub fn shutdown_transmit(&self) {
+		// The data remaining in the buffer is still to be sent
+		if self.is_tcp() {
+			tcp::shutdown(self);
+			return;
+		}
 		*self.tx_buff.lock() = None;
+		if let SocketState::Unix(state) = &*self.state.lock() {
+			state.wake_peer();
+		}
+	}
+
+	/// Closes the socket, releasing the resources associated with it.
+	fn close(&self) {
+		// The connection is terminated in the background
+		if self.is_tcp() {
+			tcp::close(self);
+		}
+		if self.is_udp() {
+			udp::close(self);
+		}
+		if self.is_icmp() {
+			icmp::close(self);
+		}
+		match self.desc.domain {
+			SocketDomain::AfNetlink => netlink::close(self),
+			SocketDomain::AfPacket => packet::close(self),
+			_ => {}
+		}
+		let state = {
+			let mut state = self.state.lock();
+			match &*state {
+				SocketState::Unix(_) => mem::replace(&mut *state, SocketState::None),
+				_ => SocketState::None,
+			}
+		};
+		if let SocketState::Unix(state) = state {
+			unix::close(self, state);
+		}
+		// Drop the files in transit. This is done after releasing the lock since closing a file
+		// may close a socket
+		let ancillary = self.ancillary.lock().clear();
+		drop(ancillary);
+		// Close pending connections
+		let backlog = self.backlog.lock().take();
+		if let Some(backlog) = backlog {
+			for sock in backlog.queue {
+				sock.close();
+			}
+		}
+		self.rx_queue.wake_all();
+		self.tx_queue.wake_all();
 	}
 }
 
@@ -168,31 +891,225 @@ impl FileOps for Socket {
 
 	fn release(&self, _file: &File) {
 		let cnt = self.open_count.fetch_sub(1, atomic::Ordering::Release);
-		if cnt == 0 {
-			// TODO close the socket
+		// `cnt` is the value before decrement
+		if cnt == 1 {
+			self.close();
+		}
+	}
+
+	fn poll(&self, _file: &File, mask: u32) -> EResult<u32> {
+		let mut events = 0;
+		if let Some(backlog) = &*self.backlog.lock() {
+			if !backlog.queue.is_empty() {
+				events |= POLLIN;
+			}
+			return Ok(events & mask);
+		}
+		// Reception
+		let eof = self.eof();
+		let rx_closed = match self.rx_buff.lock().as_ref() {
+			Some(rx_buff) => {
+				if !rx_buff.is_empty() {
+					events |= POLLIN;
+				}
+				eof.is_some()
+			}
+			None => true,
+		};
+		if rx_closed {
+			events |= POLLIN | POLLRDHUP;
+		}
+		if matches!(eof, Some(Err(_))) {
+			events |= POLLERR;
+		}
+		// Transmission
+		let (writable, tx_closed) = self.poll_tx();
+		if writable {
+			events |= POLLOUT;
+		}
+		if rx_closed && tx_closed {
+			events |= POLLHUP;
+		}
+		// Errors and hang ups are reported even if not requested
+		Ok(events & (mask | POLLERR | POLLHUP))
+	}
+
+	fn ioctl(&self, _file: &File, request: Request, argp: *const c_void) -> EResult<u32> {
+		match request.get_old_format() {
+			ioctl::FIONREAD => {
+				let stream = self.desc.type_ == SocketType::SockStream;
+				let len = self
+					.rx_buff
+					.lock()
+					.as_mut()
+					.map(|rx_buff| {
+						if stream {
+							rx_buff.get_data_len()
+						} else {
+							peek_msg_len(rx_buff)
+						}
+					})
+					.unwrap_or(0);
+				let len_ptr = SyscallPtr::<c_int>::from_ptr(argp as usize);
+				len_ptr.copy_to_user(&(len as c_int))?;
+				Ok(0)
+			}
+			req => {
+				let ap = Process::current().fs.lock().access_profile;
+				sioc::ioctl(req, argp, &ap)
+			}
 		}
 	}
 
-	fn poll(&self, _file: &File, _mask: u32) -> EResult<u32> {
-		todo!()
+	fn read(&self, file: &File, _off: u64, buf: &mut [u8]) -> EResult<usize> {
+		self.recv(buf, file.get_flags() & O_NONBLOCK != 0)
+	}
+
+	fn write(&self, file: &File, _off: u64, buf: &[u8]) -> EResult<usize> {
+		let this = file
+			.get_buffer_arc::<Self>()
+			.ok_or_else(|| errno!(ENOTSOCK))?;
+		Self::send(&this, buf, file.get_flags() & O_NONBLOCK != 0)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{file::vfs, selftest, selftest::Subsystem};
+	use utils::collections::path::Path;
+
+	#[test_case]
+	fn socket_msg_buffer() {
+		let mut data = Vec::new();
+		data.resize(64, 0).unwrap();
+		let mut buf = RingBuffer::new(data);
+		assert_eq!(peek_msg_len(&mut buf), 0);
+		assert!(push_msg(&mut buf, b"ab", b"hello"));
+		assert!(push_msg(&mut buf, b"", b"world!"));
+		// Not enough room
+		assert!(!push_msg(&mut buf, b"", &[0; 64]));
+		assert_eq!(peek_msg_len(&mut buf), 5);
+		let mut addr = Vec::new();
+		let mut out = [0u8; 3];
+		assert_eq!(
+			pop_msg(&mut buf, Some(&mut addr), &mut out).unwrap(),
+			Some(5)
+		);
+		assert_eq!(addr.as_slice(), b"ab");
+		assert_eq!(&out, b"hel");
+		assert_eq!(peek_msg_len(&mut buf), 6);
+		let mut out = [0u8; 6];
+		assert_eq!(pop_msg(&mut buf, None, &mut out).unwrap(), Some(6));
+		assert_eq!(&out, b"world!");
+		assert!(buf.is_empty());
+	}
+
+	fn files() -> ResolutionSettings {
+		selftest::require(Subsystem::Files);
+		ResolutionSettings::kernel_follow()
+	}
+
+	fn unix_socket() -> Arc<Socket> {
+		let desc = SocketDesc {
+			domain: SocketDomain::AfUnix,
+			type_: SocketType::SockDgram,
+			protocol: 0,
+		};
+		let sock = Arc::new(Socket::new(desc).unwrap()).unwrap();
+		Socket::open(&sock, &AccessProfile::KERNEL).unwrap();
+		sock
+	}
+
+	/// Builds a `sockaddr_un` structure with the given path.
+	fn sockaddr_un(path: &[u8]) -> Vec<u8> {
+		let mut addr = Vec::new();
+		let family = (SocketDomain::AfUnix.get_id() as u16).to_ne_bytes();
+		addr.extend_from_slice(&family).unwrap();
+		addr.extend_from_slice(path).unwrap();
+		addr
+	}
+
+	/// Binds a socket to `addr`, then checks another socket can connect to it until the first
+	/// one is closed.
+	fn unix_bind_connect(addr: &[u8], rs: &ResolutionSettings) {
+		let server = unix_socket();
+		Socket::bind(&server, addr, rs, 0o022).unwrap();
+		assert_eq!(server.get_sockname().lock().as_slice(), addr);
+		// The socket is already bound
+		let res = Socket::bind(&server, &sockaddr_un(b"\0other"), rs, 0o022);
+		assert_eq!(res.unwrap_err().as_int(), errno::EINVAL);
+		// The address is already in use
+		let client = unix_socket();
+		let res = Socket::bind(&client, addr, rs, 0o022);
+		assert_eq!(res.unwrap_err().as_int(), errno::EADDRINUSE);
+		Socket::connect(&client, addr, rs, false).unwrap();
+		assert_eq!(client.get_peername().unwrap().as_slice(), addr);
+		// Closing the socket releases the address
+		server.close();
+		let res = Socket::connect(&client, addr, rs, false);
+		assert_eq!(res.unwrap_err().as_int(), errno::ECONNREFUSED);
+		client.close();
+	}
+
+	#[test_case]
+	fn socket_unix_path() {
+		let rs = files();
+		let addr = sockaddr_un(b"/selftest.sock\0");
+		unix_bind_connect(&addr, &rs);
+		let path = Path::new(b"/selftest.sock").unwrap();
+		let file = vfs::get_file_from_path(path, &rs).unwrap();
+		assert_eq!(file.stat().unwrap().get_type(), Some(FileType::Socket));
+		vfs::unlink_from_path(path, &rs).unwrap();
 	}
 
-	fn ioctl(&self, _file: &File, _request: Request, _argp: *const c_void) -> EResult<u32> {
-		todo!()
+	#[test_case]
+	fn socket_unix_abstract() {
+		let rs = files();
+		let addr = sockaddr_un(b"\0selftest");
+		unix_bind_connect(&addr, &rs);
+		// No file is created for an abstract name
+		let res = vfs::get_file_from_path(Path::new(b"/selftest").unwrap(), &rs);
+		assert_eq!(res.unwrap_err().as_int(), errno::ENOENT);
 	}
 
-	fn read(&self, _file: &File, _off: u64, _buf: &mut [u8]) -> EResult<usize> {
-		if !self.desc.type_.is_stream() {
-			// TODO error
+	#[test_case]
+	fn socket_unix_dgram_peer() {
+		let rs = files();
+		let a = unix_socket();
+		let b = unix_socket();
+		let c = unix_socket();
+		let addr_a = sockaddr_un(b"\0selftest-a");
+		let addr_b = sockaddr_un(b"\0selftest-b");
+		Socket::bind(&a, &addr_a, &rs, 0o022).unwrap();
+		Socket::bind(&b, &addr_b, &rs, 0o022).unwrap();
+		Socket::connect(&b, &addr_a, &rs, false).unwrap();
+		// `b` only receives from its peer
+		let res = Socket::send_to(&c, b"hello", &addr_b, &rs, true);
+		assert_eq!(res.unwrap_err().as_int(), errno::EPERM);
+		let res = Socket::connect(&c, &addr_b, &rs, false);
+		assert_eq!(res.unwrap_err().as_int(), errno::EPERM);
+		assert_eq!(Socket::send_to(&a, b"hello", &addr_b, &rs, true), Ok(5));
+		let mut buf = [0; 5];
+		assert_eq!(b.recv(&mut buf, true), Ok(5));
+		assert_eq!(&buf, b"hello");
+		for sock in [a, b, c] {
+			sock.close();
 		}
-		todo!()
 	}
 
-	fn write(&self, _file: &File, _off: u64, _buf: &[u8]) -> EResult<usize> {
-		// A destination address is required
-		let Some(_stack) = self.stack.as_ref() else {
-			return Err(errno!(EDESTADDRREQ));
+	#[test_case]
+	fn socket_unix_desc() {
+		let desc = |type_, protocol| SocketDesc {
+			domain: SocketDomain::AfUnix,
+			type_,
+			protocol,
 		};
-		todo!()
+		assert!(unix::check_desc(&desc(SocketType::SockStream, 0)).is_ok());
+		assert!(unix::check_desc(&desc(SocketType::SockDgram, 1)).is_ok());
+		let res = unix::check_desc(&desc(SocketType::SockRaw, 0));
+		assert_eq!(res.unwrap_err().as_int(), errno::ESOCKTNOSUPPORT);
+		let res = unix::check_desc(&desc(SocketType::SockStream, 6));
+		assert_eq!(res.unwrap_err().as_int(), errno::EPROTONOSUPPORT);
 	}
 }
@@ -32,7 +32,7 @@ use super::{
 use crate::{
 	device,
 	device::DeviceID,
-	file::vfs::mountpoint::MountPoint,
+	file::{page_cache, vfs::mountpoint::MountPoint},
 	process::Process,
 	sync::{mutex::Mutex, once::OnceInit},
 	syscall::ioctl::Request,
@@ -810,7 +810,11 @@ impl super::FileOps for FileOps {
 			.read_bytes(off, buf),
 			None => {
 				let node = file.vfs_entry.as_ref().unwrap().node();
-				node.ops.read_content(&node.location, off, buf)
+				if node.use_cache() {
+					page_cache::read_file(node, off, buf)
+				} else {
+					node.ops.read_content(&node.location, off, buf)
+				}
 			}
 		}
 	}
@@ -832,7 +836,13 @@ impl super::FileOps for FileOps {
 			.write_bytes(off, buf),
 			None => {
 				let node = file.vfs_entry.as_ref().unwrap().node();
-				node.ops.write_content(&node.location, off, buf)
+				if node.use_cache() {
+					page_cache::write_file(node, off, buf)
+				} else {
+					let len = node.ops.write_content(&node.location, off, buf)?;
+					page_cache::update_file(&node.location, off, &buf[..len])?;
+					Ok(len)
+				}
 			}
 		}
 	}
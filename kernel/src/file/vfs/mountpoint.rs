@@ -24,21 +24,22 @@ use crate::{
 	file::{
 		fs,
 		fs::{Filesystem, FilesystemType},
-		vfs,
+		page_cache, vfs,
 		vfs::{node, node::Node, EntryChild, ResolutionSettings},
 		FileLocation, FileType,
 	},
 	sync::mutex::Mutex,
 };
-use core::fmt;
+use core::{fmt, ptr};
 use utils::{
 	collections::{
 		hashmap::HashMap,
 		path::{Path, PathBuf},
 		string::String,
+		vec::Vec,
 	},
 	errno,
-	errno::{AllocResult, EResult},
+	errno::{AllocResult, CollectResult, EResult},
 	ptr::arc::Arc,
 	TryClone,
 };
@@ -207,12 +208,18 @@ impl MountPoint {
 
 impl Drop for MountPoint {
 	fn drop(&mut self) {
+		// Cached pages are identified by the ID of the mountpoint, which may be reused
+		page_cache::invalidate_mountpoint(self.id);
 		// If not associated with a device, stop
 		let MountSource::Device(dev_id) = &self.source else {
 			return;
 		};
 		let mut filesystems = FILESYSTEMS.lock();
-		let Some(fs) = filesystems.get(dev_id) else {
+		// The filesystem may have been replaced if the device was removed
+		let Some(fs) = filesystems
+			.get(dev_id)
+			.filter(|fs| ptr::addr_eq(Arc::as_ptr(fs), Arc::as_ptr(&self.fs)))
+		else {
 			return;
 		};
 		/*
@@ -332,21 +339,59 @@ pub fn remove(target: Arc<vfs::Entry>) -> EResult<()> {
 	};
 	// TODO Check if another mount point is present in a subdirectory? (EBUSY)
 	// TODO Check if busy (EBUSY)
-	// TODO sync fs
-	// Detach entry from parent
+	// Cannot unmount root filesystem
+	if target.parent.is_none() {
+		return Err(errno!(EINVAL));
+	}
+	page_cache::sync_mountpoint(&mp)?;
+	detach(&mp)
+}
+
+/// Detaches the mountpoint `mp` from the VFS tree, without synchronizing data.
+///
+/// Files that are still in use remain accessible to the processes using them.
+///
+/// If `mp` is the root mountpoint, the function returns [`errno::EINVAL`].
+pub fn detach(mp: &Arc<MountPoint>) -> EResult<()> {
+	let target = &mp.root_entry;
 	let Some(parent) = &target.parent else {
-		// Cannot unmount root filesystem
 		return Err(errno!(EINVAL));
 	};
 	parent.children.lock().remove(target.name.as_bytes());
 	// If this was the last reference to the mountpoint, remove it
 	let mut mps = MOUNT_POINTS.lock();
-	if Arc::strong_count(&mp) <= 2 {
+	if Arc::strong_count(mp) <= 2 {
 		mps.remove(&mp.id);
 	}
 	Ok(())
 }
 
+/// Detaches every mountpoint whose source is the device `id`, after the device has been removed.
+///
+/// Cached data is discarded since it cannot be written back anymore. Files that are still in use
+/// remain accessible, but I/O on them fails.
+///
+/// The root mountpoint cannot be detached, in which case the function returns [`errno::EBUSY`].
+pub fn detach_device(id: &DeviceID) -> EResult<()> {
+	// Do not reuse the filesystem if another device gets the same ID
+	FILESYSTEMS.lock().remove(id);
+	let mps = MOUNT_POINTS
+		.lock()
+		.iter()
+		.filter(|(_, mp)| matches!(&mp.source, MountSource::Device(dev) if dev == id))
+		.map(|(_, mp)| mp.clone())
+		.collect::<CollectResult<Vec<_>>>()
+		.0?;
+	let mut res = Ok(());
+	for mp in mps {
+		page_cache::invalidate_mountpoint(mp.id);
+		if detach(&mp).is_err() {
+			res = Err(errno!(EBUSY));
+		}
+	}
+	res
+}
+
 /// Returns the mountpoint with id `id`.
 ///
 /// If it does not exist, the function returns `None`.
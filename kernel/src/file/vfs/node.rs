@@ -19,7 +19,7 @@
 //! Filesystem node cache, allowing to handle hard links pointing to the same node.
 
 use crate::{
-	file::{fs::NodeOps, FileLocation, FileType},
+	file::{fs::NodeOps, page_cache, page_cache::PageOwner, FileLocation, FileType},
 	sync::mutex::Mutex,
 };
 use core::{
@@ -43,6 +43,13 @@ pub struct Node {
 }
 
 impl Node {
+	/// Tells whether the content of the node goes through the page cache.
+	pub fn use_cache(&self) -> bool {
+		self.location
+			.get_filesystem()
+			.is_some_and(|fs| fs.use_cache())
+	}
+
 	/// Releases the node, removing it from the disk if this is the last reference to it.
 	pub fn release(this: Arc<Self>) -> EResult<()> {
 		// Lock to avoid race condition later
@@ -71,6 +78,7 @@ impl Node {
 		let remove = (dir && stat.nlink <= 1) || stat.nlink == 0;
 		if remove {
 			ops.remove_node(loc)?;
+			page_cache::invalidate(&PageOwner::File(loc.clone()));
 		}
 		Ok(())
 	}
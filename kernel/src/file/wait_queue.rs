@@ -23,6 +23,12 @@ use crate::{
 	process,
 	process::{pid::Pid, scheduler::Scheduler, Process},
 	sync::mutex::{IntMutex, Mutex},
+	time::{
+		clock,
+		clock::CLOCK_MONOTONIC,
+		timer,
+		unit::{Timestamp, TimestampScale},
+	},
 };
 use core::mem;
 use utils::{collections::vec::Vec, errno, errno::EResult};
@@ -68,6 +74,44 @@ impl WaitQueue {
 		}
 	}
 
+	/// Same as [`Self::wait_until`], except that the function returns [`errno::EAGAIN`] if `f`
+	/// did not return `Some` after `timeout` milliseconds.
+	///
+	/// If `timeout` is `None`, the function waits indefinitely. If it is zero, the function does
+	/// not wait.
+	pub fn wait_until_timeout<F: FnMut() -> Option<T>, T>(
+		&self,
+		timeout: Option<Timestamp>,
+		mut f: F,
+	) -> EResult<T> {
+		let Some(timeout) = timeout else {
+			return self.wait_until(f);
+		};
+		if let Some(val) = f() {
+			return Ok(val);
+		}
+		if timeout == 0 {
+			return Err(errno!(EAGAIN));
+		}
+		let deadline =
+			clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond)? + timeout;
+		let pid = Process::current().get_pid();
+		timer::schedule_wakeup(deadline, pid)?;
+		let res = self.wait_until(|| {
+			if let Some(val) = f() {
+				return Some(Ok(val));
+			}
+			let ts = clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond);
+			match ts {
+				Ok(ts) if ts < deadline => None,
+				Ok(_) => Some(Err(errno!(EAGAIN))),
+				Err(e) => Some(Err(e)),
+			}
+		});
+		timer::cancel_wakeup(deadline, pid);
+		res?
+	}
+
 	/// Wakes the next process in queue.
 	pub fn wake_next(&self) {
 		let proc = loop {
@@ -0,0 +1,89 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The writeback thread periodically writes dirty pages of the page cache back to storage.
+//!
+//! Pages are written back once they have been dirty for longer than the dirty expire interval.
+//! The thread wakes up at every dirty writeback interval to look for such pages, or earlier when
+//! too many dirty pages accumulate.
+//!
+//! Both intervals can be tuned through `/proc/sys/vm`.
+
+use crate::{
+	file::{page_cache, wait_queue::WaitQueue},
+	process::Process,
+	time::{
+		clock,
+		clock::CLOCK_MONOTONIC,
+		unit::{Timestamp, TimestampScale},
+	},
+};
+use core::sync::atomic::{
+	AtomicBool, AtomicU32,
+	Ordering::{Acquire, Relaxed, Release},
+};
+use utils::errno::EResult;
+
+/// The duration after which a dirty page has to be written back, in centiseconds.
+pub static DIRTY_EXPIRE_CENTISECS: AtomicU32 = AtomicU32::new(3000);
+/// The interval at which the writeback thread wakes up, in centiseconds.
+///
+/// If zero, periodic writeback is disabled.
+pub static DIRTY_WRITEBACK_CENTISECS: AtomicU32 = AtomicU32::new(500);
+
+/// The queue on which the writeback thread sleeps.
+static QUEUE: WaitQueue = WaitQueue::new();
+/// Tells whether the writeback thread has been requested to write back every dirty page.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Wakes the writeback thread up to write back every dirty page, without waiting for them to
+/// expire.
+pub fn wake() {
+	REQUESTED.store(true, Release);
+	QUEUE.wake_all();
+}
+
+/// The writeback thread's code.
+fn thread() -> ! {
+	loop {
+		let interval = DIRTY_WRITEBACK_CENTISECS.load(Relaxed) as Timestamp * 10;
+		let res = QUEUE.wait_until_timeout((interval > 0).then_some(interval), || {
+			REQUESTED.swap(false, Acquire).then_some(())
+		});
+		let older_than = match res {
+			// Requested explicitly: write back everything
+			Ok(()) => Timestamp::MAX,
+			Err(_) => {
+				let Ok(ts) = clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond)
+				else {
+					continue;
+				};
+				let expire = DIRTY_EXPIRE_CENTISECS.load(Relaxed) as Timestamp * 10;
+				ts.saturating_sub(expire)
+			}
+		};
+		// Pages that cannot be written back remain dirty and are retried later
+		let _ = page_cache::write_back_expired(older_than);
+	}
+}
+
+/// Starts the writeback thread.
+pub(crate) fn init() -> EResult<()> {
+	Process::kernel_thread(thread)?;
+	Ok(())
+}
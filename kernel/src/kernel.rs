@@ -80,7 +80,7 @@ pub mod tty;
 
 use crate::{
 	arch::x86::{enable_sse, has_sse, idt, idt::IntFrame},
-	file::{fs::initramfs, vfs, vfs::ResolutionSettings},
+	file::{fs::initramfs, vfs, vfs::ResolutionSettings, writeback},
 	logger::LOGGER,
 	memory::vmem,
 	process::{
@@ -135,6 +135,8 @@ fn init(init_path: String) -> EResult<()> {
 			},
 		)?;
 		let proc = Process::init()?;
+		// Kernel threads are started after init, which must have the first PID
+		writeback::init()?;
 		exec(&proc, &mut frame, program_image)?;
 		SCHEDULER.get().lock().swap_current_process(proc);
 	}
@@ -209,7 +211,7 @@ fn kernel_main_inner(magic: u32, multiboot_ptr: *const c_void) {
 		.unwrap_or_else(|e| kernel_panic!("Failed to create ramdisks! ({})", e));*/
 	println!("Initializing devices management...");
 	device::init().unwrap_or_else(|e| panic!("Failed to initialize devices management! ({e})"));
-	net::osi::init().unwrap_or_else(|e| panic!("Failed to initialize network! ({e})"));
+	net::init().unwrap_or_else(|e| panic!("Failed to initialize network! ({e})"));
 	crypto::init()
 		.unwrap_or_else(|_| panic!("Failed to initialize cryptography! (out of memory)"));
 
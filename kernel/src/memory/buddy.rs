@@ -60,6 +60,20 @@ pub const FLAG_ZONE_TYPE_MMIO: Flags = 0b01;
 /// Buddy allocator flag: allocate in kernel zone
 pub const FLAG_ZONE_TYPE_KERNEL: Flags = 0b10;
 
+/// The maximum number of registered shrinkers.
+const MAX_SHRINKERS: usize = 4;
+
+/// A function reclaiming memory when no frame is available, registered with
+/// [`register_shrinker`].
+///
+/// The function is given the number of pages to reclaim and returns the number of pages it
+/// actually freed.
+///
+/// Shrinkers are called from any context allocating memory, including interrupt handlers and
+/// sections holding an [`IntMutex`]. Thus, they must neither sleep nor wait for a lock that may be
+/// held by the caller.
+pub type Shrinker = fn(usize) -> usize;
+
 /// The size of the metadata for one frame.
 pub const FRAME_METADATA_SIZE: usize = size_of::<Frame>();
 /// Value indicating that the frame is used.
@@ -454,6 +468,39 @@ pub(crate) static ZONES: IntMutex<[Zone; ZONES_COUNT]> = IntMutex::new([
 	Zone::placeholder(),
 ]);
 
+/// The registered shrinkers.
+static SHRINKERS: IntMutex<[Option<Shrinker>; MAX_SHRINKERS]> =
+	IntMutex::new([None; MAX_SHRINKERS]);
+
+/// Registers `shrinker`, to be called when memory runs out.
+///
+/// If too many shrinkers are registered, the function returns an error.
+pub fn register_shrinker(shrinker: Shrinker) -> AllocResult<()> {
+	let mut shrinkers = SHRINKERS.lock();
+	let slot = shrinkers
+		.iter_mut()
+		.find(|s| s.is_none())
+		.ok_or(AllocError)?;
+	*slot = Some(shrinker);
+	Ok(())
+}
+
+/// Calls the registered shrinkers until `pages` pages have been reclaimed.
+///
+/// The function returns the number of reclaimed pages.
+fn shrink(pages: usize) -> usize {
+	// Copy the list so that the lock is not held while shrinkers run
+	let shrinkers = *SHRINKERS.lock();
+	let mut reclaimed = 0;
+	for shrinker in shrinkers.iter().flatten() {
+		if reclaimed >= pages {
+			break;
+		}
+		reclaimed += shrinker(pages - reclaimed);
+	}
+	reclaimed
+}
+
 /// The size in bytes of a frame with the given order `order`.
 #[inline]
 pub fn get_frame_size(order: FrameOrder) -> usize {
@@ -489,11 +536,24 @@ fn get_zone_for_addr(zones: &mut [Zone; ZONES_COUNT], phys_addr: PhysAddr) -> Op
 ///
 /// If no suitable frame is found, the function returns an error.
 ///
+/// If no frame is available, the registered shrinkers are called before retrying.
+///
 /// On success, the function returns a *physical* pointer to the allocated memory.
 pub fn alloc(order: FrameOrder, flags: Flags) -> AllocResult<PhysAddr> {
 	if order > MAX_ORDER {
 		return Err(AllocError);
 	}
+	alloc_impl(order, flags).or_else(|_| {
+		// Reclaiming pages requires freeing them, so it must be done without holding the lock
+		if shrink(math::pow2(order as usize)) == 0 {
+			return Err(AllocError);
+		}
+		alloc_impl(order, flags)
+	})
+}
+
+/// Implementation of [`alloc`], without reclaiming memory.
+fn alloc_impl(order: FrameOrder, flags: Flags) -> AllocResult<PhysAddr> {
 	// Select a zone and frame to allocate on
 	let mut zones = ZONES.lock();
 	let begin_zone = (flags & ZONE_TYPE_MASK) as usize;
@@ -0,0 +1,101 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! DMA (Direct Memory Access) allows devices to read and write the main memory without going
+//! through the CPU.
+//!
+//! Since devices use physical addresses, memory shared with a device must be physically
+//! contiguous.
+
+use super::{buddy, buddy::FrameOrder, PhysAddr, VirtAddr};
+use core::{ptr, ptr::NonNull, slice};
+use utils::errno::AllocResult;
+
+/// A chunk of physically contiguous memory, shared with a device.
+///
+/// The memory is allocated in the kernel zone, so that it is accessible from both the CPU and
+/// the device.
+#[derive(Debug)]
+pub struct DmaBuffer {
+	/// The virtual address of the chunk.
+	ptr: NonNull<u8>,
+	/// The order of the allocated frame.
+	order: FrameOrder,
+}
+
+impl DmaBuffer {
+	/// Allocates a chunk of at least `pages` pages, filled with zeros.
+	pub fn new(pages: usize) -> AllocResult<Self> {
+		let order = buddy::get_order(pages);
+		let ptr = buddy::alloc_kernel(order)?;
+		unsafe {
+			ptr::write_bytes(ptr.as_ptr(), 0, buddy::get_frame_size(order));
+		}
+		Ok(Self {
+			ptr,
+			order,
+		})
+	}
+
+	/// Returns the size of the chunk in bytes.
+	#[inline]
+	pub fn size(&self) -> usize {
+		buddy::get_frame_size(self.order)
+	}
+
+	/// Returns the virtual address of the byte at offset `off` in the chunk.
+	#[inline]
+	pub fn as_ptr(&self, off: usize) -> *mut u8 {
+		debug_assert!(off < self.size());
+		unsafe { self.ptr.as_ptr().add(off) }
+	}
+
+	/// Returns the physical address of the byte at offset `off` in the chunk, to be given to the
+	/// device.
+	#[inline]
+	pub fn phys_addr(&self, off: usize) -> PhysAddr {
+		VirtAddr::from(self.as_ptr(off))
+			.kernel_to_physical()
+			.unwrap()
+	}
+
+	/// Returns an immutable slice over the `len` bytes at offset `off`.
+	///
+	/// The device may write to the memory at any time. It is up to the caller to ensure the
+	/// device does not use this range while the slice is alive.
+	pub fn slice(&self, off: usize, len: usize) -> &[u8] {
+		assert!(off + len <= self.size());
+		unsafe { slice::from_raw_parts(self.as_ptr(off), len) }
+	}
+
+	/// Returns a mutable slice over the `len` bytes at offset `off`.
+	///
+	/// The same restrictions as [`Self::slice`] apply.
+	pub fn slice_mut(&mut self, off: usize, len: usize) -> &mut [u8] {
+		assert!(off + len <= self.size());
+		unsafe { slice::from_raw_parts_mut(self.as_ptr(off), len) }
+	}
+}
+
+impl Drop for DmaBuffer {
+	fn drop(&mut self) {
+		unsafe {
+			buddy::free_kernel(self.ptr.as_ptr(), self.order);
+		}
+	}
+}
@@ -37,6 +37,7 @@ use core::{
 
 pub mod alloc;
 pub mod buddy;
+pub mod dma;
 pub mod malloc;
 pub mod memmap;
 pub mod mmio;
@@ -0,0 +1,211 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! This module implements the Address Resolution Protocol (RFC 826), which resolves IPv4
+//! addresses to Ethernet addresses.
+//!
+//! Resolved addresses are stored in the [neighbor cache](super::neighbor).
+
+use super::{
+	eth,
+	eth::{ETHERTYPE_ARP, ETHERTYPE_IPV4},
+	neighbor, Address, Interface, LinkType, MAC,
+};
+use crate::sync::mutex::IntMutex;
+use macros::AnyRepr;
+use utils::{
+	bytes::{as_bytes, from_bytes},
+	collections::vec::Vec,
+	errno::EResult,
+};
+
+/// Hardware type: Ethernet
+const HTYPE_ETHERNET: u16 = 1;
+
+/// Operation: Request
+const OPER_REQUEST: u16 = 1;
+/// Operation: Reply
+const OPER_REPLY: u16 = 2;
+
+/// An ARP packet, resolving an IPv4 address to an Ethernet address.
+#[derive(AnyRepr, Clone, Copy)]
+#[repr(C, packed)]
+struct ArpPacket {
+	/// The hardware type, in network byte order.
+	htype: u16,
+	/// The protocol type, in network byte order.
+	ptype: u16,
+	/// The length of hardware addresses.
+	hlen: u8,
+	/// The length of protocol addresses.
+	plen: u8,
+	/// The operation, in network byte order.
+	oper: u16,
+	/// The hardware address of the sender.
+	sha: MAC,
+	/// The protocol address of the sender.
+	spa: [u8; 4],
+	/// The hardware address of the target. Ignored in requests.
+	tha: MAC,
+	/// The protocol address of the target.
+	tpa: [u8; 4],
+}
+
+impl ArpPacket {
+	/// Creates a packet for the operation `oper`.
+	fn new(oper: u16, sha: MAC, spa: [u8; 4], tha: MAC, tpa: [u8; 4]) -> Self {
+		Self {
+			htype: HTYPE_ETHERNET.to_be(),
+			ptype: ETHERTYPE_IPV4.to_be(),
+			hlen: 6,
+			plen: 4,
+			oper: oper.to_be(),
+			sha,
+			spa,
+			tha,
+			tpa,
+		}
+	}
+
+	/// Tells whether the packet resolves an IPv4 address to an Ethernet address.
+	fn is_valid(&self) -> bool {
+		u16::from_be(self.htype) == HTYPE_ETHERNET
+			&& u16::from_be(self.ptype) == ETHERTYPE_IPV4
+			&& self.hlen == 6
+			&& self.plen == 4
+	}
+}
+
+/// Broadcasts the packet `packet` on the interface `iface`.
+fn broadcast(iface: &IntMutex<dyn Interface>, packet: &ArpPacket) -> EResult<()> {
+	eth::transmit(
+		iface,
+		eth::BROADCAST,
+		ETHERTYPE_ARP,
+		as_bytes(packet).into(),
+	)
+}
+
+/// Sends a request for the address `target` on the interface `iface`.
+pub fn request(iface: &IntMutex<dyn Interface>, target: &[u8; 4]) -> EResult<()> {
+	let (mac, src) = {
+		let iface = iface.lock();
+		// Prefer an address on the same subnet as the target
+		let src = iface
+			.get_addresses()
+			.iter()
+			.filter_map(|a| match a.addr {
+				Address::IPv4(addr) => Some((a.is_matching(&Address::IPv4(*target)), addr)),
+				_ => None,
+			})
+			.max_by_key(|(matching, _)| *matching)
+			.map(|(_, addr)| addr)
+			.unwrap_or_default();
+		(*iface.get_mac(), src)
+	};
+	let packet = ArpPacket::new(OPER_REQUEST, mac, src, [0; 6], *target);
+	broadcast(iface, &packet)
+}
+
+/// Broadcasts a gratuitous ARP for the address `addr` of the interface `iface`, so that the
+/// neighbors update their caches.
+pub fn announce(iface: &IntMutex<dyn Interface>, addr: &[u8; 4]) -> EResult<()> {
+	let mac = *iface.lock().get_mac();
+	let packet = ArpPacket::new(OPER_REQUEST, mac, *addr, [0; 6], *addr);
+	broadcast(iface, &packet)
+}
+
+/// Announces the IPv4 addresses of the interface `iface`, which has just been brought up.
+///
+/// Interfaces without a link layer are left untouched.
+pub fn iface_up(iface: &IntMutex<dyn Interface>) -> EResult<()> {
+	let mut addrs = Vec::new();
+	{
+		let iface = iface.lock();
+		if iface.get_link_type() != LinkType::Ethernet {
+			return Ok(());
+		}
+		for a in iface.get_addresses() {
+			if let Address::IPv4(addr) = a.addr {
+				addrs.push(addr)?;
+			}
+		}
+	}
+	for addr in addrs {
+		announce(iface, &addr)?;
+	}
+	Ok(())
+}
+
+/// Handles the ARP packet `payload` received on the interface `iface`.
+///
+/// The sender is recorded in the neighbor cache and, if the packet is a request for an address of
+/// the interface, a reply is sent.
+pub fn receive(iface: &IntMutex<dyn Interface>, payload: &[u8]) -> EResult<()> {
+	let Some(packet) = from_bytes::<ArpPacket>(payload) else {
+		return Ok(());
+	};
+	if !packet.is_valid() {
+		return Ok(());
+	}
+	let (sha, spa, tpa) = (packet.sha, packet.spa, packet.tpa);
+	let (ours, mac) = {
+		let iface = iface.lock();
+		let ours = iface
+			.get_addresses()
+			.iter()
+			.any(|a| a.addr == Address::IPv4(tpa));
+		(ours, *iface.get_mac())
+	};
+	// Address probes have no sender address (RFC 5227). Other hosts are recorded only if they
+	// talk to us, but known entries are refreshed (RFC 826, "Packet Reception")
+	if spa != [0; 4] {
+		neighbor::update(iface, &Address::IPv4(spa), sha, ours)?;
+	}
+	if ours && u16::from_be(packet.oper) == OPER_REQUEST {
+		let reply = ArpPacket::new(OPER_REPLY, mac, tpa, sha, spa);
+		eth::transmit(iface, sha, ETHERTYPE_ARP, as_bytes(&reply).into())?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn arp_packet() {
+		let sha = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+		let packet = ArpPacket::new(OPER_REQUEST, sha, [10, 0, 2, 15], [0; 6], [10, 0, 2, 2]);
+		let bytes = as_bytes(&packet);
+		assert_eq!(bytes.len(), 28);
+		assert_eq!(&bytes[..8], &[0, 1, 8, 0, 6, 4, 0, 1]);
+		assert_eq!(&bytes[8..14], &sha);
+		assert_eq!(&bytes[14..18], &[10, 0, 2, 15]);
+		assert_eq!(&bytes[24..], &[10, 0, 2, 2]);
+		let parsed = from_bytes::<ArpPacket>(bytes).unwrap();
+		assert!(parsed.is_valid());
+		assert_eq!(u16::from_be(parsed.oper), OPER_REQUEST);
+		// IPv6 is not resolved with ARP
+		let mut bytes = [0u8; 28];
+		bytes.copy_from_slice(as_bytes(&packet));
+		bytes[2..4].copy_from_slice(&eth::ETHERTYPE_IPV6.to_be_bytes());
+		assert!(!from_bytes::<ArpPacket>(&bytes).unwrap().is_valid());
+		assert!(from_bytes::<ArpPacket>(&bytes[..20]).is_none());
+	}
+}
@@ -16,9 +16,10 @@
  * Maestro. If not, see <https://www.gnu.org/licenses/>.
  */
 
-//! TODO doc
+//! Buffers used to build packets through the layers of the network stack without copying.
 
-use core::ptr::NonNull;
+use core::{marker::PhantomData, ptr::NonNull};
+use utils::{collections::vec::Vec, errno::AllocResult};
 
 /// A linked-list of buffers representing a packet being built.
 ///
@@ -64,3 +65,41 @@ impl<'b> BuffList<'b> {
 		front
 	}
 }
+
+impl<'b> BuffList<'b> {
+	/// Returns an iterator over the buffers of the list, in order.
+	pub fn iter(&self) -> BuffListIter<'_, 'b> {
+		BuffListIter {
+			cur: Some(NonNull::from(self)),
+			_phantom: PhantomData,
+		}
+	}
+
+	/// Copies the content of the list into a single contiguous buffer.
+	pub fn to_vec(&self) -> AllocResult<Vec<u8>> {
+		let mut buf = Vec::with_capacity(self.len())?;
+		for b in self.iter() {
+			buf.extend_from_slice(b)?;
+		}
+		Ok(buf)
+	}
+}
+
+/// Iterator over the buffers of a [`BuffList`].
+pub struct BuffListIter<'l, 'b> {
+	/// The current node.
+	cur: Option<NonNull<BuffList<'b>>>,
+	/// The lifetime of the list.
+	_phantom: PhantomData<&'l BuffList<'b>>,
+}
+
+impl<'b> Iterator for BuffListIter<'_, 'b> {
+	type Item = &'b [u8];
+
+	fn next(&mut self) -> Option<Self::Item> {
+		// Safe because the lifetimes guarantee the nodes of the list remain valid
+		let cur = unsafe { self.cur?.as_ref() };
+		self.cur = cur.next;
+		Some(cur.b)
+	}
+}
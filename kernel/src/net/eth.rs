@@ -0,0 +1,87 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! This module implements the Ethernet link layer (IEEE 802.3).
+
+use super::{arp, buff::BuffList, ip, packet, Interface, MAC};
+use crate::sync::mutex::IntMutex;
+use core::mem::size_of;
+use macros::AnyRepr;
+use utils::{
+	bytes::{as_bytes, from_bytes},
+	errno::EResult,
+};
+
+/// EtherType: IPv4
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+/// EtherType: ARP
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+/// EtherType: IPv6
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// The broadcast MAC address.
+pub const BROADCAST: MAC = [0xff; 6];
+
+/// The header of an Ethernet frame.
+#[derive(AnyRepr, Clone, Copy)]
+#[repr(C, packed)]
+pub struct EthernetHeader {
+	/// The destination MAC address.
+	pub dst: MAC,
+	/// The source MAC address.
+	pub src: MAC,
+	/// The protocol of the payload, in network byte order.
+	pub ethertype: u16,
+}
+
+/// Handles the Ethernet frame `frame` received on the interface `iface`, passing its payload to
+/// the network layer.
+///
+/// Frames with an unsupported protocol are discarded.
+pub fn receive(iface: &IntMutex<dyn Interface>, frame: &[u8]) -> EResult<()> {
+	let Some(hdr) = from_bytes::<EthernetHeader>(frame) else {
+		return Ok(());
+	};
+	let payload = &frame[size_of::<EthernetHeader>()..];
+	match u16::from_be(hdr.ethertype) {
+		ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => ip::receive(iface, payload),
+		ETHERTYPE_ARP => arp::receive(iface, payload),
+		_ => Ok(()),
+	}
+}
+
+/// Transmits the network layer packet `buff` on the interface `iface`, to the neighbor with the
+/// MAC address `dst`.
+///
+/// `ethertype` is the protocol of the packet.
+pub fn transmit(
+	iface: &IntMutex<dyn Interface>,
+	dst: MAC,
+	ethertype: u16,
+	mut buff: BuffList<'_>,
+) -> EResult<()> {
+	let hdr = EthernetHeader {
+		dst,
+		src: *iface.lock().get_mac(),
+		ethertype: ethertype.to_be(),
+	};
+	let buff = buff.push_front(as_bytes(&hdr).into());
+	iface.lock().write(&buff)?;
+	// Packet sockets see the frames transmitted by the host
+	packet::transmit(iface, &buff)
+}
@@ -22,6 +22,74 @@
 //! - With IPv4: RFC 792
 //! - With IPv6 (ICMPv6): RFC 4443
 
+use super::{
+	ip,
+	ip::{IPv4Header, Packet, TxOptions},
+	ndp,
+	sockaddr::SockAddr,
+	Address, Interface, SocketDomain, SocketType,
+};
+use crate::{
+	crypto::checksum,
+	file::{
+		perm::AccessProfile,
+		socket::{push_msg, Socket, SocketState},
+	},
+	net,
+	sync::mutex::IntMutex,
+};
+use core::{cmp::min, mem::size_of};
+use utils::{
+	bytes::from_bytes,
+	collections::vec::Vec,
+	errno,
+	errno::{AllocResult, CollectResult, EResult},
+	ptr::arc::Arc,
+};
+
+/// The size of the ICMP header.
+const HDR_SIZE: usize = 8;
+/// The minimum MTU of IPv6 links, which bounds the size of ICMPv6 error messages.
+const MIN_MTU_V6: usize = 1280;
+
+/// Type: Echo reply
+pub const TYPE_ECHO_REPLY: u8 = 0;
+/// Type: Destination unreachable
+pub const TYPE_DEST_UNREACHABLE: u8 = 3;
+/// Type: Echo request
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+/// Type: Time exceeded
+pub const TYPE_TIME_EXCEEDED: u8 = 11;
+
+/// Destination unreachable code: Protocol unreachable
+pub const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+/// Destination unreachable code: Port unreachable
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+/// Destination unreachable code: Fragmentation needed and DF set
+pub const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+/// Time exceeded code: Fragment reassembly time exceeded
+pub const CODE_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+/// ICMPv6 type: Destination unreachable
+pub const TYPE6_DEST_UNREACHABLE: u8 = 1;
+/// ICMPv6 type: Packet too big
+pub const TYPE6_PACKET_TOO_BIG: u8 = 2;
+/// ICMPv6 type: Time exceeded
+pub const TYPE6_TIME_EXCEEDED: u8 = 3;
+/// ICMPv6 type: Parameter problem
+pub const TYPE6_PARAM_PROBLEM: u8 = 4;
+/// ICMPv6 type: Echo request
+pub const TYPE6_ECHO_REQUEST: u8 = 128;
+/// ICMPv6 type: Echo reply
+pub const TYPE6_ECHO_REPLY: u8 = 129;
+
+/// ICMPv6 destination unreachable code: Port unreachable
+pub const CODE6_PORT_UNREACHABLE: u8 = 4;
+/// ICMPv6 time exceeded code: Fragment reassembly time exceeded
+pub const CODE6_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+/// ICMPv6 parameter problem code: Unrecognized next header type
+pub const CODE6_UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+
 /// An enumeration of ICMP packet types.
 pub enum ICMPType {
 	/// Used by ping to reply to an echo request.
@@ -91,3 +159,565 @@ impl ICMPType {
 		}
 	}
 }
+
+/// An error reported to the source of a packet.
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+	/// The transport protocol of the packet is not supported.
+	ProtocolUnreachable,
+	/// No socket is bound to the destination port of the packet.
+	PortUnreachable,
+	/// The fragments of the packet have not been received in time.
+	ReassemblyTimeExceeded,
+	/// The packet is larger than the MTU given in the error and may not be fragmented.
+	FragmentationNeeded(u16),
+}
+
+/// Returns the protocol ID of ICMP for the family of `addr`.
+fn protocol_for(addr: &Address) -> u8 {
+	match addr {
+		Address::IPv4(_) => ip::PROTO_ICMP,
+		Address::IPv6(_) => ip::PROTO_ICMPV6,
+	}
+}
+
+/// Fills the checksum field of the ICMP message `msg`, sent from `src` to `dst`.
+///
+/// Contrary to ICMP, the checksum of ICMPv6 covers a pseudo-header from the network layer.
+fn fill_checksum(src: &Address, dst: &Address, msg: &mut [u8]) -> AllocResult<()> {
+	msg[2..4].fill(0);
+	let checksum = match src {
+		Address::IPv4(_) => checksum::compute_rfc1071(msg),
+		Address::IPv6(_) => ip::pseudo_header_checksum(ip::PROTO_ICMPV6, src, dst, msg)?,
+	};
+	msg[2..4].copy_from_slice(&checksum.to_ne_bytes());
+	Ok(())
+}
+
+/// Builds and transmits an ICMP message from `src` to `dst`.
+///
+/// Arguments:
+/// - `icmp_type` is the type of the message
+/// - `code` is the code of the message
+/// - `rest` is the content of the rest of the header, whose meaning depends on the type
+/// - `body` is the list of buffers making the body of the message
+fn send_msg(
+	src: Address,
+	dst: Address,
+	icmp_type: u8,
+	code: u8,
+	rest: u32,
+	body: &[&[u8]],
+) -> EResult<()> {
+	let mut msg = Vec::new();
+	msg.extend_from_slice(&[icmp_type, code, 0, 0])?;
+	msg.extend_from_slice(&rest.to_be_bytes())?;
+	for b in body {
+		msg.extend_from_slice(b)?;
+	}
+	fill_checksum(&src, &dst, &mut msg)?;
+	ip::transmit(
+		protocol_for(&src),
+		src,
+		dst,
+		&TxOptions::default(),
+		msg.as_slice().into(),
+	)
+}
+
+/// Tells whether an error may be reported to `addr`, the source of a packet.
+///
+/// Errors are not reported to multicast, broadcast and unspecified addresses.
+fn is_reportable(addr: &Address) -> bool {
+	let valid = match addr {
+		Address::IPv4(a) => *a != [255; 4] && a[0] < 224,
+		Address::IPv6(a) => a[0] != 0xff,
+	};
+	valid && !addr.is_unspecified()
+}
+
+/// Tells whether the ICMP message `msg` is an error message, about which no error may be
+/// reported.
+fn is_error_msg(addr: &Address, msg: &[u8]) -> bool {
+	match (addr, msg.first()) {
+		(Address::IPv4(_), Some(t)) => !matches!(*t, TYPE_ECHO_REQUEST | TYPE_ECHO_REPLY),
+		// ICMPv6 error messages have a type lower than 128 (RFC 4443)
+		(Address::IPv6(_), Some(t)) => *t < 128,
+		_ => true,
+	}
+}
+
+/// Reports the error `err` about the packet with the network layer header `header` and payload
+/// `payload` to the source of the packet.
+///
+/// No message is sent if the packet was not destined to one of the host's addresses, nor if the
+/// packet is itself an ICMP error message.
+pub fn send_error(err: Error, header: &[u8], payload: &[u8]) -> EResult<()> {
+	let (src, dst, protocol) = match header.first().map(|b| b >> 4) {
+		Some(4) => {
+			let Some(hdr) = from_bytes::<IPv4Header>(header) else {
+				return Ok(());
+			};
+			(
+				Address::IPv4(hdr.dst_addr),
+				Address::IPv4(hdr.src_addr),
+				hdr.protocol,
+			)
+		}
+		Some(6) => {
+			let Some(hdr) = from_bytes::<ip::IPv6Header>(header) else {
+				return Ok(());
+			};
+			// The protocol of the payload is after the extension headers
+			let protocol = header[ip::next_header_offset(header)];
+			(
+				Address::IPv6(hdr.dst_addr),
+				Address::IPv6(hdr.src_addr),
+				protocol,
+			)
+		}
+		_ => return Ok(()),
+	};
+	if !net::is_local_address(&src) || !is_reportable(&dst) {
+		return Ok(());
+	}
+	if protocol == protocol_for(&src) && is_error_msg(&src, payload) {
+		return Ok(());
+	}
+	match src {
+		Address::IPv4(_) => {
+			let (icmp_type, code, rest) = match err {
+				Error::ProtocolUnreachable => {
+					(TYPE_DEST_UNREACHABLE, CODE_PROTOCOL_UNREACHABLE, 0)
+				}
+				Error::PortUnreachable => (TYPE_DEST_UNREACHABLE, CODE_PORT_UNREACHABLE, 0),
+				Error::ReassemblyTimeExceeded => {
+					(TYPE_TIME_EXCEEDED, CODE_REASSEMBLY_TIME_EXCEEDED, 0)
+				}
+				// The MTU of the next hop is in the lower half of the rest of the header (RFC
+				// 1191)
+				Error::FragmentationNeeded(mtu) => {
+					(TYPE_DEST_UNREACHABLE, CODE_FRAGMENTATION_NEEDED, mtu as u32)
+				}
+			};
+			// The original header and the first 8 bytes of the payload
+			let payload = &payload[..min(8, payload.len())];
+			send_msg(src, dst, icmp_type, code, rest, &[header, payload])
+		}
+		Address::IPv6(_) => {
+			let (icmp_type, code, rest) = match err {
+				Error::ProtocolUnreachable => (
+					TYPE6_PARAM_PROBLEM,
+					CODE6_UNRECOGNIZED_NEXT_HEADER,
+					ip::next_header_offset(header) as u32,
+				),
+				Error::PortUnreachable => (TYPE6_DEST_UNREACHABLE, CODE6_PORT_UNREACHABLE, 0),
+				Error::ReassemblyTimeExceeded => {
+					(TYPE6_TIME_EXCEEDED, CODE6_REASSEMBLY_TIME_EXCEEDED, 0)
+				}
+				Error::FragmentationNeeded(mtu) => (TYPE6_PACKET_TOO_BIG, 0, mtu as u32),
+			};
+			// As much of the original packet as possible without exceeding the minimum MTU
+			let max = MIN_MTU_V6 - size_of::<ip::IPv6Header>() - HDR_SIZE;
+			let header = &header[..min(max, header.len())];
+			let payload = &payload[..min(max - header.len(), payload.len())];
+			send_msg(src, dst, icmp_type, code, rest, &[header, payload])
+		}
+	}
+}
+
+/// Answers the echo request in the packet `packet`.
+///
+/// Requests sent to broadcast or multicast addresses are ignored.
+fn reply_echo(packet: &Packet) -> EResult<()> {
+	if !net::is_local_address(&packet.dst) {
+		return Ok(());
+	}
+	let reply_type = match packet.src {
+		Address::IPv4(_) => TYPE_ECHO_REPLY,
+		Address::IPv6(_) => TYPE6_ECHO_REPLY,
+	};
+	let msg = packet.payload;
+	// The identifier and sequence number are sent back
+	let rest = u32::from_be_bytes(msg[4..8].try_into().unwrap());
+	send_msg(
+		packet.dst,
+		packet.src,
+		reply_type,
+		0,
+		rest,
+		&[&msg[HDR_SIZE..]],
+	)
+}
+
+/// Handles the ICMP message in the packet `packet`, received on the interface `iface`.
+///
+/// Messages with an invalid checksum are discarded.
+pub fn receive(iface: &IntMutex<dyn Interface>, packet: &Packet) -> EResult<()> {
+	let msg = packet.payload;
+	if msg.len() < HDR_SIZE {
+		return Ok(());
+	}
+	let valid = match packet.src {
+		Address::IPv4(_) => checksum::compute_rfc1071(msg) == 0,
+		Address::IPv6(_) => {
+			ip::pseudo_header_checksum(ip::PROTO_ICMPV6, &packet.src, &packet.dst, msg)? == 0
+		}
+	};
+	if !valid {
+		return Ok(());
+	}
+	deliver(packet)?;
+	match (packet.src, msg[0]) {
+		(Address::IPv4(_), TYPE_ECHO_REQUEST) | (Address::IPv6(_), TYPE6_ECHO_REQUEST) => {
+			reply_echo(packet)
+		}
+		(Address::IPv6(_), ndp::TYPE_ROUTER_SOLICITATION..=ndp::TYPE_NEIGHBOR_ADVERTISEMENT) => {
+			ndp::receive(iface, packet)
+		}
+		_ => Ok(()),
+	}
+}
+
+/// The ICMP state of a socket.
+///
+/// Raw sockets send and receive any ICMP message. Datagram sockets (also known as ping sockets)
+/// are available to unprivileged users: they can only send echo requests and receive the
+/// matching replies.
+#[derive(Debug, Default)]
+pub struct IcmpState {
+	/// The local address, if bound. For datagram sockets, the port is the identifier of echo
+	/// requests.
+	local: Option<SockAddr>,
+	/// The remote address, if connected.
+	remote: Option<SockAddr>,
+}
+
+impl IcmpState {
+	/// Returns the remote address, if connected.
+	pub fn remote(&self) -> Option<&SockAddr> {
+		self.remote.as_ref()
+	}
+}
+
+/// Bound sockets, along with their local endpoint.
+///
+/// Since raw sockets receive all the messages of their family, sockets are not indexed.
+static SOCKETS: IntMutex<Vec<(SockAddr, Arc<Socket>)>> = IntMutex::new(Vec::new());
+
+/// Runs `f` with the ICMP state of the socket.
+fn with_state<F: FnOnce(&mut IcmpState) -> R, R>(sock: &Socket, f: F) -> R {
+	let mut state = sock.state().lock();
+	let SocketState::Icmp(state) = &mut *state else {
+		unreachable!();
+	};
+	f(state)
+}
+
+/// Tells whether the socket is a raw socket.
+fn is_raw(sock: &Socket) -> bool {
+	sock.desc().type_ == SocketType::SockRaw
+}
+
+/// Returns the wildcard address of the socket's domain.
+fn any_addr(sock: &Socket) -> Address {
+	match sock.desc().domain {
+		SocketDomain::AfInet6 => Address::IPv6([0; 16]),
+		_ => Address::IPv4([0; 4]),
+	}
+}
+
+/// Tells whether the echo identifier `id` is used by a datagram socket bound to an address
+/// overlapping with `addr`.
+fn is_used(sockets: &[(SockAddr, Arc<Socket>)], addr: &SockAddr) -> bool {
+	sockets.iter().any(|(b, s)| {
+		!is_raw(s)
+			&& b.port == addr.port
+			&& b.addr.is_same_family(&addr.addr)
+			&& (b.addr == addr.addr || b.addr.is_unspecified() || addr.addr.is_unspecified())
+	})
+}
+
+/// Binds the socket to the local address `addr`, with state `state`.
+///
+/// For datagram sockets, if the port is zero, an unused echo identifier is allocated.
+fn do_bind(sock: &Arc<Socket>, state: &mut IcmpState, mut addr: SockAddr) -> EResult<()> {
+	{
+		let mut sockets = SOCKETS.lock();
+		sockets.retain(|(_, s)| !core::ptr::eq(Arc::as_ptr(s), Arc::as_ptr(sock)));
+		if is_raw(sock) {
+			addr.port = 0;
+		} else if addr.port == 0 {
+			addr.port = (1..=u16::MAX)
+				.find(|id| {
+					!is_used(
+						&sockets,
+						&SockAddr {
+							port: *id,
+							addr: addr.addr,
+						},
+					)
+				})
+				.ok_or_else(|| errno!(EADDRINUSE))?;
+		} else if is_used(&sockets, &addr) {
+			return Err(errno!(EADDRINUSE));
+		}
+		sockets.push((addr, sock.clone()))?;
+	}
+	state.local = Some(addr);
+	*sock.get_sockname().lock() = addr.to_bytes()?;
+	Ok(())
+}
+
+/// Registers the newly created socket `sock`.
+///
+/// Raw sockets receive messages without being bound, so they are bound to the wildcard address
+/// right away.
+pub fn open(sock: &Arc<Socket>) -> EResult<()> {
+	if !is_raw(sock) {
+		return Ok(());
+	}
+	with_state(sock, |state| {
+		do_bind(
+			sock,
+			state,
+			SockAddr {
+				port: 0,
+				addr: any_addr(sock),
+			},
+		)
+	})
+}
+
+/// Binds the socket to the address `sockaddr`.
+///
+/// For datagram sockets, the port of the address is the identifier of echo requests.
+pub fn bind(sock: &Arc<Socket>, sockaddr: &[u8], _ap: &AccessProfile) -> EResult<()> {
+	let addr = SockAddr::from_bytes(sockaddr)?;
+	addr.check_domain(sock.desc().domain)?;
+	if !addr.addr.is_unspecified() && !net::is_local_address(&addr.addr) {
+		return Err(errno!(EADDRNOTAVAIL));
+	}
+	with_state(sock, |state| {
+		let bound = state
+			.local
+			.is_some_and(|l| !is_raw(sock) || !l.addr.is_unspecified());
+		if bound {
+			return Err(errno!(EINVAL));
+		}
+		do_bind(sock, state, addr)
+	})
+}
+
+/// Sets the default destination of the socket to `sockaddr`. Messages from other sources are
+/// then discarded.
+///
+/// If the family of `sockaddr` is `AF_UNSPEC`, the socket is disconnected.
+pub fn connect(sock: &Arc<Socket>, sockaddr: &[u8]) -> EResult<()> {
+	let family = sockaddr
+		.get(..2)
+		.map(|f| u16::from_ne_bytes([f[0], f[1]]))
+		.ok_or_else(|| errno!(EINVAL))?;
+	if family == 0 {
+		with_state(sock, |state| state.remote = None);
+		return Ok(());
+	}
+	let remote = SockAddr::from_bytes(sockaddr)?;
+	remote.check_domain(sock.desc().domain)?;
+	with_state(sock, |state| {
+		if state.local.is_none() {
+			do_bind(
+				sock,
+				state,
+				SockAddr {
+					port: 0,
+					addr: any_addr(sock),
+				},
+			)?;
+		}
+		state.remote = Some(remote);
+		Ok(())
+	})
+}
+
+/// Sends the ICMP message `buf` on the socket.
+///
+/// If `sockaddr` is `None`, the message is sent to the connected peer. If the socket is not
+/// connected, the function returns [`errno::EDESTADDRREQ`].
+///
+/// The checksum of the message is computed by the kernel, except for IPv4 raw sockets. On
+/// datagram sockets, only echo requests can be sent and their identifier is replaced by the
+/// socket's.
+pub fn send(sock: &Arc<Socket>, buf: &[u8], sockaddr: Option<&[u8]>) -> EResult<usize> {
+	let dst = sockaddr.map(SockAddr::from_bytes).transpose()?;
+	if let Some(dst) = &dst {
+		dst.check_domain(sock.desc().domain)?;
+	}
+	let raw = is_raw(sock);
+	if !raw {
+		let echo_type = match sock.desc().domain {
+			SocketDomain::AfInet6 => TYPE6_ECHO_REQUEST,
+			_ => TYPE_ECHO_REQUEST,
+		};
+		if buf.len() < HDR_SIZE || buf[0] != echo_type || buf[1] != 0 {
+			return Err(errno!(EINVAL));
+		}
+	}
+	let (local, dst) = with_state(sock, |state| {
+		let dst = dst.or(state.remote).ok_or_else(|| errno!(EDESTADDRREQ))?;
+		if state.local.is_none() {
+			do_bind(
+				sock,
+				state,
+				SockAddr {
+					port: 0,
+					addr: any_addr(sock),
+				},
+			)?;
+		}
+		EResult::Ok((state.local.unwrap(), dst))
+	})?;
+	let src = if local.addr.is_unspecified() {
+		net::get_src_addr_for(&dst.addr).ok_or_else(|| errno!(ENETUNREACH))?
+	} else {
+		local.addr
+	};
+	let mut msg = Vec::try_from(buf)?;
+	if !raw {
+		msg[4..6].copy_from_slice(&local.port.to_be_bytes());
+	}
+	// The checksum of ICMPv6 messages is always computed by the kernel (RFC 3542)
+	let checksum = !raw || matches!(dst.addr, Address::IPv6(_));
+	if checksum && msg.len() >= 4 {
+		fill_checksum(&src, &dst.addr, &mut msg)?;
+	}
+	let opts = *sock.ip_opts().lock();
+	ip::transmit(
+		protocol_for(&src),
+		src,
+		dst.addr,
+		&opts,
+		msg.as_slice().into(),
+	)?;
+	Ok(buf.len())
+}
+
+/// Passes the ICMP message in the packet `packet` to the sockets it is destined to.
+///
+/// Raw sockets receive all messages. IPv4 raw sockets receive the network layer header as well.
+/// Datagram sockets receive the echo replies matching their identifier.
+fn deliver(packet: &Packet) -> EResult<()> {
+	let msg = packet.payload;
+	let echo_reply = matches!(
+		(packet.src, msg[0]),
+		(Address::IPv4(_), TYPE_ECHO_REPLY) | (Address::IPv6(_), TYPE6_ECHO_REPLY)
+	);
+	let id = u16::from_be_bytes([msg[4], msg[5]]);
+	let socks = SOCKETS
+		.lock()
+		.iter()
+		.filter(|(local, sock)| {
+			local.addr.is_same_family(&packet.dst)
+				&& (local.addr.is_unspecified() || local.addr == packet.dst)
+				&& (is_raw(sock) || (echo_reply && local.port == id))
+		})
+		.map(|(_, sock)| sock.clone())
+		.collect::<CollectResult<Vec<_>>>()
+		.0?;
+	if socks.is_empty() {
+		return Ok(());
+	}
+	let mut full = Vec::new();
+	if matches!(packet.src, Address::IPv4(_)) {
+		full.extend_from_slice(packet.header)?;
+		full.extend_from_slice(msg)?;
+	}
+	let addr = SockAddr {
+		port: 0,
+		addr: packet.src,
+	}
+	.to_bytes()?;
+	for sock in socks {
+		// A connected socket only receives messages from its peer
+		let accepted = with_state(&sock, |state| {
+			state.remote.is_none_or(|r| r.addr == packet.src)
+		});
+		if !accepted {
+			continue;
+		}
+		let data = if is_raw(&sock) && !full.is_empty() {
+			&full
+		} else {
+			msg
+		};
+		let pushed = sock
+			.rx_buff()
+			.lock()
+			.as_mut()
+			.is_some_and(|rx| push_msg(rx, &addr, data));
+		if pushed {
+			sock.rx_queue().wake_all();
+		}
+	}
+	Ok(())
+}
+
+/// Closes the socket, unbinding it.
+pub fn close(sock: &Socket) {
+	with_state(sock, |state| {
+		if state.local.take().is_some() {
+			SOCKETS
+				.lock()
+				.retain(|(_, s)| !core::ptr::eq(Arc::as_ptr(s), sock));
+		}
+	});
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn icmp_checksum() {
+		let src = Address::IPv6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+		let dst = Address::IPv6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+		let mut msg = [
+			TYPE6_ECHO_REQUEST,
+			0,
+			0xff,
+			0xff,
+			0,
+			1,
+			0,
+			1,
+			b'p',
+			b'i',
+			b'n',
+			b'g',
+		];
+		fill_checksum(&src, &dst, &mut msg).unwrap();
+		assert_eq!(
+			ip::pseudo_header_checksum(ip::PROTO_ICMPV6, &src, &dst, &msg).unwrap(),
+			0
+		);
+		// ICMP has no pseudo-header
+		let src = Address::IPv4([127, 0, 0, 1]);
+		msg[0] = TYPE_ECHO_REQUEST;
+		fill_checksum(&src, &src, &mut msg).unwrap();
+		assert_eq!(checksum::compute_rfc1071(&msg), 0);
+	}
+
+	#[test_case]
+	fn icmp_error_filter() {
+		let v4 = Address::IPv4([10, 0, 2, 15]);
+		let v6 = Address::IPv6([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+		assert!(is_reportable(&v4));
+		assert!(!is_reportable(&Address::IPv4([255; 4])));
+		assert!(!is_reportable(&Address::IPv4([0; 4])));
+		assert!(!is_reportable(&v6));
+		assert!(!is_error_msg(&v4, &[TYPE_ECHO_REQUEST]));
+		assert!(is_error_msg(&v4, &[TYPE_DEST_UNREACHABLE]));
+		assert!(!is_error_msg(&v6, &[TYPE6_ECHO_REPLY]));
+		assert!(is_error_msg(&v6, &[TYPE6_TIME_EXCEEDED]));
+	}
+}
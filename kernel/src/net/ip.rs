@@ -18,11 +18,21 @@
 
 //! This module implements the IP protocol.
 
-use super::{buff::BuffList, osi::Layer};
-use crate::crypto::checksum;
-use core::mem::size_of;
+use super::{buff::BuffList, icmp, lo, neighbor, osi, osi::Layer, Address, Interface, LinkType};
+use crate::{crypto::checksum, net, sync::mutex::IntMutex, time::timer};
+use core::{
+	cmp::{max, min},
+	mem,
+	mem::size_of,
+	sync::atomic::{AtomicU16, Ordering},
+};
 use macros::AnyRepr;
-use utils::{boxed::Box, bytes::as_bytes, errno::EResult};
+use utils::{
+	bytes::{as_bytes, from_bytes},
+	collections::vec::Vec,
+	errno,
+	errno::{AllocResult, EResult},
+};
 
 /// The default TTL value.
 const DEFAULT_TTL: u8 = 128;
@@ -30,40 +40,72 @@ const DEFAULT_TTL: u8 = 128;
 /// IPv4 flag: Do not fragment the packet
 const FLAG_DF: u8 = 0b010;
 /// IPv4 flag: More fragments are to come after this one
-const FLAG_MF: u8 = 0b100;
+const FLAG_MF: u8 = 0b001;
 
+/// The maximum length of the options of an IPv4 header.
+const MAX_OPTIONS_LEN: usize = 40;
+/// IPv4 option: End of options list
+const OPT_END: u8 = 0;
+/// IPv4 option: No operation
+const OPT_NOP: u8 = 1;
+/// IPv4 option flag: The option is copied into all fragments
+const OPT_COPIED: u8 = 0x80;
+
+/// The minimum MTU of an IPv4 link (RFC 791).
+const MIN_MTU: usize = 68;
+
+/// The maximum number of packets being reassembled at the same time.
+const MAX_REASSEMBLIES: usize = 64;
+/// The time after which a packet being reassembled is dropped, in seconds.
+const REASSEMBLY_TIMEOUT: u32 = 30;
+
+/// Protocol: ICMP
+pub const PROTO_ICMP: u8 = 0x01;
 /// Protocol: TCP
 pub const PROTO_TCP: u8 = 0x06;
 /// Protocol: UDP
 pub const PROTO_UDP: u8 = 0x11;
+/// Protocol: ICMPv6
+pub const PROTO_ICMPV6: u8 = 0x3a;
+
+/// IPv6 extension header: Hop-by-Hop options
+const EXT_HOP_BY_HOP: u8 = 0;
+/// IPv6 extension header: Routing
+const EXT_ROUTING: u8 = 43;
+/// IPv6 extension header: Fragment
+const EXT_FRAGMENT: u8 = 44;
+/// IPv6 extension header: Authentication
+const EXT_AUTH: u8 = 51;
+/// IPv6 extension header: Destination options
+const EXT_DEST_OPTS: u8 = 60;
 
 /// The IPv4 header (RFC 791).
 #[derive(AnyRepr)]
 #[repr(C, packed)]
-struct IPv4Header {
+pub struct IPv4Header {
 	/// The version of the header with the IHL (header length).
-	version_ihl: u8,
+	pub version_ihl: u8,
 	/// The type of service.
-	type_of_service: u8,
+	pub type_of_service: u8,
 	/// The total length of the datagram.
-	total_length: u16,
+	pub total_length: u16,
 
-	/// TODO doc
-	identification: u16,
-	/// TODO doc
-	flags_fragment_offset: u16,
+	/// Identifies the fragments of the same packet.
+	pub identification: u16,
+	/// The flags, followed by the offset of the fragment in the packet in units of 8 bytes.
+	pub flags_fragment_offset: u16,
 
 	/// Time-To-Live.
-	ttl: u8,
+	pub ttl: u8,
 	/// Protocol number.
-	protocol: u8,
+	pub protocol: u8,
 	/// The checksum of the header (RFC 1071).
-	hdr_checksum: u16,
+	pub hdr_checksum: u16,
 
 	/// Source address.
-	src_addr: [u8; 4],
+	pub src_addr: [u8; 4],
 	/// Destination address.
-	dst_addr: [u8; 4],
+	pub dst_addr: [u8; 4],
 }
 
 impl IPv4Header {
@@ -74,32 +116,554 @@ impl IPv4Header {
 		let slice = as_bytes(self);
 		checksum::compute_rfc1071(slice) == 0
 	}
-
-	/// Computes the checksum of the header and writes it into the appropriate field.
-	pub fn compute_checksum(&mut self) {
-		self.hdr_checksum = 0;
-		let slice = as_bytes(self);
-		self.hdr_checksum = checksum::compute_rfc1071(slice);
-	}
 }
 
 /// The IPv6 header (RFC 8200).
+#[derive(AnyRepr)]
 #[repr(C, packed)]
-struct IPv6Header {
+pub struct IPv6Header {
 	/// The version, traffic class and flow label.
-	version_traffic_class_flow_label: u32,
+	pub version_traffic_class_flow_label: u32,
 
 	/// The length of the payload.
-	payload_length: u16,
+	pub payload_length: u16,
 	/// The type of the next header.
-	next_header: u8,
+	pub next_header: u8,
 	/// The number of hops remaining before discarding the packet.
-	hop_limit: u8,
+	pub hop_limit: u8,
+
+	/// Source address.
+	pub src_addr: [u8; 16],
+	/// Destination address.
+	pub dst_addr: [u8; 16],
+}
+
+/// The options of an IPv4 header (RFC 791), without padding.
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderOptions {
+	/// The buffer storing the options.
+	buf: [u8; MAX_OPTIONS_LEN],
+	/// The length of the options in the buffer.
+	len: u8,
+}
+
+impl HeaderOptions {
+	/// Creates an instance from the raw options `options`.
+	///
+	/// If the options are too long or malformed, the function returns [`errno::EINVAL`].
+	pub fn new(options: &[u8]) -> EResult<Self> {
+		if options.len() > MAX_OPTIONS_LEN {
+			return Err(errno!(EINVAL));
+		}
+		let mut opts = options;
+		while let Some(kind) = opts.first() {
+			let len = match *kind {
+				OPT_END => break,
+				OPT_NOP => 1,
+				_ => opts.get(1).map(|l| *l as usize).unwrap_or(0),
+			};
+			if !(1..=opts.len()).contains(&len) || (*kind != OPT_NOP && len < 2) {
+				return Err(errno!(EINVAL));
+			}
+			opts = &opts[len..];
+		}
+		let mut buf = [0; MAX_OPTIONS_LEN];
+		buf[..options.len()].copy_from_slice(options);
+		Ok(Self {
+			buf,
+			len: options.len() as _,
+		})
+	}
+
+	/// Returns the options as a slice.
+	pub fn as_slice(&self) -> &[u8] {
+		&self.buf[..self.len as usize]
+	}
+}
+
+impl Default for HeaderOptions {
+	fn default() -> Self {
+		Self {
+			buf: [0; MAX_OPTIONS_LEN],
+			len: 0,
+		}
+	}
+}
+
+/// Parameters for the transmission of packets, set through socket options.
+#[derive(Clone, Copy, Debug)]
+pub struct TxOptions {
+	/// Time-To-Live.
+	pub ttl: u8,
+	/// Type of service (DSCP and ECN).
+	pub tos: u8,
+	/// If `true`, packets larger than the MTU are not fragmented.
+	pub dont_fragment: bool,
+	/// The options of the header of IPv4 packets.
+	pub options: HeaderOptions,
+}
+
+impl Default for TxOptions {
+	fn default() -> Self {
+		Self {
+			ttl: DEFAULT_TTL,
+			tos: 0,
+			dont_fragment: false,
+			options: HeaderOptions::default(),
+		}
+	}
+}
+
+/// A packet received by the network layer.
+#[derive(Debug)]
+pub struct Packet<'p> {
+	/// Source address.
+	pub src: Address,
+	/// Destination address.
+	pub dst: Address,
+	/// The ID of the transport protocol.
+	pub protocol: u8,
+	/// The Time-To-Live (IPv4) or hop limit (IPv6) of the packet.
+	pub ttl: u8,
+	/// The packet's header, including options (IPv4) or extension headers (IPv6).
+	pub header: &'p [u8],
+	/// The packet's payload.
+	pub payload: &'p [u8],
+}
+
+/// Fragmentation information of a received IPv4 packet.
+#[derive(Debug)]
+struct Fragment {
+	/// Identifies the fragments of the same packet.
+	id: u16,
+	/// The offset of the fragment's data in the packet, in bytes.
+	offset: usize,
+	/// Tells whether more fragments follow.
+	more: bool,
+	/// Tells whether the packet may not be fragmented.
+	dont_fragment: bool,
+}
+
+impl Fragment {
+	/// Tells whether the packet is a fragment of a larger packet.
+	fn is_fragment(&self) -> bool {
+		self.more || self.offset != 0
+	}
+}
+
+/// Parses the IPv4 packet `packet`.
+///
+/// If the packet is invalid, the function returns `None`.
+fn parse_v4(packet: &[u8]) -> Option<(Packet<'_>, Fragment)> {
+	let hdr = from_bytes::<IPv4Header>(packet)?;
+	if hdr.version_ihl >> 4 != 4 {
+		return None;
+	}
+	let hdr_len = (hdr.version_ihl & 0xf) as usize * 4;
+	let total_len = u16::from_be(hdr.total_length) as usize;
+	if hdr_len < size_of::<IPv4Header>() || total_len < hdr_len || total_len > packet.len() {
+		return None;
+	}
+	// The checksum covers the options
+	let valid = if hdr_len == size_of::<IPv4Header>() {
+		hdr.check_checksum()
+	} else {
+		checksum::compute_rfc1071(&packet[..hdr_len]) == 0
+	};
+	if !valid {
+		return None;
+	}
+	let flags_fragment_offset = u16::from_be(hdr.flags_fragment_offset);
+	let pkt = Packet {
+		src: Address::IPv4(hdr.src_addr),
+		dst: Address::IPv4(hdr.dst_addr),
+		protocol: hdr.protocol,
+		ttl: hdr.ttl,
+		header: &packet[..hdr_len],
+		payload: &packet[hdr_len..total_len],
+	};
+	let frag = Fragment {
+		id: u16::from_be(hdr.identification),
+		offset: (flags_fragment_offset & 0x1fff) as usize * 8,
+		more: (flags_fragment_offset >> 13) as u8 & FLAG_MF != 0,
+		dont_fragment: (flags_fragment_offset >> 13) as u8 & FLAG_DF != 0,
+	};
+	Some((pkt, frag))
+}
+
+/// Returns the length of the IPv6 extension header `ext`, of type `kind`.
+///
+/// If `kind` is not an extension header, or if the extension header is invalid, the function
+/// returns `None`.
+fn ext_header_len(kind: u8, ext: &[u8]) -> Option<usize> {
+	let len = match kind {
+		EXT_HOP_BY_HOP | EXT_ROUTING | EXT_DEST_OPTS => (*ext.get(1)? as usize + 1) * 8,
+		EXT_FRAGMENT => {
+			let offset_more = u16::from_be_bytes(ext.get(2..4)?.try_into().unwrap());
+			// Only accept atomic fragments (RFC 6946)
+			if offset_more & !0b110 != 0 {
+				return None;
+			}
+			8
+		}
+		EXT_AUTH => (*ext.get(1)? as usize + 2) * 4,
+		_ => return None,
+	};
+	(len <= ext.len()).then_some(len)
+}
+
+/// Tells whether `kind` is the type of an IPv6 extension header.
+fn is_ext_header(kind: u8) -> bool {
+	matches!(
+		kind,
+		EXT_HOP_BY_HOP | EXT_ROUTING | EXT_FRAGMENT | EXT_AUTH | EXT_DEST_OPTS
+	)
+}
+
+/// Parses the IPv6 packet `packet`, skipping its extension headers.
+///
+/// If the packet is invalid, the function returns `None`. Fragmented packets are not supported
+/// and are discarded as well.
+fn parse_v6(packet: &[u8]) -> Option<Packet<'_>> {
+	let hdr = from_bytes::<IPv6Header>(packet)?;
+	if u32::from_be(hdr.version_traffic_class_flow_label) >> 28 != 6 {
+		return None;
+	}
+	let payload_len = u16::from_be(hdr.payload_length) as usize;
+	let end = size_of::<IPv6Header>() + payload_len;
+	let packet = packet.get(..end)?;
+	let mut protocol = hdr.next_header;
+	let mut off = size_of::<IPv6Header>();
+	while is_ext_header(protocol) {
+		let ext = &packet[off..];
+		let len = ext_header_len(protocol, ext)?;
+		protocol = ext[0];
+		off += len;
+	}
+	Some(Packet {
+		src: Address::IPv6(hdr.src_addr),
+		dst: Address::IPv6(hdr.dst_addr),
+		protocol,
+		ttl: hdr.hop_limit,
+		header: &packet[..off],
+		payload: &packet[off..],
+	})
+}
+
+/// Returns the offset of the Next Header field identifying the payload in `header`, the headers
+/// of an IPv6 packet.
+pub fn next_header_offset(header: &[u8]) -> usize {
+	let mut nh = 6;
+	let mut off = size_of::<IPv6Header>();
+	while off < header.len() {
+		let Some(len) = ext_header_len(header[nh], &header[off..]) else {
+			break;
+		};
+		nh = off;
+		off += len;
+	}
+	nh
+}
 
+/// An IPv4 packet being reassembled from its fragments.
+#[derive(Debug)]
+struct Reassembly {
 	/// Source address.
-	src_addr: [u8; 16],
+	src: [u8; 4],
 	/// Destination address.
-	dst_addr: [u8; 16],
+	dst: [u8; 4],
+	/// The ID of the transport protocol.
+	protocol: u8,
+	/// Identifies the fragments of the packet.
+	id: u16,
+
+	/// The header of the first fragment. Empty if the first fragment has not been received.
+	header: Vec<u8>,
+	/// The packet's payload.
+	data: Vec<u8>,
+	/// The sorted, disjoint ranges of the payload that have been received.
+	ranges: Vec<(usize, usize)>,
+	/// The length of the payload, known once the last fragment has been received.
+	total_len: Option<usize>,
+	/// The number of seconds remaining before the packet is dropped.
+	remaining: u32,
+}
+
+impl Reassembly {
+	/// Creates a new instance for the packet with the given source, destination, protocol and
+	/// ID.
+	fn new(src: [u8; 4], dst: [u8; 4], protocol: u8, id: u16) -> Self {
+		Self {
+			src,
+			dst,
+			protocol,
+			id,
+
+			header: Vec::new(),
+			data: Vec::new(),
+			ranges: Vec::new(),
+			total_len: None,
+			remaining: REASSEMBLY_TIMEOUT,
+		}
+	}
+
+	/// Inserts the data `data` of a fragment at offset `off`. `last` tells whether this is the
+	/// last fragment.
+	///
+	/// If the fragment is inconsistent with the previous ones, the function returns `false`.
+	fn insert(&mut self, off: usize, data: &[u8], last: bool) -> AllocResult<bool> {
+		let mut start = off;
+		let mut end = off + data.len();
+		if end > u16::MAX as usize {
+			return Ok(false);
+		}
+		if last {
+			if self.total_len.is_some_and(|len| len != end) {
+				return Ok(false);
+			}
+			self.total_len = Some(end);
+		}
+		if self.total_len.is_some_and(|len| end > len) {
+			return Ok(false);
+		}
+		if self.data.len() < end {
+			self.data.resize(end, 0)?;
+		}
+		self.data[start..end].copy_from_slice(data);
+		// Merge with overlapping and adjacent ranges
+		self.ranges.retain(|(s, e)| {
+			let merge = *s <= end && start <= *e;
+			if merge {
+				start = min(start, *s);
+				end = max(end, *e);
+			}
+			!merge
+		});
+		let i = self
+			.ranges
+			.iter()
+			.position(|(s, _)| *s > start)
+			.unwrap_or(self.ranges.len());
+		self.ranges.insert(i, (start, end))?;
+		Ok(true)
+	}
+
+	/// Tells whether all the fragments have been received.
+	fn is_complete(&self) -> bool {
+		matches!(self.total_len, Some(len) if self.ranges.first() == Some(&(0, len)))
+	}
+}
+
+/// IPv4 packets being reassembled.
+static REASSEMBLIES: IntMutex<Vec<Reassembly>> = IntMutex::new(Vec::new());
+
+/// Inserts the fragment `frag` of the packet `packet` in the corresponding reassembly.
+///
+/// If the packet is complete, the function returns the header of its first fragment along with
+/// its payload.
+fn reassemble(packet: &Packet, frag: &Fragment) -> AllocResult<Option<(Vec<u8>, Vec<u8>)>> {
+	let (Address::IPv4(src), Address::IPv4(dst)) = (packet.src, packet.dst) else {
+		return Ok(None);
+	};
+	let mut reassemblies = REASSEMBLIES.lock();
+	let i = reassemblies.iter().position(|r| {
+		r.src == src && r.dst == dst && r.protocol == packet.protocol && r.id == frag.id
+	});
+	let i = match i {
+		Some(i) => i,
+		None if reassemblies.len() < MAX_REASSEMBLIES => {
+			reassemblies.push(Reassembly::new(src, dst, packet.protocol, frag.id))?;
+			reassemblies.len() - 1
+		}
+		None => return Ok(None),
+	};
+	let r = &mut reassemblies[i];
+	if frag.offset == 0 && r.header.is_empty() {
+		r.header = Vec::try_from(packet.header)?;
+	}
+	if !r.insert(frag.offset, packet.payload, !frag.more)? {
+		reassemblies.remove(i);
+		return Ok(None);
+	}
+	if !r.is_complete() {
+		return Ok(None);
+	}
+	let r = reassemblies.remove(i);
+	Ok(Some((r.header, r.data)))
+}
+
+/// Drops the packets whose reassembly timed out, reporting it to their sources.
+fn reassembly_tick() {
+	let mut expired = Vec::new();
+	REASSEMBLIES.lock().retain(|r| {
+		r.remaining -= 1;
+		if r.remaining > 0 {
+			return true;
+		}
+		// The error is reported only if the first fragment has been received (RFC 792)
+		if !r.header.is_empty() {
+			let _ = expired.push((mem::take(&mut r.header), mem::take(&mut r.data)));
+		}
+		false
+	});
+	for (header, data) in expired {
+		let data = &data[..min(8, data.len())];
+		let _ = icmp::send_error(icmp::Error::ReassemblyTimeExceeded, &header, data);
+	}
+}
+
+/// Initializes the IP protocol.
+pub(crate) fn init() -> EResult<()> {
+	timer::register_kernel_timer(1000, reassembly_tick)?;
+	neighbor::init()
+}
+
+/// Tells whether a packet sent to `addr` is to be received by the host.
+fn is_for_host(addr: &Address) -> bool {
+	match addr {
+		// Limited broadcast and multicast
+		Address::IPv4(a) if *a == [255; 4] || (224..240).contains(&a[0]) => true,
+		// Multicast
+		Address::IPv6(a) if a[0] == 0xff => true,
+		addr => net::is_local_address(addr),
+	}
+}
+
+/// Passes the payload of a packet received on the interface `iface` to the transport layer.
+///
+/// If the packet cannot be delivered, the error is reported to its source.
+fn deliver(iface: &IntMutex<dyn Interface>, packet: &Packet) -> EResult<()> {
+	let res = match packet.protocol {
+		// ICMP is handled by the network layer itself
+		PROTO_ICMP | PROTO_ICMPV6 => icmp::receive(iface, packet),
+		protocol => match osi::get_receive_handler(protocol) {
+			Some(handler) => handler(packet.src, packet.dst, packet.payload),
+			None => Err(errno!(EPROTONOSUPPORT)),
+		},
+	};
+	let err = match res {
+		Err(e) if e.as_int() == errno::EPROTONOSUPPORT => icmp::Error::ProtocolUnreachable,
+		Err(e) if e.as_int() == errno::ECONNREFUSED => icmp::Error::PortUnreachable,
+		res => return res,
+	};
+	icmp::send_error(err, packet.header, packet.payload)
+}
+
+/// If `packet`, received on the interface `iface`, is larger than the MTU of the interface,
+/// returns the MTU.
+fn exceeded_mtu(iface: &IntMutex<dyn Interface>, packet: &Packet) -> Option<u16> {
+	let mtu = iface.lock().get_mtu();
+	let len = packet.header.len() + packet.payload.len();
+	(len > mtu).then_some(min(mtu, u16::MAX as usize) as u16)
+}
+
+/// Handles the packet `packet` received by the network layer on the interface `iface`, passing
+/// its payload to the transport layer.
+///
+/// Invalid packets, packets that are not destined to the host and packets with an unsupported
+/// protocol are discarded.
+///
+/// Packets larger than the MTU of the interface which may not be fragmented (IPv4 packets with
+/// the DF flag, and all IPv6 packets) are discarded as well, and the error is reported to their
+/// source.
+pub fn receive(iface: &IntMutex<dyn Interface>, packet: &[u8]) -> EResult<()> {
+	match packet.first().map(|b| b >> 4) {
+		Some(4) => {
+			let Some((packet, frag)) = parse_v4(packet) else {
+				return Ok(());
+			};
+			if !is_for_host(&packet.dst) {
+				return Ok(());
+			}
+			if let Some(mtu) = exceeded_mtu(iface, &packet).filter(|_| frag.dont_fragment) {
+				let err = icmp::Error::FragmentationNeeded(mtu);
+				return icmp::send_error(err, packet.header, packet.payload);
+			}
+			if !frag.is_fragment() {
+				return deliver(iface, &packet);
+			}
+			let Some((header, payload)) = reassemble(&packet, &frag)? else {
+				return Ok(());
+			};
+			deliver(
+				iface,
+				&Packet {
+					header: &header,
+					payload: &payload,
+					..packet
+				},
+			)
+		}
+		Some(6) => {
+			let Some(packet) = parse_v6(packet) else {
+				return Ok(());
+			};
+			if !is_for_host(&packet.dst) {
+				return Ok(());
+			}
+			if let Some(mtu) = exceeded_mtu(iface, &packet) {
+				let err = icmp::Error::FragmentationNeeded(mtu);
+				return icmp::send_error(err, packet.header, packet.payload);
+			}
+			deliver(iface, &packet)
+		}
+		_ => Ok(()),
+	}
+}
+
+/// Computes the checksum of the transport layer packet `packet` of the given `protocol`,
+/// including the pseudo-header built from the given addresses (RFC 793 and RFC 8200).
+///
+/// If the checksum field of the packet is already filled, the result is zero if it is valid.
+pub fn pseudo_header_checksum(
+	protocol: u8,
+	src: &Address,
+	dst: &Address,
+	packet: &[u8],
+) -> AllocResult<u16> {
+	let mut buf = Vec::with_capacity(40 + packet.len())?;
+	match (src, dst) {
+		(Address::IPv4(src), Address::IPv4(dst)) => {
+			buf.extend_from_slice(src)?;
+			buf.extend_from_slice(dst)?;
+			buf.extend_from_slice(&[0, protocol])?;
+			buf.extend_from_slice(&(packet.len() as u16).to_be_bytes())?;
+		}
+		(Address::IPv6(src), Address::IPv6(dst)) => {
+			buf.extend_from_slice(src)?;
+			buf.extend_from_slice(dst)?;
+			buf.extend_from_slice(&(packet.len() as u32).to_be_bytes())?;
+			buf.extend_from_slice(&[0, 0, 0, protocol])?;
+		}
+		_ => return Ok(0),
+	}
+	buf.extend_from_slice(packet)?;
+	Ok(checksum::compute_rfc1071(&buf))
+}
+
+/// Returns the options of `options` which must be copied into all the fragments of a packet.
+fn copied_options(options: &[u8]) -> AllocResult<Vec<u8>> {
+	let mut copied = Vec::new();
+	let mut opts = options;
+	while let Some(kind) = opts.first() {
+		let len = match *kind {
+			OPT_END => break,
+			OPT_NOP => 1,
+			_ => opts.get(1).map(|l| *l as usize).unwrap_or(opts.len()),
+		};
+		let len = len.clamp(1, opts.len());
+		if kind & OPT_COPIED != 0 {
+			copied.extend_from_slice(&opts[..len])?;
+		}
+		opts = &opts[len..];
+	}
+	Ok(copied)
+}
+
+/// Returns an identification for a new packet.
+fn next_id() -> u16 {
+	static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+	NEXT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
 /// The network layer for the IPv4 protocol.
@@ -108,54 +672,412 @@ pub struct IPv4Layer {
 	/// The protocol ID.
 	pub protocol: u8,
 
+	/// The source IPv4.
+	pub src_addr: [u8; 4],
 	/// The destination IPv4.
 	pub dst_addr: [u8; 4],
+
+	/// Transmission parameters.
+	pub opts: TxOptions,
+	/// The options of the header (RFC 791). The layer pads them to a multiple of 4 bytes.
+	pub options: Vec<u8>,
+	/// The Maximum Transmission Unit of the interface the packets are sent on.
+	pub mtu: usize,
+}
+
+impl IPv4Layer {
+	/// Builds the header of a packet.
+	///
+	/// Arguments:
+	/// - `options` is the options of the header
+	/// - `len` is the length of the payload
+	/// - `id` is the identification of the packet
+	/// - `offset` is the offset of the payload in the original packet, in bytes
+	/// - `more` tells whether more fragments follow
+	fn build_header(
+		&self,
+		options: &[u8],
+		len: usize,
+		id: u16,
+		offset: usize,
+		more: bool,
+	) -> AllocResult<Vec<u8>> {
+		let hdr_len = size_of::<IPv4Header>() + options.len().next_multiple_of(4);
+		let mut flags = 0;
+		if self.opts.dont_fragment {
+			flags |= FLAG_DF;
+		}
+		if more {
+			flags |= FLAG_MF;
+		}
+		let hdr = IPv4Header {
+			version_ihl: (4 << 4) | (hdr_len / 4) as u8,
+			type_of_service: self.opts.tos,
+			total_length: ((hdr_len + len) as u16).to_be(),
+
+			identification: id.to_be(),
+			flags_fragment_offset: (((flags as u16) << 13) | (offset / 8) as u16).to_be(),
+
+			ttl: self.opts.ttl,
+			protocol: self.protocol,
+			hdr_checksum: 0,
+
+			src_addr: self.src_addr,
+			dst_addr: self.dst_addr,
+		};
+		let mut buf = Vec::with_capacity(hdr_len)?;
+		buf.extend_from_slice(as_bytes(&hdr))?;
+		buf.extend_from_slice(options)?;
+		// Padding with the end of options list
+		buf.resize(hdr_len, OPT_END)?;
+		let checksum = checksum::compute_rfc1071(&buf);
+		buf[10..12].copy_from_slice(&checksum.to_ne_bytes());
+		Ok(buf)
+	}
 }
 
 impl Layer for IPv4Layer {
-	fn transmit<'c, F>(&self, mut buff: BuffList<'c>, next: F) -> EResult<()>
-	where
-		F: Fn(BuffList<'c>) -> EResult<()>,
-	{
-		let hdr_len = size_of::<IPv4Header>() as u16; // TODO add options support?
+	fn transmit(
+		&self,
+		mut buff: BuffList<'_>,
+		next: &dyn Fn(BuffList<'_>) -> EResult<()>,
+	) -> EResult<()> {
+		if self.options.len() > MAX_OPTIONS_LEN {
+			return Err(errno!(EINVAL));
+		}
+		let id = next_id();
+		let hdr_len = size_of::<IPv4Header>() + self.options.len().next_multiple_of(4);
+		let len = buff.len();
+		if hdr_len + len <= self.mtu {
+			if hdr_len + len > u16::MAX as usize {
+				return Err(errno!(EMSGSIZE));
+			}
+			let hdr = self.build_header(&self.options, len, id, 0, false)?;
+			return next(buff.push_front(hdr.as_slice().into()));
+		}
+		if self.opts.dont_fragment || self.mtu < MIN_MTU || hdr_len + len > u16::MAX as usize {
+			return Err(errno!(EMSGSIZE));
+		}
+		// Fragmentation
+		let payload = buff.to_vec()?;
+		let copied = copied_options(&self.options)?;
+		let mut off = 0;
+		while off < payload.len() {
+			let options = if off == 0 { &self.options } else { &copied };
+			let hdr_len = size_of::<IPv4Header>() + options.len().next_multiple_of(4);
+			// The offset of fragments is in units of 8 bytes
+			let max_len = (self.mtu - hdr_len) & !7;
+			let len = min(max_len, payload.len() - off);
+			let more = off + len < payload.len();
+			let hdr = self.build_header(options, len, id, off, more)?;
+			let mut data = BuffList::from(&payload[off..(off + len)]);
+			next(data.push_front(hdr.as_slice().into()))?;
+			off += len;
+		}
+		Ok(())
+	}
+}
+
+/// The network layer for the IPv6 protocol.
+///
+/// Packets larger than the MTU are not fragmented: the upper layers are expected to fit in the
+/// MTU.
+#[derive(Debug)]
+pub struct IPv6Layer {
+	/// The protocol ID.
+	pub protocol: u8,
 
-		let dscp = 0; // TODO
-		let ecn = 0; // TODO
+	/// The source IPv6.
+	pub src_addr: [u8; 16],
+	/// The destination IPv6.
+	pub dst_addr: [u8; 16],
 
-		// TODO check endianness
-		let mut hdr = IPv4Header {
-			version_ihl: 4 | (((hdr_len / 4) as u8) << 4),
-			type_of_service: (dscp << 2) | ecn,
-			total_length: hdr_len + buff.len() as u16,
+	/// Transmission parameters. The TTL is used as hop limit and the type of service as traffic
+	/// class.
+	pub opts: TxOptions,
+	/// The Maximum Transmission Unit of the interface the packets are sent on.
+	pub mtu: usize,
+}
 
-			identification: 0,        // TODO
-			flags_fragment_offset: 0, // TODO
+impl Layer for IPv6Layer {
+	fn transmit(
+		&self,
+		mut buff: BuffList<'_>,
+		next: &dyn Fn(BuffList<'_>) -> EResult<()>,
+	) -> EResult<()> {
+		let len = buff.len();
+		if size_of::<IPv6Header>() + len > self.mtu || len > u16::MAX as usize {
+			return Err(errno!(EMSGSIZE));
+		}
+		let hdr = IPv6Header {
+			version_traffic_class_flow_label: ((6 << 28) | ((self.opts.tos as u32) << 20)).to_be(),
 
-			// TODO allow setting a different value
-			ttl: DEFAULT_TTL,
-			protocol: self.protocol,
-			hdr_checksum: 0,
+			payload_length: (len as u16).to_be(),
+			next_header: self.protocol,
+			hop_limit: self.opts.ttl,
 
-			src_addr: [0; 4], // IPADDR_ANY
+			src_addr: self.src_addr,
 			dst_addr: self.dst_addr,
 		};
-		hdr.compute_checksum();
-		let hdr_buff = as_bytes(&hdr);
-		buff.push_front(hdr_buff.into());
-		next(buff)
+		next(buff.push_front(as_bytes(&hdr).into()))
 	}
 }
 
-/// Builds an IPv4 layer with the given `sockaddr`.
-pub fn inet_build(_sockaddr: &[u8]) -> EResult<Box<dyn Layer>> {
-	// TODO
-	todo!()
+/// Writes the network layer packet `buff` on the interface `iface`, to the neighbor `next_hop`.
+fn output(iface: &IntMutex<dyn Interface>, next_hop: Address, buff: BuffList<'_>) -> EResult<()> {
+	let (link, up) = {
+		let iface = iface.lock();
+		(iface.get_link_type(), iface.is_up())
+	};
+	if !up {
+		return Err(errno!(ENETDOWN));
+	}
+	match link {
+		LinkType::Loopback => {
+			iface.lock().write(&buff)?;
+			// Packets sent on the loopback are received right away
+			lo::process(iface)
+		}
+		LinkType::Ethernet => neighbor::output(iface, &next_hop, buff),
+	}
 }
 
-// TODO IPv6
+/// Transmits the transport layer packet `buff` on the interface `iface`.
+///
+/// Arguments:
+/// - `next_hop` is the address of the neighbor the packet is sent to
+/// - `protocol` is the ID of the transport protocol
+/// - `src` is the source address
+/// - `dst` is the destination address
+/// - `opts` is the transmission parameters
+///
+/// If the source and destination addresses are not of the same family, the function returns
+/// [`errno::EAFNOSUPPORT`].
+pub fn transmit_on(
+	iface: &IntMutex<dyn Interface>,
+	next_hop: Address,
+	protocol: u8,
+	src: Address,
+	dst: Address,
+	opts: &TxOptions,
+	buff: BuffList<'_>,
+) -> EResult<()> {
+	let mtu = iface.lock().get_mtu();
+	let next = |buff: BuffList<'_>| output(iface, next_hop, buff);
+	match (src, dst) {
+		(Address::IPv4(src_addr), Address::IPv4(dst_addr)) => IPv4Layer {
+			protocol,
+
+			src_addr,
+			dst_addr,
+
+			opts: *opts,
+			options: Vec::try_from(opts.options.as_slice())?,
+			mtu,
+		}
+		.transmit(buff, &next),
+		(Address::IPv6(src_addr), Address::IPv6(dst_addr)) => IPv6Layer {
+			protocol,
+
+			src_addr,
+			dst_addr,
+
+			opts: *opts,
+			mtu,
+		}
+		.transmit(buff, &next),
+		_ => Err(errno!(EAFNOSUPPORT)),
+	}
+}
+
+/// Transmits the transport layer packet `buff` over IP.
+///
+/// Arguments:
+/// - `protocol` is the ID of the transport protocol
+/// - `src` is the source address
+/// - `dst` is the destination address
+/// - `opts` is the transmission parameters
+///
+/// The interface used for transmission is selected from the routing table. If no route to the
+/// destination exists, the function returns [`errno::ENETUNREACH`].
+pub fn transmit(
+	protocol: u8,
+	src: Address,
+	dst: Address,
+	opts: &TxOptions,
+	buff: BuffList<'_>,
+) -> EResult<()> {
+	let (iface, next_hop) = net::get_route_for(&dst).ok_or_else(|| errno!(ENETUNREACH))?;
+	transmit_on(&iface, next_hop, protocol, src, dst, opts, buff)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::cell::RefCell;
+	use utils::errno::CollectResult;
+
+	/// Transmits `payload` through `layer`, returning the resulting packets.
+	fn transmit_packets(layer: &dyn Layer, payload: &[u8]) -> Vec<Vec<u8>> {
+		let out = RefCell::new(Vec::new());
+		layer
+			.transmit(payload.into(), &|buff| {
+				out.borrow_mut().push(buff.to_vec()?)?;
+				Ok(())
+			})
+			.unwrap();
+		out.into_inner()
+	}
+
+	#[test_case]
+	fn ipv4_parse() {
+		let layer = IPv4Layer {
+			protocol: PROTO_UDP,
+
+			src_addr: [10, 0, 2, 15],
+			dst_addr: [127, 0, 0, 1],
 
-/// Builds an IPv6 layer with the given `sockaddr`.
-pub fn inet6_build(_sockaddr: &[u8]) -> EResult<Box<dyn Layer>> {
-	// TODO
-	todo!()
+			opts: TxOptions::default(),
+			options: Vec::new(),
+			mtu: 1500,
+		};
+		let mut packets = transmit_packets(&layer, b"payload");
+		assert_eq!(packets.len(), 1);
+		let packet = &mut packets[0];
+		let (parsed, frag) = parse_v4(packet).unwrap();
+		assert_eq!(parsed.src, Address::IPv4([10, 0, 2, 15]));
+		assert_eq!(parsed.dst, Address::IPv4([127, 0, 0, 1]));
+		assert_eq!(parsed.protocol, PROTO_UDP);
+		assert_eq!(parsed.payload, b"payload");
+		assert!(!frag.is_fragment());
+		// Corrupted header
+		packet[8] ^= 0xff;
+		assert!(parse_v4(packet).is_none());
+	}
+
+	#[test_case]
+	fn ipv4_fragment() {
+		let mut layer = IPv4Layer {
+			protocol: PROTO_UDP,
+
+			src_addr: [10, 0, 2, 15],
+			dst_addr: [10, 0, 2, 2],
+
+			opts: TxOptions::default(),
+			// Security (copied), then record route (not copied)
+			options: Vec::try_from(&[0x82, 3, 0, 7, 3, 4][..]).unwrap(),
+			mtu: 100,
+		};
+		let payload: Vec<u8> = (0..250u8).collect::<CollectResult<_>>().0.unwrap();
+		let packets = transmit_packets(&layer, &payload);
+		assert_eq!(packets.len(), 4);
+		let mut r = Reassembly::new(layer.src_addr, layer.dst_addr, PROTO_UDP, 0);
+		for (i, packet) in packets.iter().enumerate() {
+			assert!(packet.len() <= layer.mtu);
+			let (parsed, frag) = parse_v4(packet).unwrap();
+			assert!(frag.is_fragment());
+			assert_eq!(frag.more, i + 1 < packets.len());
+			assert_eq!(frag.offset % 8, 0);
+			let hdr_len = packet.len() - parsed.payload.len();
+			if i == 0 {
+				assert_eq!(hdr_len, 28);
+			} else {
+				// Only the copied option remains
+				assert_eq!(hdr_len, 24);
+				assert_eq!(&packet[20..23], &[0x82, 3, 0]);
+			}
+			assert!(r.insert(frag.offset, parsed.payload, !frag.more).unwrap());
+		}
+		assert!(r.is_complete());
+		assert_eq!(r.data, payload);
+		// Fragmentation forbidden
+		layer.opts.dont_fragment = true;
+		let res = layer.transmit(payload.as_slice().into(), &|_| Ok(()));
+		assert_eq!(res, Err(errno!(EMSGSIZE)));
+	}
+
+	#[test_case]
+	fn ipv4_header_options() {
+		// Record route, then padding
+		let raw = [7, 7, 4, 0, 0, 0, 0, OPT_NOP];
+		let options = HeaderOptions::new(&raw).unwrap();
+		assert_eq!(options.as_slice(), &raw);
+		// Malformed options
+		assert!(HeaderOptions::new(&[7]).is_err());
+		assert!(HeaderOptions::new(&[7, 9, 4]).is_err());
+		assert!(HeaderOptions::new(&[0x82, 0]).is_err());
+		assert!(HeaderOptions::new(&[OPT_NOP; MAX_OPTIONS_LEN + 1]).is_err());
+		let layer = IPv4Layer {
+			protocol: PROTO_UDP,
+
+			src_addr: [10, 0, 2, 15],
+			dst_addr: [10, 0, 2, 2],
+
+			opts: TxOptions {
+				dont_fragment: true,
+				options,
+				..Default::default()
+			},
+			options: Vec::try_from(options.as_slice()).unwrap(),
+			mtu: 1500,
+		};
+		let packets = transmit_packets(&layer, b"payload");
+		let (parsed, frag) = parse_v4(&packets[0]).unwrap();
+		assert_eq!(&parsed.header[size_of::<IPv4Header>()..], &raw);
+		assert_eq!(parsed.payload, b"payload");
+		assert!(frag.dont_fragment);
+	}
+
+	#[test_case]
+	fn ipv4_reassembly() {
+		let mut r = Reassembly::new([10, 0, 2, 15], [10, 0, 2, 2], PROTO_TCP, 42);
+		// Out of order and overlapping fragments
+		assert!(r.insert(16, &[2; 8], true).unwrap());
+		assert!(!r.is_complete());
+		assert!(r.insert(0, &[0; 8], false).unwrap());
+		assert!(!r.is_complete());
+		assert_eq!(r.ranges.as_slice(), &[(0, 8), (16, 24)]);
+		assert!(r.insert(8, &[1; 16], false).unwrap());
+		assert_eq!(r.ranges.as_slice(), &[(0, 24)]);
+		assert!(r.is_complete());
+		assert_eq!(&r.data[..8], &[0; 8]);
+		assert_eq!(&r.data[8..24], &[1; 16]);
+		// Data past the end
+		assert!(!r.insert(24, &[3; 8], false).unwrap());
+		// Conflicting end
+		assert!(!r.insert(8, &[1; 8], true).unwrap());
+	}
+
+	#[test_case]
+	fn ipv6_parse() {
+		let layer = IPv6Layer {
+			protocol: EXT_DEST_OPTS,
+
+			src_addr: lo::LOCALHOST_V6,
+			dst_addr: lo::LOCALHOST_V6,
+
+			opts: TxOptions::default(),
+			mtu: 1500,
+		};
+		// Destination options header with padding, followed by the payload
+		let mut payload = [0u8; 15];
+		payload[0] = PROTO_UDP;
+		payload[8..].copy_from_slice(b"payload");
+		let packets = transmit_packets(&layer, &payload);
+		let parsed = parse_v6(&packets[0]).unwrap();
+		assert_eq!(parsed.src, Address::IPv6(lo::LOCALHOST_V6));
+		assert_eq!(parsed.protocol, PROTO_UDP);
+		assert_eq!(parsed.ttl, DEFAULT_TTL);
+		assert_eq!(parsed.payload, b"payload");
+		// Truncated extension header
+		let packets = transmit_packets(&layer, &payload[..4]);
+		assert!(parse_v6(&packets[0]).is_none());
+		// Too large for the MTU
+		let small = IPv6Layer {
+			mtu: 40,
+			..layer
+		};
+		let res = small.transmit(b"payload"[..].into(), &|_| Ok(()));
+		assert_eq!(res, Err(errno!(EMSGSIZE)));
+	}
 }
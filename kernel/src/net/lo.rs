@@ -18,19 +18,102 @@
 
 //! This module implements the local loopback.
 
-use super::{buff::BuffList, Address, BindAddress, Interface, MAC};
-use utils::errno::EResult;
+use super::{buff::BuffList, Address, BindAddress, IfaceStats, Interface, LinkType, MAC};
+use crate::{net, sync::mutex::IntMutex};
+use core::{
+	cmp::min,
+	mem,
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use utils::{
+	collections::vec::Vec,
+	errno::{AllocResult, EResult},
+};
+
+/// The maximum number of packets waiting in the queue. Packets written when the queue is full
+/// are dropped.
+const QUEUE_LEN: usize = 1024;
+
+/// The loopback's Maximum Transmission Unit.
+const MTU: usize = 65536;
+
+/// The loopback's IPv4 address.
+pub const LOCALHOST_V4: [u8; 4] = [127, 0, 0, 1];
+/// The loopback's IPv6 address.
+pub const LOCALHOST_V6: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+/// Tells whether packets queued on the loopback are being processed.
+static PROCESSING: AtomicBool = AtomicBool::new(false);
+/// The number of packets in the loopback's queue.
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
 
 /// Local loopback interfaces allows the system to write data to itself.
-pub struct LocalLoopback {}
+pub struct LocalLoopback {
+	/// The addresses bound to the interface.
+	addresses: Vec<BindAddress>,
+	/// The packets written on the interface, waiting to be read, used as a ring buffer of
+	/// [`QUEUE_LEN`] slots.
+	queue: Vec<Vec<u8>>,
+	/// The index of the slot of the next packet to be read.
+	head: usize,
+	/// The number of packets in the queue.
+	len: usize,
+	/// Traffic counters.
+	stats: IfaceStats,
+	/// Tells whether the interface has been brought up by the administrator.
+	enabled: bool,
+}
+
+impl LocalLoopback {
+	/// Creates a new instance, bound to the loopback addresses.
+	pub fn new() -> AllocResult<Self> {
+		let mut queue = Vec::with_capacity(QUEUE_LEN)?;
+		for _ in 0..QUEUE_LEN {
+			queue.push(Vec::new())?;
+		}
+		Ok(Self {
+			addresses: Vec::try_from([
+				BindAddress {
+					addr: Address::IPv4(LOCALHOST_V4),
+					subnet_mask: 8,
+				},
+				BindAddress {
+					addr: Address::IPv6(LOCALHOST_V6),
+					subnet_mask: 128,
+				},
+			])?,
+			queue,
+			head: 0,
+			len: 0,
+			stats: IfaceStats::default(),
+			enabled: true,
+		})
+	}
+}
 
 impl Interface for LocalLoopback {
 	fn get_name(&self) -> &[u8] {
 		b"lo"
 	}
 
+	fn get_link_type(&self) -> LinkType {
+		LinkType::Loopback
+	}
+
 	fn is_up(&self) -> bool {
-		true
+		self.enabled
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	fn get_mtu(&self) -> usize {
+		MTU
 	}
 
 	fn get_mac(&self) -> &MAC {
@@ -38,28 +121,91 @@ impl Interface for LocalLoopback {
 	}
 
 	fn get_addresses(&self) -> &[BindAddress] {
-		&[
-			BindAddress {
-				addr: Address::IPv4([127, 0, 0, 1]),
-				subnet_mask: 8,
-			},
-			BindAddress {
-				addr: Address::IPv6([
-					0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-					0x00, 0x00, 0x01,
-				]),
-				subnet_mask: 128,
-			},
-		]
-	}
-
-	fn read(&mut self, _buff: &mut [u8]) -> EResult<u64> {
-		// TODO Write to ring buffer
-		todo!();
-	}
-
-	fn write(&mut self, _buff: &BuffList<'_>) -> EResult<u64> {
-		// TODO Read from ring buffer
-		todo!();
+		&self.addresses
+	}
+
+	fn get_stats(&self) -> &IfaceStats {
+		&self.stats
+	}
+
+	fn add_address(&mut self, addr: BindAddress) -> AllocResult<()> {
+		self.addresses.push(addr)
+	}
+
+	fn remove_address(&mut self, addr: &Address) -> bool {
+		let len = self.addresses.len();
+		self.addresses.retain(|a| a.addr != *addr);
+		self.addresses.len() != len
+	}
+
+	fn read(&mut self, buff: &mut [u8]) -> EResult<u64> {
+		if self.len == 0 {
+			return Ok(0);
+		}
+		let packet = mem::take(&mut self.queue[self.head]);
+		self.head = (self.head + 1) % QUEUE_LEN;
+		self.len -= 1;
+		QUEUED.fetch_sub(1, Ordering::Release);
+		let len = min(packet.len(), buff.len());
+		buff[..len].copy_from_slice(&packet[..len]);
+		self.stats.received(len);
+		Ok(len as _)
+	}
+
+	fn write(&mut self, buff: &BuffList<'_>) -> EResult<u64> {
+		let packet = buff.to_vec()?;
+		let len = packet.len();
+		if self.len < QUEUE_LEN {
+			self.queue[(self.head + self.len) % QUEUE_LEN] = packet;
+			self.len += 1;
+			QUEUED.fetch_add(1, Ordering::Release);
+			self.stats.transmitted(len);
+		} else {
+			self.stats.tx_dropped += 1;
+		}
+		Ok(len as _)
+	}
+}
+
+/// Passes the packets queued on the loopback interface `iface` up the network stack.
+///
+/// Handling a packet may cause other packets to be written on the loopback. To avoid deep
+/// recursions, if the queue is already being processed further up in the call stack, the function
+/// returns immediately and the packets are processed by the outer call.
+pub fn process(iface: &IntMutex<dyn Interface>) -> EResult<()> {
+	// Loop in case packets have been queued by another CPU after the queue has been emptied
+	while QUEUED.load(Ordering::Acquire) > 0 {
+		if PROCESSING.swap(true, Ordering::Acquire) {
+			break;
+		}
+		let res = net::receive(iface);
+		PROCESSING.store(false, Ordering::Release);
+		res?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn loopback_queue() {
+		let mut lo = LocalLoopback::new().unwrap();
+		let mut buf = [0u8; 4];
+		assert_eq!(lo.read(&mut buf), Ok(0));
+		// Fill the queue several times, so that it wraps around
+		for round in 0..3u32 {
+			for i in 0..(QUEUE_LEN as u32 + 1) {
+				let packet = (round * 10000 + i).to_ne_bytes();
+				assert_eq!(lo.write(&packet.as_slice().into()), Ok(4));
+			}
+			assert_eq!(lo.stats.tx_dropped, round as u64 + 1);
+			for i in 0..(QUEUE_LEN as u32) {
+				assert_eq!(lo.read(&mut buf), Ok(4));
+				assert_eq!(u32::from_ne_bytes(buf), round * 10000 + i);
+			}
+			assert_eq!(lo.read(&mut buf), Ok(0));
+		}
 	}
 }
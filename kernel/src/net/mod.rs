@@ -18,27 +18,42 @@
 
 //! Network stack implementation.
 
+pub mod arp;
 pub mod buff;
+pub mod eth;
 pub mod icmp;
 pub mod ip;
 pub mod lo;
+pub mod ndp;
+pub mod neighbor;
 pub mod netlink;
 pub mod osi;
+pub mod packet;
+pub mod sioc;
 pub mod sockaddr;
+pub mod sockopt;
 pub mod tcp;
+pub mod udp;
+pub mod unix;
 
 use crate::{
+	file,
 	file::perm::AccessProfile,
-	net::sockaddr::{SockAddrIn, SockAddrIn6},
-	sync::mutex::Mutex,
+	net::sockaddr::{SockAddrIn, SockAddrIn6, SockAddrLl, SockAddrNl, SockAddrUn},
+	sync::mutex::{IntMutex, Mutex},
 };
 use buff::BuffList;
-use core::{cmp::Ordering, mem::size_of};
+use core::{
+	cmp::{min, Ordering},
+	mem::size_of,
+	sync::{atomic, atomic::AtomicU32},
+};
 use utils::{
 	collections::{hashmap::HashMap, string::String, vec::Vec},
 	errno,
-	errno::{EResult, Errno},
+	errno::{AllocResult, EResult, Errno},
 	ptr::arc::Arc,
+	vec, TryClone,
 };
 
 /// Type representing a Media Access Control (MAC) address.
@@ -47,7 +62,7 @@ pub type MAC = [u8; 6];
 // TODO allow implementation of custom protocols
 
 /// An enumeration of network address types.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Address {
 	/// Internet Protocol version 4.
 	IPv4([u8; 4]),
@@ -55,8 +70,39 @@ pub enum Address {
 	IPv6([u8; 16]),
 }
 
+impl Address {
+	/// Returns the unspecified address (`INADDR_ANY`) of the same family as `self`.
+	pub fn unspecified_like(&self) -> Self {
+		match self {
+			Self::IPv4(_) => Self::IPv4([0; 4]),
+			Self::IPv6(_) => Self::IPv6([0; 16]),
+		}
+	}
+
+	/// Tells whether the address and `other` are of the same family.
+	pub fn is_same_family(&self, other: &Self) -> bool {
+		matches!(
+			(self, other),
+			(Self::IPv4(_), Self::IPv4(_)) | (Self::IPv6(_), Self::IPv6(_))
+		)
+	}
+
+	/// Tells whether the address is an IPv6 link-local address (`fe80::/10`).
+	pub fn is_link_local(&self) -> bool {
+		matches!(self, Self::IPv6(a) if a[0] == 0xfe && a[1] & 0xc0 == 0x80)
+	}
+
+	/// Tells whether the address is the unspecified address (`INADDR_ANY`).
+	pub fn is_unspecified(&self) -> bool {
+		match self {
+			Self::IPv4(a) => a.iter().all(|b| *b == 0),
+			Self::IPv6(a) => a.iter().all(|b| *b == 0),
+		}
+	}
+}
+
 /// An address/subnet mask pair to be bound to an interface.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct BindAddress {
 	/// The bound address.
 	pub addr: Address,
@@ -73,11 +119,11 @@ impl BindAddress {
 				.zip(b.array_chunks::<4>())
 				.enumerate()
 				.all(|(i, (a, b))| {
-					let a = u32::from_ne_bytes(*a);
-					let b = u32::from_ne_bytes(*b);
+					let a = u32::from_be_bytes(*a);
+					let b = u32::from_be_bytes(*b);
 
-					let order = 32 - mask.saturating_sub(i * 32);
-					let mask = !((1 << order) - 1);
+					let bits = min(mask.saturating_sub(i * 32), 32) as u32;
+					let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
 
 					(a & mask) == (b & mask)
 				})
@@ -89,25 +135,115 @@ impl BindAddress {
 			_ => false,
 		}
 	}
+
+	/// Returns the address of the network the address belongs to, which is the address with the
+	/// bits of the host part cleared.
+	pub fn network(&self) -> Address {
+		fn mask<const N: usize>(a: &[u8; N], mask: usize) -> [u8; N] {
+			let mut res = *a;
+			for (i, b) in res.iter_mut().enumerate() {
+				let bits = min(mask.saturating_sub(i * 8), 8) as u32;
+				*b &= u8::MAX.checked_shl(8 - bits).unwrap_or(0);
+			}
+			res
+		}
+
+		match &self.addr {
+			Address::IPv4(a) => Address::IPv4(mask(a, self.subnet_mask as _)),
+			Address::IPv6(a) => Address::IPv6(mask(a, self.subnet_mask as _)),
+		}
+	}
+}
+
+/// The type of the link layer of a network interface.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkType {
+	/// Local loopback. Frames are network layer packets.
+	Loopback,
+	/// Ethernet.
+	Ethernet,
 }
 
+/// Traffic counters of a network interface.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IfaceStats {
+	/// The number of bytes received.
+	pub rx_bytes: u64,
+	/// The number of frames received.
+	pub rx_packets: u64,
+	/// The number of received frames that were invalid.
+	pub rx_errors: u64,
+	/// The number of received frames dropped for lack of resources.
+	pub rx_dropped: u64,
+	/// The number of bytes transmitted.
+	pub tx_bytes: u64,
+	/// The number of frames transmitted.
+	pub tx_packets: u64,
+	/// The number of frames that could not be transmitted because of an error.
+	pub tx_errors: u64,
+	/// The number of frames dropped for lack of resources before being transmitted.
+	pub tx_dropped: u64,
+}
+
+impl IfaceStats {
+	/// Accounts for a frame of `len` bytes that has been received.
+	pub fn received(&mut self, len: usize) {
+		self.rx_packets += 1;
+		self.rx_bytes += len as u64;
+	}
+
+	/// Accounts for a frame of `len` bytes that has been transmitted.
+	pub fn transmitted(&mut self, len: usize) {
+		self.tx_packets += 1;
+		self.tx_bytes += len as u64;
+	}
+}
+
+/// The maximum size of a frame received on an interface.
+const MAX_FRAME_SIZE: usize = 65536;
+
 /// Trait representing a network interface.
 pub trait Interface {
 	/// Returns the name of the interface.
 	fn get_name(&self) -> &[u8];
 
-	/// Tells whether the interface is UP.
+	/// Returns the type of the interface's link layer.
+	fn get_link_type(&self) -> LinkType;
+
+	/// Tells whether the interface is UP, that is enabled with its link up.
 	fn is_up(&self) -> bool;
 
+	/// Tells whether the interface has been enabled by the administrator.
+	fn is_enabled(&self) -> bool;
+
+	/// Enables or disables the interface. A disabled interface neither transmits nor receives
+	/// frames.
+	fn set_enabled(&mut self, enabled: bool);
+
+	/// Returns the Maximum Transmission Unit of the interface, in bytes.
+	fn get_mtu(&self) -> usize;
+
 	/// Returns the mac address of the interface.
 	fn get_mac(&self) -> &MAC;
 
 	/// Returns the list of addresses bound to the interface.
 	fn get_addresses(&self) -> &[BindAddress];
 
-	/// Reads data from the network interface and writes it into `buff`.
+	/// Returns the traffic counters of the interface.
+	fn get_stats(&self) -> &IfaceStats;
+
+	/// Binds the address `addr` to the interface.
+	fn add_address(&mut self, addr: BindAddress) -> AllocResult<()>;
+
+	/// Unbinds the address `addr` from the interface.
 	///
-	/// The function returns the number of bytes read.
+	/// The function returns `true` if the address was bound to the interface.
+	fn remove_address(&mut self, addr: &Address) -> bool;
+
+	/// Reads the next received frame from the network interface and writes it into `buff`.
+	///
+	/// The function returns the number of bytes read. If no frame is pending, the function returns
+	/// zero.
 	fn read(&mut self, buff: &mut [u8]) -> EResult<u64>;
 
 	/// Reads data from `buff` and writes it into the network interface.
@@ -123,7 +259,8 @@ pub struct Route {
 
 	/// The name of the network interface.
 	iface: String,
-	/// The gateway's address.
+	/// The gateway's address. If unspecified, the destination is directly reachable on the
+	/// interface. The gateway also gives the address family of default routes.
 	gateway: Address,
 
 	/// The route's metric. The route with the lowest metric has priority.
@@ -131,8 +268,31 @@ pub struct Route {
 }
 
 impl Route {
+	/// Returns the destination of the route. If `None`, this is a default route.
+	pub fn dst(&self) -> Option<&BindAddress> {
+		self.dst.as_ref()
+	}
+
+	/// Returns the name of the interface of the route.
+	pub fn iface(&self) -> &[u8] {
+		&self.iface
+	}
+
+	/// Returns the address of the gateway.
+	pub fn gateway(&self) -> &Address {
+		&self.gateway
+	}
+
+	/// Returns the metric of the route.
+	pub fn metric(&self) -> u32 {
+		self.metric
+	}
+
 	/// Tells whether the route matches the given address.
 	pub fn is_matching(&self, addr: &Address) -> bool {
+		if !self.gateway.is_same_family(addr) {
+			return false;
+		}
 		// Check gateway
 		if &self.gateway == addr {
 			return true;
@@ -147,83 +307,290 @@ impl Route {
 		dst.is_matching(addr)
 	}
 
+	/// Returns the length of the prefix of the route's destination if it matches `addr`.
+	///
+	/// Default routes have a prefix of length zero.
+	fn prefix_len(&self, addr: &Address) -> Option<u8> {
+		match &self.dst {
+			Some(dst) => dst.is_matching(addr).then_some(dst.subnet_mask),
+			None => Some(0),
+		}
+	}
+
 	/// Compares the current route with the given route `other`.
 	///
-	/// Ordering is done so that the best route is the greatest.
+	/// Ordering is done so that the best route is the greatest: a route whose gateway is `addr`
+	/// is preferred, then the route with the longest matching prefix, then the route with the
+	/// lowest metric.
 	pub fn cmp_for(&self, other: &Self, addr: &Address) -> Ordering {
 		// Check gateway
 		let self_match = addr == &self.gateway;
 		let other_match = addr == &other.gateway;
-
 		self_match
 			.cmp(&other_match)
-			.then_with(|| {
-				// Check for matching network prefix
-
-				let self_match = self
-					.dst
-					.as_ref()
-					.map(|dst| dst.is_matching(addr))
-					// Default address
-					.unwrap_or(true);
-
-				let other_match = other
-					.dst
-					.as_ref()
-					.map(|dst| dst.is_matching(addr))
-					// Default address
-					.unwrap_or(true);
-
-				self_match.cmp(&other_match)
-			})
-			.then_with(|| {
-				// Check metric
-				self.metric.cmp(&other.metric)
-			})
+			// Check for the longest matching network prefix
+			.then_with(|| self.prefix_len(addr).cmp(&other.prefix_len(addr)))
+			// Check metric, the lowest being the best
+			.then_with(|| other.metric.cmp(&self.metric))
 	}
 }
 
-/// The list of network interfaces.
-pub static INTERFACES: Mutex<HashMap<String, Arc<Mutex<dyn Interface>>>> =
-	Mutex::new(HashMap::new());
+/// Returns the best route in `routes` to transmit a packet to `addr`.
+fn select_route<'r, I: Iterator<Item = &'r Route>>(
+	routes: I,
+	addr: &Address,
+) -> Option<&'r Route> {
+	routes
+		.filter(|route| route.is_matching(addr))
+		.max_by(|a, b| a.cmp_for(b, addr))
+}
+
+/// The list of network interfaces, by name. Each interface is associated with its index.
+///
+/// Interfaces are used from interrupt handlers (timers, reception), hence the locks masking
+/// interrupts.
+#[allow(clippy::type_complexity)]
+pub static INTERFACES: IntMutex<HashMap<String, (u32, Arc<IntMutex<dyn Interface>>)>> =
+	IntMutex::new(HashMap::new());
+/// The index to be given to the next registered interface. Indexes start at `1`.
+static NEXT_INDEX: AtomicU32 = AtomicU32::new(1);
 /// The routing table.
-pub static ROUTING_TABLE: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+pub static ROUTING_TABLE: IntMutex<Vec<Route>> = IntMutex::new(Vec::new());
+
+/// The buffer frames are received into.
+///
+/// Frames may be received while the buffer is in use, either from an interrupt or because
+/// handling a frame caused another one to be received on the loopback. In this case, a temporary
+/// buffer is allocated.
+static RX_BUF: Mutex<[u8; MAX_FRAME_SIZE]> = Mutex::new([0; MAX_FRAME_SIZE]);
 
 /// Registers the given network interface.
 ///
 /// Arguments:
 /// - `name` is the name of the interface.
 /// - `iface` is the interface to register.
+///
+/// If the interface is up, its link-local address is configured and its IPv4 addresses are
+/// announced to the link.
 pub fn register_iface<I: 'static + Interface>(name: String, iface: I) -> EResult<()> {
-	let mut interfaces = INTERFACES.lock();
+	let i: Arc<IntMutex<dyn Interface>> = Arc::new(IntMutex::new(iface))?;
+	let index = NEXT_INDEX.fetch_add(1, atomic::Ordering::Relaxed);
+	INTERFACES.lock().insert(name, (index, i.clone()))?;
+	if i.lock().is_up() {
+		link_up(&i)?;
+	}
+	Ok(())
+}
 
-	let i = Arc::new(Mutex::new(iface))?;
-	interfaces.insert(name, i)?;
+/// Configures the interface `iface` after its link came up.
+///
+/// Drivers call this function when they detect a link status change. Calling it while the link
+/// is already configured has no effect beyond announcing the interface's addresses again.
+pub fn link_up(iface: &IntMutex<dyn Interface>) -> EResult<()> {
+	ndp::iface_up(iface)?;
+	arp::iface_up(iface)
+}
 
+/// Enables or disables the interface `iface`.
+///
+/// If the interface comes up, it is configured the same way as when its link comes up.
+pub fn set_enabled(iface: &IntMutex<dyn Interface>, enabled: bool) -> EResult<()> {
+	let up = {
+		let mut iface = iface.lock();
+		let was_up = iface.is_up();
+		iface.set_enabled(enabled);
+		!was_up && iface.is_up()
+	};
+	if up {
+		link_up(iface)?;
+	}
 	Ok(())
 }
 
-/// Unregisters the network interface with the given name.
-pub fn unregister_iface(name: &[u8]) {
-	let mut interfaces = INTERFACES.lock();
-	interfaces.remove(name);
+/// Unregisters the network interface with the given name, along with its routes.
+///
+/// The function returns the interface, if it was registered.
+pub fn unregister_iface(name: &[u8]) -> Option<Arc<IntMutex<dyn Interface>>> {
+	ROUTING_TABLE.lock().retain(|r| r.iface() != name);
+	INTERFACES.lock().remove(name).map(|(_, iface)| iface)
 }
 
 /// Returns the network interface with the given name.
 ///
 /// If the interface doesn't exist, thhe function returns `None`.
-pub fn get_iface(name: &[u8]) -> Option<Arc<Mutex<dyn Interface>>> {
-	INTERFACES.lock().get(name).cloned()
+pub fn get_iface(name: &[u8]) -> Option<Arc<IntMutex<dyn Interface>>> {
+	INTERFACES.lock().get(name).map(|(_, i)| i.clone())
 }
 
-/// Returns the network interface to be used to transmit a packet to the given destination address.
-pub fn get_iface_for(addr: Address) -> Option<Arc<Mutex<dyn Interface>>> {
+/// Returns the registered interfaces with their names, sorted by index.
+#[allow(clippy::type_complexity)]
+pub fn interfaces() -> AllocResult<Vec<(u32, String, Arc<IntMutex<dyn Interface>>)>> {
+	let mut ifaces = Vec::new();
+	for (name, (index, iface)) in INTERFACES.lock().iter() {
+		ifaces.push((*index, name.try_clone()?, iface.clone()))?;
+	}
+	ifaces.sort_unstable_by_key(|(index, ..)| *index);
+	Ok(ifaces)
+}
+
+/// Returns the network interface with the given index.
+///
+/// If the interface doesn't exist, the function returns `None`.
+pub fn get_iface_by_index(index: u32) -> Option<Arc<IntMutex<dyn Interface>>> {
+	INTERFACES
+		.lock()
+		.iter()
+		.find(|(_, (i, _))| *i == index)
+		.map(|(_, (_, i))| i.clone())
+}
+
+/// Returns the index of the network interface with the given name.
+pub fn get_iface_index(name: &[u8]) -> Option<u32> {
+	INTERFACES.lock().get(name).map(|(index, _)| *index)
+}
+
+/// Binds the address `addr` to the interface `iface`, if not already bound.
+///
+/// If the interface is up, a new IPv4 address is announced to the link with a gratuitous ARP, so
+/// that the neighbors update their caches.
+pub fn add_address(iface: &IntMutex<dyn Interface>, addr: BindAddress) -> EResult<()> {
+	let announce = {
+		let mut iface = iface.lock();
+		if iface.get_addresses().iter().any(|a| a.addr == addr.addr) {
+			return Ok(());
+		}
+		iface.add_address(addr)?;
+		iface.is_up() && iface.get_link_type() == LinkType::Ethernet
+	};
+	match addr.addr {
+		Address::IPv4(a) if announce => arp::announce(iface, &a),
+		_ => Ok(()),
+	}
+}
+
+/// Unbinds the address `addr` from the interface `iface`.
+///
+/// If the address is not bound to the interface, the function returns
+/// [`errno::EADDRNOTAVAIL`].
+pub fn remove_address(iface: &IntMutex<dyn Interface>, addr: &Address) -> EResult<()> {
+	if iface.lock().remove_address(addr) {
+		Ok(())
+	} else {
+		Err(errno!(EADDRNOTAVAIL))
+	}
+}
+
+/// Reads all the frames pending on the interface `iface` and passes them up the network stack.
+///
+/// Drivers call this function when frames have been received. The interface is not locked while
+/// frames are processed, so that protocols can transmit replies on it.
+pub fn receive(iface: &IntMutex<dyn Interface>) -> EResult<()> {
+	match RX_BUF.try_lock() {
+		Some(mut buf) => receive_into(iface, &mut buf[..]),
+		None => receive_into(iface, &mut vec![0u8; MAX_FRAME_SIZE]?),
+	}
+}
+
+/// Reads all the frames pending on the interface `iface` into `buf`, passing them up the network
+/// stack one after the other.
+fn receive_into(iface: &IntMutex<dyn Interface>, buf: &mut [u8]) -> EResult<()> {
+	loop {
+		let (link, enabled, len) = {
+			let mut iface = iface.lock();
+			let len = iface.read(buf)? as usize;
+			(iface.get_link_type(), iface.is_enabled(), len)
+		};
+		if len == 0 {
+			break;
+		}
+		// Frames received on a disabled interface are dropped
+		if !enabled {
+			continue;
+		}
+		// Invalid frames are dropped
+		let _ = osi::receive_frame(iface, link, &buf[..len]);
+	}
+	Ok(())
+}
+
+/// Initializes the network stack, bringing up the loopback interface.
+pub(crate) fn init() -> EResult<()> {
+	osi::init()?;
+	ip::init()?;
+	tcp::init()?;
+	init_loopback()
+}
+
+/// Registers the loopback interface along with its routes.
+fn init_loopback() -> EResult<()> {
+	register_iface(String::try_from(b"lo")?, lo::LocalLoopback::new()?)?;
+	let mut routing_table = ROUTING_TABLE.lock();
+	routing_table.push(Route {
+		dst: Some(BindAddress {
+			addr: Address::IPv4(lo::LOCALHOST_V4),
+			subnet_mask: 8,
+		}),
+		iface: String::try_from(b"lo")?,
+		gateway: Address::IPv4([0; 4]),
+		metric: 0,
+	})?;
+	routing_table.push(Route {
+		dst: Some(BindAddress {
+			addr: Address::IPv6(lo::LOCALHOST_V6),
+			subnet_mask: 128,
+		}),
+		iface: String::try_from(b"lo")?,
+		gateway: Address::IPv6([0; 16]),
+		metric: 0,
+	})?;
+	Ok(())
+}
+
+/// Returns the network interface to be used to transmit a packet to the given destination
+/// address, along with the address of the next hop.
+pub fn get_route_for(addr: &Address) -> Option<(Arc<IntMutex<dyn Interface>>, Address)> {
 	let routing_table = ROUTING_TABLE.lock();
-	let route = routing_table
+	let route = select_route(routing_table.iter(), addr)?;
+	let next_hop = if route.gateway.is_unspecified() {
+		*addr
+	} else {
+		route.gateway
+	};
+	Some((get_iface(&route.iface)?, next_hop))
+}
+
+/// Returns the network interface to be used to transmit a packet to the given destination address.
+pub fn get_iface_for(addr: Address) -> Option<Arc<IntMutex<dyn Interface>>> {
+	get_route_for(&addr).map(|(iface, _)| iface)
+}
+
+/// Returns the address of the local interface to be used as source to transmit a packet to the
+/// given destination address.
+///
+/// If no route to the destination exists, the function returns `None`.
+pub fn get_src_addr_for(dst: &Address) -> Option<Address> {
+	let iface = get_iface_for(*dst)?;
+	let iface = iface.lock();
+	// Prefer an address on the same subnet, then an address of the same scope
+	iface
+		.get_addresses()
 		.iter()
-		.filter(|route| route.is_matching(&addr))
-		.max_by(|a, b| a.cmp_for(b, &addr))?;
-	get_iface(&route.iface)
+		.filter(|a| a.addr.is_same_family(dst))
+		.max_by_key(|a| {
+			(
+				a.is_matching(dst),
+				a.addr.is_link_local() == dst.is_link_local(),
+			)
+		})
+		.map(|a| a.addr)
+}
+
+/// Tells whether `addr` is the address of one of the network interfaces.
+pub fn is_local_address(addr: &Address) -> bool {
+	INTERFACES
+		.lock()
+		.iter()
+		.any(|(_, (_, iface))| iface.lock().get_addresses().iter().any(|a| a.addr == *addr))
 }
 
 /// Enumeration of socket domains.
@@ -272,10 +639,11 @@ impl SocketDomain {
 	/// Returns the size of the sockaddr structure for the domain.
 	pub fn get_sockaddr_len(&self) -> usize {
 		match self {
+			Self::AfUnix => size_of::<SockAddrUn>(),
 			Self::AfInet => size_of::<SockAddrIn>(),
 			Self::AfInet6 => size_of::<SockAddrIn6>(),
-			// TODO add others
-			_ => 0,
+			Self::AfNetlink => size_of::<SockAddrNl>(),
+			Self::AfPacket => size_of::<SockAddrLl>(),
 		}
 	}
 }
@@ -290,6 +658,11 @@ impl AccessProfile {
 	}
 }
 
+/// Socket type flag: Open the socket in non-blocking mode
+pub const SOCK_NONBLOCK: i32 = file::O_NONBLOCK;
+/// Socket type flag: Set the close-on-exec flag on the file descriptor
+pub const SOCK_CLOEXEC: i32 = file::O_CLOEXEC;
+
 /// Enumeration of socket types.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum SocketType {
@@ -347,7 +720,7 @@ impl AccessProfile {
 }
 
 /// Socket network stack descriptor.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SocketDesc {
 	/// The socket's domain.
 	pub domain: SocketDomain,
@@ -356,3 +729,136 @@ pub struct SocketDesc {
 	/// The socket's protocol. `0` means using the default protocol for the domain/type pair.
 	pub protocol: i32,
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::iter;
+
+	#[test_case]
+	fn bind_address_matching() {
+		let lo = BindAddress {
+			addr: Address::IPv4([127, 0, 0, 1]),
+			subnet_mask: 8,
+		};
+		assert!(lo.is_matching(&Address::IPv4([127, 1, 2, 3])));
+		assert!(!lo.is_matching(&Address::IPv4([128, 0, 0, 1])));
+		assert!(!lo.is_matching(&Address::IPv6([0; 16])));
+		let any = BindAddress {
+			addr: Address::IPv4([0; 4]),
+			subnet_mask: 0,
+		};
+		assert!(any.is_matching(&Address::IPv4([10, 0, 2, 15])));
+		let mut localhost = [0; 16];
+		localhost[15] = 1;
+		let lo6 = BindAddress {
+			addr: Address::IPv6(localhost),
+			subnet_mask: 128,
+		};
+		assert!(lo6.is_matching(&Address::IPv6(localhost)));
+		localhost[0] = 0xfe;
+		assert!(!lo6.is_matching(&Address::IPv6(localhost)));
+	}
+
+	#[test_case]
+	fn bind_address_network() {
+		let addr = BindAddress {
+			addr: Address::IPv4([192, 168, 1, 10]),
+			subnet_mask: 20,
+		};
+		assert_eq!(addr.network(), Address::IPv4([192, 168, 0, 0]));
+		let mut a = [0xff; 16];
+		a[0] = 0x20;
+		let addr = BindAddress {
+			addr: Address::IPv6(a),
+			subnet_mask: 64,
+		};
+		let mut net = [0; 16];
+		net[..8].copy_from_slice(&a[..8]);
+		assert_eq!(addr.network(), Address::IPv6(net));
+		let any = BindAddress {
+			addr: Address::IPv4([10, 0, 2, 15]),
+			subnet_mask: 0,
+		};
+		assert_eq!(any.network(), Address::IPv4([0; 4]));
+	}
+
+	#[test_case]
+	fn route_selection() {
+		let lo = Route {
+			dst: Some(BindAddress {
+				addr: Address::IPv4([127, 0, 0, 1]),
+				subnet_mask: 8,
+			}),
+			iface: String::try_from(b"lo").unwrap(),
+			gateway: Address::IPv4([0; 4]),
+			metric: 100,
+		};
+		let default = Route {
+			dst: None,
+			iface: String::try_from(b"eth0").unwrap(),
+			gateway: Address::IPv4([10, 0, 2, 2]),
+			metric: 0,
+		};
+		let lan = Route {
+			dst: Some(BindAddress {
+				addr: Address::IPv4([10, 0, 2, 0]),
+				subnet_mask: 24,
+			}),
+			iface: String::try_from(b"eth0").unwrap(),
+			gateway: Address::IPv4([0; 4]),
+			metric: 200,
+		};
+		let routes = [default, lo, lan];
+		// The longest prefix wins over the metric
+		let route = select_route(routes.iter(), &Address::IPv4([127, 0, 0, 1])).unwrap();
+		assert_eq!(route.iface(), b"lo");
+		let route = select_route(routes.iter(), &Address::IPv4([10, 0, 2, 15])).unwrap();
+		assert_eq!(route.metric(), 200);
+		let route = select_route(routes.iter(), &Address::IPv4([1, 1, 1, 1])).unwrap();
+		assert!(route.dst().is_none());
+		assert!(select_route(routes.iter(), &Address::IPv6([0; 16])).is_none());
+		// On equal prefixes, the lowest metric wins
+		let backup = Route {
+			dst: None,
+			iface: String::try_from(b"eth1").unwrap(),
+			gateway: Address::IPv4([10, 0, 3, 2]),
+			metric: 10,
+		};
+		let route = select_route(
+			routes.iter().chain(iter::once(&backup)),
+			&Address::IPv4([1, 1, 1, 1]),
+		)
+		.unwrap();
+		assert_eq!(route.metric(), 0);
+	}
+
+	#[test_case]
+	fn unregister_iface_routes() {
+		let name = b"selftest0";
+		register_iface(
+			String::try_from(name).unwrap(),
+			lo::LocalLoopback::new().unwrap(),
+		)
+		.unwrap();
+		let dst = Address::IPv4([192, 0, 2, 1]);
+		ROUTING_TABLE
+			.lock()
+			.push(Route {
+				dst: Some(BindAddress {
+					addr: Address::IPv4([192, 0, 2, 0]),
+					subnet_mask: 24,
+				}),
+				iface: String::try_from(name).unwrap(),
+				gateway: Address::IPv4([0; 4]),
+				metric: 0,
+			})
+			.unwrap();
+		assert!(get_route_for(&dst).is_some());
+		assert!(unregister_iface(name).is_some());
+		assert!(get_iface(name).is_none());
+		assert!(get_route_for(&dst).is_none());
+		assert!(!ROUTING_TABLE.lock().iter().any(|r| r.iface() == name));
+		assert!(unregister_iface(name).is_none());
+	}
+}
@@ -0,0 +1,437 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! This module implements the Neighbor Discovery Protocol of IPv6 (RFC 4861), along with the
+//! stateless address autoconfiguration (RFC 4862).
+//!
+//! Duplicate address detection is not performed: addresses are used as soon as they are
+//! configured.
+
+use super::{
+	ip,
+	ip::{Packet, TxOptions},
+	neighbor, Address, BindAddress, Interface, LinkType, Route, MAC, ROUTING_TABLE,
+};
+use crate::{net, sync::mutex::IntMutex};
+use utils::{
+	collections::string::String,
+	errno::{AllocResult, EResult},
+	TryClone,
+};
+
+/// ICMPv6 type: Router Solicitation
+pub const TYPE_ROUTER_SOLICITATION: u8 = 133;
+/// ICMPv6 type: Router Advertisement
+pub const TYPE_ROUTER_ADVERTISEMENT: u8 = 134;
+/// ICMPv6 type: Neighbor Solicitation
+pub const TYPE_NEIGHBOR_SOLICITATION: u8 = 135;
+/// ICMPv6 type: Neighbor Advertisement
+pub const TYPE_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// Option: Source link-layer address
+const OPT_SOURCE_LINK_ADDR: u8 = 1;
+/// Option: Target link-layer address
+const OPT_TARGET_LINK_ADDR: u8 = 2;
+/// Option: Prefix information
+const OPT_PREFIX_INFO: u8 = 3;
+
+/// Neighbor Advertisement flag: The advertisement answers a solicitation
+const NA_FLAG_SOLICITED: u8 = 0x40;
+/// Neighbor Advertisement flag: The advertisement overrides the cached link-layer address
+const NA_FLAG_OVERRIDE: u8 = 0x20;
+
+/// Prefix information flag: Addresses with the prefix are on the link
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+/// Prefix information flag: The prefix can be used for address autoconfiguration
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// The hop limit of Neighbor Discovery messages. Since routers decrement it, receiving this
+/// value guarantees the message comes from the link.
+const HOP_LIMIT: u8 = 255;
+
+/// The link-local prefix (`fe80::/64`).
+const LINK_LOCAL_PREFIX: [u8; 16] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// The all-nodes multicast address (`ff02::1`).
+const ALL_NODES: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+/// The all-routers multicast address (`ff02::2`).
+const ALL_ROUTERS: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+/// The metric of routes configured automatically.
+const AUTOCONF_METRIC: u32 = 256;
+
+/// Returns the interface identifier associated with the link-layer address `mac`, in the
+/// modified EUI-64 format (RFC 4291, appendix A).
+pub fn interface_id(mac: &MAC) -> [u8; 8] {
+	[
+		mac[0] ^ 0x02,
+		mac[1],
+		mac[2],
+		0xff,
+		0xfe,
+		mac[3],
+		mac[4],
+		mac[5],
+	]
+}
+
+/// Returns the solicited-node multicast address of `addr` (RFC 4291, section 2.7.1).
+pub fn solicited_node(addr: &[u8; 16]) -> [u8; 16] {
+	let mut res = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0, 0, 0];
+	res[13..].copy_from_slice(&addr[13..]);
+	res
+}
+
+/// Returns the Ethernet address the multicast address `addr` is mapped to (RFC 2464, section
+/// 7).
+pub fn multicast_mac(addr: &[u8; 16]) -> MAC {
+	[0x33, 0x33, addr[12], addr[13], addr[14], addr[15]]
+}
+
+/// Returns a link-layer address option of the given kind, containing `mac`.
+fn link_addr_option(kind: u8, mac: &MAC) -> [u8; 8] {
+	[kind, 1, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]]
+}
+
+/// Iterator over the options of a Neighbor Discovery message.
+///
+/// Each item is the type of the option, along with the whole option.
+struct Options<'m>(&'m [u8]);
+
+impl<'m> Iterator for Options<'m> {
+	type Item = (u8, &'m [u8]);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let len = *self.0.get(1)? as usize * 8;
+		let opt = self.0.get(..len).filter(|_| len > 0);
+		// Stop on invalid options (RFC 4861, section 4.6)
+		let Some(opt) = opt else {
+			self.0 = &[];
+			return None;
+		};
+		self.0 = &self.0[len..];
+		Some((opt[0], opt))
+	}
+}
+
+/// Returns the link-layer address contained in the option `opt`.
+fn parse_link_addr(opt: &[u8]) -> Option<MAC> {
+	opt.get(2..8)?.try_into().ok()
+}
+
+/// Returns the first IPv6 address of the interface `iface`, preferring the link-local address.
+fn iface_addr(iface: &dyn Interface) -> Option<[u8; 16]> {
+	iface
+		.get_addresses()
+		.iter()
+		.filter_map(|a| match a.addr {
+			Address::IPv6(addr) => Some((a.addr.is_link_local(), addr)),
+			_ => None,
+		})
+		.max_by_key(|(link_local, _)| *link_local)
+		.map(|(_, addr)| addr)
+}
+
+/// Inserts `route` in the routing table, if not already present.
+fn add_route(route: Route) -> AllocResult<()> {
+	let mut routing_table = ROUTING_TABLE.lock();
+	let present = routing_table
+		.iter()
+		.any(|r| r.dst == route.dst && r.iface == route.iface && r.gateway == route.gateway);
+	if !present {
+		routing_table.push(route)?;
+	}
+	Ok(())
+}
+
+/// Sends the Neighbor Discovery message `msg` on the interface `iface`, from `src` to `dst`.
+///
+/// The function fills the checksum of the message.
+fn send(
+	iface: &IntMutex<dyn Interface>,
+	src: [u8; 16],
+	dst: [u8; 16],
+	msg: &mut [u8],
+) -> EResult<()> {
+	let (src, dst) = (Address::IPv6(src), Address::IPv6(dst));
+	let checksum = ip::pseudo_header_checksum(ip::PROTO_ICMPV6, &src, &dst, msg)?;
+	msg[2..4].copy_from_slice(&checksum.to_ne_bytes());
+	let opts = TxOptions {
+		ttl: HOP_LIMIT,
+		..Default::default()
+	};
+	ip::transmit_on(
+		iface,
+		dst,
+		ip::PROTO_ICMPV6,
+		src,
+		dst,
+		&opts,
+		(&*msg).into(),
+	)
+}
+
+/// Sends a Neighbor Solicitation for the address `target` on the interface `iface`.
+pub fn solicit(iface: &IntMutex<dyn Interface>, target: &[u8; 16]) -> EResult<()> {
+	let (src, mac) = {
+		let iface = iface.lock();
+		let Some(src) = iface_addr(&*iface) else {
+			return Ok(());
+		};
+		(src, *iface.get_mac())
+	};
+	let mut msg = [0u8; 32];
+	msg[0] = TYPE_NEIGHBOR_SOLICITATION;
+	msg[8..24].copy_from_slice(target);
+	msg[24..].copy_from_slice(&link_addr_option(OPT_SOURCE_LINK_ADDR, &mac));
+	send(iface, src, solicited_node(target), &mut msg)
+}
+
+/// Handles a Neighbor Solicitation from `src`, answering it if it targets an address of the
+/// interface.
+fn receive_solicitation(
+	iface: &IntMutex<dyn Interface>,
+	src: &[u8; 16],
+	msg: &[u8],
+) -> EResult<()> {
+	let Some(target) = msg.get(8..24) else {
+		return Ok(());
+	};
+	let target: [u8; 16] = target.try_into().unwrap();
+	let mac = {
+		let iface = iface.lock();
+		let ours = iface
+			.get_addresses()
+			.iter()
+			.any(|a| a.addr == Address::IPv6(target));
+		if !ours {
+			return Ok(());
+		}
+		*iface.get_mac()
+	};
+	// A node performing duplicate address detection has no address yet
+	let (dst, flags) = if *src == [0; 16] {
+		(ALL_NODES, NA_FLAG_OVERRIDE)
+	} else {
+		let src_mac = Options(&msg[24..])
+			.find(|(kind, _)| *kind == OPT_SOURCE_LINK_ADDR)
+			.and_then(|(_, opt)| parse_link_addr(opt));
+		if let Some(src_mac) = src_mac {
+			neighbor::update(iface, &Address::IPv6(*src), src_mac, true)?;
+		}
+		(*src, NA_FLAG_SOLICITED | NA_FLAG_OVERRIDE)
+	};
+	let mut reply = [0u8; 32];
+	reply[0] = TYPE_NEIGHBOR_ADVERTISEMENT;
+	reply[4] = flags;
+	reply[8..24].copy_from_slice(&target);
+	reply[24..].copy_from_slice(&link_addr_option(OPT_TARGET_LINK_ADDR, &mac));
+	send(iface, target, dst, &mut reply)
+}
+
+/// Handles a Neighbor Advertisement, updating the neighbor cache.
+fn receive_advertisement(iface: &IntMutex<dyn Interface>, msg: &[u8]) -> EResult<()> {
+	let Some(target) = msg.get(8..24) else {
+		return Ok(());
+	};
+	let target: [u8; 16] = target.try_into().unwrap();
+	let mac = Options(&msg[24..])
+		.find(|(kind, _)| *kind == OPT_TARGET_LINK_ADDR)
+		.and_then(|(_, opt)| parse_link_addr(opt));
+	match mac {
+		Some(mac) => neighbor::update(iface, &Address::IPv6(target), mac, false),
+		None => Ok(()),
+	}
+}
+
+/// Handles the prefix information option `opt` of a Router Advertisement received on the
+/// interface `iface`, named `name`, with the link-layer address `mac`.
+fn configure_prefix(
+	iface: &IntMutex<dyn Interface>,
+	name: &String,
+	mac: &MAC,
+	opt: &[u8],
+) -> EResult<()> {
+	if opt.len() < 32 {
+		return Ok(());
+	}
+	let prefix_len = opt[2];
+	let flags = opt[3];
+	let valid_lifetime = u32::from_be_bytes(opt[4..8].try_into().unwrap());
+	let prefix: [u8; 16] = opt[16..32].try_into().unwrap();
+	if valid_lifetime == 0 || prefix_len > 128 || Address::IPv6(prefix).is_link_local() {
+		return Ok(());
+	}
+	// TODO expire addresses and routes with their lifetimes
+	if flags & PREFIX_FLAG_ON_LINK != 0 {
+		add_route(Route {
+			dst: Some(BindAddress {
+				addr: Address::IPv6(prefix),
+				subnet_mask: prefix_len,
+			}),
+			iface: name.try_clone()?,
+			gateway: Address::IPv6([0; 16]),
+			metric: AUTOCONF_METRIC,
+		})?;
+	}
+	// Addresses are made of a 64 bits prefix and the interface identifier
+	if flags & PREFIX_FLAG_AUTONOMOUS != 0 && prefix_len == 64 {
+		let mut addr = prefix;
+		addr[8..].copy_from_slice(&interface_id(mac));
+		net::add_address(
+			iface,
+			BindAddress {
+				addr: Address::IPv6(addr),
+				subnet_mask: 64,
+			},
+		)?;
+	}
+	Ok(())
+}
+
+/// Handles a Router Advertisement from `src`, configuring the addresses and routes it
+/// advertises.
+fn receive_router_advertisement(
+	iface: &IntMutex<dyn Interface>,
+	src: &[u8; 16],
+	msg: &[u8],
+) -> EResult<()> {
+	// Routers advertise from their link-local address
+	if !Address::IPv6(*src).is_link_local() || msg.len() < 16 {
+		return Ok(());
+	}
+	let router_lifetime = u16::from_be_bytes([msg[6], msg[7]]);
+	let (name, mac) = {
+		let iface = iface.lock();
+		(String::try_from(iface.get_name())?, *iface.get_mac())
+	};
+	for (kind, opt) in Options(&msg[16..]) {
+		match kind {
+			OPT_SOURCE_LINK_ADDR => {
+				if let Some(mac) = parse_link_addr(opt) {
+					neighbor::update(iface, &Address::IPv6(*src), mac, true)?;
+				}
+			}
+			OPT_PREFIX_INFO => configure_prefix(iface, &name, &mac, opt)?,
+			_ => {}
+		}
+	}
+	if router_lifetime > 0 {
+		add_route(Route {
+			dst: None,
+			iface: name,
+			gateway: Address::IPv6(*src),
+			metric: AUTOCONF_METRIC,
+		})?;
+	}
+	Ok(())
+}
+
+/// Handles the Neighbor Discovery message in the packet `packet`, received on the interface
+/// `iface`.
+///
+/// The checksum of the message is expected to have been checked already.
+pub fn receive(iface: &IntMutex<dyn Interface>, packet: &Packet) -> EResult<()> {
+	let Address::IPv6(src) = packet.src else {
+		return Ok(());
+	};
+	let msg = packet.payload;
+	// Messages must come from the link
+	if packet.ttl != HOP_LIMIT || msg.get(1) != Some(&0) {
+		return Ok(());
+	}
+	match msg[0] {
+		TYPE_NEIGHBOR_SOLICITATION => receive_solicitation(iface, &src, msg),
+		TYPE_NEIGHBOR_ADVERTISEMENT => receive_advertisement(iface, msg),
+		TYPE_ROUTER_ADVERTISEMENT => receive_router_advertisement(iface, &src, msg),
+		_ => Ok(()),
+	}
+}
+
+/// Configures the link-local address of the interface `iface`, which has just been brought up,
+/// then solicits the routers of the link to configure global addresses.
+///
+/// Interfaces without a link layer are left untouched.
+pub fn iface_up(iface: &IntMutex<dyn Interface>) -> EResult<()> {
+	let (name, mac) = {
+		let iface = iface.lock();
+		if iface.get_link_type() != LinkType::Ethernet {
+			return Ok(());
+		}
+		(String::try_from(iface.get_name())?, *iface.get_mac())
+	};
+	let mut addr = LINK_LOCAL_PREFIX;
+	addr[8..].copy_from_slice(&interface_id(&mac));
+	net::add_address(
+		iface,
+		BindAddress {
+			addr: Address::IPv6(addr),
+			subnet_mask: 64,
+		},
+	)?;
+	add_route(Route {
+		dst: Some(BindAddress {
+			addr: Address::IPv6(LINK_LOCAL_PREFIX),
+			subnet_mask: 64,
+		}),
+		iface: name,
+		gateway: Address::IPv6([0; 16]),
+		metric: AUTOCONF_METRIC,
+	})?;
+	let mut msg = [0u8; 16];
+	msg[0] = TYPE_ROUTER_SOLICITATION;
+	msg[8..].copy_from_slice(&link_addr_option(OPT_SOURCE_LINK_ADDR, &mac));
+	send(iface, addr, ALL_ROUTERS, &mut msg)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn ndp_addresses() {
+		let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+		assert_eq!(
+			interface_id(&mac),
+			[0x50, 0x54, 0x00, 0xff, 0xfe, 0x12, 0x34, 0x56]
+		);
+		let addr = [
+			0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x50, 0x54, 0x00, 0xff, 0xfe, 0x12, 0x34, 0x56,
+		];
+		let sn = solicited_node(&addr);
+		assert_eq!(
+			sn,
+			[0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0x12, 0x34, 0x56]
+		);
+		assert_eq!(multicast_mac(&sn), [0x33, 0x33, 0xff, 0x12, 0x34, 0x56]);
+	}
+
+	#[test_case]
+	fn ndp_options() {
+		let mut opts = [0u8; 16];
+		opts[..8].copy_from_slice(&link_addr_option(OPT_SOURCE_LINK_ADDR, &[1, 2, 3, 4, 5, 6]));
+		// Invalid option with a zero length
+		opts[8] = OPT_TARGET_LINK_ADDR;
+		let mut iter = Options(&opts);
+		let (kind, opt) = iter.next().unwrap();
+		assert_eq!(kind, OPT_SOURCE_LINK_ADDR);
+		assert_eq!(parse_link_addr(opt), Some([1, 2, 3, 4, 5, 6]));
+		assert!(iter.next().is_none());
+		// Truncated option
+		assert!(Options(&opts[..6]).next().is_none());
+	}
+}
@@ -0,0 +1,254 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The neighbor cache associates the addresses of the hosts on a link with their link-layer
+//! addresses.
+//!
+//! Addresses are resolved with ARP for IPv4, and with the Neighbor Discovery Protocol for IPv6.
+//! Packets sent to a neighbor whose address is being resolved are queued until the resolution
+//! completes.
+
+use super::{
+	arp,
+	buff::BuffList,
+	eth,
+	eth::{ETHERTYPE_IPV4, ETHERTYPE_IPV6},
+	ndp, Address, BindAddress, Interface, MAC,
+};
+use crate::{net, sync::mutex::IntMutex, time::timer};
+use utils::{
+	collections::{hashmap::HashMap, string::String, vec::Vec},
+	errno::EResult,
+	vec, TryClone,
+};
+
+/// The number of solicitations sent before giving up the resolution of an address.
+const MAX_PROBES: u32 = 3;
+/// The delay between solicitations, in seconds.
+const RETRANS_TIMER: u32 = 1;
+/// The time during which a resolved neighbor is considered reachable, in seconds.
+const REACHABLE_TIME: u32 = 30;
+/// The maximum number of packets queued while an address is being resolved.
+const MAX_PENDING: usize = 3;
+
+/// An entry of the neighbor cache.
+pub struct Neighbor {
+	/// The name of the interface the neighbor is on.
+	pub iface: String,
+	/// The neighbor's link-layer address. If `None`, the address is being resolved.
+	pub mac: Option<MAC>,
+	/// The packets waiting for the resolution of the address.
+	pending: Vec<Vec<u8>>,
+	/// The number of solicitations sent.
+	probes: u32,
+	/// The number of seconds remaining before the next solicitation or, if resolved, before
+	/// the entry expires.
+	remaining: u32,
+}
+
+/// The neighbor cache, associating network layer addresses with link-layer addresses.
+///
+/// The cache is updated from interrupt handlers, hence the lock masking interrupts.
+pub static NEIGHBORS: IntMutex<HashMap<Address, Neighbor>> = IntMutex::new(HashMap::new());
+
+/// Returns the EtherType of packets sent to `addr`.
+fn ethertype(addr: &Address) -> u16 {
+	match addr {
+		Address::IPv4(_) => ETHERTYPE_IPV4,
+		Address::IPv6(_) => ETHERTYPE_IPV6,
+	}
+}
+
+/// Tells whether `addr` is the broadcast address of the subnet of `bind`.
+fn is_subnet_broadcast(bind: &BindAddress, addr: &[u8; 4]) -> bool {
+	// Point-to-point subnets have no broadcast address (RFC 3021)
+	if !matches!(bind.addr, Address::IPv4(_)) || bind.subnet_mask >= 31 {
+		return false;
+	}
+	let host = u32::MAX.checked_shr(bind.subnet_mask as _).unwrap_or(0);
+	u32::from_be_bytes(*addr) & host == host && bind.is_matching(&Address::IPv4(*addr))
+}
+
+/// Returns the link-layer address of `addr` on the interface `iface` if it does not require
+/// resolution, which is the case of broadcast and multicast addresses.
+fn static_mac(iface: &dyn Interface, addr: &Address) -> Option<MAC> {
+	match addr {
+		Address::IPv4(a) => {
+			let broadcast = *a == [0xff; 4]
+				|| iface
+					.get_addresses()
+					.iter()
+					.any(|b| is_subnet_broadcast(b, a));
+			if broadcast {
+				Some(eth::BROADCAST)
+			} else if a[0] & 0xf0 == 0xe0 {
+				// Multicast (RFC 1112, section 6.4)
+				Some([0x01, 0x00, 0x5e, a[1] & 0x7f, a[2], a[3]])
+			} else {
+				None
+			}
+		}
+		Address::IPv6(a) => (a[0] == 0xff).then(|| ndp::multicast_mac(a)),
+	}
+}
+
+/// Sends a solicitation for the address `addr` on the interface `iface`.
+fn solicit(iface: &IntMutex<dyn Interface>, addr: &Address) -> EResult<()> {
+	match addr {
+		Address::IPv4(addr) => arp::request(iface, addr),
+		Address::IPv6(addr) => ndp::solicit(iface, addr),
+	}
+}
+
+/// Writes the network layer packet `buff` on the Ethernet interface `iface`, to the neighbor
+/// `addr`.
+///
+/// If the link-layer address of the neighbor is unknown, the packet is queued and the address
+/// is resolved.
+pub fn output(iface: &IntMutex<dyn Interface>, addr: &Address, buff: BuffList<'_>) -> EResult<()> {
+	let ethertype = ethertype(addr);
+	let mac = static_mac(&*iface.lock(), addr);
+	if let Some(mac) = mac {
+		return eth::transmit(iface, mac, ethertype, buff);
+	}
+	let name = String::try_from(iface.lock().get_name())?;
+	let mac = {
+		let mut neighbors = NEIGHBORS.lock();
+		match neighbors.get_mut(addr) {
+			Some(n) => {
+				if n.mac.is_none() && n.pending.len() < MAX_PENDING {
+					n.pending.push(buff.to_vec()?)?;
+				}
+				n.mac
+			}
+			None => {
+				neighbors.insert(
+					*addr,
+					Neighbor {
+						iface: name,
+						mac: None,
+						pending: vec![buff.to_vec()?]?,
+						probes: 1,
+						remaining: RETRANS_TIMER,
+					},
+				)?;
+				drop(neighbors);
+				return solicit(iface, addr);
+			}
+		}
+	};
+	match mac {
+		Some(mac) => eth::transmit(iface, mac, ethertype, buff),
+		None => Ok(()),
+	}
+}
+
+/// Records that the neighbor `addr` on the interface `iface` has the link-layer address `mac`,
+/// then transmits the packets waiting for it.
+///
+/// If `create` is `false`, the cache is updated only if it already has an entry for the
+/// neighbor.
+pub fn update(
+	iface: &IntMutex<dyn Interface>,
+	addr: &Address,
+	mac: MAC,
+	create: bool,
+) -> EResult<()> {
+	let pending = {
+		let mut neighbors = NEIGHBORS.lock();
+		match neighbors.get_mut(addr) {
+			Some(n) => {
+				n.mac = Some(mac);
+				n.remaining = REACHABLE_TIME;
+				core::mem::take(&mut n.pending)
+			}
+			None if create => {
+				let name = String::try_from(iface.lock().get_name())?;
+				neighbors.insert(
+					*addr,
+					Neighbor {
+						iface: name,
+						mac: Some(mac),
+						pending: Vec::new(),
+						probes: 0,
+						remaining: REACHABLE_TIME,
+					},
+				)?;
+				Vec::new()
+			}
+			None => Vec::new(),
+		}
+	};
+	let ethertype = ethertype(addr);
+	for packet in pending {
+		eth::transmit(iface, mac, ethertype, packet.as_slice().into())?;
+	}
+	Ok(())
+}
+
+/// Ages the entries of the neighbor cache and retransmits solicitations.
+fn tick() {
+	let mut probes = Vec::new();
+	NEIGHBORS.lock().retain(|addr, n| {
+		n.remaining = n.remaining.saturating_sub(1);
+		if n.remaining > 0 {
+			return true;
+		}
+		// Expired entry or unresolved address
+		if n.mac.is_some() || n.probes >= MAX_PROBES {
+			return false;
+		}
+		n.probes += 1;
+		n.remaining = RETRANS_TIMER;
+		if let Ok(name) = n.iface.try_clone() {
+			let _ = probes.push((*addr, name));
+		}
+		true
+	});
+	for (addr, name) in probes {
+		if let Some(iface) = net::get_iface(&name) {
+			let _ = solicit(&iface, &addr);
+		}
+	}
+}
+
+/// Initializes the neighbor cache.
+pub(crate) fn init() -> EResult<()> {
+	timer::register_kernel_timer(1000, tick)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn neighbor_subnet_broadcast() {
+		let bind = BindAddress {
+			addr: Address::IPv4([192, 168, 1, 10]),
+			subnet_mask: 24,
+		};
+		assert!(is_subnet_broadcast(&bind, &[192, 168, 1, 255]));
+		assert!(!is_subnet_broadcast(&bind, &[192, 168, 1, 254]));
+		assert!(!is_subnet_broadcast(&bind, &[192, 168, 2, 255]));
+		let bind = BindAddress {
+			addr: Address::IPv4([10, 0, 0, 1]),
+			subnet_mask: 31,
+		};
+		assert!(!is_subnet_broadcast(&bind, &[10, 0, 0, 1]));
+	}
+}
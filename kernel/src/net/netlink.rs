@@ -17,16 +17,174 @@
  */
 
 //! `netlink` is an interface between the kernel and userspace.
+//!
+//! Only the `NETLINK_ROUTE` protocol is supported, which allows to configure the network
+//! interfaces, their addresses and the routing table.
+//!
+//! Requests are handled synchronously when they are sent: replies are queued on the socket right
+//! away.
 
-use core::mem::size_of;
+use super::{
+	sockaddr::SockAddrNl, Address, BindAddress, Interface, LinkType, Route, SocketDomain,
+	ROUTING_TABLE,
+};
+use crate::{
+	file::{
+		perm::AccessProfile,
+		socket::{push_msg, Socket, SocketState},
+	},
+	net,
+	process::Process,
+	sync::mutex::IntMutex,
+};
+use core::{
+	mem::size_of,
+	sync::{atomic, atomic::AtomicU32},
+};
+use macros::AnyRepr;
 use utils::{
-	collections::{ring_buffer::RingBuffer, vec::Vec},
-	errno::EResult,
+	bytes::{as_bytes, from_bytes, AnyRepr},
+	collections::{string::String, vec::Vec},
+	errno,
+	errno::{AllocResult, EResult, Errno},
+	ptr::arc::Arc,
 	vec,
 };
 
+/// Netlink protocol: Routing and link configuration
+pub const NETLINK_ROUTE: u32 = 0;
+
+/// Message flag: The message is a request
+const NLM_F_REQUEST: u16 = 0x1;
+/// Message flag: The message is part of a multipart reply, terminated by [`NLMSG_DONE`]
+const NLM_F_MULTI: u16 = 0x2;
+/// Message flag: The request must be acknowledged
+const NLM_F_ACK: u16 = 0x4;
+/// Get request flag: Return all the entries
+const NLM_F_DUMP: u16 = 0x300;
+/// New request flag: Replace the existing entry
+const NLM_F_REPLACE: u16 = 0x100;
+/// New request flag: Fail if the entry already exists
+const NLM_F_EXCL: u16 = 0x200;
+
+/// Message type: Error, or acknowledgement if the error code is zero
+const NLMSG_ERROR: u16 = 2;
+/// Message type: End of a multipart reply
+const NLMSG_DONE: u16 = 3;
+
+/// Message type: New interface. This is also the first message type of the protocol
+const RTM_NEWLINK: u16 = 16;
+/// Message type: Get interfaces
+const RTM_GETLINK: u16 = 18;
+/// Message type: Add an address
+const RTM_NEWADDR: u16 = 20;
+/// Message type: Remove an address
+const RTM_DELADDR: u16 = 21;
+/// Message type: Get addresses
+const RTM_GETADDR: u16 = 22;
+/// Message type: Add a route
+const RTM_NEWROUTE: u16 = 24;
+/// Message type: Remove a route
+const RTM_DELROUTE: u16 = 25;
+/// Message type: Get routes
+const RTM_GETROUTE: u16 = 26;
+
+/// Address family: Unspecified, matching all families
+const AF_UNSPEC: u8 = 0;
+
+/// Hardware type: Ethernet
+pub const ARPHRD_ETHER: u16 = 1;
+/// Hardware type: Loopback
+pub const ARPHRD_LOOPBACK: u16 = 772;
+
+/// Interface flag: The interface is up
+pub const IFF_UP: u32 = 0x1;
+/// Interface flag: The interface supports broadcast
+const IFF_BROADCAST: u32 = 0x2;
+/// Interface flag: The interface is a loopback
+const IFF_LOOPBACK: u32 = 0x8;
+/// Interface flag: The interface is operational
+const IFF_RUNNING: u32 = 0x40;
+/// Interface flag: The interface supports multicast
+const IFF_MULTICAST: u32 = 0x1000;
+/// Interface flag: The link is up
+const IFF_LOWER_UP: u32 = 0x10000;
+
+/// Operational state: Down
+const IF_OPER_DOWN: u8 = 2;
+/// Operational state: Up
+const IF_OPER_UP: u8 = 6;
+
+/// Interface attribute: Hardware address
+const IFLA_ADDRESS: u16 = 1;
+/// Interface attribute: Hardware broadcast address
+const IFLA_BROADCAST: u16 = 2;
+/// Interface attribute: Name
+const IFLA_IFNAME: u16 = 3;
+/// Interface attribute: MTU
+const IFLA_MTU: u16 = 4;
+/// Interface attribute: Operational state
+const IFLA_OPERSTATE: u16 = 16;
+
+/// Address attribute: Address of the interface, or of the peer on point-to-point links
+const IFA_ADDRESS: u16 = 1;
+/// Address attribute: Address of the interface
+const IFA_LOCAL: u16 = 2;
+/// Address attribute: Name of the interface
+const IFA_LABEL: u16 = 3;
+/// Address attribute: Broadcast address
+const IFA_BROADCAST: u16 = 4;
+
+/// Address flag: The address does not expire
+const IFA_F_PERMANENT: u8 = 0x80;
+
+/// Route attribute: Destination
+const RTA_DST: u16 = 1;
+/// Route attribute: Output interface index
+const RTA_OIF: u16 = 4;
+/// Route attribute: Gateway
+const RTA_GATEWAY: u16 = 5;
+/// Route attribute: Metric
+const RTA_PRIORITY: u16 = 6;
+/// Route attribute: Preferred source address
+const RTA_PREFSRC: u16 = 7;
+/// Route attribute: Routing table
+const RTA_TABLE: u16 = 15;
+
+/// Routing table: Unspecified
+const RT_TABLE_UNSPEC: u8 = 0;
+/// Routing table: Main, which is the only one supported
+const RT_TABLE_MAIN: u8 = 254;
+
+/// Route origin: Installed by the administrator
+const RTPROT_BOOT: u8 = 3;
+
+/// Scope: Global
+const RT_SCOPE_UNIVERSE: u8 = 0;
+/// Scope: On the link
+const RT_SCOPE_LINK: u8 = 253;
+/// Scope: On the host
+const RT_SCOPE_HOST: u8 = 254;
+
+/// Route type: Unspecified
+const RTN_UNSPEC: u8 = 0;
+/// Route type: Gateway or direct route
+const RTN_UNICAST: u8 = 1;
+
+/// The metric of the routes added along with IPv6 addresses.
+const PREFIX_METRIC_V6: u32 = 256;
+
+/// The alignment of messages and attributes.
+const ALIGN: usize = 4;
+/// The maximum size of a datagram of a multipart reply.
+const DUMP_SIZE: usize = 8192;
+
+/// The first port ID used for sockets whose process ID is already in use, decreasing.
+const AUTOBIND_START: u32 = -4096i32 as u32;
+
 /// Netlink message header.
-#[repr(C)]
+#[derive(AnyRepr, Clone, Copy)]
+#[repr(C, packed)]
 struct NLMsgHdr {
 	/// Length of message including header
 	nlmsg_len: u32,
@@ -40,64 +198,1061 @@ struct NLMsgHdr {
 	nlmsg_pid: u32,
 }
 
-/// The netlink handle for a socket. Each socket must have its own instance.
-#[derive(Debug)]
-pub struct Handle {
-	/// Socket family being used
-	pub family: i32,
+/// Header of interface messages.
+#[derive(AnyRepr, Clone, Copy, Default)]
+#[repr(C, packed)]
+struct IfInfoMsg {
+	/// Address family
+	ifi_family: u8,
+	/// Padding
+	ifi_pad: u8,
+	/// Hardware type
+	ifi_type: u16,
+	/// Interface index
+	ifi_index: i32,
+	/// Interface flags
+	ifi_flags: u32,
+	/// Mask of the flags to change
+	ifi_change: u32,
+}
+
+/// Header of address messages.
+#[derive(AnyRepr, Clone, Copy, Default)]
+#[repr(C, packed)]
+struct IfAddrMsg {
+	/// Address family
+	ifa_family: u8,
+	/// Prefix length
+	ifa_prefixlen: u8,
+	/// Address flags
+	ifa_flags: u8,
+	/// Address scope
+	ifa_scope: u8,
+	/// Interface index
+	ifa_index: u32,
+}
 
-	/// The buffer for read operations.
-	read_buff: RingBuffer<u8, Vec<u8>>,
-	/// The buffer for write operations.
-	write_buff: RingBuffer<u8, Vec<u8>>,
+/// Header of route messages.
+#[derive(AnyRepr, Clone, Copy, Default)]
+#[repr(C, packed)]
+struct RtMsg {
+	/// Address family
+	rtm_family: u8,
+	/// Length of the destination prefix
+	rtm_dst_len: u8,
+	/// Length of the source prefix
+	rtm_src_len: u8,
+	/// Type of service
+	rtm_tos: u8,
+	/// Routing table
+	rtm_table: u8,
+	/// Origin of the route
+	rtm_protocol: u8,
+	/// Distance to the destination
+	rtm_scope: u8,
+	/// Route type
+	rtm_type: u8,
+	/// Route flags
+	rtm_flags: u32,
 }
 
-impl Handle {
-	/// Creates a new handle.
-	pub fn new() -> EResult<Self> {
-		Ok(Self {
-			family: 0,
+/// The state of a netlink socket.
+#[derive(Debug, Default)]
+pub struct NetlinkState {
+	/// The port ID the socket is bound to. If zero, the socket is not bound yet.
+	port: u32,
+	/// Tells whether the socket has been created by a process allowed to change the
+	/// configuration of the network.
+	privileged: bool,
+}
 
-			read_buff: RingBuffer::new(vec![0; 16384]?),
-			write_buff: RingBuffer::new(vec![0; 16384]?),
-		})
+/// The list of port IDs in use.
+static PORTS: IntMutex<Vec<u32>> = IntMutex::new(Vec::new());
+/// The next port ID to try when autobinding a socket whose process ID is in use.
+static NEXT_PORT: AtomicU32 = AtomicU32::new(AUTOBIND_START);
+
+/// Rounds `len` up to the alignment of messages and attributes.
+fn align(len: usize) -> usize {
+	len.next_multiple_of(ALIGN)
+}
+
+/// Returns the address family ID of `addr`.
+fn family_of(addr: &Address) -> u8 {
+	let domain = match addr {
+		Address::IPv4(_) => SocketDomain::AfInet,
+		Address::IPv6(_) => SocketDomain::AfInet6,
+	};
+	domain.get_id() as _
+}
+
+/// Returns the unspecified address of the address family `family`.
+///
+/// If the family is not supported, the function returns [`errno::EAFNOSUPPORT`].
+fn unspecified(family: u8) -> EResult<Address> {
+	match SocketDomain::try_from(family as u32)? {
+		SocketDomain::AfInet => Ok(Address::IPv4([0; 4])),
+		SocketDomain::AfInet6 => Ok(Address::IPv6([0; 16])),
+		_ => Err(errno!(EAFNOSUPPORT)),
+	}
+}
+
+/// Parses the address `data`, of the address family `family`.
+///
+/// If the length of the address does not match the family, the function returns
+/// [`errno::EINVAL`].
+fn parse_address(family: u8, data: &[u8]) -> EResult<Address> {
+	match unspecified(family)? {
+		Address::IPv4(_) => data.try_into().map(Address::IPv4),
+		Address::IPv6(_) => data.try_into().map(Address::IPv6),
+	}
+	.map_err(|_| errno!(EINVAL))
+}
+
+/// Returns the bytes of the address `addr`.
+fn address_bytes(addr: &Address) -> &[u8] {
+	match addr {
+		Address::IPv4(a) => a,
+		Address::IPv6(a) => a,
+	}
+}
+
+/// Returns the length in bits of addresses of the same family as `addr`.
+fn address_bits(addr: &Address) -> u8 {
+	match addr {
+		Address::IPv4(_) => 32,
+		Address::IPv6(_) => 128,
+	}
+}
+
+/// Returns the scope of the address `addr`.
+fn address_scope(addr: &Address) -> u8 {
+	let loopback = match addr {
+		Address::IPv4(a) => a[0] == 127,
+		Address::IPv6(_) => *addr == Address::IPv6(net::lo::LOCALHOST_V6),
+	};
+	if loopback {
+		RT_SCOPE_HOST
+	} else if addr.is_link_local() {
+		RT_SCOPE_LINK
+	} else {
+		RT_SCOPE_UNIVERSE
+	}
+}
+
+/// Reads a value of type `T` at the beginning of the payload of a message.
+///
+/// If the payload is too small, the function returns [`errno::EINVAL`].
+fn read_header<T: AnyRepr + Copy>(payload: &[u8]) -> EResult<T> {
+	from_bytes::<T>(payload)
+		.copied()
+		.ok_or_else(|| errno!(EINVAL))
+}
+
+/// Iterator over the attributes of a message.
+///
+/// Each item is the type of the attribute, along with its payload.
+struct Attrs<'m>(&'m [u8]);
+
+impl<'m> Iterator for Attrs<'m> {
+	type Item = (u16, &'m [u8]);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let hdr = self.0.get(..4)?;
+		let len = u16::from_ne_bytes([hdr[0], hdr[1]]) as usize;
+		// The upper bits of the type are flags
+		let kind = u16::from_ne_bytes([hdr[2], hdr[3]]) & 0x3fff;
+		let Some(data) = self.0.get(4..len) else {
+			self.0 = &[];
+			return None;
+		};
+		self.0 = self.0.get(align(len)..).unwrap_or_default();
+		Some((kind, data))
 	}
 }
 
-impl Handle {
-	/// Reads data from the I/O and writes it into `buff`.
+/// Returns the attributes of the message `payload`, whose fixed header is of type `T`.
+fn attrs<T>(payload: &[u8]) -> Attrs<'_> {
+	Attrs(payload.get(align(size_of::<T>())..).unwrap_or_default())
+}
+
+/// A request received on a socket.
+struct Request<'r> {
+	/// The header of the message.
+	hdr: NLMsgHdr,
+	/// The payload of the message.
+	payload: &'r [u8],
+	/// The port ID of the socket.
+	port: u32,
+	/// Tells whether the sender is allowed to change the configuration of the network.
+	privileged: bool,
+}
+
+impl Request<'_> {
+	/// Tells whether the request is a dump of all the entries.
+	fn is_dump(&self) -> bool {
+		// Like Linux, any of the flags composing `NLM_F_DUMP` is enough
+		self.hdr.nlmsg_flags & NLM_F_DUMP != 0
+	}
+
+	/// Tells whether the request has the given flag set.
+	fn has_flag(&self, flag: u16) -> bool {
+		self.hdr.nlmsg_flags & flag != 0
+	}
+
+	/// Checks the sender is allowed to change the configuration of the network.
 	///
-	/// The function returns the number of bytes read.
-	pub fn read(&mut self, buff: &mut [u8]) -> EResult<(usize, bool)> {
-		let len = self.read_buff.read(buff);
-		Ok((len, false))
+	/// If not, the function returns [`errno::EPERM`].
+	fn check_privileged(&self) -> EResult<()> {
+		if self.privileged {
+			Ok(())
+		} else {
+			Err(errno!(EPERM))
+		}
+	}
+}
+
+/// A message being built, in reply to a request.
+struct Message(Vec<u8>);
+
+impl Message {
+	/// Creates a message of type `kind` with the flags `flags`, replying to `req`.
+	fn new(req: &Request, kind: u16, flags: u16) -> AllocResult<Self> {
+		let hdr = NLMsgHdr {
+			nlmsg_len: 0,
+			nlmsg_type: kind,
+			nlmsg_flags: flags,
+			nlmsg_seq: req.hdr.nlmsg_seq,
+			nlmsg_pid: req.port,
+		};
+		Ok(Self(Vec::try_from(as_bytes(&hdr))?))
+	}
+
+	/// Appends `data` to the message, followed by the padding required for alignment.
+	fn push(&mut self, data: &[u8]) -> AllocResult<()> {
+		self.0.extend_from_slice(data)?;
+		self.0.resize(align(self.0.len()), 0)
+	}
+
+	/// Appends an attribute of type `kind` with the payload `data` to the message.
+	fn attr(&mut self, kind: u16, data: &[u8]) -> AllocResult<()> {
+		let len = (4 + data.len()) as u16;
+		self.0.extend_from_slice(&len.to_ne_bytes())?;
+		self.0.extend_from_slice(&kind.to_ne_bytes())?;
+		self.push(data)
+	}
+
+	/// Returns the message's bytes, with the length in the header updated.
+	fn finish(mut self) -> Vec<u8> {
+		let len = self.0.len() as u32;
+		self.0[..4].copy_from_slice(&len.to_ne_bytes());
+		self.0
+	}
+}
+
+/// The datagrams to be sent in reply to requests.
+#[derive(Default)]
+struct Replies {
+	/// The datagrams.
+	datagrams: Vec<Vec<u8>>,
+	/// Tells whether the last datagram belongs to a multipart reply which is being built.
+	multipart: bool,
+}
+
+impl Replies {
+	/// Sends the message `msg` in its own datagram.
+	fn send(&mut self, msg: Vec<u8>) -> AllocResult<()> {
+		self.multipart = false;
+		self.datagrams.push(msg)
 	}
 
-	/// Reads data from `buff` and writes it into the I/O.
+	/// Sends the message `msg` as a part of a multipart reply.
 	///
-	/// The function returns the number of bytes written.
-	pub fn write(&mut self, buff: &[u8]) -> EResult<usize> {
-		let len = self.write_buff.write(buff);
+	/// Messages are gathered in the same datagram as long as it does not grow too large.
+	fn send_part(&mut self, msg: Vec<u8>) -> AllocResult<()> {
+		match self.datagrams.last_mut() {
+			Some(d) if self.multipart && d.len() + msg.len() <= DUMP_SIZE => {
+				d.extend_from_slice(&msg)
+			}
+			_ => {
+				self.multipart = true;
+				self.datagrams.push(msg)
+			}
+		}
+	}
+
+	/// Terminates the multipart reply to `req`.
+	fn end_parts(&mut self, req: &Request) -> AllocResult<()> {
+		let mut msg = Message::new(req, NLMSG_DONE, NLM_F_MULTI)?;
+		msg.push(&0i32.to_ne_bytes())?;
+		self.send_part(msg.finish())?;
+		self.multipart = false;
+		Ok(())
+	}
+
+	/// Sends the error `err` in reply to `req`. If `err` is `None`, an acknowledgement is sent.
+	fn send_error(&mut self, req: &Request, err: Option<Errno>) -> AllocResult<()> {
+		let mut msg = Message::new(req, NLMSG_ERROR, 0)?;
+		let code = err.map(|e| -e.as_int()).unwrap_or(0);
+		msg.push(&code.to_ne_bytes())?;
+		msg.push(as_bytes(&req.hdr))?;
+		// Like Linux, errors also contain the payload of the request
+		if err.is_some() {
+			msg.push(req.payload)?;
+		}
+		self.send(msg.finish())
+	}
+}
+
+/// Returns the interface with the index `index`, along with its name.
+///
+/// If the interface does not exist, the function returns [`errno::ENODEV`].
+fn iface_by_index(index: u32) -> EResult<(String, Arc<IntMutex<dyn Interface>>)> {
+	let iface = net::get_iface_by_index(index).ok_or_else(|| errno!(ENODEV))?;
+	let name = String::try_from(iface.lock().get_name())?;
+	Ok((name, iface))
+}
+
+/// Returns the hardware type and the `IFF_*` flags of the interface `iface`.
+pub(super) fn link_info(iface: &dyn Interface) -> (u16, u32) {
+	let (kind, link_flags) = match iface.get_link_type() {
+		LinkType::Loopback => (ARPHRD_LOOPBACK, IFF_LOOPBACK),
+		LinkType::Ethernet => (ARPHRD_ETHER, IFF_BROADCAST | IFF_MULTICAST),
+	};
+	let up_flags = if iface.is_up() {
+		IFF_UP | IFF_RUNNING | IFF_LOWER_UP
+	} else if iface.is_enabled() {
+		IFF_UP
+	} else {
+		0
+	};
+	(kind, link_flags | up_flags)
+}
+
+/// Builds the message describing the interface `iface`, with the index `index`.
+fn link_msg(req: &Request, flags: u16, index: u32, iface: &dyn Interface) -> AllocResult<Vec<u8>> {
+	let (kind, link_flags) = link_info(iface);
+	let operstate = if iface.is_up() {
+		IF_OPER_UP
+	} else {
+		IF_OPER_DOWN
+	};
+	let mut msg = Message::new(req, RTM_NEWLINK, flags)?;
+	msg.push(as_bytes(&IfInfoMsg {
+		ifi_type: kind,
+		ifi_index: index as _,
+		ifi_flags: link_flags,
+		..Default::default()
+	}))?;
+	// The name is NUL-terminated
+	let name = iface.get_name();
+	let mut name_attr = vec![0u8; name.len() + 1]?;
+	name_attr[..name.len()].copy_from_slice(name);
+	msg.attr(IFLA_IFNAME, &name_attr)?;
+	msg.attr(IFLA_MTU, &(iface.get_mtu() as u32).to_ne_bytes())?;
+	msg.attr(IFLA_OPERSTATE, &[operstate])?;
+	msg.attr(IFLA_ADDRESS, iface.get_mac())?;
+	if iface.get_link_type() == LinkType::Ethernet {
+		msg.attr(IFLA_BROADCAST, &net::eth::BROADCAST)?;
+	}
+	Ok(msg.finish())
+}
 
-		// Read message header
-		let mut buf: [u8; size_of::<NLMsgHdr>()] = [0; size_of::<NLMsgHdr>()];
-		let l = self.write_buff.peek(buf.as_mut_slice());
-		if l < buf.len() {
-			return Ok(len);
+/// Handles a `RTM_GETLINK` request.
+fn get_link(req: &Request, replies: &mut Replies) -> EResult<()> {
+	if req.is_dump() {
+		for (index, _, iface) in net::interfaces()? {
+			let msg = link_msg(req, NLM_F_MULTI, index, &*iface.lock())?;
+			replies.send_part(msg)?;
 		}
-		let _hdr = buf.as_ptr() as *const NLMsgHdr;
+		replies.end_parts(req)?;
+		return Ok(());
+	}
+	let info: IfInfoMsg = read_header(req.payload)?;
+	// The interface is given either by index or by name
+	let iface = if info.ifi_index > 0 {
+		net::get_iface_by_index(info.ifi_index as _)
+	} else {
+		attrs::<IfInfoMsg>(req.payload)
+			.find(|(kind, _)| *kind == IFLA_IFNAME)
+			.and_then(|(_, name)| {
+				let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+				net::get_iface(&name[..len])
+			})
+	};
+	let iface = iface.ok_or_else(|| errno!(ENODEV))?;
+	let name = String::try_from(iface.lock().get_name())?;
+	let index = net::get_iface_index(&name).ok_or_else(|| errno!(ENODEV))?;
+	let msg = link_msg(req, 0, index, &*iface.lock())?;
+	replies.send(msg)?;
+	Ok(())
+}
 
-		// TODO handle message
-		todo!();
+/// Builds the message describing the address `addr` of the interface `name`, with the index
+/// `index`.
+fn addr_msg(
+	req: &Request,
+	flags: u16,
+	index: u32,
+	name: &[u8],
+	addr: &BindAddress,
+) -> AllocResult<Vec<u8>> {
+	let mut msg = Message::new(req, RTM_NEWADDR, flags)?;
+	msg.push(as_bytes(&IfAddrMsg {
+		ifa_family: family_of(&addr.addr),
+		ifa_prefixlen: addr.subnet_mask,
+		ifa_flags: IFA_F_PERMANENT,
+		ifa_scope: address_scope(&addr.addr),
+		ifa_index: index,
+	}))?;
+	let bytes = address_bytes(&addr.addr);
+	msg.attr(IFA_ADDRESS, bytes)?;
+	if let Address::IPv4(a) = addr.addr {
+		msg.attr(IFA_LOCAL, bytes)?;
+		if addr.subnet_mask < 31 {
+			let host = u32::MAX.checked_shr(addr.subnet_mask as _).unwrap_or(0);
+			let broadcast = u32::from_be_bytes(a) | host;
+			msg.attr(IFA_BROADCAST, &broadcast.to_be_bytes())?;
+		}
+		let mut label = vec![0u8; name.len() + 1]?;
+		label[..name.len()].copy_from_slice(name);
+		msg.attr(IFA_LABEL, &label)?;
 	}
+	Ok(msg.finish())
+}
 
-	/// Tells whether the specified events are available on the I/O interface.
-	///
-	/// `mask` is a mask containing the mask of operations to check for.
+/// Handles a `RTM_GETADDR` request.
+///
+/// Addresses can only be dumped.
+fn get_addr(req: &Request, replies: &mut Replies) -> EResult<()> {
+	// The header is either a `ifaddrmsg` or a `rtgenmsg`, both beginning with the family
+	let family = req.payload.first().copied().unwrap_or(AF_UNSPEC);
+	let filter_index = from_bytes::<IfAddrMsg>(req.payload)
+		.map(|m| m.ifa_index)
+		.unwrap_or(0);
+	for (index, name, iface) in net::interfaces()? {
+		if filter_index != 0 && index != filter_index {
+			continue;
+		}
+		let iface = iface.lock();
+		for addr in iface.get_addresses() {
+			if family != AF_UNSPEC && family != family_of(&addr.addr) {
+				continue;
+			}
+			replies.send_part(addr_msg(req, NLM_F_MULTI, index, &name, addr)?)?;
+		}
+	}
+	replies.end_parts(req)?;
+	Ok(())
+}
+
+/// Parses the header and the address of an `RTM_NEWADDR` or `RTM_DELADDR` request.
+///
+/// The function returns the interface along with its name, and the address.
+#[allow(clippy::type_complexity)]
+fn parse_addr_request(
+	req: &Request,
+) -> EResult<(String, Arc<IntMutex<dyn Interface>>, BindAddress)> {
+	let hdr: IfAddrMsg = read_header(req.payload)?;
+	let mut addr = None;
+	for (kind, data) in attrs::<IfAddrMsg>(req.payload) {
+		match kind {
+			// On point-to-point links, `IFA_ADDRESS` is the address of the peer
+			IFA_LOCAL => addr = Some(parse_address(hdr.ifa_family, data)?),
+			IFA_ADDRESS if addr.is_none() => addr = Some(parse_address(hdr.ifa_family, data)?),
+			_ => {}
+		}
+	}
+	let addr = addr.ok_or_else(|| errno!(EINVAL))?;
+	if hdr.ifa_prefixlen > address_bits(&addr) {
+		return Err(errno!(EINVAL));
+	}
+	let (name, iface) = iface_by_index(hdr.ifa_index)?;
+	Ok((
+		name,
+		iface,
+		BindAddress {
+			addr,
+			subnet_mask: hdr.ifa_prefixlen,
+		},
+	))
+}
+
+/// Returns the route to the network of the address `addr`, bound to the interface `name`.
+fn prefix_route(name: String, addr: &BindAddress) -> Route {
+	let metric = match addr.addr {
+		Address::IPv4(_) => 0,
+		Address::IPv6(_) => PREFIX_METRIC_V6,
+	};
+	Route {
+		dst: Some(BindAddress {
+			addr: addr.network(),
+			subnet_mask: addr.subnet_mask,
+		}),
+		iface: name,
+		gateway: addr.addr.unspecified_like(),
+		metric,
+	}
+}
+
+/// Tells whether the routes `a` and `b` are the same.
+fn is_same_route(a: &Route, b: &Route) -> bool {
+	a.dst == b.dst && a.iface == b.iface && a.gateway == b.gateway
+}
+
+/// Binds the address `addr` to the interface `iface` named `name`, along with a route to its
+/// network.
+pub(super) fn bind_address(
+	name: String,
+	iface: &IntMutex<dyn Interface>,
+	addr: BindAddress,
+) -> EResult<()> {
+	net::add_address(iface, addr)?;
+	if addr.subnet_mask < address_bits(&addr.addr) {
+		let route = prefix_route(name, &addr);
+		let mut routing_table = ROUTING_TABLE.lock();
+		if !routing_table.iter().any(|r| is_same_route(r, &route)) {
+			routing_table.push(route)?;
+		}
+	}
+	Ok(())
+}
+
+/// Unbinds the address `addr` from the interface `iface` named `name`.
+///
+/// The route to the network of the address is removed if no other address of the interface is
+/// on it.
+pub(super) fn unbind_address(
+	name: String,
+	iface: &IntMutex<dyn Interface>,
+	addr: BindAddress,
+) -> EResult<()> {
+	net::remove_address(iface, &addr.addr)?;
+	let network = addr.network();
+	let remaining = iface
+		.lock()
+		.get_addresses()
+		.iter()
+		.any(|a| a.subnet_mask == addr.subnet_mask && a.is_matching(&network));
+	if !remaining {
+		let route = prefix_route(name, &addr);
+		ROUTING_TABLE.lock().retain(|r| !is_same_route(r, &route));
+	}
+	Ok(())
+}
+
+/// Handles a `RTM_NEWADDR` request.
+///
+/// Along with the address, a route to its network is added.
+fn new_addr(req: &Request) -> EResult<()> {
+	req.check_privileged()?;
+	let (name, iface, addr) = parse_addr_request(req)?;
+	let exists = iface
+		.lock()
+		.get_addresses()
+		.iter()
+		.any(|a| a.addr == addr.addr);
+	if exists {
+		return if req.has_flag(NLM_F_EXCL) {
+			Err(errno!(EEXIST))
+		} else {
+			Ok(())
+		};
+	}
+	bind_address(name, &iface, addr)
+}
+
+/// Handles a `RTM_DELADDR` request.
+///
+/// The route to the network of the address is removed if no other address of the interface is
+/// on it.
+fn del_addr(req: &Request) -> EResult<()> {
+	req.check_privileged()?;
+	let (name, iface, addr) = parse_addr_request(req)?;
+	unbind_address(name, &iface, addr)
+}
+
+/// Builds the message describing the route `route`, whose interface has the index `index`.
+///
+/// `prefsrc` is the source address to be used with the route, if any.
+fn route_msg(
+	req: &Request,
+	flags: u16,
+	route: &Route,
+	index: u32,
+	prefsrc: Option<Address>,
+) -> AllocResult<Vec<u8>> {
+	let scope = if route.gateway.is_unspecified() {
+		RT_SCOPE_LINK
+	} else {
+		RT_SCOPE_UNIVERSE
+	};
+	let mut msg = Message::new(req, RTM_NEWROUTE, flags)?;
+	msg.push(as_bytes(&RtMsg {
+		rtm_family: family_of(&route.gateway),
+		rtm_dst_len: route.dst.map(|d| d.subnet_mask).unwrap_or(0),
+		rtm_table: RT_TABLE_MAIN,
+		rtm_protocol: RTPROT_BOOT,
+		rtm_scope: scope,
+		rtm_type: RTN_UNICAST,
+		..Default::default()
+	}))?;
+	msg.attr(RTA_TABLE, &(RT_TABLE_MAIN as u32).to_ne_bytes())?;
+	if let Some(dst) = &route.dst {
+		msg.attr(RTA_DST, address_bytes(&dst.addr))?;
+	}
+	if let Some(src) = &prefsrc {
+		msg.attr(RTA_PREFSRC, address_bytes(src))?;
+	}
+	if !route.gateway.is_unspecified() {
+		msg.attr(RTA_GATEWAY, address_bytes(&route.gateway))?;
+	}
+	msg.attr(RTA_PRIORITY, &route.metric.to_ne_bytes())?;
+	msg.attr(RTA_OIF, &index.to_ne_bytes())?;
+	Ok(msg.finish())
+}
+
+/// The fields of a `RTM_NEWROUTE`, `RTM_DELROUTE` or `RTM_GETROUTE` request.
+struct RouteRequest {
+	/// The header of the request.
+	hdr: RtMsg,
+	/// The destination.
+	dst: Option<Address>,
+	/// The gateway.
+	gateway: Option<Address>,
+	/// The index of the output interface.
+	oif: Option<u32>,
+	/// The metric.
+	metric: Option<u32>,
+}
+
+impl RouteRequest {
+	/// Parses the route request `req`.
+	fn parse(req: &Request) -> EResult<Self> {
+		let mut hdr: RtMsg = read_header(req.payload)?;
+		let mut res = Self {
+			hdr,
+			dst: None,
+			gateway: None,
+			oif: None,
+			metric: None,
+		};
+		let u32_attr = |data: &[u8]| -> EResult<u32> {
+			data.try_into()
+				.map(u32::from_ne_bytes)
+				.map_err(|_| errno!(EINVAL))
+		};
+		for (kind, data) in attrs::<RtMsg>(req.payload) {
+			match kind {
+				RTA_DST => res.dst = Some(parse_address(hdr.rtm_family, data)?),
+				RTA_GATEWAY => res.gateway = Some(parse_address(hdr.rtm_family, data)?),
+				RTA_OIF => res.oif = Some(u32_attr(data)?),
+				RTA_PRIORITY => res.metric = Some(u32_attr(data)?),
+				// Tables are numbered on 32 bits, the header only has room for the first ones
+				RTA_TABLE => hdr.rtm_table = u32_attr(data)?.try_into().unwrap_or(u8::MAX),
+				_ => {}
+			}
+		}
+		res.hdr = hdr;
+		Ok(res)
+	}
+
+	/// Returns the destination of the route, checking it is consistent with the family.
+	fn destination(&self) -> EResult<Option<BindAddress>> {
+		let unspecified = unspecified(self.hdr.rtm_family)?;
+		if self.hdr.rtm_dst_len > address_bits(&unspecified) {
+			return Err(errno!(EINVAL));
+		}
+		if self.hdr.rtm_dst_len == 0 {
+			return Ok(None);
+		}
+		let dst = BindAddress {
+			addr: self.dst.unwrap_or(unspecified),
+			subnet_mask: self.hdr.rtm_dst_len,
+		};
+		Ok(Some(BindAddress {
+			addr: dst.network(),
+			subnet_mask: dst.subnet_mask,
+		}))
+	}
+
+	/// Checks the route is a unicast route of the main table.
 	///
-	/// The function returns the mask with available events set.
-	pub fn poll(&mut self, _mask: u32) -> EResult<u32> {
-		// TODO
-		todo!();
+	/// If not, the function returns [`errno::EOPNOTSUPP`].
+	fn check_supported(&self) -> EResult<()> {
+		let table = matches!(self.hdr.rtm_table, RT_TABLE_UNSPEC | RT_TABLE_MAIN);
+		let kind = matches!(self.hdr.rtm_type, RTN_UNSPEC | RTN_UNICAST);
+		if table && kind {
+			Ok(())
+		} else {
+			Err(errno!(EOPNOTSUPP))
+		}
+	}
+}
+
+/// Handles a `RTM_NEWROUTE` request.
+fn new_route(req: &Request) -> EResult<()> {
+	req.check_privileged()?;
+	let r = RouteRequest::parse(req)?;
+	r.check_supported()?;
+	let dst = r.destination()?;
+	let gateway = match r.gateway {
+		Some(gateway) => gateway,
+		None => unspecified(r.hdr.rtm_family)?,
+	};
+	// Without an output interface, the gateway must be reachable
+	let iface = match r.oif {
+		Some(index) => iface_by_index(index)?.0,
+		None if !gateway.is_unspecified() => {
+			let (iface, _) = net::get_route_for(&gateway).ok_or_else(|| errno!(ENETUNREACH))?;
+			let iface = iface.lock();
+			String::try_from(iface.get_name())?
+		}
+		None => return Err(errno!(ENODEV)),
+	};
+	let route = Route {
+		dst,
+		iface,
+		gateway,
+		metric: r.metric.unwrap_or(0),
+	};
+	let mut routing_table = ROUTING_TABLE.lock();
+	// Like Linux, routes are identified by their destination and metric
+	let existing = routing_table.iter().position(|r| {
+		r.gateway.is_same_family(&route.gateway) && r.dst == route.dst && r.metric == route.metric
+	});
+	match existing {
+		Some(_) if req.has_flag(NLM_F_EXCL) => Err(errno!(EEXIST)),
+		Some(i) if req.has_flag(NLM_F_REPLACE) => {
+			routing_table[i] = route;
+			Ok(())
+		}
+		_ => Ok(routing_table.push(route)?),
+	}
+}
+
+/// Handles a `RTM_DELROUTE` request.
+///
+/// The attributes which are not specified match all routes.
+fn del_route(req: &Request) -> EResult<()> {
+	req.check_privileged()?;
+	let r = RouteRequest::parse(req)?;
+	let dst = r.destination()?;
+	let family = unspecified(r.hdr.rtm_family)?;
+	let iface = r.oif.map(iface_by_index).transpose()?.map(|(name, _)| name);
+	let mut routing_table = ROUTING_TABLE.lock();
+	let i = routing_table
+		.iter()
+		.position(|route| {
+			route.gateway.is_same_family(&family)
+				&& route.dst == dst
+				&& r.gateway.is_none_or(|g| g == route.gateway)
+				&& iface.as_ref().is_none_or(|i| *i == route.iface)
+				&& r.metric.is_none_or(|m| m == route.metric)
+		})
+		.ok_or_else(|| errno!(ESRCH))?;
+	routing_table.remove(i);
+	Ok(())
+}
+
+/// Handles a `RTM_GETROUTE` request.
+///
+/// If the request is not a dump, the function replies with the route used to reach the
+/// destination of the request.
+fn get_route(req: &Request, replies: &mut Replies) -> EResult<()> {
+	if req.is_dump() {
+		let family = req.payload.first().copied().unwrap_or(AF_UNSPEC);
+		let routing_table = ROUTING_TABLE.lock();
+		for route in routing_table.iter() {
+			if family != AF_UNSPEC && family != family_of(&route.gateway) {
+				continue;
+			}
+			let Some(index) = net::get_iface_index(&route.iface) else {
+				continue;
+			};
+			replies.send_part(route_msg(req, NLM_F_MULTI, route, index, None)?)?;
+		}
+		drop(routing_table);
+		replies.end_parts(req)?;
+		return Ok(());
+	}
+	let r = RouteRequest::parse(req)?;
+	let dst = match r.dst {
+		Some(dst) => dst,
+		None => unspecified(r.hdr.rtm_family)?,
+	};
+	let (iface, next_hop) = net::get_route_for(&dst).ok_or_else(|| errno!(ENETUNREACH))?;
+	let name = String::try_from(iface.lock().get_name())?;
+	let index = net::get_iface_index(&name).ok_or_else(|| errno!(ENODEV))?;
+	let gateway = if next_hop == dst {
+		dst.unspecified_like()
+	} else {
+		next_hop
+	};
+	let route = Route {
+		dst: Some(BindAddress {
+			addr: dst,
+			subnet_mask: address_bits(&dst),
+		}),
+		iface: name,
+		gateway,
+		metric: 0,
+	};
+	let src = net::get_src_addr_for(&dst);
+	replies.send(route_msg(req, 0, &route, index, src)?)?;
+	Ok(())
+}
+
+/// Handles the request `req`, writing the replies to `replies`.
+fn handle(req: &Request, replies: &mut Replies) -> EResult<()> {
+	match req.hdr.nlmsg_type {
+		RTM_GETLINK => get_link(req, replies),
+		RTM_NEWADDR => new_addr(req),
+		RTM_DELADDR => del_addr(req),
+		RTM_GETADDR => get_addr(req, replies),
+		RTM_NEWROUTE => new_route(req),
+		RTM_DELROUTE => del_route(req),
+		RTM_GETROUTE => get_route(req, replies),
+		_ => Err(errno!(EOPNOTSUPP)),
+	}
+}
+
+/// Handles the messages in `buf`, returning the datagrams to be sent in reply.
+///
+/// Arguments:
+/// - `port` is the port ID of the sending socket
+/// - `privileged` tells whether the sender is allowed to change the configuration of the network
+fn process(buf: &[u8], port: u32, privileged: bool) -> AllocResult<Replies> {
+	let mut replies = Replies::default();
+	let mut buf = buf;
+	while let Some(hdr) = from_bytes::<NLMsgHdr>(buf) {
+		let len = hdr.nlmsg_len as usize;
+		if len < size_of::<NLMsgHdr>() || len > buf.len() {
+			break;
+		}
+		let req = Request {
+			hdr: *hdr,
+			payload: &buf[size_of::<NLMsgHdr>()..len],
+			port,
+			privileged,
+		};
+		buf = buf.get(align(len)..).unwrap_or_default();
+		// Messages from the kernel and control messages are ignored
+		if !req.has_flag(NLM_F_REQUEST) || req.hdr.nlmsg_type < RTM_NEWLINK {
+			continue;
+		}
+		match handle(&req, &mut replies) {
+			Ok(()) if req.has_flag(NLM_F_ACK) => replies.send_error(&req, None)?,
+			Ok(()) => {}
+			Err(e) => replies.send_error(&req, Some(e))?,
+		}
+	}
+	Ok(replies)
+}
+
+/// Runs `f` with the netlink state of the socket.
+fn with_state<F: FnOnce(&mut NetlinkState) -> R, R>(sock: &Socket, f: F) -> R {
+	let mut state = sock.state().lock();
+	let SocketState::Netlink(state) = &mut *state else {
+		unreachable!();
+	};
+	f(state)
+}
+
+/// Returns a `sockaddr_nl` structure for the port ID `port`.
+fn sockaddr_for(port: u32) -> [u8; size_of::<SockAddrNl>()] {
+	let mut buf = [0u8; size_of::<SockAddrNl>()];
+	buf[..2].copy_from_slice(&(SocketDomain::AfNetlink.get_id() as u16).to_ne_bytes());
+	buf[4..8].copy_from_slice(&port.to_ne_bytes());
+	buf
+}
+
+/// Returns the address of the peer of a netlink socket, which is always the kernel.
+pub fn peername() -> AllocResult<Vec<u8>> {
+	Vec::try_from(&sockaddr_for(0)[..])
+}
+
+/// Parses the `sockaddr_nl` structure `sockaddr`, returning the port ID it contains.
+///
+/// If the structure is invalid, the function returns [`errno::EINVAL`].
+fn parse_sockaddr(sockaddr: &[u8]) -> EResult<u32> {
+	if sockaddr.len() < size_of::<SockAddrNl>() {
+		return Err(errno!(EINVAL));
+	}
+	let family = u16::from_ne_bytes([sockaddr[0], sockaddr[1]]) as u32;
+	if family != SocketDomain::AfNetlink.get_id() {
+		return Err(errno!(EINVAL));
+	}
+	Ok(u32::from_ne_bytes(sockaddr[4..8].try_into().unwrap()))
+}
+
+/// Binds the socket to the port ID `port`. If zero, a port ID is chosen, starting with the
+/// process ID.
+///
+/// The function returns the port ID.
+fn do_bind(sock: &Socket, state: &mut NetlinkState, port: u32) -> EResult<u32> {
+	let mut ports = PORTS.lock();
+	let port = if port != 0 {
+		if ports.contains(&port) {
+			return Err(errno!(EADDRINUSE));
+		}
+		port
+	} else {
+		let pid = Process::current().get_pid() as u32;
+		if ports.contains(&pid) {
+			loop {
+				let port = NEXT_PORT.fetch_sub(1, atomic::Ordering::Relaxed);
+				if !ports.contains(&port) {
+					break port;
+				}
+			}
+		} else {
+			pid
+		}
+	};
+	ports.push(port)?;
+	state.port = port;
+	*sock.get_sockname().lock() = Vec::try_from(sockaddr_for(port).as_slice())?;
+	Ok(port)
+}
+
+/// Records the privileges of the process creating the socket, `ap`.
+pub fn open(sock: &Socket, ap: &AccessProfile) {
+	with_state(sock, |state| state.privileged = ap.is_privileged());
+}
+
+/// Binds the socket to the address `sockaddr`.
+///
+/// Multicast groups are not supported: the groups in the address are ignored.
+pub fn bind(sock: &Socket, sockaddr: &[u8]) -> EResult<()> {
+	let port = parse_sockaddr(sockaddr)?;
+	with_state(sock, |state| {
+		if state.port == 0 {
+			do_bind(sock, state, port)?;
+		} else if port != 0 && port != state.port {
+			return Err(errno!(EINVAL));
+		}
+		Ok(())
+	})
+}
+
+/// Sends the messages in `buf` on the socket.
+///
+/// `sockaddr` is the destination address. Only the kernel can be addressed.
+///
+/// The replies are written to the receive buffer of the socket. If they do not fit, the function
+/// returns [`errno::ENOBUFS`].
+pub fn send(sock: &Socket, buf: &[u8], sockaddr: Option<&[u8]>) -> EResult<usize> {
+	if let Some(sockaddr) = sockaddr {
+		if parse_sockaddr(sockaddr)? != 0 {
+			return Err(errno!(ECONNREFUSED));
+		}
+	}
+	// Sockets are bound on first use
+	let (port, privileged) = with_state(sock, |state| {
+		let port = match state.port {
+			0 => do_bind(sock, state, 0)?,
+			port => port,
+		};
+		Ok::<_, Errno>((port, state.privileged))
+	})?;
+	let replies = process(buf, port, privileged)?;
+	{
+		let mut rx_buff = sock.rx_buff().lock();
+		let Some(rx_buff) = rx_buff.as_mut() else {
+			return Ok(buf.len());
+		};
+		let kernel = sockaddr_for(0);
+		for datagram in replies.datagrams {
+			if !push_msg(rx_buff, &kernel, &datagram) {
+				return Err(errno!(ENOBUFS));
+			}
+		}
+	}
+	sock.rx_queue().wake_all();
+	Ok(buf.len())
+}
+
+/// Releases the port ID of the socket.
+pub fn close(sock: &Socket) {
+	with_state(sock, |state| {
+		if state.port != 0 {
+			PORTS.lock().retain(|p| *p != state.port);
+			state.port = 0;
+		}
+	});
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn netlink_attrs() {
+		let req = Request {
+			hdr: NLMsgHdr {
+				nlmsg_len: 0,
+				nlmsg_type: RTM_GETLINK,
+				nlmsg_flags: NLM_F_REQUEST,
+				nlmsg_seq: 42,
+				nlmsg_pid: 0,
+			},
+			payload: &[],
+			port: 1234,
+			privileged: false,
+		};
+		let mut msg = Message::new(&req, RTM_NEWADDR, 0).unwrap();
+		msg.push(as_bytes(&IfAddrMsg::default())).unwrap();
+		msg.attr(IFA_LABEL, b"lo\0").unwrap();
+		msg.attr(IFA_ADDRESS, &[127, 0, 0, 1]).unwrap();
+		let msg = msg.finish();
+		assert_eq!(msg.len(), 16 + 8 + 8 + 8);
+		let hdr = from_bytes::<NLMsgHdr>(&msg).unwrap();
+		assert_eq!({ hdr.nlmsg_len }, msg.len() as u32);
+		assert_eq!({ hdr.nlmsg_seq }, 42);
+		assert_eq!({ hdr.nlmsg_pid }, 1234);
+		let mut iter = attrs::<IfAddrMsg>(&msg[16..]);
+		assert_eq!(iter.next(), Some((IFA_LABEL, &b"lo\0"[..])));
+		assert_eq!(iter.next(), Some((IFA_ADDRESS, &[127, 0, 0, 1][..])));
+		assert_eq!(iter.next(), None);
+		// Truncated attribute
+		assert_eq!(Attrs(&[8, 0, 1, 0, 127]).next(), None);
+	}
+
+	#[test_case]
+	fn netlink_replies() {
+		let req = Request {
+			hdr: NLMsgHdr {
+				nlmsg_len: 16,
+				nlmsg_type: RTM_NEWROUTE,
+				nlmsg_flags: NLM_F_REQUEST | NLM_F_ACK,
+				nlmsg_seq: 1,
+				nlmsg_pid: 0,
+			},
+			payload: &[],
+			port: 1,
+			privileged: false,
+		};
+		let mut part = Vec::new();
+		part.resize(DUMP_SIZE - 16, 0).unwrap();
+		let mut replies = Replies::default();
+		replies.send_error(&req, Some(errno!(EPERM))).unwrap();
+		replies.send_part(part).unwrap();
+		replies.end_parts(&req).unwrap();
+		replies.send_error(&req, None).unwrap();
+		// The end of the dump does not fit in the first part's datagram
+		assert_eq!(replies.datagrams.len(), 4);
+		let err = &replies.datagrams[0];
+		assert_eq!(
+			{ from_bytes::<NLMsgHdr>(err).unwrap().nlmsg_type },
+			NLMSG_ERROR
+		);
+		assert_eq!(
+			i32::from_ne_bytes(err[16..20].try_into().unwrap()),
+			-errno!(EPERM).as_int()
+		);
+		let done = from_bytes::<NLMsgHdr>(&replies.datagrams[2]).unwrap();
+		assert_eq!({ done.nlmsg_type }, NLMSG_DONE);
+		assert_eq!({ done.nlmsg_flags }, NLM_F_MULTI);
+		assert_eq!(replies.datagrams[3].len(), 16 + 4 + 16);
 	}
 }
@@ -18,125 +18,146 @@
 
 //! The Open Systems Interconnection (OSI) model defines the architecure of a network stack.
 
-use super::{buff::BuffList, ip, SocketDesc, SocketDomain, SocketType};
-use crate::sync::mutex::Mutex;
+use super::{
+	buff::BuffList, eth, ip, netlink, packet, tcp, udp, Address, Interface, LinkType, SocketDesc,
+	SocketDomain, SocketType,
+};
+use crate::sync::mutex::{IntMutex, Mutex};
 use core::fmt::Debug;
-use utils::{boxed::Box, collections::hashmap::HashMap, errno, errno::EResult};
+use utils::{collections::hashmap::HashMap, errno, errno::EResult};
 
 /// An OSI layer.
 ///
 /// A layer stack acts as a pipeline, passing data from one layer to the other.
+///
+/// Layers only handle transmission: received packets are not bound to a socket until they have
+/// been demultiplexed by the transport layer, so they go through [`receive_frame`] instead.
 pub trait Layer: Debug {
-	// TODO receive
-
 	/// Transmits data in the given buffer.
 	///
 	/// Arguments:
 	/// - `buff` is the list of buffer which composes the packet being built.
 	/// - `next` is the function called to pass the buffers list to the next layer.
-	fn transmit<'c, F>(&self, buff: BuffList<'c>, next: F) -> EResult<()>
-	where
-		Self: Sized,
-		F: Fn(BuffList<'c>) -> EResult<()>;
+	fn transmit(
+		&self,
+		buff: BuffList<'_>,
+		next: &dyn Fn(BuffList<'_>) -> EResult<()>,
+	) -> EResult<()>;
 }
 
-/// Function used to build a layer from a given sockaddr structure.
-pub type LayerBuilder = fn(&[u8]) -> EResult<Box<dyn Layer>>;
+/// Function handling a packet received by the network layer for a given transport protocol.
+///
+/// Arguments are the source address, the destination address and the packet.
+///
+/// If no socket is bound to the destination port, the handler returns [`errno::ECONNREFUSED`] so
+/// that the network layer reports it to the source.
+pub type ReceiveHandler = fn(Address, Address, &[u8]) -> EResult<()>;
 
-/// Collection of OSI layers 3 (network)
-static DOMAINS: Mutex<HashMap<u32, LayerBuilder>> = Mutex::new(HashMap::new());
-/// Collection of OSI layers 4 (transport)
-static PROTOCOLS: Mutex<HashMap<u32, LayerBuilder>> = Mutex::new(HashMap::new());
+/// Receive handlers, by transport protocol ID.
+///
+/// Reception happens in interrupt context, hence the lock masking interrupts.
+static RECEIVE_HANDLERS: IntMutex<HashMap<u8, ReceiveHandler>> = IntMutex::new(HashMap::new());
 
 /// Collection of default protocols ID for domain/type pairs.
 ///
 /// If this collection doesn't contain a pair, it is considered invalid.
 static DEFAULT_PROTOCOLS: Mutex<HashMap<(u32, SocketType), u32>> = Mutex::new(HashMap::new());
 
-/// A stack of layers for a socket.
-#[derive(Debug)]
-pub struct Stack {
-	/// The socket's protocol on OSI layer 3.
-	pub domain: Box<dyn Layer>,
-	/// The socket's protocol on OSI layer 4.
-	pub protocol: Box<dyn Layer>,
+/// Returns the ID of the transport protocol to be used for the socket with the given descriptor.
+///
+/// If the protocol of the descriptor is zero, the default protocol for the domain/type pair is
+/// returned.
+///
+/// If the domain/type/protocol combination is not supported, the function returns
+/// [`errno::EPROTONOSUPPORT`].
+pub fn get_protocol(desc: &SocketDesc) -> EResult<u32> {
+	if desc.protocol == 0 {
+		DEFAULT_PROTOCOLS
+			.lock()
+			.get(&(desc.domain.get_id(), desc.type_))
+			.cloned()
+			.ok_or_else(|| errno!(EPROTONOSUPPORT))
+	} else if is_supported(desc) {
+		Ok(desc.protocol as _)
+	} else {
+		Err(errno!(EPROTONOSUPPORT))
+	}
 }
 
-impl Stack {
-	/// Creates a new socket network stack.
-	///
-	/// Arguments:
-	/// - `desc` is the descriptor of the socket.
-	/// - `sockaddr` is the socket address structure containing information to initialize the
-	///   stack.
-	///
-	/// If the descriptor is invalid or if the stack cannot be created, the function returns an
-	/// error.
-	pub fn new(desc: &SocketDesc, sockaddr: &[u8]) -> EResult<Stack> {
-		let domain = {
-			let guard = DOMAINS.lock();
-			let builder = guard
-				.get(&desc.domain.get_id())
-				.ok_or_else(|| errno!(EINVAL))?;
-			builder(sockaddr)?
-		};
+/// Tells whether the transport protocol of the descriptor can be used with its domain and type.
+fn is_supported(desc: &SocketDesc) -> bool {
+	let Ok(protocol) = u8::try_from(desc.protocol) else {
+		return false;
+	};
+	let icmp = match desc.domain {
+		SocketDomain::AfInet => ip::PROTO_ICMP,
+		SocketDomain::AfInet6 => ip::PROTO_ICMPV6,
+		_ => return false,
+	};
+	match desc.type_ {
+		SocketType::SockStream => protocol == ip::PROTO_TCP,
+		SocketType::SockDgram => protocol == ip::PROTO_UDP || protocol == icmp,
+		// Only ICMP is supported on raw sockets
+		SocketType::SockRaw => protocol == icmp,
+		SocketType::SockSeqpacket => false,
+	}
+}
 
-		let protocol: u32 = if desc.protocol != 0 {
-			desc.protocol as _
-		} else {
-			*DEFAULT_PROTOCOLS
-				.lock()
-				.get(&(desc.domain.get_id(), desc.type_))
-				.ok_or_else(|| errno!(EINVAL))?
-		};
-		let protocol = {
-			let guard = PROTOCOLS.lock();
-			let builder = guard.get(&protocol).ok_or_else(|| errno!(EINVAL))?;
-			builder(sockaddr)?
-		};
+/// Returns the receive handler for the transport protocol with the given ID.
+pub fn get_receive_handler(protocol: u8) -> Option<ReceiveHandler> {
+	RECEIVE_HANDLERS.lock().get(&protocol).cloned()
+}
 
-		Ok(Stack {
-			domain,
-			protocol,
-		})
+/// Handles the frame `frame` received on the interface `iface`, with the given link type, passing
+/// it up the network stack until it reaches the socket it is destined to.
+pub fn receive_frame(
+	iface: &IntMutex<dyn Interface>,
+	link: LinkType,
+	frame: &[u8],
+) -> EResult<()> {
+	match link {
+		// Loopback frames have no link layer header
+		LinkType::Loopback => ip::receive(iface, frame),
+		LinkType::Ethernet => {
+			packet::receive(iface, frame)?;
+			eth::receive(iface, frame)
+		}
 	}
 }
 
 /// Registers default domains/types/protocols.
 pub(crate) fn init() -> EResult<()> {
-	let domains = HashMap::try_from([
-		// TODO unix
+	let default_protocols = HashMap::try_from([
 		(
-			SocketDomain::AfInet.get_id(),
-			ip::inet_build as LayerBuilder,
+			(SocketDomain::AfInet.get_id(), SocketType::SockStream),
+			ip::PROTO_TCP as u32,
 		),
 		(
-			SocketDomain::AfInet6.get_id(),
-			ip::inet6_build as LayerBuilder,
+			(SocketDomain::AfInet.get_id(), SocketType::SockDgram),
+			ip::PROTO_UDP as u32,
+		),
+		(
+			(SocketDomain::AfInet6.get_id(), SocketType::SockStream),
+			ip::PROTO_TCP as u32,
+		),
+		(
+			(SocketDomain::AfInet6.get_id(), SocketType::SockDgram),
+			ip::PROTO_UDP as u32,
+		),
+		(
+			(SocketDomain::AfNetlink.get_id(), SocketType::SockRaw),
+			netlink::NETLINK_ROUTE,
+		),
+		(
+			(SocketDomain::AfNetlink.get_id(), SocketType::SockDgram),
+			netlink::NETLINK_ROUTE,
 		),
-		// TODO netlink
-		// TODO packet
-	])?;
-	let protocols = HashMap::try_from([
-		// TODO tcp
-		// TODO udp
-	])?;
-	let default_protocols = HashMap::try_from([
-		// TODO unix
-
-		// ((SocketDomain::AfInet.get_id(), SocketType::SockStream.get_id()), /* TODO: ipv4/tcp */),
-		// ((SocketDomain::AfInet.get_id(), SocketType::SockDgram.get_id()), /* TODO: ipv4/udp */),
-
-		// ((SocketDomain::AfInet6.get_id(), SocketType::SockStream.get_id()), /* TODO: ipv6/tcp */),
-		// ((SocketDomain::AfInet6.get_id(), SocketType::SockDgram.get_id()), /* TODO: ipv6/udp */),
-
-		// TODO netlink
-		// TODO packet
 	])?;
 
-	*DOMAINS.lock() = domains;
-	*PROTOCOLS.lock() = protocols;
 	*DEFAULT_PROTOCOLS.lock() = default_protocols;
-
+	*RECEIVE_HANDLERS.lock() = HashMap::try_from([
+		(ip::PROTO_TCP, tcp::receive as ReceiveHandler),
+		(ip::PROTO_UDP, udp::receive as ReceiveHandler),
+	])?;
 	Ok(())
 }
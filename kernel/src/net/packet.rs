@@ -0,0 +1,326 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Packet sockets (`AF_PACKET`) give access to the link layer.
+//!
+//! They receive a copy of every frame matching their protocol, on the interface they are bound to
+//! or on all interfaces, and can transmit frames directly on an interface:
+//! - raw sockets (`SOCK_RAW`) handle whole frames, including the link layer header
+//! - datagram sockets (`SOCK_DGRAM`) handle the payload of frames, the header being built by the
+//!   kernel from the destination address
+//!
+//! Only Ethernet interfaces are supported.
+
+use super::{
+	buff::BuffList, eth, eth::EthernetHeader, netlink::ARPHRD_ETHER, sockaddr::LinkAddr,
+	Interface, LinkType, SocketType,
+};
+use crate::{
+	file::socket::{push_msg, Socket, SocketState},
+	net,
+	sync::mutex::IntMutex,
+};
+use core::{ffi::c_int, mem::size_of, ptr};
+use utils::{
+	bytes::{as_bytes, from_bytes},
+	collections::vec::Vec,
+	errno,
+	errno::{CollectResult, EResult},
+	ptr::arc::Arc,
+};
+
+/// Protocol: Every protocol
+pub const ETH_P_ALL: u16 = 0x0003;
+
+/// Packet type: Destined to the local host
+pub const PACKET_HOST: u8 = 0;
+/// Packet type: Broadcast
+pub const PACKET_BROADCAST: u8 = 1;
+/// Packet type: Multicast
+pub const PACKET_MULTICAST: u8 = 2;
+/// Packet type: Destined to another host
+pub const PACKET_OTHERHOST: u8 = 3;
+/// Packet type: Transmitted by the local host
+pub const PACKET_OUTGOING: u8 = 4;
+
+/// The packet state of a socket.
+#[derive(Debug)]
+pub struct PacketState {
+	/// The protocol of the frames received by the socket, in host byte order. If zero, the socket
+	/// receives nothing. If [`ETH_P_ALL`], it receives all frames.
+	protocol: u16,
+	/// The index of the interface the socket is bound to. If zero, the socket is bound to all
+	/// interfaces.
+	ifindex: u32,
+}
+
+impl PacketState {
+	/// Creates a new state for a socket created with the protocol `protocol`, in network byte
+	/// order.
+	pub fn new(protocol: c_int) -> Self {
+		Self {
+			protocol: u16::from_be(protocol as _),
+			ifindex: 0,
+		}
+	}
+
+	/// Tells whether the socket receives the frame with the given EtherType, on the interface
+	/// with the given index.
+	///
+	/// If `outgoing` is set, the frame has been transmitted by the local host. Such frames are
+	/// only received by sockets using the protocol [`ETH_P_ALL`].
+	fn accepts(&self, ifindex: u32, ethertype: u16, outgoing: bool) -> bool {
+		let protocol = match self.protocol {
+			0 => false,
+			ETH_P_ALL => true,
+			p => !outgoing && p == ethertype,
+		};
+		protocol && (self.ifindex == 0 || self.ifindex == ifindex)
+	}
+}
+
+/// Open packet sockets.
+static SOCKETS: IntMutex<Vec<Arc<Socket>>> = IntMutex::new(Vec::new());
+
+/// Runs `f` with the packet state of the socket.
+fn with_state<F: FnOnce(&mut PacketState) -> R, R>(sock: &Socket, f: F) -> R {
+	let mut state = sock.state().lock();
+	let SocketState::Packet(state) = &mut *state else {
+		unreachable!();
+	};
+	f(state)
+}
+
+/// Returns the index and MAC address of the interface `iface`.
+fn iface_info(iface: &IntMutex<dyn Interface>) -> (u32, [u8; 8]) {
+	let iface = iface.lock();
+	let index = net::get_iface_index(iface.get_name()).unwrap_or(0);
+	let mut addr = [0; 8];
+	addr[..6].copy_from_slice(iface.get_mac());
+	(index, addr)
+}
+
+/// Registers the newly created socket `sock`.
+pub fn open(sock: &Arc<Socket>) -> EResult<()> {
+	SOCKETS.lock().push(sock.clone())?;
+	Ok(())
+}
+
+/// Binds the socket to the interface and protocol in the address `sockaddr`.
+///
+/// If the interface index is zero, the socket is bound to all interfaces. If the protocol is
+/// zero, the protocol of the socket is left unchanged.
+///
+/// If the interface does not exist, the function returns [`errno::ENODEV`].
+pub fn bind(sock: &Arc<Socket>, sockaddr: &[u8]) -> EResult<()> {
+	let addr = LinkAddr::from_bytes(sockaddr)?;
+	let mut name = LinkAddr {
+		ifindex: addr.ifindex,
+		hatype: ARPHRD_ETHER,
+		..Default::default()
+	};
+	if addr.ifindex != 0 {
+		let iface = net::get_iface_by_index(addr.ifindex).ok_or_else(|| errno!(ENODEV))?;
+		(_, name.addr) = iface_info(&iface);
+		name.halen = 6;
+	}
+	name.protocol = with_state(sock, |state| {
+		state.ifindex = addr.ifindex;
+		if addr.protocol != 0 {
+			state.protocol = addr.protocol;
+		}
+		state.protocol
+	});
+	*sock.get_sockname().lock() = name.to_bytes()?;
+	Ok(())
+}
+
+/// Transmits a frame on the socket.
+///
+/// If `sockaddr` is `None`, the interface and protocol the socket is bound to are used.
+///
+/// For raw sockets, `buf` is the whole frame. For datagram sockets, `buf` is the payload and the
+/// destination address is required.
+pub fn send(sock: &Arc<Socket>, buf: &[u8], sockaddr: Option<&[u8]>) -> EResult<usize> {
+	let dst = sockaddr.map(LinkAddr::from_bytes).transpose()?;
+	let (ifindex, protocol) = with_state(sock, |state| match &dst {
+		Some(dst) => (dst.ifindex, dst.protocol),
+		None => (state.ifindex, state.protocol),
+	});
+	let iface = net::get_iface_by_index(ifindex).ok_or_else(|| errno!(ENXIO))?;
+	let raw = sock.desc().type_ == SocketType::SockRaw;
+	let mut frame = Vec::new();
+	{
+		let mut iface = iface.lock();
+		if iface.get_link_type() != LinkType::Ethernet {
+			return Err(errno!(EOPNOTSUPP));
+		}
+		if !iface.is_up() {
+			return Err(errno!(ENETDOWN));
+		}
+		let max = iface.get_mtu() + size_of::<EthernetHeader>();
+		if raw {
+			if buf.len() < size_of::<EthernetHeader>() {
+				return Err(errno!(EINVAL));
+			}
+			frame.extend_from_slice(buf)?;
+		} else {
+			let dst = dst
+				.filter(|dst| dst.halen >= 6)
+				.ok_or_else(|| errno!(EDESTADDRREQ))?;
+			let hdr = EthernetHeader {
+				dst: dst.addr[..6].try_into().unwrap(),
+				src: *iface.get_mac(),
+				ethertype: protocol.to_be(),
+			};
+			frame.extend_from_slice(as_bytes(&hdr))?;
+			frame.extend_from_slice(buf)?;
+		}
+		if frame.len() > max {
+			return Err(errno!(EMSGSIZE));
+		}
+		iface.write(&frame.as_slice().into())?;
+	}
+	deliver(ifindex, &frame, PACKET_OUTGOING, Some(sock))?;
+	Ok(buf.len())
+}
+
+/// Passes a copy of the frame `frame`, received on the interface `iface`, to packet sockets.
+pub fn receive(iface: &IntMutex<dyn Interface>, frame: &[u8]) -> EResult<()> {
+	let Some(hdr) = from_bytes::<EthernetHeader>(frame) else {
+		return Ok(());
+	};
+	let (ifindex, mac) = iface_info(iface);
+	let pkttype = if hdr.dst == eth::BROADCAST {
+		PACKET_BROADCAST
+	} else if hdr.dst[0] & 1 != 0 {
+		PACKET_MULTICAST
+	} else if hdr.dst == mac[..6] {
+		PACKET_HOST
+	} else {
+		PACKET_OTHERHOST
+	};
+	deliver(ifindex, frame, pkttype, None)
+}
+
+/// Passes a copy of the frame `buff`, transmitted on the interface `iface` by the network stack,
+/// to packet sockets.
+pub fn transmit(iface: &IntMutex<dyn Interface>, buff: &BuffList<'_>) -> EResult<()> {
+	// Avoid copying the frame if nobody is listening
+	if SOCKETS.lock().is_empty() {
+		return Ok(());
+	}
+	let frame = buff.to_vec()?;
+	let (ifindex, _) = iface_info(iface);
+	deliver(ifindex, &frame, PACKET_OUTGOING, None)
+}
+
+/// Passes the frame `frame` to the sockets accepting it, except `exclude`.
+///
+/// Arguments:
+/// - `ifindex` is the index of the interface the frame went through
+/// - `pkttype` is the type of the packet
+fn deliver(ifindex: u32, frame: &[u8], pkttype: u8, exclude: Option<&Socket>) -> EResult<()> {
+	let Some(hdr) = from_bytes::<EthernetHeader>(frame) else {
+		return Ok(());
+	};
+	let ethertype = u16::from_be(hdr.ethertype);
+	let socks = SOCKETS
+		.lock()
+		.iter()
+		.filter(|s| exclude.is_none_or(|e| !ptr::eq(Arc::as_ptr(s), e)))
+		.cloned()
+		.collect::<CollectResult<Vec<_>>>()
+		.0?;
+	if socks.is_empty() {
+		return Ok(());
+	}
+	let mut addr = LinkAddr {
+		protocol: ethertype,
+		ifindex,
+		hatype: ARPHRD_ETHER,
+		pkttype,
+		halen: 6,
+		..Default::default()
+	};
+	addr.addr[..6].copy_from_slice(&hdr.src);
+	let addr = addr.to_bytes()?;
+	let outgoing = pkttype == PACKET_OUTGOING;
+	for sock in socks {
+		if !with_state(&sock, |state| state.accepts(ifindex, ethertype, outgoing)) {
+			continue;
+		}
+		let data = match sock.desc().type_ {
+			SocketType::SockRaw => frame,
+			_ => &frame[size_of::<EthernetHeader>()..],
+		};
+		let pushed = sock
+			.rx_buff()
+			.lock()
+			.as_mut()
+			.is_some_and(|rx| push_msg(rx, &addr, data));
+		if pushed {
+			sock.rx_queue().wake_all();
+		}
+	}
+	Ok(())
+}
+
+/// Closes the socket, unregistering it.
+pub fn close(sock: &Socket) {
+	SOCKETS.lock().retain(|s| !ptr::eq(Arc::as_ptr(s), sock));
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn packet_sockaddr() {
+		let addr = LinkAddr {
+			protocol: 0x0800,
+			ifindex: 2,
+			hatype: ARPHRD_ETHER,
+			pkttype: PACKET_BROADCAST,
+			halen: 6,
+			addr: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0, 0],
+		};
+		let bytes = addr.to_bytes().unwrap();
+		assert_eq!(bytes.len(), 20);
+		// The protocol is in network byte order
+		assert_eq!(&bytes[2..4], &[0x08, 0x00]);
+		assert_eq!(LinkAddr::from_bytes(&bytes).unwrap(), addr);
+		assert!(LinkAddr::from_bytes(&bytes[..12]).is_err());
+	}
+
+	#[test_case]
+	fn packet_filter() {
+		let state = PacketState::new((0x0800u16).to_be() as _);
+		assert!(state.accepts(1, 0x0800, false));
+		assert!(!state.accepts(1, 0x0806, false));
+		// Outgoing frames are only seen by sockets receiving all protocols
+		assert!(!state.accepts(1, 0x0800, true));
+		let state = PacketState {
+			protocol: ETH_P_ALL,
+			ifindex: 2,
+		};
+		assert!(state.accepts(2, 0x86dd, true));
+		assert!(!state.accepts(1, 0x86dd, false));
+		assert!(!PacketState::new(0).accepts(1, 0x0800, false));
+	}
+}
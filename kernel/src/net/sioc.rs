@@ -0,0 +1,477 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Implementation of the `SIOC*` socket ioctl requests, which allow to configure the network
+//! interfaces and the routing table the BSD way.
+//!
+//! Only IPv4 is supported through these requests, IPv6 being configured through netlink.
+
+use super::{
+	netlink, netlink::IFF_UP, sockaddr::SockAddr, Address, BindAddress, Interface, Route,
+	ROUTING_TABLE,
+};
+use crate::{
+	file::perm::AccessProfile,
+	net,
+	process::mem_space::copy::{SyscallPtr, SyscallSlice, SyscallString},
+	sync::mutex::IntMutex,
+	syscall::{ioctl, FromSyscallArg},
+};
+use core::{
+	ffi::{c_int, c_short, c_ulong, c_ushort, c_void},
+	mem::size_of,
+};
+use macros::AnyRepr;
+use utils::{bytes::as_bytes, collections::string::String, errno, errno::EResult, TryClone};
+
+/// The maximum length of an interface name, including the terminating NUL byte.
+const IFNAMSIZ: usize = 16;
+/// The size of the union of `struct ifreq`, whose largest member is `struct ifmap`.
+const IFRU_SIZE: usize = 2 * size_of::<c_ulong>() + 8;
+/// The size of `struct sockaddr`.
+const SOCKADDR_SIZE: usize = 16;
+
+/// Route flag: The route is usable
+const RTF_UP: c_ushort = 0x1;
+/// Route flag: The destination is reached through a gateway
+const RTF_GATEWAY: c_ushort = 0x2;
+/// Route flag: The destination is a host
+const RTF_HOST: c_ushort = 0x4;
+
+/// Request on a network interface.
+#[repr(C)]
+#[derive(AnyRepr, Debug)]
+struct IfReq {
+	/// The name of the interface.
+	ifr_name: [u8; IFNAMSIZ],
+	/// The request's parameter, whose type depends on the request.
+	ifr_ifru: [u8; IFRU_SIZE],
+}
+
+/// List of the addresses of network interfaces.
+#[repr(C)]
+#[derive(Debug)]
+struct IfConf {
+	/// The size of the buffer in bytes.
+	ifc_len: c_int,
+	/// Pointer to the buffer, an array of [`IfReq`].
+	ifc_buf: usize,
+}
+
+/// An entry of the routing table.
+#[repr(C)]
+#[derive(Debug)]
+struct RtEntry {
+	/// Padding.
+	rt_pad1: c_ulong,
+	/// The destination.
+	rt_dst: [u8; SOCKADDR_SIZE],
+	/// The gateway.
+	rt_gateway: [u8; SOCKADDR_SIZE],
+	/// The netmask of the destination.
+	rt_genmask: [u8; SOCKADDR_SIZE],
+	/// Route flags.
+	rt_flags: c_ushort,
+	/// Padding.
+	rt_pad2: c_short,
+	/// Padding.
+	rt_pad3: c_ulong,
+	/// Padding.
+	rt_pad4: usize,
+	/// The metric of the route, plus one.
+	rt_metric: c_short,
+	/// Pointer to the name of the interface, if any.
+	rt_dev: usize,
+	/// The MTU of the route.
+	rt_mtu: c_ulong,
+	/// The window size of the route.
+	rt_window: c_ulong,
+	/// The initial round trip time of the route.
+	rt_irtt: c_ushort,
+}
+
+/// Returns the length of the prefix of the IPv4 netmask `mask`.
+///
+/// If the mask is not contiguous, the function returns `None`.
+fn mask_to_prefix(mask: [u8; 4]) -> Option<u8> {
+	let mask = u32::from_be_bytes(mask);
+	let len = mask.leading_ones();
+	(mask.checked_shl(len).unwrap_or(0) == 0).then_some(len as u8)
+}
+
+/// Returns the IPv4 netmask for a prefix of `len` bits.
+fn prefix_to_mask(len: u8) -> [u8; 4] {
+	let mask = BindAddress {
+		addr: Address::IPv4([0xff; 4]),
+		subnet_mask: len,
+	}
+	.network();
+	match mask {
+		Address::IPv4(mask) => mask,
+		Address::IPv6(_) => unreachable!(),
+	}
+}
+
+/// Returns the prefix length of the classful network of the IPv4 address `addr`.
+///
+/// Like Linux, this is used as a default when an address is assigned without a netmask.
+fn classful_prefix(addr: [u8; 4]) -> u8 {
+	match addr[0] {
+		_ if addr == [0; 4] => 0,
+		0..128 => 8,
+		128..192 => 16,
+		192..224 => 24,
+		_ => 32,
+	}
+}
+
+/// Parses the IPv4 `struct sockaddr` `sockaddr`.
+///
+/// If the address is not an IPv4 address, the function returns [`errno::EINVAL`].
+fn parse_sockaddr(sockaddr: &[u8]) -> EResult<[u8; 4]> {
+	match SockAddr::from_bytes(sockaddr) {
+		Ok(SockAddr {
+			addr: Address::IPv4(addr),
+			..
+		}) => Ok(addr),
+		_ => Err(errno!(EINVAL)),
+	}
+}
+
+/// Writes the IPv4 address `addr` into `buf`, as a `struct sockaddr`.
+fn write_sockaddr(buf: &mut [u8], addr: [u8; 4]) -> EResult<()> {
+	let sockaddr = SockAddr {
+		port: 0,
+		addr: Address::IPv4(addr),
+	}
+	.to_bytes()?;
+	buf[..sockaddr.len()].copy_from_slice(&sockaddr);
+	Ok(())
+}
+
+/// Returns the first IPv4 address bound to the interface `iface`.
+fn ipv4_address(iface: &dyn Interface) -> Option<([u8; 4], u8)> {
+	iface.get_addresses().iter().find_map(|a| match a.addr {
+		Address::IPv4(addr) => Some((addr, a.subnet_mask)),
+		Address::IPv6(_) => None,
+	})
+}
+
+/// Replaces the IPv4 address of the interface `iface` named `name` with `addr`.
+///
+/// If `addr` is `None`, the address is removed.
+fn set_ipv4_address(
+	name: &String,
+	iface: &IntMutex<dyn Interface>,
+	addr: Option<BindAddress>,
+) -> EResult<()> {
+	let old = ipv4_address(&*iface.lock());
+	if let Some((old, subnet_mask)) = old {
+		let old = BindAddress {
+			addr: Address::IPv4(old),
+			subnet_mask,
+		};
+		netlink::unbind_address(name.try_clone()?, iface, old)?;
+	}
+	match addr {
+		Some(addr) => netlink::bind_address(name.try_clone()?, iface, addr),
+		None => Ok(()),
+	}
+}
+
+/// Returns the name of the interface designated by the request `req`.
+fn req_name(req: &IfReq) -> &[u8] {
+	let len = req
+		.ifr_name
+		.iter()
+		.position(|b| *b == 0)
+		.unwrap_or(IFNAMSIZ);
+	&req.ifr_name[..len]
+}
+
+/// Handles the request `request` on the interface designated by `req`, updating it with the
+/// result.
+fn iface_request(request: c_ulong, req: &mut IfReq, ap: &AccessProfile) -> EResult<()> {
+	let name = String::try_from(req_name(req))?;
+	let index = net::get_iface_index(&name).ok_or_else(|| errno!(ENODEV))?;
+	let iface = net::get_iface(&name).ok_or_else(|| errno!(ENODEV))?;
+	let set = matches!(
+		request,
+		ioctl::SIOCSIFFLAGS | ioctl::SIOCSIFADDR | ioctl::SIOCSIFNETMASK
+	);
+	if set && !ap.is_privileged() {
+		return Err(errno!(EPERM));
+	}
+	let ifru = &mut req.ifr_ifru;
+	match request {
+		ioctl::SIOCGIFFLAGS => {
+			let (_, flags) = netlink::link_info(&*iface.lock());
+			// The field is a `short`, extended flags are not reported
+			ifru[..2].copy_from_slice(&(flags as c_short).to_ne_bytes());
+		}
+		ioctl::SIOCSIFFLAGS => {
+			let flags = c_short::from_ne_bytes([ifru[0], ifru[1]]) as u16 as u32;
+			net::set_enabled(&iface, flags & IFF_UP != 0)?;
+		}
+		ioctl::SIOCGIFADDR => {
+			let (addr, _) = ipv4_address(&*iface.lock()).ok_or_else(|| errno!(EADDRNOTAVAIL))?;
+			write_sockaddr(ifru, addr)?;
+		}
+		ioctl::SIOCSIFADDR => {
+			let addr = parse_sockaddr(&ifru[..SOCKADDR_SIZE])?;
+			let addr = (addr != [0; 4]).then(|| {
+				// Keep the current netmask, if any
+				let subnet_mask = ipv4_address(&*iface.lock())
+					.map(|(_, subnet_mask)| subnet_mask)
+					.unwrap_or_else(|| classful_prefix(addr));
+				BindAddress {
+					addr: Address::IPv4(addr),
+					subnet_mask,
+				}
+			});
+			set_ipv4_address(&name, &iface, addr)?;
+		}
+		ioctl::SIOCGIFNETMASK => {
+			let (_, prefix) = ipv4_address(&*iface.lock()).ok_or_else(|| errno!(EADDRNOTAVAIL))?;
+			write_sockaddr(ifru, prefix_to_mask(prefix))?;
+		}
+		ioctl::SIOCSIFNETMASK => {
+			let mask = parse_sockaddr(&ifru[..SOCKADDR_SIZE])?;
+			let subnet_mask = mask_to_prefix(mask).ok_or_else(|| errno!(EINVAL))?;
+			let (addr, _) = ipv4_address(&*iface.lock()).ok_or_else(|| errno!(EADDRNOTAVAIL))?;
+			let addr = BindAddress {
+				addr: Address::IPv4(addr),
+				subnet_mask,
+			};
+			set_ipv4_address(&name, &iface, Some(addr))?;
+		}
+		ioctl::SIOCGIFHWADDR => {
+			let iface = iface.lock();
+			let (kind, _) = netlink::link_info(&*iface);
+			ifru[..2].copy_from_slice(&kind.to_ne_bytes());
+			let mac = iface.get_mac();
+			ifru[2..(2 + mac.len())].copy_from_slice(mac);
+		}
+		ioctl::SIOCGIFINDEX => ifru[..4].copy_from_slice(&(index as c_int).to_ne_bytes()),
+		ioctl::SIOCGIFMTU => {
+			let mtu = iface.lock().get_mtu() as c_int;
+			ifru[..4].copy_from_slice(&mtu.to_ne_bytes());
+		}
+		_ => return Err(errno!(ENOTTY)),
+	}
+	Ok(())
+}
+
+/// Handles the `SIOCGIFCONF` request, listing the IPv4 addresses of the interfaces.
+///
+/// If the buffer is null, the function only returns the size required to list all addresses.
+fn get_conf(argp: *const c_void) -> EResult<()> {
+	let conf_ptr = SyscallPtr::<IfConf>::from_ptr(argp as usize);
+	let mut conf = conf_ptr.copy_from_user()?.ok_or_else(|| errno!(EFAULT))?;
+	let buf = SyscallSlice::<u8>::from_ptr(conf.ifc_buf);
+	let capacity = conf.ifc_len.max(0) as usize;
+	let mut off = 0;
+	for (_, name, iface) in net::interfaces()? {
+		let Some((addr, _)) = ipv4_address(&*iface.lock()) else {
+			continue;
+		};
+		if conf.ifc_buf != 0 {
+			if off + size_of::<IfReq>() > capacity {
+				break;
+			}
+			let mut req = IfReq {
+				ifr_name: [0; IFNAMSIZ],
+				ifr_ifru: [0; IFRU_SIZE],
+			};
+			let len = name.len().min(IFNAMSIZ - 1);
+			req.ifr_name[..len].copy_from_slice(&name[..len]);
+			write_sockaddr(&mut req.ifr_ifru, addr)?;
+			buf.copy_to_user(off, as_bytes(&req))?;
+		}
+		off += size_of::<IfReq>();
+	}
+	conf.ifc_len = off as _;
+	conf_ptr.copy_to_user(&conf)?;
+	Ok(())
+}
+
+/// The fields of a `SIOCADDRT` or `SIOCDELRT` request.
+struct RouteRequest {
+	/// The destination. If `None`, this is the default destination.
+	dst: Option<BindAddress>,
+	/// The gateway, if any.
+	gateway: Option<Address>,
+	/// The name of the interface, if specified.
+	dev: Option<String>,
+	/// The metric.
+	metric: u32,
+}
+
+impl RouteRequest {
+	/// Parses the routing table entry `entry`.
+	fn parse(entry: &RtEntry) -> EResult<Self> {
+		let dst = parse_sockaddr(&entry.rt_dst)?;
+		let subnet_mask = if entry.rt_flags & RTF_HOST != 0 {
+			32
+		} else {
+			let mask = parse_sockaddr(&entry.rt_genmask).unwrap_or([0; 4]);
+			mask_to_prefix(mask).ok_or_else(|| errno!(EINVAL))?
+		};
+		let dst = (subnet_mask > 0).then(|| {
+			let dst = BindAddress {
+				addr: Address::IPv4(dst),
+				subnet_mask,
+			};
+			BindAddress {
+				addr: dst.network(),
+				subnet_mask,
+			}
+		});
+		let gateway = if entry.rt_flags & RTF_GATEWAY != 0 {
+			Some(Address::IPv4(parse_sockaddr(&entry.rt_gateway)?))
+		} else {
+			None
+		};
+		Ok(Self {
+			dst,
+			gateway,
+			dev: SyscallString::from_ptr(entry.rt_dev).copy_from_user()?,
+			// Userspace passes the metric plus one
+			metric: entry.rt_metric.saturating_sub(1).max(0) as u32,
+		})
+	}
+
+	/// Tells whether the request matches the route `route`. Unspecified fields match all routes.
+	fn is_matching(&self, route: &Route) -> bool {
+		matches!(route.gateway, Address::IPv4(_))
+			&& route.dst == self.dst
+			&& route.metric == self.metric
+			&& self.gateway.is_none_or(|g| g == route.gateway)
+			&& self.dev.as_ref().is_none_or(|d| *d == route.iface)
+	}
+}
+
+/// Handles the `SIOCADDRT` request.
+fn add_route(r: RouteRequest) -> EResult<()> {
+	let gateway = r.gateway.unwrap_or(Address::IPv4([0; 4]));
+	// Without an interface, the gateway must be reachable
+	let iface = match r.dev {
+		Some(dev) => {
+			net::get_iface(&dev).ok_or_else(|| errno!(ENODEV))?;
+			dev
+		}
+		None if !gateway.is_unspecified() => {
+			let (iface, _) = net::get_route_for(&gateway).ok_or_else(|| errno!(ENETUNREACH))?;
+			let iface = iface.lock();
+			String::try_from(iface.get_name())?
+		}
+		None => return Err(errno!(ENODEV)),
+	};
+	let mut routing_table = ROUTING_TABLE.lock();
+	// Like Linux, routes are identified by their destination and metric
+	let exists = routing_table.iter().any(|route| {
+		matches!(route.gateway, Address::IPv4(_)) && route.dst == r.dst && route.metric == r.metric
+	});
+	if exists {
+		return Err(errno!(EEXIST));
+	}
+	routing_table.push(Route {
+		dst: r.dst,
+		iface,
+		gateway,
+		metric: r.metric,
+	})?;
+	Ok(())
+}
+
+/// Handles the `SIOCDELRT` request.
+fn del_route(r: RouteRequest) -> EResult<()> {
+	let mut routing_table = ROUTING_TABLE.lock();
+	let i = routing_table
+		.iter()
+		.position(|route| r.is_matching(route))
+		.ok_or_else(|| errno!(ESRCH))?;
+	routing_table.remove(i);
+	Ok(())
+}
+
+/// Handles the `SIOCADDRT` and `SIOCDELRT` requests.
+fn route_request(request: c_ulong, argp: *const c_void, ap: &AccessProfile) -> EResult<()> {
+	if !ap.is_privileged() {
+		return Err(errno!(EPERM));
+	}
+	let entry = SyscallPtr::<RtEntry>::from_ptr(argp as usize)
+		.copy_from_user()?
+		.ok_or_else(|| errno!(EFAULT))?;
+	let r = RouteRequest::parse(&entry)?;
+	if request == ioctl::SIOCADDRT {
+		if entry.rt_flags & RTF_UP == 0 {
+			return Err(errno!(EINVAL));
+		}
+		add_route(r)
+	} else {
+		del_route(r)
+	}
+}
+
+/// Handles the `SIOC*` request `request` with the argument `argp`.
+///
+/// `ap` is the access profile of the caller, which must be privileged to change the
+/// configuration.
+///
+/// If the request is not supported, the function returns [`errno::ENOTTY`].
+pub fn ioctl(request: c_ulong, argp: *const c_void, ap: &AccessProfile) -> EResult<u32> {
+	match request {
+		ioctl::SIOCGIFCONF => get_conf(argp)?,
+		ioctl::SIOCADDRT | ioctl::SIOCDELRT => route_request(request, argp, ap)?,
+		ioctl::SIOCGIFFLAGS
+		| ioctl::SIOCSIFFLAGS
+		| ioctl::SIOCGIFADDR
+		| ioctl::SIOCSIFADDR
+		| ioctl::SIOCGIFNETMASK
+		| ioctl::SIOCSIFNETMASK
+		| ioctl::SIOCGIFHWADDR
+		| ioctl::SIOCGIFINDEX
+		| ioctl::SIOCGIFMTU => {
+			let req_ptr = SyscallPtr::<IfReq>::from_ptr(argp as usize);
+			let mut req = req_ptr.copy_from_user()?.ok_or_else(|| errno!(EFAULT))?;
+			iface_request(request, &mut req, ap)?;
+			req_ptr.copy_to_user(&req)?;
+		}
+		_ => return Err(errno!(ENOTTY)),
+	}
+	Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn sioc_netmask() {
+		assert_eq!(mask_to_prefix([0, 0, 0, 0]), Some(0));
+		assert_eq!(mask_to_prefix([255, 255, 255, 0]), Some(24));
+		assert_eq!(mask_to_prefix([255, 255, 255, 255]), Some(32));
+		assert_eq!(mask_to_prefix([255, 0, 255, 0]), None);
+		assert_eq!(prefix_to_mask(0), [0, 0, 0, 0]);
+		assert_eq!(prefix_to_mask(20), [255, 255, 240, 0]);
+		assert_eq!(prefix_to_mask(32), [255; 4]);
+		assert_eq!(classful_prefix([10, 0, 0, 1]), 8);
+		assert_eq!(classful_prefix([192, 168, 1, 1]), 24);
+	}
+}
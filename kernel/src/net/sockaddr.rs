@@ -19,8 +19,29 @@
 //! This module defines sockaddr structures used by system calls to define connection informations
 //! on sockets.
 
-use super::Address;
-use core::ffi::c_short;
+use super::{Address, SocketDomain};
+use core::{
+	cmp::min,
+	ffi::{c_int, c_short, c_uchar, c_ushort},
+	mem::size_of,
+};
+use utils::{
+	collections::vec::Vec,
+	errno,
+	errno::{AllocResult, EResult},
+	vec,
+};
+
+/// Structure providing connection informations for local sockets.
+#[repr(C)]
+#[derive(Clone)]
+pub struct SockAddrUn {
+	/// The family of the socket.
+	sun_family: c_short,
+	/// The path to the socket file. If the first byte is zero, the name is in the abstract
+	/// namespace.
+	sun_path: [u8; 108],
+}
 
 /// Structure providing connection informations for sockets with IPv4.
 #[repr(C)]
@@ -36,6 +57,40 @@ pub struct SockAddrIn {
 	sin_zero: [u8; 8],
 }
 
+/// Structure providing the address of netlink sockets.
+#[repr(C)]
+#[derive(Clone)]
+pub struct SockAddrNl {
+	/// The family of the socket.
+	nl_family: c_short,
+	/// Padding.
+	nl_pad: c_short,
+	/// The port ID of the socket. Zero designates the kernel.
+	nl_pid: u32,
+	/// The mask of multicast groups.
+	nl_groups: u32,
+}
+
+/// Structure providing the link layer address of packet sockets.
+#[repr(C)]
+#[derive(Clone)]
+pub struct SockAddrLl {
+	/// The family of the socket.
+	sll_family: c_ushort,
+	/// The link layer protocol, in network byte order.
+	sll_protocol: c_ushort,
+	/// The index of the interface. Zero designates any interface.
+	sll_ifindex: c_int,
+	/// The ARP hardware type.
+	sll_hatype: c_ushort,
+	/// The packet type.
+	sll_pkttype: c_uchar,
+	/// The length of the hardware address.
+	sll_halen: c_uchar,
+	/// The hardware address.
+	sll_addr: [u8; 8],
+}
+
 /// Structure representing an IPv6 address.
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -62,7 +117,7 @@ pub struct SockAddrIn6 {
 }
 
 /// A unified structure which contains data passed from userspace.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct SockAddr {
 	/// The port used by the socket.
 	pub port: u16,
@@ -70,18 +125,75 @@ pub struct SockAddr {
 	pub addr: Address,
 }
 
+impl SockAddr {
+	/// Parses the given `sockaddr_in` or `sockaddr_in6` structure.
+	///
+	/// If the structure is too small, the function returns [`errno::EINVAL`].
+	///
+	/// If the family is not supported, the function returns [`errno::EAFNOSUPPORT`].
+	pub fn from_bytes(sockaddr: &[u8]) -> EResult<Self> {
+		if sockaddr.len() < size_of::<c_short>() {
+			return Err(errno!(EINVAL));
+		}
+		let family = u16::from_ne_bytes([sockaddr[0], sockaddr[1]]) as u32;
+		let port = |b: &[u8]| u16::from_be_bytes([b[2], b[3]]);
+		if family == SocketDomain::AfInet.get_id() {
+			if sockaddr.len() < size_of::<SockAddrIn>() {
+				return Err(errno!(EINVAL));
+			}
+			Ok(Self {
+				port: port(sockaddr),
+				addr: Address::IPv4(sockaddr[4..8].try_into().unwrap()),
+			})
+		} else if family == SocketDomain::AfInet6.get_id() {
+			// Like Linux, accept the structure without `sin6_scope_id` (RFC 2133)
+			if sockaddr.len() < size_of::<SockAddrIn6>() - size_of::<u32>() {
+				return Err(errno!(EINVAL));
+			}
+			Ok(Self {
+				port: port(sockaddr),
+				addr: Address::IPv6(sockaddr[8..24].try_into().unwrap()),
+			})
+		} else {
+			Err(errno!(EAFNOSUPPORT))
+		}
+	}
+
+	/// Checks the address belongs to the given socket domain.
+	///
+	/// If not, the function returns [`errno::EAFNOSUPPORT`].
+	pub fn check_domain(&self, domain: SocketDomain) -> EResult<()> {
+		match (domain, self.addr) {
+			(SocketDomain::AfInet, Address::IPv4(_))
+			| (SocketDomain::AfInet6, Address::IPv6(_)) => Ok(()),
+			_ => Err(errno!(EAFNOSUPPORT)),
+		}
+	}
+
+	/// Serializes the address into a `sockaddr_in` or `sockaddr_in6` structure, depending on the
+	/// address family.
+	pub fn to_bytes(&self) -> AllocResult<Vec<u8>> {
+		let (family, len) = match self.addr {
+			Address::IPv4(_) => (SocketDomain::AfInet, size_of::<SockAddrIn>()),
+			Address::IPv6(_) => (SocketDomain::AfInet6, size_of::<SockAddrIn6>()),
+		};
+		let mut buf = vec![0u8; len]?;
+		buf[..2].copy_from_slice(&(family.get_id() as u16).to_ne_bytes());
+		buf[2..4].copy_from_slice(&self.port.to_be_bytes());
+		match &self.addr {
+			Address::IPv4(addr) => buf[4..8].copy_from_slice(addr),
+			Address::IPv6(addr) => buf[8..24].copy_from_slice(addr),
+		}
+		Ok(buf)
+	}
+}
+
 impl From<SockAddrIn> for SockAddr {
 	fn from(val: SockAddrIn) -> Self {
-		let addr: [u8; 4] = [
-			((val.sin_addr >> 24) & 0xff) as u8,
-			((val.sin_addr >> 16) & 0xff) as u8,
-			((val.sin_addr >> 8) & 0xff) as u8,
-			(val.sin_addr & 0xff) as u8,
-		];
-
+		// Both fields are in network byte order
 		Self {
-			port: val.sin_port as _,
-			addr: Address::IPv4(addr),
+			port: u16::from_be(val.sin_port as _),
+			addr: Address::IPv4(val.sin_addr.to_ne_bytes()),
 		}
 	}
 }
@@ -91,8 +203,62 @@ impl From<SockAddrIn6> for SockAddr {
 		let addr = unsafe { val.sin6_addr.__s6_addr };
 
 		Self {
-			port: val.sin6_port as _,
+			port: u16::from_be(val.sin6_port as _),
 			addr: Address::IPv6(addr),
 		}
 	}
 }
+
+/// A unified link layer address, for packet sockets.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LinkAddr {
+	/// The link layer protocol (EtherType), in host byte order.
+	pub protocol: u16,
+	/// The index of the interface. Zero designates any interface.
+	pub ifindex: u32,
+	/// The ARP hardware type.
+	pub hatype: u16,
+	/// The packet type.
+	pub pkttype: u8,
+	/// The length of the hardware address.
+	pub halen: u8,
+	/// The hardware address.
+	pub addr: [u8; 8],
+}
+
+impl LinkAddr {
+	/// Parses the given `sockaddr_ll` structure.
+	///
+	/// If the structure is too small or if the family is not `AF_PACKET`, the function returns
+	/// [`errno::EINVAL`].
+	pub fn from_bytes(sockaddr: &[u8]) -> EResult<Self> {
+		if sockaddr.len() < size_of::<SockAddrLl>() {
+			return Err(errno!(EINVAL));
+		}
+		let family = u16::from_ne_bytes([sockaddr[0], sockaddr[1]]) as u32;
+		if family != SocketDomain::AfPacket.get_id() {
+			return Err(errno!(EINVAL));
+		}
+		Ok(Self {
+			protocol: u16::from_be_bytes([sockaddr[2], sockaddr[3]]),
+			ifindex: u32::from_ne_bytes(sockaddr[4..8].try_into().unwrap()),
+			hatype: u16::from_ne_bytes([sockaddr[8], sockaddr[9]]),
+			pkttype: sockaddr[10],
+			halen: min(sockaddr[11], 8),
+			addr: sockaddr[12..20].try_into().unwrap(),
+		})
+	}
+
+	/// Serializes the address into a `sockaddr_ll` structure.
+	pub fn to_bytes(&self) -> AllocResult<Vec<u8>> {
+		let mut buf = vec![0u8; size_of::<SockAddrLl>()]?;
+		buf[..2].copy_from_slice(&(SocketDomain::AfPacket.get_id() as u16).to_ne_bytes());
+		buf[2..4].copy_from_slice(&self.protocol.to_be_bytes());
+		buf[4..8].copy_from_slice(&self.ifindex.to_ne_bytes());
+		buf[8..10].copy_from_slice(&self.hatype.to_ne_bytes());
+		buf[10] = self.pkttype;
+		buf[11] = self.halen;
+		buf[12..20].copy_from_slice(&self.addr);
+		Ok(buf)
+	}
+}
@@ -0,0 +1,593 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Socket options, read and written with the `getsockopt` and `setsockopt` system calls.
+//!
+//! Options are described by a registry, in which each option is identified by its level and
+//! name. An entry gives the type of the option's value, the sockets it applies to, and the
+//! functions used to read and write it.
+
+use super::{
+	ip::{HeaderOptions, TxOptions},
+	tcp,
+	unix::Credentials,
+	SocketDomain,
+};
+use crate::{
+	file::socket::{Socket, SocketState},
+	time::unit::{Timestamp, Timeval},
+};
+use core::{ffi::c_int, mem::size_of};
+use utils::{bytes::as_bytes, collections::vec::Vec, errno, errno::EResult};
+
+/// Option level: Socket
+pub const SOL_SOCKET: c_int = 1;
+/// Option level: IPv4
+pub const IPPROTO_IP: c_int = 0;
+/// Option level: TCP
+pub const IPPROTO_TCP: c_int = 6;
+/// Option level: IPv6
+pub const IPPROTO_IPV6: c_int = 41;
+
+/// Socket option: Allow reusing local addresses
+const SO_REUSEADDR: c_int = 2;
+/// Socket option: The type of the socket
+const SO_TYPE: c_int = 3;
+/// Socket option: The error that caused the connection to be dropped
+const SO_ERROR: c_int = 4;
+/// Socket option: The size of the send buffer
+const SO_SNDBUF: c_int = 7;
+/// Socket option: The size of the receive buffer
+const SO_RCVBUF: c_int = 8;
+/// Socket option: Probe the peer when a connection is idle
+const SO_KEEPALIVE: c_int = 9;
+/// Socket option: Receive the credentials of the sender on local sockets
+const SO_PASSCRED: c_int = 16;
+/// Socket option: The credentials of the peer on local sockets
+const SO_PEERCRED: c_int = 17;
+/// Socket option: Receive timeout
+const SO_RCVTIMEO: c_int = 20;
+/// Socket option: Send timeout
+const SO_SNDTIMEO: c_int = 21;
+/// Socket option: Tells whether the socket is listening
+const SO_ACCEPTCONN: c_int = 30;
+/// Socket option: The protocol of the socket
+const SO_PROTOCOL: c_int = 38;
+/// Socket option: The domain of the socket
+const SO_DOMAIN: c_int = 39;
+
+/// IPv4 socket option: Type of service
+const IP_TOS: c_int = 1;
+/// IPv4 socket option: Time-To-Live
+const IP_TTL: c_int = 2;
+/// IPv4 socket option: Options of the header of packets
+const IP_OPTIONS: c_int = 4;
+/// IPv4 socket option: Path MTU discovery
+const IP_MTU_DISCOVER: c_int = 10;
+
+/// Path MTU discovery: Do not set the Don't Fragment flag
+const IP_PMTUDISC_DONT: c_int = 0;
+/// Path MTU discovery: Set the Don't Fragment flag
+const IP_PMTUDISC_DO: c_int = 2;
+/// Path MTU discovery: Set the Don't Fragment flag, ignoring the path MTU
+const IP_PMTUDISC_PROBE: c_int = 3;
+
+/// TCP socket option: Disable Nagle's algorithm
+const TCP_NODELAY: c_int = 1;
+
+/// IPv6 socket option: Hop limit for unicast packets
+const IPV6_UNICAST_HOPS: c_int = 16;
+/// IPv6 socket option: Traffic class
+const IPV6_TCLASS: c_int = 67;
+
+/// The minimum size of a socket's buffers.
+const BUFFER_MIN: usize = 2048;
+/// The maximum size of a socket's buffers.
+const BUFFER_MAX: usize = 425984;
+
+/// Options of a socket that are not specific to a protocol layer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketOptions {
+	/// Allows binding to an endpoint already in use, unless a socket is listening on it.
+	pub reuse_addr: bool,
+	/// Probes the peer of an idle connection.
+	pub keepalive: bool,
+	/// Sends small segments without waiting for outstanding data to be acknowledged.
+	pub nodelay: bool,
+	/// The receive timeout in milliseconds. If `None`, receiving waits indefinitely.
+	pub rcv_timeout: Option<Timestamp>,
+	/// The send timeout in milliseconds. If `None`, sending waits indefinitely.
+	pub snd_timeout: Option<Timestamp>,
+}
+
+/// The type of the value of an option.
+#[derive(Clone, Copy)]
+enum Kind {
+	/// An `int`. Booleans are represented by integers.
+	Int,
+	/// A duration, represented by a `struct timeval`.
+	Time,
+	/// Process credentials, represented by a `struct ucred`.
+	Creds,
+	/// IPv4 header options, represented by their raw bytes.
+	IpOptions,
+}
+
+/// The value of an option.
+#[derive(Clone, Copy, Debug)]
+pub enum Value {
+	/// An integer.
+	Int(c_int),
+	/// A duration in milliseconds. `None` stands for an infinite duration.
+	Time(Option<Timestamp>),
+	/// Process credentials.
+	Creds(Credentials),
+	/// IPv4 header options.
+	IpOptions(HeaderOptions),
+}
+
+impl Value {
+	/// Parses a value of kind `kind` from the user-provided buffer `buf`.
+	///
+	/// If the buffer is too small, the function returns [`errno::EINVAL`].
+	fn parse(kind: Kind, buf: &[u8]) -> EResult<Self> {
+		match kind {
+			Kind::Int => {
+				let val = buf
+					.get(..size_of::<c_int>())
+					.ok_or_else(|| errno!(EINVAL))?;
+				Ok(Self::Int(c_int::from_ne_bytes(val.try_into().unwrap())))
+			}
+			Kind::Time => {
+				let val = buf
+					.get(..size_of::<Timeval>())
+					.ok_or_else(|| errno!(EINVAL))?;
+				let (sec, usec) = val.split_at(size_of::<Timestamp>());
+				let sec = i64::from_ne_bytes(sec.try_into().unwrap());
+				let usec = i64::from_ne_bytes(usec.try_into().unwrap());
+				if !(0..1000000).contains(&usec) {
+					return Err(errno!(EDOM));
+				}
+				let val = match (sec, usec) {
+					(0, 0) => None,
+					// A negative duration does not wait
+					(..0, _) => Some(0),
+					// Round up to the next millisecond
+					_ => Some((sec as u64).saturating_mul(1000) + (usec as u64).div_ceil(1000)),
+				};
+				Ok(Self::Time(val))
+			}
+			// Credentials cannot be written
+			Kind::Creds => Err(errno!(EINVAL)),
+			Kind::IpOptions => Ok(Self::IpOptions(HeaderOptions::new(buf)?)),
+		}
+	}
+
+	/// Returns the value as an integer.
+	fn int(self) -> c_int {
+		match self {
+			Self::Int(val) => val,
+			_ => 0,
+		}
+	}
+
+	/// Returns the value as a duration.
+	fn time(self) -> Option<Timestamp> {
+		match self {
+			Self::Time(val) => val,
+			_ => None,
+		}
+	}
+
+	/// Returns the representation of the value passed to the user.
+	fn to_bytes(self) -> EResult<Vec<u8>> {
+		let bytes = match self {
+			Self::Int(val) => Vec::try_from(&val.to_ne_bytes()[..])?,
+			Self::Time(val) => {
+				let ms = val.unwrap_or(0);
+				let tv = Timeval {
+					tv_sec: ms / 1000,
+					tv_usec: (ms % 1000) * 1000,
+				};
+				Vec::try_from(as_bytes(&tv))?
+			}
+			Self::Creds(creds) => Vec::try_from(as_bytes(&creds))?,
+			Self::IpOptions(opts) => Vec::try_from(opts.as_slice())?,
+		};
+		Ok(bytes)
+	}
+}
+
+/// An entry of the registry of options.
+struct SocketOpt {
+	/// The level of the option.
+	level: c_int,
+	/// The name of the option.
+	name: c_int,
+	/// The type of the option's value.
+	kind: Kind,
+	/// Tells whether the option applies to the given socket.
+	applies: fn(&Socket) -> bool,
+	/// Reads the value of the option.
+	get: fn(&Socket) -> EResult<Value>,
+	/// Writes the value of the option. If `None`, the option is read-only.
+	set: Option<fn(&Socket, Value) -> EResult<()>>,
+}
+
+/// Applicability predicate of options that apply to all sockets.
+fn any(_: &Socket) -> bool {
+	true
+}
+
+/// Tells whether the socket is a local socket.
+fn is_unix(sock: &Socket) -> bool {
+	sock.desc().domain == SocketDomain::AfUnix
+}
+
+/// Tells whether the socket is a TCP socket.
+fn is_tcp(sock: &Socket) -> bool {
+	matches!(&*sock.state().lock(), SocketState::Tcp(_))
+}
+
+/// Tells whether the socket uses IPv4.
+fn is_inet(sock: &Socket) -> bool {
+	sock.desc().domain == SocketDomain::AfInet
+}
+
+/// Tells whether the socket uses IPv6.
+fn is_inet6(sock: &Socket) -> bool {
+	sock.desc().domain == SocketDomain::AfInet6
+}
+
+/// Returns the size to give to a buffer when the user requests `val` bytes.
+///
+/// Like Linux, the size is doubled to leave room for bookkeeping.
+fn buffer_size(val: c_int) -> usize {
+	(val.max(0) as usize)
+		.saturating_mul(2)
+		.clamp(BUFFER_MIN, BUFFER_MAX)
+}
+
+/// Converts the boolean `b` into an option value.
+fn bool_val(b: bool) -> EResult<Value> {
+	Ok(Value::Int(b as _))
+}
+
+/// Returns a TTL or hop limit given by the user.
+///
+/// `-1` stands for the default value. If the value is out of range, the function returns
+/// [`errno::EINVAL`].
+fn parse_ttl(val: c_int) -> EResult<u8> {
+	match val {
+		-1 => Ok(TxOptions::default().ttl),
+		1..=255 => Ok(val as _),
+		_ => Err(errno!(EINVAL)),
+	}
+}
+
+/// The registry of options.
+static OPTIONS: &[SocketOpt] = &[
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_REUSEADDR,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| bool_val(sock.opts().lock().reuse_addr),
+		set: Some(|sock, val| {
+			sock.opts().lock().reuse_addr = val.int() != 0;
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_TYPE,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| Ok(Value::Int(sock.desc().type_.get_id() as _)),
+		set: None,
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_ERROR,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| {
+			let err = match &*sock.state().lock() {
+				SocketState::Tcp(state) => state.tcb().and_then(|tcb| tcb.error()),
+				_ => None,
+			};
+			Ok(Value::Int(err.map_or(0, |e| e.as_int())))
+		},
+		set: None,
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_SNDBUF,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| {
+			let size = sock.tx_buff().lock().as_ref().map_or(0, |b| b.get_size());
+			Ok(Value::Int(size as _))
+		},
+		set: Some(|sock, val| sock.resize_tx_buff(buffer_size(val.int()))),
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_RCVBUF,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| {
+			let size = sock.rx_buff().lock().as_ref().map_or(0, |b| b.get_size());
+			Ok(Value::Int(size as _))
+		},
+		set: Some(|sock, val| sock.resize_rx_buff(buffer_size(val.int()))),
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_KEEPALIVE,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| bool_val(sock.opts().lock().keepalive),
+		set: Some(|sock, val| {
+			sock.opts().lock().keepalive = val.int() != 0;
+			if is_tcp(sock) {
+				tcp::update_opts(sock);
+			}
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_PASSCRED,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| match &*sock.state().lock() {
+			SocketState::Unix(state) => bool_val(state.passcred),
+			_ => bool_val(false),
+		},
+		set: Some(|sock, val| {
+			if let SocketState::Unix(state) = &mut *sock.state().lock() {
+				state.passcred = val.int() != 0;
+			}
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_PEERCRED,
+		kind: Kind::Creds,
+		applies: is_unix,
+		get: |sock| {
+			let creds = match &*sock.state().lock() {
+				SocketState::Unix(state) => state.peer_creds().copied(),
+				_ => None,
+			};
+			// Like Linux, report invalid credentials if there is no peer
+			Ok(Value::Creds(creds.unwrap_or(Credentials {
+				pid: 0,
+				uid: u32::MAX,
+				gid: u32::MAX,
+			})))
+		},
+		set: None,
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_RCVTIMEO,
+		kind: Kind::Time,
+		applies: any,
+		get: |sock| Ok(Value::Time(sock.opts().lock().rcv_timeout)),
+		set: Some(|sock, val| {
+			sock.opts().lock().rcv_timeout = val.time();
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_SNDTIMEO,
+		kind: Kind::Time,
+		applies: any,
+		get: |sock| Ok(Value::Time(sock.opts().lock().snd_timeout)),
+		set: Some(|sock, val| {
+			sock.opts().lock().snd_timeout = val.time();
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_ACCEPTCONN,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| bool_val(sock.is_listening()),
+		set: None,
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_PROTOCOL,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| Ok(Value::Int(sock.desc().protocol)),
+		set: None,
+	},
+	SocketOpt {
+		level: SOL_SOCKET,
+		name: SO_DOMAIN,
+		kind: Kind::Int,
+		applies: any,
+		get: |sock| Ok(Value::Int(sock.desc().domain.get_id() as _)),
+		set: None,
+	},
+	SocketOpt {
+		level: IPPROTO_IP,
+		name: IP_TOS,
+		kind: Kind::Int,
+		applies: is_inet,
+		get: |sock| Ok(Value::Int(sock.ip_opts().lock().tos as _)),
+		set: Some(|sock, val| {
+			sock.ip_opts().lock().tos = val.int() as _;
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: IPPROTO_IP,
+		name: IP_TTL,
+		kind: Kind::Int,
+		applies: is_inet,
+		get: |sock| Ok(Value::Int(sock.ip_opts().lock().ttl as _)),
+		set: Some(|sock, val| {
+			sock.ip_opts().lock().ttl = parse_ttl(val.int())?;
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: IPPROTO_IP,
+		name: IP_OPTIONS,
+		kind: Kind::IpOptions,
+		applies: is_inet,
+		get: |sock| Ok(Value::IpOptions(sock.ip_opts().lock().options)),
+		set: Some(|sock, val| {
+			if let Value::IpOptions(opts) = val {
+				sock.ip_opts().lock().options = opts;
+			}
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: IPPROTO_IP,
+		name: IP_MTU_DISCOVER,
+		kind: Kind::Int,
+		applies: is_inet,
+		get: |sock| {
+			let dont_fragment = sock.ip_opts().lock().dont_fragment;
+			Ok(Value::Int(if dont_fragment {
+				IP_PMTUDISC_DO
+			} else {
+				IP_PMTUDISC_DONT
+			}))
+		},
+		set: Some(|sock, val| {
+			let val = val.int();
+			if !(0..=5).contains(&val) {
+				return Err(errno!(EINVAL));
+			}
+			sock.ip_opts().lock().dont_fragment =
+				matches!(val, IP_PMTUDISC_DO | IP_PMTUDISC_PROBE);
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: IPPROTO_TCP,
+		name: TCP_NODELAY,
+		kind: Kind::Int,
+		applies: is_tcp,
+		get: |sock| bool_val(sock.opts().lock().nodelay),
+		set: Some(|sock, val| {
+			sock.opts().lock().nodelay = val.int() != 0;
+			tcp::update_opts(sock);
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: IPPROTO_IPV6,
+		name: IPV6_UNICAST_HOPS,
+		kind: Kind::Int,
+		applies: is_inet6,
+		get: |sock| Ok(Value::Int(sock.ip_opts().lock().ttl as _)),
+		set: Some(|sock, val| {
+			sock.ip_opts().lock().ttl = parse_ttl(val.int())?;
+			Ok(())
+		}),
+	},
+	SocketOpt {
+		level: IPPROTO_IPV6,
+		name: IPV6_TCLASS,
+		kind: Kind::Int,
+		applies: is_inet6,
+		get: |sock| Ok(Value::Int(sock.ip_opts().lock().tos as _)),
+		set: Some(|sock, val| {
+			sock.ip_opts().lock().tos = match val.int() {
+				-1 => 0,
+				val @ 0..=255 => val as _,
+				_ => return Err(errno!(EINVAL)),
+			};
+			Ok(())
+		}),
+	},
+];
+
+/// Returns the entry of the registry for the option `name` at level `level`, if it applies to
+/// the socket `sock`.
+///
+/// If the option does not exist, the function returns [`errno::ENOPROTOOPT`].
+fn lookup(sock: &Socket, level: c_int, name: c_int) -> EResult<&'static SocketOpt> {
+	OPTIONS
+		.iter()
+		.find(|opt| opt.level == level && opt.name == name && (opt.applies)(sock))
+		.ok_or_else(|| errno!(ENOPROTOOPT))
+}
+
+/// Reads the option `name` at level `level` on `sock`, returning the value to pass to the user.
+pub fn get(sock: &Socket, level: c_int, name: c_int) -> EResult<Vec<u8>> {
+	let opt = lookup(sock, level, name)?;
+	(opt.get)(sock)?.to_bytes()
+}
+
+/// Writes the option `name` at level `level` on `sock`, with the user-provided value `optval`.
+///
+/// If the value is invalid, the function returns [`errno::EINVAL`]. If the option is read-only,
+/// the function returns [`errno::ENOPROTOOPT`].
+pub fn set(sock: &Socket, level: c_int, name: c_int, optval: &[u8]) -> EResult<()> {
+	let opt = lookup(sock, level, name)?;
+	let set = opt.set.ok_or_else(|| errno!(ENOPROTOOPT))?;
+	let val = Value::parse(opt.kind, optval)?;
+	set(sock, val)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn sockopt_timeval() {
+		let mut buf = Vec::new();
+		buf.extend_from_slice(&2i64.to_ne_bytes()).unwrap();
+		buf.extend_from_slice(&500001i64.to_ne_bytes()).unwrap();
+		let Value::Time(val) = Value::parse(Kind::Time, &buf).unwrap() else {
+			panic!();
+		};
+		assert_eq!(val, Some(2501));
+		let bytes = Value::Time(val).to_bytes().unwrap();
+		assert_eq!(&bytes[..8], &2u64.to_ne_bytes());
+		assert_eq!(&bytes[8..], &501000u64.to_ne_bytes());
+		// Zero stands for no timeout
+		buf.clear();
+		buf.resize(16, 0).unwrap();
+		assert!(matches!(
+			Value::parse(Kind::Time, &buf).unwrap(),
+			Value::Time(None)
+		));
+		assert!(Value::parse(Kind::Time, &buf[..8]).is_err());
+	}
+
+	#[test_case]
+	fn sockopt_buffer_size() {
+		assert_eq!(buffer_size(-1), BUFFER_MIN);
+		assert_eq!(buffer_size(4096), 8192);
+		assert_eq!(buffer_size(c_int::MAX), BUFFER_MAX);
+	}
+}
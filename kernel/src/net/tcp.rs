@@ -18,12 +18,93 @@
 
 //! The Transmission Control Protocol (TCP) is a protocol transmitting sequenced, reliable,
 //! two-way, connection-based byte streams.
+//!
+//! The state machine of a connection is implemented by [`Tcb`], which is independent from
+//! sockets and network interfaces. The rest of the module binds it to sockets.
+//!
+//! Segments received out of order are dropped, relying on the peer to retransmit them.
 
-use super::{buff::BuffList, osi::Layer};
-use crate::file::socket::Socket;
-use utils::errno::EResult;
+use super::{
+	buff::BuffList, ip, ip::TxOptions, osi::Layer, sockaddr::SockAddr, sockopt::SocketOptions,
+	Address,
+};
+use crate::{
+	crypto::rand::ENTROPY_POOL,
+	file::{
+		perm::AccessProfile,
+		socket::{Socket, SocketState},
+	},
+	net,
+	process::{signal::Signal, Process},
+	sync::mutex::IntMutex,
+	time::{
+		clock::{current_time, CLOCK_MONOTONIC},
+		timer,
+		unit::TimestampScale,
+	},
+};
+use core::{cmp::min, mem::size_of};
+use macros::AnyRepr;
+use utils::{
+	bytes::{as_bytes, from_bytes},
+	collections::{hashmap::HashMap, ring_buffer::RingBuffer, vec::Vec},
+	errno,
+	errno::{AllocResult, CollectResult, EResult, Errno},
+	ptr::arc::Arc,
+	vec,
+};
+
+/// Flag: No more data from sender.
+const FLAG_FIN: u8 = 0x01;
+/// Flag: Synchronize sequence numbers.
+const FLAG_SYN: u8 = 0x02;
+/// Flag: Reset the connection.
+const FLAG_RST: u8 = 0x04;
+/// Flag: Push function.
+const FLAG_PSH: u8 = 0x08;
+/// Flag: The acknowledgment field is significant.
+const FLAG_ACK: u8 = 0x10;
+
+/// Option: End of options list.
+const OPT_END: u8 = 0;
+/// Option: No operation.
+const OPT_NOP: u8 = 1;
+/// Option: Maximum Segment Size.
+const OPT_MSS: u8 = 2;
+
+/// The MSS to use if the peer does not specify one.
+const DEFAULT_MSS: u16 = 536;
+/// The MSS advertised to peers.
+const LOCAL_MSS: u16 = 1460;
+
+/// The period of the TCP timer, in milliseconds.
+const TICK_PERIOD: u64 = 100;
+/// The initial retransmission timeout, in ticks.
+const RTO_INIT: u32 = 10;
+/// The maximum retransmission timeout, in ticks.
+const RTO_MAX: u32 = 600;
+/// The number of retransmissions after which the connection is dropped.
+const MAX_RETRIES: u32 = 8;
+/// The duration of the TIME-WAIT state, in ticks (two times a Maximum Segment Lifetime of 30
+/// seconds).
+const TIME_WAIT_DURATION: u32 = 600;
+/// The idle time after which keepalive probes are sent, in ticks (two hours).
+const KEEPALIVE_IDLE: u32 = 72000;
+/// The interval between two keepalive probes, in ticks.
+const KEEPALIVE_INTERVAL: u32 = 750;
+/// The number of unanswered keepalive probes after which the connection is dropped.
+const KEEPALIVE_PROBES: u32 = 9;
+
+/// The first port of the range used for ephemeral ports.
+const EPHEMERAL_BEGIN: u16 = 49152;
+/// The first privileged port.
+const PRIVILEGED_END: u16 = 1024;
+
+/// Type of the buffers used by connections.
+pub type Buffer = RingBuffer<u8, Vec<u8>>;
 
 /// The TCP segment header.
+#[derive(AnyRepr, Clone, Copy)]
 #[repr(C, packed)]
 pub struct TCPHdr {
 	/// Source port.
@@ -34,7 +115,7 @@ pub struct TCPHdr {
 	/// Sequence number.
 	seq_nbr: u32,
 
-	/// TODO doc
+	/// If the ACK flag is set, the next sequence number the sender is expecting to receive.
 	ack_nbr: u32,
 
 	/// The size of the header in units of 4 bytes.
@@ -43,31 +124,1625 @@ pub struct TCPHdr {
 	data_offset: u8,
 	/// The segment's flags.
 	flags: u8,
-	/// TODO doc
+	/// The number of bytes the sender is willing to receive.
 	win_size: u16,
 
-	/// TODO doc
+	/// The checksum of the segment, including a pseudo-header from the network layer.
 	checksum: u16,
-	/// TODO doc
+	/// Urgent pointer. Unsupported.
 	urg_ptr: u16,
 }
 
+/// A TCP segment, independent of its binary representation.
+#[derive(Debug)]
+pub struct Segment {
+	/// Sequence number.
+	pub seq: u32,
+	/// Acknowledgement number. Significant only with the ACK flag.
+	pub ack: u32,
+	/// The segment's flags.
+	pub flags: u8,
+	/// The window advertised by the sender.
+	pub wnd: u16,
+	/// The value of the MSS option, if present.
+	pub mss: Option<u16>,
+	/// The segment's data.
+	pub data: Vec<u8>,
+}
+
+impl Segment {
+	/// Returns the length of the segment in the sequence space.
+	pub fn seq_len(&self) -> u32 {
+		self.data.len() as u32
+			+ (self.flags & FLAG_SYN != 0) as u32
+			+ (self.flags & FLAG_FIN != 0) as u32
+	}
+
+	/// Tells whether the segment has the given flag.
+	fn has(&self, flag: u8) -> bool {
+		self.flags & flag != 0
+	}
+
+	/// Parses a segment from its binary representation `buf`.
+	///
+	/// On success, the function returns the source port, destination port and segment. If the
+	/// segment is invalid, the function returns `None`.
+	fn parse(buf: &[u8]) -> AllocResult<Option<(u16, u16, Self)>> {
+		let Some(hdr) = from_bytes::<TCPHdr>(buf) else {
+			return Ok(None);
+		};
+		let hdr_len = (hdr.data_offset >> 4) as usize * 4;
+		if hdr_len < size_of::<TCPHdr>() || hdr_len > buf.len() {
+			return Ok(None);
+		}
+		// Parse options
+		let mut mss = None;
+		let mut opts = &buf[size_of::<TCPHdr>()..hdr_len];
+		while let Some(kind) = opts.first() {
+			match *kind {
+				OPT_END => break,
+				OPT_NOP => opts = &opts[1..],
+				kind => {
+					let Some(len) = opts.get(1).map(|l| *l as usize) else {
+						break;
+					};
+					if len < 2 || len > opts.len() {
+						return Ok(None);
+					}
+					if kind == OPT_MSS && len == 4 {
+						mss = Some(u16::from_be_bytes([opts[2], opts[3]]));
+					}
+					opts = &opts[len..];
+				}
+			}
+		}
+		let seg = Self {
+			seq: u32::from_be(hdr.seq_nbr),
+			ack: u32::from_be(hdr.ack_nbr),
+			flags: hdr.flags,
+			wnd: u16::from_be(hdr.win_size),
+			mss,
+			data: Vec::try_from(&buf[hdr_len..])?,
+		};
+		Ok(Some((
+			u16::from_be(hdr.src_port),
+			u16::from_be(hdr.dst_port),
+			seg,
+		)))
+	}
+
+	/// Returns the binary representation of the segment, without checksum.
+	fn serialize(&self, src_port: u16, dst_port: u16) -> AllocResult<Vec<u8>> {
+		let opts_len = if self.mss.is_some() { 4 } else { 0 };
+		let hdr_len = size_of::<TCPHdr>() + opts_len;
+		let hdr = TCPHdr {
+			src_port: src_port.to_be(),
+			dst_port: dst_port.to_be(),
+
+			seq_nbr: self.seq.to_be(),
+			ack_nbr: self.ack.to_be(),
+
+			data_offset: ((hdr_len / 4) as u8) << 4,
+			flags: self.flags,
+			win_size: self.wnd.to_be(),
+
+			checksum: 0,
+			urg_ptr: 0,
+		};
+		let mut buf = Vec::with_capacity(hdr_len + self.data.len())?;
+		buf.extend_from_slice(as_bytes(&hdr))?;
+		if let Some(mss) = self.mss {
+			buf.extend_from_slice(&[OPT_MSS, 4])?;
+			buf.extend_from_slice(&mss.to_be_bytes())?;
+		}
+		buf.extend_from_slice(&self.data)?;
+		Ok(buf)
+	}
+}
+
+/// Tells whether the sequence number `a` is before `b`.
+fn seq_lt(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) < 0
+}
+
+/// Tells whether the sequence number `a` is before or equal to `b`.
+fn seq_le(a: u32, b: u32) -> bool {
+	!seq_lt(b, a)
+}
+
+/// The state of a TCP connection (RFC 9293).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum State {
+	/// No connection.
+	Closed,
+	/// Waiting for a connection request.
+	Listen,
+	/// Waiting for a matching connection request after having sent a connection request.
+	SynSent,
+	/// Waiting for an acknowledgement of a connection request after having both received and sent
+	/// one.
+	SynReceived,
+	/// The connection is open.
+	Established,
+	/// Waiting for a connection termination request from the peer, or for an acknowledgement of
+	/// the connection termination request previously sent.
+	FinWait1,
+	/// Waiting for a connection termination request from the peer.
+	FinWait2,
+	/// Waiting for a connection termination request from the local user.
+	CloseWait,
+	/// Waiting for an acknowledgement of the connection termination request from the peer.
+	Closing,
+	/// Waiting for an acknowledgement of the connection termination request previously sent to
+	/// the peer, after the peer did the same.
+	LastAck,
+	/// Waiting for enough time to pass to be sure the peer received the acknowledgement of its
+	/// connection termination request.
+	TimeWait,
+}
+
+/// Transmission Control Block, the state of a connection.
+///
+/// Functions processing the connection take the following arguments when relevant:
+/// - `tx` is the buffer of data to be sent. It starts with the first unacknowledged byte
+/// - `rx` is the buffer of received data
+/// - `out` is the list to which segments to be transmitted are appended
+#[derive(Debug)]
+pub struct Tcb {
+	/// The state of the connection.
+	state: State,
+
+	/// Initial send sequence number.
+	iss: u32,
+	/// Oldest unacknowledged sequence number.
+	snd_una: u32,
+	/// Next sequence number to be sent.
+	snd_nxt: u32,
+	/// The window advertised by the peer.
+	snd_wnd: u32,
+	/// Sequence number of the segment used for the last window update.
+	snd_wl1: u32,
+	/// Acknowledgement number of the segment used for the last window update.
+	snd_wl2: u32,
+	/// The maximum size of a segment's data for the peer.
+	snd_mss: u16,
+
+	/// Next sequence number expected on an incoming segment.
+	rcv_nxt: u32,
+	/// The last window advertised to the peer.
+	rcv_wnd: u32,
+
+	/// Tells whether the user closed the sending side, meaning a FIN is to be sent after the
+	/// data.
+	fin_queued: bool,
+	/// The sequence number of the FIN, if sent.
+	fin_seq: Option<u32>,
+	/// Tells whether an acknowledgement must be sent.
+	ack_now: bool,
+	/// If `true`, small segments are sent without waiting for outstanding data to be
+	/// acknowledged (Nagle's algorithm disabled).
+	pub nodelay: bool,
+	/// If `true`, the peer is probed when the connection is idle (`SO_KEEPALIVE`).
+	pub keepalive: bool,
+
+	/// The retransmission timeout, in ticks.
+	rto: u32,
+	/// The remaining ticks before retransmission. If `None`, the timer is stopped.
+	rtx_timer: Option<u32>,
+	/// The number of retransmissions of the oldest unacknowledged segment.
+	retries: u32,
+	/// The remaining ticks before leaving the TIME-WAIT state.
+	time_wait: u32,
+	/// The number of ticks since the last segment has been received.
+	idle: u32,
+	/// The number of keepalive probes sent without an answer.
+	probes: u32,
+
+	/// The error that caused the connection to be dropped.
+	error: Option<Errno>,
+}
+
+impl Tcb {
+	/// Creates a connection in the given state, with the initial sequence number `iss`.
+	fn new(state: State, iss: u32) -> Self {
+		Self {
+			state,
+
+			iss,
+			snd_una: iss,
+			snd_nxt: iss.wrapping_add(1),
+			snd_wnd: 0,
+			snd_wl1: 0,
+			snd_wl2: 0,
+			snd_mss: DEFAULT_MSS,
+
+			rcv_nxt: 0,
+			rcv_wnd: 0,
+
+			fin_queued: false,
+			fin_seq: None,
+			ack_now: false,
+			nodelay: false,
+			keepalive: false,
+
+			rto: RTO_INIT,
+			rtx_timer: Some(RTO_INIT),
+			retries: 0,
+			time_wait: 0,
+			idle: 0,
+			probes: 0,
+
+			error: None,
+		}
+	}
+
+	/// Initiates a connection (active open), sending a SYN.
+	pub fn connect(iss: u32, rx_space: usize, out: &mut Vec<Segment>) -> AllocResult<Self> {
+		let mut tcb = Self::new(State::SynSent, iss);
+		out.push(tcb.make_seg(iss, FLAG_SYN, Vec::new(), rx_space))?;
+		Ok(tcb)
+	}
+
+	/// Accepts the connection request `syn` received on a listening socket (passive open),
+	/// sending a SYN-ACK.
+	pub fn accept(
+		iss: u32,
+		syn: &Segment,
+		rx_space: usize,
+		out: &mut Vec<Segment>,
+	) -> AllocResult<Self> {
+		let mut tcb = Self::new(State::SynReceived, iss);
+		tcb.rcv_nxt = syn.seq.wrapping_add(1);
+		tcb.snd_wnd = syn.wnd as _;
+		tcb.snd_wl1 = syn.seq;
+		tcb.snd_mss = syn.mss.unwrap_or(DEFAULT_MSS);
+		out.push(tcb.make_seg(iss, FLAG_SYN | FLAG_ACK, Vec::new(), rx_space))?;
+		Ok(tcb)
+	}
+
+	/// Returns the state of the connection.
+	pub fn state(&self) -> State {
+		self.state
+	}
+
+	/// Returns the error that caused the connection to be dropped, if any.
+	pub fn error(&self) -> Option<Errno> {
+		self.error
+	}
+
+	/// Tells whether the connection has been established, even if it has been closed since.
+	pub fn is_synchronized(&self) -> bool {
+		!matches!(
+			self.state,
+			State::Closed | State::Listen | State::SynSent | State::SynReceived
+		)
+	}
+
+	/// Tells whether the user can still send data on the connection.
+	pub fn can_send(&self) -> bool {
+		matches!(self.state, State::Established | State::CloseWait) && !self.fin_queued
+	}
+
+	/// Tells whether the peer has closed its sending side, or if the connection has been dropped.
+	pub fn is_eof(&self) -> bool {
+		matches!(
+			self.state,
+			State::Closed | State::CloseWait | State::LastAck | State::Closing | State::TimeWait
+		)
+	}
+
+	/// Builds a segment with the given sequence number, flags and data.
+	fn make_seg(&mut self, seq: u32, flags: u8, data: Vec<u8>, rx_space: usize) -> Segment {
+		let wnd = min(rx_space, u16::MAX as usize) as u16;
+		self.rcv_wnd = wnd as _;
+		if flags & FLAG_ACK != 0 {
+			self.ack_now = false;
+		}
+		Segment {
+			seq,
+			ack: if flags & FLAG_ACK != 0 {
+				self.rcv_nxt
+			} else {
+				0
+			},
+			flags,
+			wnd,
+			mss: (flags & FLAG_SYN != 0).then_some(LOCAL_MSS),
+			data,
+		}
+	}
+
+	/// Builds a reset segment in response to `seg`, which has been received on a port with no
+	/// associated connection.
+	pub fn reset_for(seg: &Segment) -> Option<Segment> {
+		if seg.has(FLAG_RST) {
+			return None;
+		}
+		let (seq, ack, flags) = if seg.has(FLAG_ACK) {
+			(seg.ack, 0, FLAG_RST)
+		} else {
+			(0, seg.seq.wrapping_add(seg.seq_len()), FLAG_RST | FLAG_ACK)
+		};
+		Some(Segment {
+			seq,
+			ack,
+			flags,
+			wnd: 0,
+			mss: None,
+			data: Vec::new(),
+		})
+	}
+
+	/// Enters the TIME-WAIT state.
+	fn enter_time_wait(&mut self) {
+		self.state = State::TimeWait;
+		self.time_wait = TIME_WAIT_DURATION;
+		self.rtx_timer = None;
+	}
+
+	/// Drops the connection, reporting `error` to the user.
+	fn drop_connection(&mut self, error: Option<Errno>) {
+		self.state = State::Closed;
+		self.rtx_timer = None;
+		if self.error.is_none() {
+			self.error = error;
+		}
+	}
+
+	/// Arms the retransmission timer if not running.
+	fn arm_timer(&mut self) {
+		if self.rtx_timer.is_none() {
+			self.rtx_timer = Some(self.rto);
+		}
+	}
+
+	/// Returns the number of data bytes sent but not acknowledged yet.
+	fn data_in_flight(&self) -> usize {
+		let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+		in_flight - self.fin_seq.is_some() as usize
+	}
+
+	/// Sends as much data as possible from `tx`, along with a FIN if requested by the user.
+	///
+	/// If an acknowledgement is pending and no segment has been sent, an empty one is sent.
+	pub fn output(
+		&mut self,
+		tx: &mut Buffer,
+		rx_space: usize,
+		out: &mut Vec<Segment>,
+	) -> AllocResult<()> {
+		let can_send = matches!(
+			self.state,
+			State::Established
+				| State::CloseWait
+				| State::FinWait1
+				| State::Closing
+				| State::LastAck
+		);
+		while can_send && self.fin_seq.is_none() {
+			let in_flight = self.data_in_flight();
+			let unsent = tx.get_data_len().saturating_sub(in_flight);
+			let wnd_avail = (self.snd_wnd as usize).saturating_sub(in_flight);
+			let len = min(min(unsent, wnd_avail), self.snd_mss as usize);
+			let fin = self.fin_queued && len == unsent;
+			if len == 0 && !fin {
+				// If the peer's window is closed, arm the timer to probe it later
+				if unsent > 0 && in_flight == 0 {
+					self.arm_timer();
+				}
+				break;
+			}
+			// Nagle's algorithm: do not send small segments while data is outstanding
+			if !self.nodelay && !fin && len < self.snd_mss as usize && in_flight > 0 {
+				break;
+			}
+			let mut data = vec![0; len]?;
+			tx.peek_at(in_flight, &mut data);
+			let mut flags = FLAG_ACK;
+			if len > 0 {
+				flags |= FLAG_PSH;
+			}
+			if fin {
+				flags |= FLAG_FIN;
+				self.fin_seq = Some(self.snd_nxt.wrapping_add(len as _));
+			}
+			let seq = self.snd_nxt;
+			let seg = self.make_seg(seq, flags, data, rx_space);
+			self.snd_nxt = self.snd_nxt.wrapping_add(seg.seq_len());
+			self.arm_timer();
+			out.push(seg)?;
+		}
+		if self.ack_now {
+			let seq = self.snd_nxt;
+			let seg = self.make_seg(seq, FLAG_ACK, Vec::new(), rx_space);
+			out.push(seg)?;
+		}
+		Ok(())
+	}
+
+	/// Retransmits the oldest unacknowledged segment, or probes the peer's window.
+	fn retransmit(
+		&mut self,
+		tx: &mut Buffer,
+		rx_space: usize,
+		out: &mut Vec<Segment>,
+	) -> AllocResult<()> {
+		let seg = match self.state {
+			State::SynSent => self.make_seg(self.iss, FLAG_SYN, Vec::new(), rx_space),
+			State::SynReceived => {
+				self.make_seg(self.iss, FLAG_SYN | FLAG_ACK, Vec::new(), rx_space)
+			}
+			State::Established
+			| State::CloseWait
+			| State::FinWait1
+			| State::Closing
+			| State::LastAck => {
+				let in_flight = self.data_in_flight();
+				let len = if in_flight == 0 && self.fin_seq.is_none() {
+					// Window probe: send one byte beyond the window
+					if tx.is_empty() {
+						return Ok(());
+					}
+					self.snd_nxt = self.snd_nxt.wrapping_add(1);
+					1
+				} else {
+					min(in_flight.max(1), self.snd_mss as usize)
+				};
+				let len = min(len, tx.get_data_len());
+				let mut data = vec![0; len]?;
+				tx.peek_at(0, &mut data);
+				let mut flags = FLAG_ACK;
+				if len > 0 {
+					flags |= FLAG_PSH;
+				}
+				if self.fin_seq == Some(self.snd_una.wrapping_add(len as _)) {
+					flags |= FLAG_FIN;
+				}
+				let seq = self.snd_una;
+				self.make_seg(seq, flags, data, rx_space)
+			}
+			_ => return Ok(()),
+		};
+		out.push(seg)?;
+		Ok(())
+	}
+
+	/// Handles a tick of the TCP timer, retransmitting segments if necessary.
+	pub fn tick(
+		&mut self,
+		tx: &mut Buffer,
+		rx_space: usize,
+		out: &mut Vec<Segment>,
+	) -> AllocResult<()> {
+		if self.state == State::TimeWait {
+			self.time_wait = self.time_wait.saturating_sub(1);
+			if self.time_wait == 0 {
+				self.state = State::Closed;
+			}
+			return Ok(());
+		}
+		let Some(timer) = self.rtx_timer else {
+			return self.keepalive_tick(rx_space, out);
+		};
+		if timer > 1 {
+			self.rtx_timer = Some(timer - 1);
+			return Ok(());
+		}
+		self.retries += 1;
+		if self.retries > MAX_RETRIES {
+			if self.is_synchronized() {
+				let seq = self.snd_nxt;
+				let seg = self.make_seg(seq, FLAG_RST, Vec::new(), rx_space);
+				out.push(seg)?;
+			}
+			self.drop_connection(Some(errno!(ETIMEDOUT)));
+			return Ok(());
+		}
+		self.rto = min(self.rto * 2, RTO_MAX);
+		self.rtx_timer = Some(self.rto);
+		self.retransmit(tx, rx_space, out)
+	}
+
+	/// Handles a tick of the keepalive timer, while no data is waiting for acknowledgement.
+	fn keepalive_tick(&mut self, rx_space: usize, out: &mut Vec<Segment>) -> AllocResult<()> {
+		if !self.keepalive || self.state != State::Established {
+			return Ok(());
+		}
+		self.idle = self.idle.saturating_add(1);
+		if self.idle < KEEPALIVE_IDLE + self.probes * KEEPALIVE_INTERVAL {
+			return Ok(());
+		}
+		if self.probes >= KEEPALIVE_PROBES {
+			let seq = self.snd_nxt;
+			let seg = self.make_seg(seq, FLAG_RST, Vec::new(), rx_space);
+			out.push(seg)?;
+			self.drop_connection(Some(errno!(ETIMEDOUT)));
+			return Ok(());
+		}
+		self.probes += 1;
+		// A segment with an already acknowledged sequence number forces the peer to answer
+		let seq = self.snd_una.wrapping_sub(1);
+		let seg = self.make_seg(seq, FLAG_ACK, Vec::new(), rx_space);
+		out.push(seg)?;
+		Ok(())
+	}
+
+	/// Closes the sending side of the connection. Data remaining in `tx` is sent before the
+	/// FIN.
+	pub fn close(
+		&mut self,
+		tx: &mut Buffer,
+		rx_space: usize,
+		out: &mut Vec<Segment>,
+	) -> AllocResult<()> {
+		match self.state {
+			State::Closed | State::Listen | State::SynSent => self.drop_connection(None),
+			State::SynReceived | State::Established => {
+				self.fin_queued = true;
+				self.state = State::FinWait1;
+			}
+			State::CloseWait => {
+				self.fin_queued = true;
+				self.state = State::LastAck;
+			}
+			_ => {}
+		}
+		self.output(tx, rx_space, out)
+	}
+
+	/// Drops the connection, sending a reset to the peer if necessary.
+	pub fn abort(&mut self, rx_space: usize, out: &mut Vec<Segment>) -> AllocResult<()> {
+		if matches!(
+			self.state,
+			State::SynReceived
+				| State::Established
+				| State::FinWait1
+				| State::FinWait2
+				| State::CloseWait
+		) {
+			let seq = self.snd_nxt;
+			let seg = self.make_seg(seq, FLAG_RST, Vec::new(), rx_space);
+			out.push(seg)?;
+		}
+		self.drop_connection(None);
+		Ok(())
+	}
+
+	/// Sends a window update if the user read enough data from the receive buffer since the last
+	/// advertisement.
+	pub fn window_update(
+		&mut self,
+		tx: &mut Buffer,
+		rx_space: usize,
+		out: &mut Vec<Segment>,
+	) -> AllocResult<()> {
+		let opened = (rx_space as u32).saturating_sub(self.rcv_wnd);
+		let receiving = matches!(
+			self.state,
+			State::Established | State::FinWait1 | State::FinWait2
+		);
+		if receiving && opened >= self.snd_mss as u32 && self.rcv_wnd < self.snd_mss as u32 {
+			self.ack_now = true;
+			self.output(tx, rx_space, out)?;
+		}
+		Ok(())
+	}
+
+	/// Handles the segment `seg` in the SYN-SENT state.
+	fn input_syn_sent(
+		&mut self,
+		seg: &Segment,
+		tx: &mut Buffer,
+		rx_space: usize,
+		out: &mut Vec<Segment>,
+	) -> AllocResult<()> {
+		if seg.has(FLAG_ACK) && (seq_le(seg.ack, self.iss) || seq_lt(self.snd_nxt, seg.ack)) {
+			if let Some(rst) = Self::reset_for(seg) {
+				out.push(rst)?;
+			}
+			return Ok(());
+		}
+		if seg.has(FLAG_RST) {
+			if seg.has(FLAG_ACK) {
+				self.drop_connection(Some(errno!(ECONNREFUSED)));
+			}
+			return Ok(());
+		}
+		if !seg.has(FLAG_SYN) {
+			return Ok(());
+		}
+		self.rcv_nxt = seg.seq.wrapping_add(1);
+		self.snd_mss = seg.mss.unwrap_or(DEFAULT_MSS);
+		if seg.has(FLAG_ACK) {
+			self.snd_una = seg.ack;
+		}
+		if seq_lt(self.iss, self.snd_una) {
+			self.state = State::Established;
+			self.snd_wnd = seg.wnd as _;
+			self.snd_wl1 = seg.seq;
+			self.snd_wl2 = seg.ack;
+			self.retries = 0;
+			self.rto = RTO_INIT;
+			self.rtx_timer = None;
+			self.ack_now = true;
+			self.output(tx, rx_space, out)
+		} else {
+			// Simultaneous open
+			self.state = State::SynReceived;
+			let seg = self.make_seg(self.iss, FLAG_SYN | FLAG_ACK, Vec::new(), rx_space);
+			out.push(seg)?;
+			Ok(())
+		}
+	}
+
+	/// Handles the incoming segment `seg`.
+	pub fn input(
+		&mut self,
+		seg: &Segment,
+		tx: &mut Buffer,
+		rx: &mut Buffer,
+		out: &mut Vec<Segment>,
+	) -> AllocResult<()> {
+		match self.state {
+			State::Closed | State::Listen => return Ok(()),
+			State::SynSent => return self.input_syn_sent(seg, tx, rx.get_available_len(), out),
+			_ => {}
+		}
+		self.idle = 0;
+		self.probes = 0;
+		// Check the segment is in the receive window
+		let rcv_wnd = min(rx.get_available_len(), u16::MAX as usize) as u32;
+		let in_window = |seq: u32| {
+			seq_le(self.rcv_nxt, seq) && seq_lt(seq, self.rcv_nxt.wrapping_add(rcv_wnd))
+		};
+		let acceptable = match (seg.seq_len(), rcv_wnd) {
+			(0, 0) => seg.seq == self.rcv_nxt,
+			(0, _) => in_window(seg.seq),
+			(_, 0) => false,
+			(len, _) => in_window(seg.seq) || in_window(seg.seq.wrapping_add(len - 1)),
+		};
+		// If the window is closed, acknowledgements must still be processed
+		if !(acceptable || rcv_wnd == 0 && seg.seq == self.rcv_nxt) {
+			if !seg.has(FLAG_RST) {
+				self.ack_now = true;
+				if self.state == State::TimeWait {
+					self.time_wait = TIME_WAIT_DURATION;
+				}
+				self.output(tx, rx.get_available_len(), out)?;
+			}
+			return Ok(());
+		}
+		if seg.has(FLAG_RST) {
+			let error = match self.state {
+				State::SynReceived => Some(errno!(ECONNREFUSED)),
+				State::Established | State::FinWait1 | State::FinWait2 | State::CloseWait => {
+					Some(errno!(ECONNRESET))
+				}
+				_ => None,
+			};
+			self.drop_connection(error);
+			return Ok(());
+		}
+		if seg.has(FLAG_SYN) {
+			self.abort(rx.get_available_len(), out)?;
+			self.error = Some(errno!(ECONNRESET));
+			return Ok(());
+		}
+		if !seg.has(FLAG_ACK) {
+			return Ok(());
+		}
+		// Process acknowledgement
+		if self.state == State::SynReceived {
+			if seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_nxt) {
+				self.state = State::Established;
+				self.snd_wnd = seg.wnd as _;
+				self.snd_wl1 = seg.seq;
+				self.snd_wl2 = seg.ack;
+			} else {
+				if let Some(rst) = Self::reset_for(seg) {
+					out.push(rst)?;
+				}
+				return Ok(());
+			}
+		}
+		if seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_nxt) {
+			let mut acked = seg.ack.wrapping_sub(self.snd_una) as usize;
+			// Do not count the SYN and FIN, which are not in the buffer
+			if self.snd_una == self.iss {
+				acked -= 1;
+			}
+			if self.fin_seq.is_some_and(|fin| seq_lt(fin, seg.ack)) {
+				acked -= 1;
+			}
+			tx.skip(acked);
+			self.snd_una = seg.ack;
+			self.retries = 0;
+			self.rto = RTO_INIT;
+			self.rtx_timer = (self.snd_una != self.snd_nxt).then_some(self.rto);
+		} else if seq_lt(self.snd_nxt, seg.ack) {
+			// Acknowledgement of data that has not been sent yet
+			self.ack_now = true;
+			return self.output(tx, rx.get_available_len(), out);
+		}
+		// Update the send window
+		if seq_lt(self.snd_wl1, seg.seq)
+			|| (self.snd_wl1 == seg.seq && seq_le(self.snd_wl2, seg.ack))
+		{
+			self.snd_wnd = seg.wnd as _;
+			self.snd_wl1 = seg.seq;
+			self.snd_wl2 = seg.ack;
+		}
+		let fin_acked = self.fin_seq.is_some_and(|fin| seq_lt(fin, self.snd_una));
+		match self.state {
+			State::FinWait1 if fin_acked => self.state = State::FinWait2,
+			State::Closing if fin_acked => self.enter_time_wait(),
+			State::LastAck if fin_acked => {
+				self.drop_connection(None);
+				return Ok(());
+			}
+			_ => {}
+		}
+		// Process data
+		let mut end = seg.seq;
+		if acceptable {
+			let receiving = matches!(
+				self.state,
+				State::Established | State::FinWait1 | State::FinWait2
+			);
+			if receiving && !seg.data.is_empty() {
+				self.ack_now = true;
+				let skip = self.rcv_nxt.wrapping_sub(seg.seq) as usize;
+				// Segments starting after the next expected byte are dropped
+				if seq_le(seg.seq, self.rcv_nxt) && skip < seg.data.len() {
+					let len = rx.write(&seg.data[skip..]);
+					self.rcv_nxt = self.rcv_nxt.wrapping_add(len as _);
+				}
+			}
+			end = end.wrapping_add(seg.data.len() as _);
+		}
+		// Process FIN
+		if acceptable && seg.has(FLAG_FIN) && end == self.rcv_nxt {
+			self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+			self.ack_now = true;
+			match self.state {
+				State::SynReceived | State::Established => self.state = State::CloseWait,
+				State::FinWait1 if fin_acked => self.enter_time_wait(),
+				State::FinWait1 => self.state = State::Closing,
+				State::FinWait2 | State::TimeWait => self.enter_time_wait(),
+				_ => {}
+			}
+		}
+		self.output(tx, rx.get_available_len(), out)
+	}
+}
+
 /// The network layer for the TCP protocol.
+///
+/// The layer takes segments without checksum and fills it.
 #[derive(Debug)]
-pub struct TCPLayer {}
+pub struct TCPLayer {
+	/// The source address.
+	pub src_addr: Address,
+	/// The destination address.
+	pub dst_addr: Address,
+}
 
 impl Layer for TCPLayer {
-	fn transmit<'c, F>(&self, _buff: BuffList<'c>, _next: F) -> EResult<()>
-	where
-		F: Fn(BuffList<'c>) -> EResult<()>,
-	{
-		// TODO
-		todo!();
+	fn transmit(
+		&self,
+		buff: BuffList<'_>,
+		next: &dyn Fn(BuffList<'_>) -> EResult<()>,
+	) -> EResult<()> {
+		let mut seg = buff.to_vec()?;
+		if seg.len() < size_of::<TCPHdr>() {
+			return Err(errno!(EINVAL));
+		}
+		let checksum =
+			ip::pseudo_header_checksum(ip::PROTO_TCP, &self.src_addr, &self.dst_addr, &seg)?;
+		seg[16..18].copy_from_slice(&checksum.to_ne_bytes());
+		next(seg.as_slice().into())
+	}
+}
+
+/// Transmits the segment `seg` from `src` to `dst`, with the transmission parameters `opts`.
+fn transmit(src: &SockAddr, dst: &SockAddr, opts: &TxOptions, seg: &Segment) -> EResult<()> {
+	let buf = seg.serialize(src.port, dst.port)?;
+	let layer = TCPLayer {
+		src_addr: src.addr,
+		dst_addr: dst.addr,
+	};
+	layer.transmit(buf.as_slice().into(), &|buff| {
+		ip::transmit(ip::PROTO_TCP, src.addr, dst.addr, opts, buff)
+	})
+}
+
+/// Transmits all the segments in `out` from `src` to `dst`, with the transmission parameters
+/// `opts`.
+///
+/// Transmission errors are ignored since lost segments are retransmitted.
+fn transmit_all(src: &SockAddr, dst: &SockAddr, opts: &TxOptions, out: &[Segment]) {
+	for seg in out {
+		let _ = transmit(src, dst, opts, seg);
+	}
+}
+
+/// Returns an initial sequence number for a new connection (RFC 6528).
+fn gen_iss() -> u32 {
+	// The clock makes the sequence number grow every 4 microseconds
+	let ts = current_time(CLOCK_MONOTONIC, TimestampScale::Microsecond).unwrap_or(0) / 4;
+	let mut rand = [0u8; 4];
+	if let Some(pool) = ENTROPY_POOL.lock().as_mut() {
+		pool.read(&mut rand, true);
 	}
+	(ts as u32).wrapping_add(u32::from_ne_bytes(rand))
 }
 
-/// Initiates a TCP connection on the given socket `sock`.
-pub fn init_connection(_sock: &mut Socket) -> EResult<()> {
-	// TODO
-	todo!();
+/// The TCP state of a socket.
+#[derive(Debug, Default)]
+pub struct TcpState {
+	/// The local address and port, if bound.
+	local: Option<SockAddr>,
+	/// The remote address and port, if connected.
+	remote: Option<SockAddr>,
+	/// The endpoint registered in [`BINDS`], if any.
+	bound: Option<SockAddr>,
+	/// The connection, if any.
+	tcb: Option<Tcb>,
+	/// For a connection that has not been accepted yet, the listening socket that received it.
+	listener: Option<Arc<Socket>>,
+	/// Tells whether the socket has been closed by the user.
+	orphan: bool,
+}
+
+impl TcpState {
+	/// Returns the connection, if any.
+	pub fn tcb(&self) -> Option<&Tcb> {
+		self.tcb.as_ref()
+	}
+
+	/// Returns the remote address and port, if connected.
+	pub fn remote(&self) -> Option<&SockAddr> {
+		self.remote.as_ref()
+	}
+
+	/// Returns the local address and port, if bound.
+	pub fn local(&self) -> Option<&SockAddr> {
+		self.local.as_ref()
+	}
+
+	/// Tells whether the end of the stream has been reached, returning the error to report to
+	/// the user if any.
+	pub fn eof(&self) -> Option<EResult<usize>> {
+		let tcb = self.tcb.as_ref()?;
+		if let Some(e) = tcb.error() {
+			return Some(Err(e));
+		}
+		tcb.is_eof().then_some(Ok(0))
+	}
+}
+
+/// Established connections, by local and remote endpoints.
+static CONNECTIONS: IntMutex<HashMap<(SockAddr, SockAddr), Arc<Socket>>> =
+	IntMutex::new(HashMap::new());
+/// Listening sockets, by local endpoint.
+static LISTENERS: IntMutex<HashMap<SockAddr, Arc<Socket>>> = IntMutex::new(HashMap::new());
+/// Bound local endpoints.
+static BINDS: IntMutex<HashMap<SockAddr, Bind>> = IntMutex::new(HashMap::new());
+
+/// A bound local endpoint.
+#[derive(Debug)]
+struct Bind {
+	/// The number of sockets bound to the endpoint.
+	count: usize,
+	/// Tells whether the sockets bound to the endpoint allow sharing it (`SO_REUSEADDR`).
+	reuse: bool,
+}
+
+/// Returns the list of listening sockets, followed by the sockets with a connection.
+pub fn sockets() -> AllocResult<Vec<Arc<Socket>>> {
+	let mut socks = LISTENERS
+		.lock()
+		.iter()
+		.map(|(_, s)| s.clone())
+		.collect::<CollectResult<Vec<_>>>()
+		.0?;
+	for (_, s) in CONNECTIONS.lock().iter() {
+		socks.push(s.clone())?;
+	}
+	Ok(socks)
+}
+
+/// Applies the socket options `opts` to the connection `tcb`.
+fn apply_opts(opts: &SocketOptions, tcb: &mut Tcb) {
+	tcb.nodelay = opts.nodelay;
+	tcb.keepalive = opts.keepalive;
+}
+
+/// Applies the socket's options to its connection, after they have been changed by the user.
+pub fn update_opts(sock: &Socket) {
+	let opts = *sock.opts().lock();
+	with_state(sock, |state| {
+		if let Some(tcb) = state.tcb.as_mut() {
+			apply_opts(&opts, tcb);
+		}
+	});
+}
+
+/// Runs `f` with the TCP state of the socket.
+fn with_state<F: FnOnce(&mut TcpState) -> R, R>(sock: &Socket, f: F) -> R {
+	let mut state = sock.state().lock();
+	let SocketState::Tcp(state) = &mut *state else {
+		unreachable!();
+	};
+	f(state)
+}
+
+/// Tells whether the local endpoint `addr` conflicts with a bound one.
+///
+/// If `reuse` is set, endpoints whose sockets allow sharing them do not conflict, unless a
+/// socket is listening on it.
+fn is_used(binds: &HashMap<SockAddr, Bind>, addr: &SockAddr, reuse: bool) -> bool {
+	let listeners = LISTENERS.lock();
+	binds.iter().any(|(b, bind)| {
+		let overlap = b.port == addr.port
+			&& (b.addr == addr.addr || b.addr.is_unspecified() || addr.addr.is_unspecified());
+		let shared = reuse && bind.reuse && !listeners.contains_key(b);
+		overlap && !shared
+	})
+}
+
+/// Registers the local endpoint `addr`. If the port is zero, an ephemeral port is allocated.
+///
+/// `reuse` tells whether the endpoint may be shared with other sockets (`SO_REUSEADDR`).
+///
+/// On success, the function returns the registered endpoint.
+fn register_bind(mut addr: SockAddr, reuse: bool) -> EResult<SockAddr> {
+	let mut binds = BINDS.lock();
+	if addr.port == 0 {
+		addr.port = (EPHEMERAL_BEGIN..=u16::MAX)
+			.find(|port| {
+				!is_used(
+					&binds,
+					&SockAddr {
+						port: *port,
+						addr: addr.addr,
+					},
+					false,
+				)
+			})
+			.ok_or_else(|| errno!(EADDRINUSE))?;
+	} else if is_used(&binds, &addr, reuse) {
+		return Err(errno!(EADDRINUSE));
+	}
+	match binds.get_mut(&addr) {
+		Some(bind) => bind.count += 1,
+		None => {
+			binds.insert(
+				addr,
+				Bind {
+					count: 1,
+					reuse,
+				},
+			)?;
+		}
+	}
+	Ok(addr)
+}
+
+/// Binds the socket to the address `sockaddr`.
+pub fn bind(sock: &Socket, sockaddr: &[u8], ap: &AccessProfile) -> EResult<()> {
+	let addr = SockAddr::from_bytes(sockaddr)?;
+	addr.check_domain(sock.desc().domain)?;
+	if addr.port != 0 && addr.port < PRIVILEGED_END && !ap.is_privileged() {
+		return Err(errno!(EACCES));
+	}
+	if !addr.addr.is_unspecified() && !net::is_local_address(&addr.addr) {
+		return Err(errno!(EADDRNOTAVAIL));
+	}
+	let reuse = sock.opts().lock().reuse_addr;
+	with_state(sock, |state| {
+		if state.local.is_some() {
+			return Err(errno!(EINVAL));
+		}
+		let addr = register_bind(addr, reuse)?;
+		state.local = Some(addr);
+		state.bound = Some(addr);
+		*sock.get_sockname().lock() = addr.to_bytes()?;
+		Ok(())
+	})
+}
+
+/// Marks the socket as listening for connections.
+///
+/// If the socket is not bound, it is bound to an ephemeral port.
+pub fn listen(sock: &Arc<Socket>) -> EResult<()> {
+	with_state(sock, |state| {
+		if state.tcb.is_some() {
+			return Err(errno!(EINVAL));
+		}
+		let local = match state.local {
+			Some(local) => local,
+			None => {
+				let any = match sock.desc().domain {
+					net::SocketDomain::AfInet6 => Address::IPv6([0; 16]),
+					_ => Address::IPv4([0; 4]),
+				};
+				let local = register_bind(
+					SockAddr {
+						port: 0,
+						addr: any,
+					},
+					false,
+				)?;
+				state.local = Some(local);
+				state.bound = Some(local);
+				*sock.get_sockname().lock() = local.to_bytes()?;
+				local
+			}
+		};
+		let mut listeners = LISTENERS.lock();
+		match listeners.get(&local) {
+			// The endpoint is shared with a listening socket
+			Some(s) if !core::ptr::eq(Arc::as_ptr(s), Arc::as_ptr(sock)) => {
+				return Err(errno!(EADDRINUSE));
+			}
+			Some(_) => {}
+			None => {
+				listeners.insert(local, sock.clone())?;
+			}
+		}
+		Ok(())
+	})
+}
+
+/// Connects the socket to the address `sockaddr`, waiting for the connection to be established.
+///
+/// If `nonblock` is set, the function returns [`errno::EINPROGRESS`] after sending the connection
+/// request instead of waiting. Completion is then reported by polling for `POLLOUT`, and the
+/// outcome by the `SO_ERROR` option.
+pub fn connect(sock: &Arc<Socket>, sockaddr: &[u8], nonblock: bool) -> EResult<()> {
+	let remote = SockAddr::from_bytes(sockaddr)?;
+	remote.check_domain(sock.desc().domain)?;
+	if sock.is_listening() {
+		return Err(errno!(EINVAL));
+	}
+	let (local, out) = with_state(sock, |state| {
+		if let Some(tcb) = &state.tcb {
+			return Err(match tcb.state() {
+				State::SynSent | State::SynReceived => errno!(EALREADY),
+				_ => errno!(EISCONN),
+			});
+		}
+		// Select the local endpoint
+		let src = match state.local {
+			Some(local) if !local.addr.is_unspecified() => local.addr,
+			_ => net::get_src_addr_for(&remote.addr).ok_or_else(|| errno!(ENETUNREACH))?,
+		};
+		let local = match state.local {
+			Some(local) => SockAddr {
+				port: local.port,
+				addr: src,
+			},
+			None => {
+				let local = register_bind(
+					SockAddr {
+						port: 0,
+						addr: src,
+					},
+					false,
+				)?;
+				state.bound = Some(local);
+				local
+			}
+		};
+		let mut connections = CONNECTIONS.lock();
+		if connections.contains_key(&(local, remote)) {
+			return Err(errno!(EADDRINUSE));
+		}
+		let mut out = Vec::new();
+		let rx_space = sock
+			.rx_buff()
+			.lock()
+			.as_ref()
+			.map_or(0, |b| b.get_available_len());
+		let mut tcb = Tcb::connect(gen_iss(), rx_space, &mut out)?;
+		apply_opts(&sock.opts().lock(), &mut tcb);
+		connections.insert((local, remote), sock.clone())?;
+		state.local = Some(local);
+		state.remote = Some(remote);
+		state.tcb = Some(tcb);
+		*sock.get_sockname().lock() = local.to_bytes()?;
+		Ok((local, out))
+	})?;
+	transmit_all(&local, &remote, &sock.ip_opts().lock(), &out);
+	// Wait for the connection to be established
+	let res = sock
+		.tx_queue()
+		.wait_until_timeout(nonblock.then_some(0), || {
+			with_state(sock, |state| {
+				let tcb = state.tcb.as_ref()?;
+				match tcb.state() {
+					State::SynSent | State::SynReceived => None,
+					State::Closed => Some(Err(tcb.error().unwrap_or(errno!(ECONNREFUSED)))),
+					_ => Some(Ok(())),
+				}
+			})
+		});
+	match res {
+		Err(e) if nonblock && e.as_int() == errno::EAGAIN => Err(errno!(EINPROGRESS)),
+		res => res?,
+	}
+}
+
+/// Sends the data in `buf` on the connected socket `sock`.
+///
+/// The function blocks until all the data has been written in the send buffer, unless `nonblock`
+/// is set or the send timeout expires. In which case, the function returns the length of the
+/// data written before blocking, or [`errno::EAGAIN`] if nothing could be written.
+pub fn send(sock: &Socket, buf: &[u8], nonblock: bool) -> EResult<usize> {
+	let timeout = sock.snd_timeout(nonblock);
+	let mut off = 0;
+	while off < buf.len() {
+		let res = sock.tx_queue().wait_until_timeout(timeout, || {
+			with_state(sock, |state| {
+				let Some(tcb) = state.tcb.as_mut() else {
+					return Some(Err(errno!(ENOTCONN)));
+				};
+				if let Some(e) = tcb.error() {
+					return Some(Err(e));
+				}
+				if !tcb.can_send() {
+					if tcb.is_synchronized() {
+						Process::current().kill(Signal::SIGPIPE);
+						return Some(Err(errno!(EPIPE)));
+					}
+					return Some(Err(errno!(ENOTCONN)));
+				}
+				let mut tx_buff = sock.tx_buff().lock();
+				let tx = tx_buff.as_mut()?;
+				let len = tx.write(&buf[off..]);
+				if len == 0 {
+					return None;
+				}
+				let rx_space = sock
+					.rx_buff()
+					.lock()
+					.as_ref()
+					.map_or(0, |b| b.get_available_len());
+				let mut out = Vec::new();
+				if let Err(e) = tcb.output(tx, rx_space, &mut out) {
+					return Some(Err(e.into()));
+				}
+				Some(Ok((len, (state.local?, state.remote?), out)))
+			})
+		});
+		let (len, endpoints, out) = match res {
+			Err(e) if off > 0 && matches!(e.as_int(), errno::EAGAIN | errno::EINTR) => break,
+			res => res??,
+		};
+		transmit_all(&endpoints.0, &endpoints.1, &sock.ip_opts().lock(), &out);
+		off += len;
+	}
+	Ok(off)
+}
+
+/// Runs `f` on the connection of the socket, then transmits the resulting segments.
+fn process<F>(sock: &Socket, f: F)
+where
+	F: FnOnce(&mut Tcb, &mut Buffer, &mut Buffer, &mut Vec<Segment>) -> AllocResult<()>,
+{
+	let res = with_state(sock, |state| {
+		let tcb = state.tcb.as_mut()?;
+		let (local, remote) = (state.local?, state.remote?);
+		let mut tx_buff = sock.tx_buff().lock();
+		let mut rx_buff = sock.rx_buff().lock();
+		let (Some(tx), Some(rx)) = (tx_buff.as_mut(), rx_buff.as_mut()) else {
+			return None;
+		};
+		let mut out = Vec::new();
+		let _ = f(tcb, tx, rx, &mut out);
+		Some((local, remote, out))
+	});
+	if let Some((local, remote, out)) = res {
+		transmit_all(&local, &remote, &sock.ip_opts().lock(), &out);
+	}
+}
+
+/// Notifies the connection that the user has read data from the receive buffer.
+pub fn on_recv(sock: &Socket) {
+	process(sock, |tcb, tx, rx, out| {
+		tcb.window_update(tx, rx.get_available_len(), out)
+	});
+}
+
+/// Closes the sending side of the connection.
+pub fn shutdown(sock: &Socket) {
+	process(sock, |tcb, tx, rx, out| {
+		tcb.close(tx, rx.get_available_len(), out)
+	});
+}
+
+/// Unregisters the socket's connection and endpoints.
+fn unregister(sock: &Socket, state: &mut TcpState) {
+	if let (Some(local), Some(remote)) = (state.local, state.remote) {
+		let mut connections = CONNECTIONS.lock();
+		let registered = connections
+			.get(&(local, remote))
+			.is_some_and(|s| core::ptr::eq(Arc::as_ptr(s), sock));
+		if registered {
+			connections.remove(&(local, remote));
+		}
+	}
+	if let Some(local) = state.local {
+		let mut listeners = LISTENERS.lock();
+		let registered = listeners
+			.get(&local)
+			.is_some_and(|s| core::ptr::eq(Arc::as_ptr(s), sock));
+		if registered {
+			listeners.remove(&local);
+		}
+	}
+	if let Some(bound) = state.bound.take() {
+		let mut binds = BINDS.lock();
+		if let Some(bind) = binds.get_mut(&bound) {
+			bind.count -= 1;
+			if bind.count == 0 {
+				binds.remove(&bound);
+			}
+		}
+	}
+	state.listener = None;
+}
+
+/// Closes the socket after the user released it.
+///
+/// The connection is gracefully terminated in the background, after which the socket is freed.
+pub fn close(sock: &Socket) {
+	process(sock, |tcb, tx, rx, out| {
+		tcb.close(tx, rx.get_available_len(), out)
+	});
+	with_state(sock, |state| {
+		state.orphan = true;
+		// A listening socket or a socket without an open connection can be freed now
+		let closed = state
+			.tcb
+			.as_ref()
+			.is_none_or(|tcb| tcb.state() == State::Closed);
+		if closed {
+			unregister(sock, state);
+		}
+	});
+}
+
+/// Creates the socket for a connection request `syn` received on the listening socket
+/// `listener`.
+fn accept_syn(listener: &Arc<Socket>, local: SockAddr, remote: SockAddr, syn: &Segment) {
+	let res = (|| -> EResult<Vec<Segment>> {
+		let sock = Arc::new(Socket::new(listener.desc().clone())?)?;
+		// The connection inherits the options of the listening socket
+		*sock.ip_opts().lock() = *listener.ip_opts().lock();
+		*sock.opts().lock() = *listener.opts().lock();
+		*sock.get_sockname().lock() = local.to_bytes()?;
+		let rx_space = sock
+			.rx_buff()
+			.lock()
+			.as_ref()
+			.map_or(0, |b| b.get_available_len());
+		let mut out = Vec::new();
+		let mut tcb = Tcb::accept(gen_iss(), syn, rx_space, &mut out)?;
+		apply_opts(&sock.opts().lock(), &mut tcb);
+		with_state(&sock, |state| {
+			state.local = Some(local);
+			state.remote = Some(remote);
+			state.tcb = Some(tcb);
+			state.listener = Some(listener.clone());
+		});
+		CONNECTIONS.lock().insert((local, remote), sock)?;
+		Ok(out)
+	})();
+	if let Ok(out) = res {
+		transmit_all(&local, &remote, &listener.ip_opts().lock(), &out);
+	}
+}
+
+/// Handles the segment `buf` received from `src` to `dst` by the network layer.
+pub fn receive(src: Address, dst: Address, buf: &[u8]) -> EResult<()> {
+	if ip::pseudo_header_checksum(ip::PROTO_TCP, &src, &dst, buf)? != 0 {
+		return Ok(());
+	}
+	let Some((src_port, dst_port, seg)) = Segment::parse(buf)? else {
+		return Ok(());
+	};
+	let local = SockAddr {
+		port: dst_port,
+		addr: dst,
+	};
+	let remote = SockAddr {
+		port: src_port,
+		addr: src,
+	};
+	let sock = CONNECTIONS.lock().get(&(local, remote)).cloned();
+	if let Some(sock) = sock {
+		input(&sock, &seg);
+		return Ok(());
+	}
+	let listener = {
+		let listeners = LISTENERS.lock();
+		let any = SockAddr {
+			port: dst_port,
+			addr: dst.unspecified_like(),
+		};
+		listeners
+			.get(&local)
+			.or_else(|| listeners.get(&any))
+			.cloned()
+	};
+	match listener {
+		Some(listener) if seg.flags & (FLAG_SYN | FLAG_ACK | FLAG_RST) == FLAG_SYN => {
+			accept_syn(&listener, local, remote, &seg);
+		}
+		_ => {
+			if let Some(rst) = Tcb::reset_for(&seg) {
+				let _ = transmit(&local, &remote, &TxOptions::default(), &rst);
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Handles the segment `seg` received on the connection of `sock`.
+fn input(sock: &Arc<Socket>, seg: &Segment) {
+	process(sock, |tcb, tx, rx, out| tcb.input(seg, tx, rx, out));
+	after_event(sock);
+}
+
+/// Updates the socket after an event on its connection, waking up processes waiting on it.
+fn after_event(sock: &Arc<Socket>) {
+	let (listener, free) = with_state(sock, |state| {
+		let Some(tcb) = state.tcb.as_ref() else {
+			return (None, false);
+		};
+		let listener = if tcb.is_synchronized() {
+			state.listener.take()
+		} else {
+			None
+		};
+		let free = tcb.state() == State::Closed && (state.orphan || state.listener.is_some());
+		if free {
+			unregister(sock, state);
+		}
+		(listener, free)
+	});
+	// The connection is established: it can be accepted
+	if let Some(listener) = listener {
+		if listener.enqueue_connection(sock.clone()).is_err() {
+			process(sock, |tcb, _, rx, out| {
+				tcb.abort(rx.get_available_len(), out)
+			});
+			with_state(sock, |state| unregister(sock, state));
+		}
+	}
+	if !free {
+		sock.rx_queue().wake_all();
+		sock.tx_queue().wake_all();
+	}
+}
+
+/// Ticks the timers of all connections.
+fn tick() {
+	let Ok(socks) = CONNECTIONS
+		.lock()
+		.iter()
+		.map(|(_, s)| s.clone())
+		.collect::<CollectResult<Vec<_>>>()
+		.0
+	else {
+		return;
+	};
+	for sock in socks {
+		process(&sock, |tcb, tx, rx, out| {
+			tcb.tick(tx, rx.get_available_len(), out)
+		});
+		after_event(&sock);
+	}
+}
+
+/// Initializes the TCP protocol.
+pub(crate) fn init() -> EResult<()> {
+	timer::register_kernel_timer(TICK_PERIOD, tick)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{net::SocketDesc, selftest, selftest::Subsystem};
+
+	/// An end of a connection.
+	struct Peer {
+		tcb: Tcb,
+		tx: Buffer,
+		rx: Buffer,
+	}
+
+	fn buffer() -> AllocResult<Buffer> {
+		Ok(RingBuffer::new(vec![0; 4096]?))
+	}
+
+	impl Peer {
+		fn new(tcb: Tcb) -> Self {
+			Self {
+				tcb,
+				tx: buffer().unwrap(),
+				rx: buffer().unwrap(),
+			}
+		}
+
+		/// Writes data to be sent.
+		fn send(&mut self, data: &[u8]) -> Vec<Segment> {
+			assert_eq!(self.tx.write(data), data.len());
+			let mut out = Vec::new();
+			self.tcb
+				.output(&mut self.tx, self.rx.get_available_len(), &mut out)
+				.unwrap();
+			out
+		}
+
+		fn input(&mut self, segs: Vec<Segment>) -> Vec<Segment> {
+			let mut out = Vec::new();
+			for seg in segs {
+				self.tcb
+					.input(&seg, &mut self.tx, &mut self.rx, &mut out)
+					.unwrap();
+			}
+			out
+		}
+	}
+
+	/// Exchanges segments between `a` and `b` over a lossless loopback, until none is left.
+	///
+	/// `to_b` are the segments sent by `a` to `b`.
+	fn exchange(a: &mut Peer, b: &mut Peer, mut to_b: Vec<Segment>) {
+		while !to_b.is_empty() {
+			let to_a = b.input(to_b);
+			to_b = a.input(to_a);
+		}
+	}
+
+	/// Establishes a connection between two peers.
+	fn connect() -> (Peer, Peer) {
+		let mut out = Vec::new();
+		let client = Tcb::connect(0xfffffff0, 4095, &mut out).unwrap();
+		assert_eq!(client.state(), State::SynSent);
+		let syn = out.pop().unwrap();
+		let mut out = Vec::new();
+		let server = Tcb::accept(1000, &syn, 4095, &mut out).unwrap();
+		let mut client = Peer::new(client);
+		let mut server = Peer::new(server);
+		let to_server = client.input(out);
+		exchange(&mut client, &mut server, to_server);
+		assert_eq!(client.tcb.state(), State::Established);
+		assert_eq!(server.tcb.state(), State::Established);
+		(client, server)
+	}
+
+	#[test_case]
+	fn tcp_loopback() {
+		let (mut client, mut server) = connect();
+		// Data transfer, crossing the sequence numbers wrap-around
+		let out = client.send(b"hello");
+		exchange(&mut client, &mut server, out);
+		let mut buf = [0u8; 16];
+		assert_eq!(server.rx.read(&mut buf), 5);
+		assert_eq!(&buf[..5], b"hello");
+		assert!(client.tx.is_empty());
+		let out = server.send(b"world");
+		exchange(&mut server, &mut client, out);
+		assert_eq!(client.rx.read(&mut buf), 5);
+		assert_eq!(&buf[..5], b"world");
+		// Teardown
+		let mut out = Vec::new();
+		client.tcb.close(&mut client.tx, 4095, &mut out).unwrap();
+		exchange(&mut client, &mut server, out);
+		assert_eq!(client.tcb.state(), State::FinWait2);
+		assert_eq!(server.tcb.state(), State::CloseWait);
+		assert!(server.tcb.is_eof());
+		let mut out = Vec::new();
+		server.tcb.close(&mut server.tx, 4095, &mut out).unwrap();
+		exchange(&mut server, &mut client, out);
+		assert_eq!(client.tcb.state(), State::TimeWait);
+		assert_eq!(server.tcb.state(), State::Closed);
+		assert!(server.tcb.error().is_none());
+	}
+
+	#[test_case]
+	fn tcp_retransmit() {
+		let (mut client, mut server) = connect();
+		// The segment is lost
+		let out = client.send(b"lost");
+		assert_eq!(out.len(), 1);
+		// Wait for the retransmission timeout
+		let mut out = Vec::new();
+		while out.is_empty() {
+			client.tcb.tick(&mut client.tx, 4095, &mut out).unwrap();
+		}
+		assert_eq!(out[0].data.as_slice(), b"lost");
+		exchange(&mut client, &mut server, out);
+		let mut buf = [0u8; 4];
+		assert_eq!(server.rx.read(&mut buf), 4);
+		assert_eq!(&buf, b"lost");
+		assert!(client.tcb.rtx_timer.is_none());
+	}
+
+	#[test_case]
+	fn tcp_reset() {
+		let (mut client, mut server) = connect();
+		let mut out = Vec::new();
+		server.tcb.abort(4095, &mut out).unwrap();
+		assert_eq!(server.tcb.state(), State::Closed);
+		client.input(out);
+		assert_eq!(client.tcb.state(), State::Closed);
+		assert_eq!(
+			client.tcb.error().map(|e| e.as_int()),
+			Some(errno::ECONNRESET)
+		);
+		// Connection refused
+		let mut out = Vec::new();
+		let tcb = Tcb::connect(42, 4095, &mut out).unwrap();
+		let rst = Tcb::reset_for(&out[0]).unwrap();
+		let mut client = Peer::new(tcb);
+		client.input([rst].try_into().unwrap());
+		assert_eq!(
+			client.tcb.error().map(|e| e.as_int()),
+			Some(errno::ECONNREFUSED)
+		);
+	}
+
+	fn inet_socket() -> Arc<Socket> {
+		let desc = SocketDesc {
+			domain: net::SocketDomain::AfInet,
+			type_: net::SocketType::SockStream,
+			protocol: 0,
+		};
+		Arc::new(Socket::new(desc).unwrap()).unwrap()
+	}
+
+	#[test_case]
+	fn tcp_socket_loopback() {
+		selftest::require(Subsystem::Net);
+		let local = SockAddr {
+			port: 4242,
+			addr: Address::IPv4(net::lo::LOCALHOST_V4),
+		};
+		let addr = local.to_bytes().unwrap();
+		let server = inet_socket();
+		bind(&server, &addr, &AccessProfile::KERNEL).unwrap();
+		Socket::listen(&server, 1).unwrap();
+		// The handshake is performed right away on the loopback
+		let client = inet_socket();
+		super::connect(&client, &addr, false).unwrap();
+		let conn = server.accept(true).unwrap();
+		assert_eq!(
+			conn.get_peername().unwrap().as_slice(),
+			client.get_sockname().lock().as_slice()
+		);
+		// Data transfer
+		let mut buf = [0u8; 16];
+		assert_eq!(Socket::send(&client, b"hello", true).unwrap(), 5);
+		assert_eq!(conn.recv(&mut buf, true).unwrap(), 5);
+		assert_eq!(&buf[..5], b"hello");
+		assert_eq!(Socket::send(&conn, b"world", true).unwrap(), 5);
+		assert_eq!(client.recv(&mut buf, true).unwrap(), 5);
+		assert_eq!(&buf[..5], b"world");
+		// Teardown
+		client.shutdown_transmit();
+		assert_eq!(conn.recv(&mut buf, true).unwrap(), 0);
+		close(&conn);
+		close(&client);
+		close(&server);
+		let conn_state = with_state(&conn, |state| state.tcb.as_ref().map(Tcb::state));
+		assert_eq!(conn_state, Some(State::Closed));
+		let client_state = with_state(&client, |state| state.tcb.as_ref().map(Tcb::state));
+		assert_eq!(client_state, Some(State::TimeWait));
+		assert!(!LISTENERS.lock().contains_key(&local));
+	}
 }
@@ -0,0 +1,389 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The User Datagram Protocol (UDP) is a protocol transmitting unreliable, connectionless
+//! datagrams.
+
+use super::{buff::BuffList, ip, osi::Layer, sockaddr::SockAddr, Address, SocketDomain};
+use crate::{
+	file::{
+		perm::AccessProfile,
+		socket::{push_msg, Socket, SocketState},
+	},
+	net,
+	sync::mutex::IntMutex,
+};
+use core::mem::size_of;
+use macros::AnyRepr;
+use utils::{
+	bytes::{as_bytes, from_bytes},
+	collections::{hashmap::HashMap, vec::Vec},
+	errno,
+	errno::{AllocResult, CollectResult, EResult},
+	ptr::arc::Arc,
+};
+
+/// The first port of the range used for ephemeral ports.
+const EPHEMERAL_BEGIN: u16 = 49152;
+/// The first privileged port.
+const PRIVILEGED_END: u16 = 1024;
+
+/// The UDP datagram header.
+#[derive(AnyRepr, Clone, Copy)]
+#[repr(C, packed)]
+pub struct UDPHdr {
+	/// Source port.
+	src_port: u16,
+	/// Destination port.
+	dst_port: u16,
+	/// The length of the datagram, including the header.
+	length: u16,
+	/// The checksum of the datagram, including a pseudo-header from the network layer.
+	///
+	/// Over IPv4, the value zero means no checksum has been computed.
+	checksum: u16,
+}
+
+/// The maximum size of a datagram's payload, which must fit in an IPv4 packet with a header of
+/// 20 bytes.
+const MAX_PAYLOAD: usize = u16::MAX as usize - 20 - size_of::<UDPHdr>();
+
+/// The network layer for the UDP protocol.
+#[derive(Debug)]
+pub struct UDPLayer {
+	/// The source address.
+	pub src_addr: Address,
+	/// The source port.
+	pub src_port: u16,
+	/// The destination address.
+	pub dst_addr: Address,
+	/// The destination port.
+	pub dst_port: u16,
+}
+
+impl Layer for UDPLayer {
+	fn transmit(
+		&self,
+		buff: BuffList<'_>,
+		next: &dyn Fn(BuffList<'_>) -> EResult<()>,
+	) -> EResult<()> {
+		let len = size_of::<UDPHdr>() + buff.len();
+		if len > u16::MAX as usize {
+			return Err(errno!(EMSGSIZE));
+		}
+		let hdr = UDPHdr {
+			src_port: self.src_port.to_be(),
+			dst_port: self.dst_port.to_be(),
+			length: (len as u16).to_be(),
+			checksum: 0,
+		};
+		let mut dgram = Vec::with_capacity(len)?;
+		dgram.extend_from_slice(as_bytes(&hdr))?;
+		for b in buff.iter() {
+			dgram.extend_from_slice(b)?;
+		}
+		let checksum =
+			ip::pseudo_header_checksum(ip::PROTO_UDP, &self.src_addr, &self.dst_addr, &dgram)?;
+		// Zero means no checksum, so use the equivalent one's complement value instead
+		let checksum = if checksum == 0 { 0xffff } else { checksum };
+		dgram[6..8].copy_from_slice(&checksum.to_ne_bytes());
+		next(dgram.as_slice().into())
+	}
+}
+
+/// The UDP state of a socket.
+#[derive(Debug, Default)]
+pub struct UdpState {
+	/// The local address and port, if bound.
+	local: Option<SockAddr>,
+	/// The remote address and port, if connected.
+	remote: Option<SockAddr>,
+}
+
+impl UdpState {
+	/// Returns the local address and port, if bound.
+	pub fn local(&self) -> Option<&SockAddr> {
+		self.local.as_ref()
+	}
+
+	/// Returns the remote address and port, if connected.
+	pub fn remote(&self) -> Option<&SockAddr> {
+		self.remote.as_ref()
+	}
+}
+
+/// Bound sockets, by local endpoint.
+static SOCKETS: IntMutex<HashMap<SockAddr, Arc<Socket>>> = IntMutex::new(HashMap::new());
+
+/// Returns the list of bound sockets.
+pub fn sockets() -> AllocResult<Vec<Arc<Socket>>> {
+	SOCKETS
+		.lock()
+		.iter()
+		.map(|(_, s)| s.clone())
+		.collect::<CollectResult<Vec<_>>>()
+		.0
+}
+
+/// Runs `f` with the UDP state of the socket.
+fn with_state<F: FnOnce(&mut UdpState) -> R, R>(sock: &Socket, f: F) -> R {
+	let mut state = sock.state().lock();
+	let SocketState::Udp(state) = &mut *state else {
+		unreachable!();
+	};
+	f(state)
+}
+
+/// Tells whether the local endpoint `addr` conflicts with a bound one.
+fn is_used(sockets: &HashMap<SockAddr, Arc<Socket>>, addr: &SockAddr) -> bool {
+	sockets.iter().any(|(b, _)| {
+		b.port == addr.port
+			&& (b.addr == addr.addr || b.addr.is_unspecified() || addr.addr.is_unspecified())
+	})
+}
+
+/// Binds the socket to the local endpoint `addr`, with state `state`. If the port is zero, an
+/// ephemeral port is allocated.
+fn do_bind(sock: &Arc<Socket>, state: &mut UdpState, mut addr: SockAddr) -> EResult<()> {
+	{
+		let mut sockets = SOCKETS.lock();
+		if addr.port == 0 {
+			addr.port = (EPHEMERAL_BEGIN..=u16::MAX)
+				.find(|port| {
+					!is_used(
+						&sockets,
+						&SockAddr {
+							port: *port,
+							addr: addr.addr,
+						},
+					)
+				})
+				.ok_or_else(|| errno!(EADDRINUSE))?;
+		} else if is_used(&sockets, &addr) {
+			return Err(errno!(EADDRINUSE));
+		}
+		sockets.insert(addr, sock.clone())?;
+	}
+	state.local = Some(addr);
+	*sock.get_sockname().lock() = addr.to_bytes()?;
+	Ok(())
+}
+
+/// Returns the wildcard address of the socket's domain.
+fn any_addr(sock: &Socket) -> Address {
+	match sock.desc().domain {
+		SocketDomain::AfInet6 => Address::IPv6([0; 16]),
+		_ => Address::IPv4([0; 4]),
+	}
+}
+
+/// Binds the socket to the address `sockaddr`.
+pub fn bind(sock: &Arc<Socket>, sockaddr: &[u8], ap: &AccessProfile) -> EResult<()> {
+	let addr = SockAddr::from_bytes(sockaddr)?;
+	addr.check_domain(sock.desc().domain)?;
+	if addr.port != 0 && addr.port < PRIVILEGED_END && !ap.is_privileged() {
+		return Err(errno!(EACCES));
+	}
+	if !addr.addr.is_unspecified() && !net::is_local_address(&addr.addr) {
+		return Err(errno!(EADDRNOTAVAIL));
+	}
+	with_state(sock, |state| {
+		if state.local.is_some() {
+			return Err(errno!(EINVAL));
+		}
+		do_bind(sock, state, addr)
+	})
+}
+
+/// Sets the default destination of the socket to `sockaddr`. Datagrams from other sources are
+/// then discarded.
+///
+/// If the family of `sockaddr` is `AF_UNSPEC`, the socket is disconnected.
+pub fn connect(sock: &Arc<Socket>, sockaddr: &[u8]) -> EResult<()> {
+	let family = sockaddr
+		.get(..2)
+		.map(|f| u16::from_ne_bytes([f[0], f[1]]))
+		.ok_or_else(|| errno!(EINVAL))?;
+	if family == 0 {
+		with_state(sock, |state| state.remote = None);
+		return Ok(());
+	}
+	let remote = SockAddr::from_bytes(sockaddr)?;
+	remote.check_domain(sock.desc().domain)?;
+	with_state(sock, |state| {
+		if state.local.is_none() {
+			do_bind(
+				sock,
+				state,
+				SockAddr {
+					port: 0,
+					addr: any_addr(sock),
+				},
+			)?;
+		}
+		state.remote = Some(remote);
+		Ok(())
+	})
+}
+
+/// Sends the datagram `buf` on the socket.
+///
+/// If `sockaddr` is `None`, the datagram is sent to the connected peer. If the socket is not
+/// connected, the function returns [`errno::EDESTADDRREQ`].
+pub fn send(sock: &Arc<Socket>, buf: &[u8], sockaddr: Option<&[u8]>) -> EResult<usize> {
+	if buf.len() > MAX_PAYLOAD {
+		return Err(errno!(EMSGSIZE));
+	}
+	let dst = sockaddr.map(SockAddr::from_bytes).transpose()?;
+	if let Some(dst) = &dst {
+		dst.check_domain(sock.desc().domain)?;
+	}
+	let (src, dst) = with_state(sock, |state| {
+		let dst = dst.or(state.remote).ok_or_else(|| errno!(EDESTADDRREQ))?;
+		if state.local.is_none() {
+			do_bind(
+				sock,
+				state,
+				SockAddr {
+					port: 0,
+					addr: any_addr(sock),
+				},
+			)?;
+		}
+		EResult::Ok((state.local.unwrap(), dst))
+	})?;
+	let src_addr = if src.addr.is_unspecified() {
+		net::get_src_addr_for(&dst.addr).ok_or_else(|| errno!(ENETUNREACH))?
+	} else {
+		src.addr
+	};
+	let layer = UDPLayer {
+		src_addr,
+		src_port: src.port,
+		dst_addr: dst.addr,
+		dst_port: dst.port,
+	};
+	let opts = *sock.ip_opts().lock();
+	layer.transmit(buf.into(), &|buff| {
+		ip::transmit(ip::PROTO_UDP, src_addr, dst.addr, &opts, buff)
+	})?;
+	Ok(buf.len())
+}
+
+/// Handles the datagram `buf` received from `src` to `dst` by the network layer.
+///
+/// If the receive buffer of the socket is full, the datagram is discarded. If no socket is bound
+/// to the destination, the function returns [`errno::ECONNREFUSED`].
+pub fn receive(src: Address, dst: Address, buf: &[u8]) -> EResult<()> {
+	let Some(hdr) = from_bytes::<UDPHdr>(buf) else {
+		return Ok(());
+	};
+	let len = u16::from_be(hdr.length) as usize;
+	if len < size_of::<UDPHdr>() || len > buf.len() {
+		return Ok(());
+	}
+	let buf = &buf[..len];
+	let no_checksum = hdr.checksum == 0 && matches!(src, Address::IPv4(_));
+	if !no_checksum && ip::pseudo_header_checksum(ip::PROTO_UDP, &src, &dst, buf)? != 0 {
+		return Ok(());
+	}
+	let local = SockAddr {
+		port: u16::from_be(hdr.dst_port),
+		addr: dst,
+	};
+	let remote = SockAddr {
+		port: u16::from_be(hdr.src_port),
+		addr: src,
+	};
+	let sock = {
+		let sockets = SOCKETS.lock();
+		let any = SockAddr {
+			port: local.port,
+			addr: dst.unspecified_like(),
+		};
+		sockets.get(&local).or_else(|| sockets.get(&any)).cloned()
+	};
+	let Some(sock) = sock else {
+		return Err(errno!(ECONNREFUSED));
+	};
+	// A connected socket only receives datagrams from its peer
+	let accepted = with_state(&sock, |state| state.remote.is_none_or(|r| r == remote));
+	if !accepted {
+		return Ok(());
+	}
+	let addr = remote.to_bytes()?;
+	let pushed = sock
+		.rx_buff()
+		.lock()
+		.as_mut()
+		.is_some_and(|rx| push_msg(rx, &addr, &buf[size_of::<UDPHdr>()..]));
+	if pushed {
+		sock.rx_queue().wake_all();
+	}
+	Ok(())
+}
+
+/// Closes the socket, unbinding it.
+pub fn close(sock: &Socket) {
+	with_state(sock, |state| {
+		let Some(local) = state.local.take() else {
+			return;
+		};
+		let mut sockets = SOCKETS.lock();
+		let registered = sockets
+			.get(&local)
+			.is_some_and(|s| core::ptr::eq(Arc::as_ptr(s), sock));
+		if registered {
+			sockets.remove(&local);
+		}
+	});
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::cell::RefCell;
+
+	#[test_case]
+	fn udp_checksum() {
+		let src = Address::IPv4([127, 0, 0, 1]);
+		let dst = Address::IPv4([10, 0, 2, 15]);
+		let layer = UDPLayer {
+			src_addr: src,
+			src_port: 1234,
+			dst_addr: dst,
+			dst_port: 53,
+		};
+		let out = RefCell::new(Vec::new());
+		layer
+			.transmit(b"hello world"[..].into(), &|buff| {
+				*out.borrow_mut() = buff.to_vec()?;
+				Ok(())
+			})
+			.unwrap();
+		let dgram = out.into_inner();
+		assert_eq!(dgram.len(), size_of::<UDPHdr>() + 11);
+		let hdr = from_bytes::<UDPHdr>(&dgram).unwrap();
+		assert_eq!(u16::from_be(hdr.dst_port), 53);
+		assert_eq!(u16::from_be(hdr.length) as usize, dgram.len());
+		assert_eq!(
+			ip::pseudo_header_checksum(ip::PROTO_UDP, &src, &dst, &dgram).unwrap(),
+			0
+		);
+	}
+}
@@ -0,0 +1,794 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Local (UNIX domain) sockets.
+//!
+//! Local sockets do not go through the layers of the network stack. Instead, data is directly
+//! written into the receive buffer of the peer socket.
+//!
+//! A local socket can be bound to either:
+//! - a path on the filesystem, in which case a socket file is created
+//! - an abstract name, which is not visible on the filesystem. Such a name starts with a null byte
+
+use crate::{
+	file::{
+		perm::AccessProfile,
+		socket::{push_msg, Socket, SocketState, MSG_HDR_SIZE},
+		vfs,
+		vfs::ResolutionSettings,
+		File, FileLocation, FileType, Mode, Stat,
+	},
+	net::{SocketDesc, SocketDomain, SocketType},
+	process::{signal::Signal, Process},
+	sync::mutex::Mutex,
+	time::{
+		clock::{current_time, CLOCK_REALTIME},
+		unit::TimestampScale,
+	},
+};
+use core::{
+	intrinsics::unlikely,
+	mem,
+	mem::size_of,
+	ptr,
+	sync::atomic::{AtomicU32, Ordering::Relaxed},
+};
+use macros::AnyRepr;
+use utils::{
+	collections::{
+		hashmap::HashMap, path::Path, ring_buffer::RingBuffer, string::String, vec::Vec,
+	},
+	errno,
+	errno::{AllocResult, CollectResult, EResult},
+	ptr::arc::Arc,
+	TryClone,
+};
+
+/// The offset of the path in the `sockaddr_un` structure.
+const PATH_OFF: usize = size_of::<u16>();
+/// The maximum length of a path in the `sockaddr_un` structure.
+const PATH_MAX: usize = 108;
+
+/// Sockets bound to a path, by location of their socket file.
+static PATHS: Mutex<HashMap<FileLocation, Arc<Socket>>> = Mutex::new(HashMap::new());
+/// Sockets bound to an abstract name.
+static ABSTRACT: Mutex<HashMap<String, Arc<Socket>>> = Mutex::new(HashMap::new());
+/// All open local sockets.
+static SOCKETS: Mutex<Vec<Arc<Socket>>> = Mutex::new(Vec::new());
+/// Counter used to generate names for autobind.
+static AUTOBIND_COUNTER: AtomicU32 = AtomicU32::new(0);
+/// The number of names that can be generated for autobind.
+const AUTOBIND_NAMES: u32 = 1 << 20;
+
+/// The name a local socket is bound to.
+#[derive(Debug)]
+enum Name {
+	/// Bound to a socket file.
+	Path(FileLocation),
+	/// Bound to an abstract name.
+	Abstract(String),
+}
+
+/// The state of a local socket.
+#[derive(Debug, Default)]
+pub struct UnixState {
+	/// The name the socket is bound to.
+	name: Option<Name>,
+	/// The peer socket.
+	///
+	/// For connection-oriented sockets, this is the other end of the connection. For datagram
+	/// sockets, this is the default destination.
+	peer: Option<Arc<Socket>>,
+	/// Tells whether a connection has been established. If `true` and `peer` is `None`, the peer
+	/// has been closed.
+	connected: bool,
+	/// Tells whether the credentials of the sender are received along with the data
+	/// (`SO_PASSCRED`).
+	pub passcred: bool,
+	/// For a listening socket, the credentials of the process that started listening. They are
+	/// given to the sockets connecting to it.
+	listen_creds: Option<Credentials>,
+	/// The credentials of the peer at the time the connection was established (`SO_PEERCRED`).
+	peer_creds: Option<Credentials>,
+}
+
+impl UnixState {
+	/// Returns the peer socket, if any.
+	pub fn peer(&self) -> Option<&Arc<Socket>> {
+		self.peer.as_ref()
+	}
+
+	/// Tells whether the socket is connected.
+	pub fn is_connected(&self) -> bool {
+		self.connected
+	}
+
+	/// Returns the credentials of the peer at the time the connection was established.
+	pub fn peer_creds(&self) -> Option<&Credentials> {
+		self.peer_creds.as_ref()
+	}
+
+	/// Tells whether the end of the stream has been reached, meaning the peer cannot send data
+	/// anymore.
+	pub fn is_eof(&self) -> bool {
+		if !self.connected {
+			return false;
+		}
+		match &self.peer {
+			Some(peer) => peer.tx_buff().lock().is_none(),
+			None => true,
+		}
+	}
+
+	/// Wakes processes waiting on the peer socket.
+	pub fn wake_peer(&self) {
+		if let Some(peer) = &self.peer {
+			peer.rx_queue().wake_all();
+			peer.tx_queue().wake_all();
+		}
+	}
+}
+
+/// The credentials of a process, as passed with `SCM_CREDENTIALS` (`struct ucred`).
+#[derive(AnyRepr, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Credentials {
+	/// The process ID.
+	pub pid: i32,
+	/// The user ID.
+	pub uid: u32,
+	/// The group ID.
+	pub gid: u32,
+}
+
+impl Credentials {
+	/// Returns the credentials of the current process.
+	pub fn current() -> Self {
+		let proc = Process::current();
+		let ap = proc.fs.lock().access_profile;
+		Self {
+			pid: proc.get_pid() as _,
+			uid: ap.euid as _,
+			gid: ap.egid as _,
+		}
+	}
+
+	/// Checks the agent with the process ID `pid` and the access profile `ap` is allowed to send
+	/// the credentials.
+	///
+	/// An unprivileged agent can only send its own process ID, and one of its user and group IDs.
+	/// Else, the function returns [`errno::EPERM`].
+	pub fn check(&self, pid: i32, ap: &AccessProfile) -> EResult<()> {
+		if ap.is_privileged() {
+			return Ok(());
+		}
+		let uid_ok =
+			[ap.uid, ap.euid, ap.suid].contains(&(self.uid as _)) && self.uid <= u16::MAX as u32;
+		let gid_ok =
+			[ap.gid, ap.egid, ap.sgid].contains(&(self.gid as _)) && self.gid <= u16::MAX as u32;
+		if self.pid != pid || !uid_ok || !gid_ok {
+			return Err(errno!(EPERM));
+		}
+		Ok(())
+	}
+}
+
+/// Ancillary data sent along with data on a local socket.
+#[derive(Debug, Default)]
+pub struct Ancillary {
+	/// The files being passed (`SCM_RIGHTS`).
+	pub files: Vec<Arc<File>>,
+	/// The credentials of the sender (`SCM_CREDENTIALS`).
+	pub creds: Option<Credentials>,
+}
+
+impl Ancillary {
+	/// Tells whether there is no ancillary data.
+	pub fn is_empty(&self) -> bool {
+		self.files.is_empty() && self.creds.is_none()
+	}
+}
+
+/// Ancillary data attached to the data in the receive buffer of a local socket.
+///
+/// Since the receive buffer is a stream of bytes, ancillary data is located by the position of
+/// the first byte it is attached to, counted from the creation of the socket.
+#[derive(Debug, Default)]
+pub struct AncillaryQueue {
+	/// The number of bytes read from the receive buffer since the creation of the socket.
+	read: u64,
+	/// Ancillary data, along with its position, in ascending order.
+	entries: Vec<(u64, Ancillary)>,
+}
+
+impl AncillaryQueue {
+	/// Attaches `anc` to the next byte to be written to `rx_buff`.
+	pub fn push(&mut self, rx_buff: &RingBuffer<u8, Vec<u8>>, anc: Ancillary) -> AllocResult<()> {
+		let pos = self.read + rx_buff.get_data_len() as u64;
+		self.entries.push((pos, anc))
+	}
+
+	/// Returns the maximum number of bytes that can be read at once from a stream, so that a read
+	/// does not receive data attached to several ancillary data.
+	///
+	/// If there is no limit, the function returns `None`.
+	pub fn read_limit(&self) -> Option<usize> {
+		self.entries
+			.iter()
+			.find(|(pos, _)| *pos > self.read)
+			.map(|(pos, _)| (*pos - self.read) as usize)
+	}
+
+	/// Advances the read position by `len` bytes, returning the ancillary data attached to the
+	/// data that has been read, if any.
+	///
+	/// Reads are limited by [`Self::read_limit`] for streams and messages carry at most one
+	/// ancillary data, so a read cannot consume more than one.
+	pub fn consume(&mut self, len: usize) -> Option<Ancillary> {
+		self.read += len as u64;
+		let (pos, _) = self.entries.first()?;
+		if *pos >= self.read {
+			return None;
+		}
+		Some(self.entries.remove(0).1)
+	}
+
+	/// Removes all the ancillary data, returning it.
+	pub fn clear(&mut self) -> Vec<(u64, Ancillary)> {
+		mem::take(&mut self.entries)
+	}
+}
+
+/// A parsed `sockaddr_un` structure.
+enum SockAddr<'a> {
+	/// Unnamed address.
+	Unnamed,
+	/// Path to a socket file.
+	Path(&'a [u8]),
+	/// Abstract name, without the leading null byte.
+	Abstract(&'a [u8]),
+}
+
+/// Checks the descriptor `desc` of a local socket is valid.
+///
+/// If the type is not supported, the function returns [`errno::ESOCKTNOSUPPORT`]. If the protocol
+/// is not supported, the function returns [`errno::EPROTONOSUPPORT`].
+pub fn check_desc(desc: &SocketDesc) -> EResult<()> {
+	if desc.type_ == SocketType::SockRaw {
+		return Err(errno!(ESOCKTNOSUPPORT));
+	}
+	// The protocol can also be the domain itself
+	if desc.protocol != 0 && desc.protocol as u32 != SocketDomain::AfUnix.get_id() {
+		return Err(errno!(EPROTONOSUPPORT));
+	}
+	Ok(())
+}
+
+/// Parses the given `sockaddr_un` structure.
+fn parse_sockaddr(sockaddr: &[u8]) -> EResult<SockAddr<'_>> {
+	if unlikely(sockaddr.len() < PATH_OFF) {
+		return Err(errno!(EINVAL));
+	}
+	let family = u16::from_ne_bytes([sockaddr[0], sockaddr[1]]);
+	if unlikely(family as u32 != SocketDomain::AfUnix.get_id()) {
+		return Err(errno!(EINVAL));
+	}
+	let path = &sockaddr[PATH_OFF..];
+	let path = &path[..path.len().min(PATH_MAX)];
+	match path {
+		[] => Ok(SockAddr::Unnamed),
+		[0, name @ ..] => Ok(SockAddr::Abstract(name)),
+		_ => {
+			let len = path.iter().position(|b| *b == 0).unwrap_or(path.len());
+			Ok(SockAddr::Path(&path[..len]))
+		}
+	}
+}
+
+/// Builds the `sockaddr_un` structure for the given path or abstract name.
+fn build_sockaddr(abstract_: bool, name: &[u8]) -> EResult<Vec<u8>> {
+	let mut buf = Vec::with_capacity(PATH_OFF + 1 + name.len())?;
+	buf.extend_from_slice(&(SocketDomain::AfUnix.get_id() as u16).to_ne_bytes())?;
+	if abstract_ {
+		buf.push(0)?;
+		buf.extend_from_slice(name)?;
+	} else {
+		buf.extend_from_slice(name)?;
+		buf.push(0)?;
+	}
+	Ok(buf)
+}
+
+/// Binds `sock` to a generated abstract name.
+///
+/// If every name is already in use, the function returns [`errno::EADDRINUSE`].
+fn autobind(sock: &Arc<Socket>) -> EResult<Name> {
+	let mut abstract_ = ABSTRACT.lock();
+	let mut tries = 0;
+	let name = loop {
+		if tries == AUTOBIND_NAMES {
+			return Err(errno!(EADDRINUSE));
+		}
+		tries += 1;
+		let id = AUTOBIND_COUNTER.fetch_add(1, Relaxed) % AUTOBIND_NAMES;
+		// Five hexadecimal digits, like Linux
+		let mut name = String::new();
+		for i in (0..5).rev() {
+			let digit = ((id >> (i * 4)) & 0xf) as u8;
+			name.push(if digit < 10 {
+				b'0' + digit
+			} else {
+				b'a' + digit - 10
+			})?;
+		}
+		if !abstract_.contains_key(name.as_bytes()) {
+			break name;
+		}
+	};
+	*sock.get_sockname().lock() = build_sockaddr(true, &name)?;
+	abstract_.insert(name.try_clone()?, sock.clone())?;
+	Ok(Name::Abstract(name))
+}
+
+/// Binds the socket to the address `sockaddr`.
+///
+/// If the address is a path, a socket file is created with the permissions `0o777` masked by
+/// `umask`.
+pub fn bind(
+	sock: &Arc<Socket>,
+	sockaddr: &[u8],
+	rs: &ResolutionSettings,
+	umask: Mode,
+) -> EResult<()> {
+	let addr = parse_sockaddr(sockaddr)?;
+	let bound = || {
+		let state = sock.state().lock();
+		let SocketState::Unix(state) = &*state else {
+			unreachable!();
+		};
+		state.name.is_some()
+	};
+	if bound() {
+		return Err(errno!(EINVAL));
+	}
+	// The socket file is created without holding the socket's lock, since this may sleep
+	let file = match addr {
+		SockAddr::Path(raw_path) => {
+			let path = Path::new(raw_path)?;
+			let parent_path = path.parent().unwrap_or(Path::root());
+			let name = path.file_name().ok_or_else(|| errno!(EINVAL))?;
+			let parent = vfs::get_file_from_path(parent_path, rs)?;
+			let ts = current_time(CLOCK_REALTIME, TimestampScale::Second)?;
+			let file = vfs::create_file(
+				parent.clone(),
+				name,
+				&rs.access_profile,
+				Stat {
+					mode: FileType::Socket.to_mode() | (0o777 & !umask),
+					ctime: ts,
+					mtime: ts,
+					atime: ts,
+					..Default::default()
+				},
+			)
+			.map_err(|e| {
+				if e.as_int() == errno::EEXIST {
+					errno!(EADDRINUSE)
+				} else {
+					e
+				}
+			})?;
+			Some((parent, name, file))
+		}
+		_ => None,
+	};
+	let res = bind_name(sock, addr, file.as_ref().map(|(_, _, file)| file));
+	// On failure, remove the socket file that has been created
+	if let (Err(_), Some((parent, name, _))) = (&res, file) {
+		let _ = vfs::unlink(parent, name, &rs.access_profile);
+	}
+	res
+}
+
+/// Publishes the name of `sock`, bound to `addr`.
+///
+/// `file` is the socket file created for a path address.
+///
+/// If the socket has been bound concurrently, the function returns [`errno::EINVAL`].
+fn bind_name(sock: &Arc<Socket>, addr: SockAddr, file: Option<&Arc<vfs::Entry>>) -> EResult<()> {
+	let mut state = sock.state().lock();
+	let SocketState::Unix(state) = &mut *state else {
+		unreachable!();
+	};
+	if state.name.is_some() {
+		return Err(errno!(EINVAL));
+	}
+	let name = match addr {
+		SockAddr::Unnamed => autobind(sock)?,
+		SockAddr::Abstract(name) => {
+			let mut abstract_ = ABSTRACT.lock();
+			if abstract_.contains_key(name) {
+				return Err(errno!(EADDRINUSE));
+			}
+			*sock.get_sockname().lock() = build_sockaddr(true, name)?;
+			abstract_.insert(String::try_from(name)?, sock.clone())?;
+			Name::Abstract(String::try_from(name)?)
+		}
+		SockAddr::Path(raw_path) => {
+			let loc = file.unwrap().node().location.clone();
+			*sock.get_sockname().lock() = build_sockaddr(false, raw_path)?;
+			PATHS.lock().insert(loc.clone(), sock.clone())?;
+			Name::Path(loc)
+		}
+	};
+	state.name = Some(name);
+	Ok(())
+}
+
+/// Returns the socket bound to the address `sockaddr`.
+///
+/// If no socket is bound to the address, the function returns [`errno::ECONNREFUSED`].
+pub fn lookup(sockaddr: &[u8], rs: &ResolutionSettings) -> EResult<Arc<Socket>> {
+	match parse_sockaddr(sockaddr)? {
+		SockAddr::Unnamed => Err(errno!(EINVAL)),
+		SockAddr::Abstract(name) => ABSTRACT
+			.lock()
+			.get(name)
+			.cloned()
+			.ok_or_else(|| errno!(ECONNREFUSED)),
+		SockAddr::Path(path) => {
+			let file = vfs::get_file_from_path(Path::new(path)?, rs)?;
+			let stat = file.stat()?;
+			if stat.get_type() != Some(FileType::Socket) {
+				return Err(errno!(ECONNREFUSED));
+			}
+			if !rs.access_profile.can_write_file(&stat) {
+				return Err(errno!(EACCES));
+			}
+			PATHS
+				.lock()
+				.get(&file.node().location)
+				.cloned()
+				.ok_or_else(|| errno!(ECONNREFUSED))
+		}
+	}
+}
+
+/// Links `a` and `b` as the two ends of a connection.
+///
+/// `a_creds` and `b_creds` are the credentials of the processes on each end.
+fn link(a: &Arc<Socket>, b: &Arc<Socket>, a_creds: Credentials, b_creds: Credentials) {
+	let connected = a.desc().type_ != SocketType::SockDgram;
+	for (sock, peer, peer_creds) in [(a, b, b_creds), (b, a, a_creds)] {
+		if let SocketState::Unix(state) = &mut *sock.state().lock() {
+			state.peer = Some(peer.clone());
+			state.connected = connected;
+			state.peer_creds = Some(peer_creds);
+		}
+	}
+}
+
+/// Connects the socket to the address `sockaddr`.
+///
+/// For connection-oriented sockets, the function waits until the connection is inserted into the
+/// queue of pending connections of the listening socket.
+///
+/// For datagram sockets, the function sets the default destination of the socket.
+pub fn connect(sock: &Arc<Socket>, sockaddr: &[u8], rs: &ResolutionSettings) -> EResult<()> {
+	let target = lookup(sockaddr, rs)?;
+	if target.desc().type_ != sock.desc().type_ {
+		return Err(errno!(EPROTOTYPE));
+	}
+	if sock.desc().type_ == SocketType::SockDgram {
+		if !may_send(sock, &target) {
+			return Err(errno!(EPERM));
+		}
+		if let SocketState::Unix(state) = &mut *sock.state().lock() {
+			state.peer = Some(target);
+		}
+		return Ok(());
+	}
+	if sock.is_listening() {
+		return Err(errno!(EINVAL));
+	}
+	if let SocketState::Unix(state) = &*sock.state().lock() {
+		if state.connected {
+			return Err(errno!(EISCONN));
+		}
+	}
+	// Create the server-side end of the connection
+	let server = Arc::new(Socket::new(sock.desc().clone())?)?;
+	open(&server)?;
+	*server.get_sockname().lock() = target.get_sockname().lock().try_clone()?;
+	let creds = Credentials::current();
+	let listen_creds = match &*target.state().lock() {
+		SocketState::Unix(state) => state.listen_creds,
+		_ => None,
+	};
+	link(sock, &server, creds, listen_creds.unwrap_or(creds));
+	// Wait for room in the queue of pending connections
+	let res = target
+		.tx_queue()
+		.wait_until(|| match target.enqueue_connection(server.clone()) {
+			Err(e) if e.as_int() == errno::EAGAIN => None,
+			res => Some(res),
+		})
+		.and_then(|r| r);
+	if res.is_err() {
+		SOCKETS
+			.lock()
+			.retain(|s| !ptr::eq(Arc::as_ptr(s), Arc::as_ptr(&server)));
+		// Undo the link
+		for s in [sock, &server] {
+			if let SocketState::Unix(state) = &mut *s.state().lock() {
+				state.peer = None;
+				state.connected = false;
+				state.peer_creds = None;
+			}
+		}
+	}
+	res
+}
+
+/// Checks the socket is in a suitable state to start listening.
+///
+/// If the socket is not bound, it is bound to a generated abstract name.
+pub fn listen(sock: &Arc<Socket>) -> EResult<()> {
+	let mut state = sock.state().lock();
+	let SocketState::Unix(state) = &mut *state else {
+		unreachable!();
+	};
+	if state.connected {
+		return Err(errno!(EINVAL));
+	}
+	if state.name.is_none() {
+		state.name = Some(autobind(sock)?);
+	}
+	state.listen_creds = Some(Credentials::current());
+	Ok(())
+}
+
+/// Returns the address of the peer of the socket whose state is `state`.
+///
+/// If the peer is not bound, the address only contains the address family.
+///
+/// If the socket has no peer, the function returns [`errno::ENOTCONN`].
+pub fn peername(state: &UnixState) -> EResult<Vec<u8>> {
+	let peer = state.peer.as_ref().ok_or_else(|| errno!(ENOTCONN))?;
+	let name = peer.get_sockname().lock();
+	if name.is_empty() {
+		let family = (SocketDomain::AfUnix.get_id() as u16).to_ne_bytes();
+		return Ok(Vec::try_from(&family[..])?);
+	}
+	Ok(name.try_clone()?)
+}
+
+/// Registers the newly created socket `sock`.
+pub fn open(sock: &Arc<Socket>) -> AllocResult<()> {
+	SOCKETS.lock().push(sock.clone())
+}
+
+/// Returns the list of open local sockets.
+pub fn sockets() -> AllocResult<Vec<Arc<Socket>>> {
+	SOCKETS
+		.lock()
+		.iter()
+		.cloned()
+		.collect::<CollectResult<_>>()
+		.0
+}
+
+/// Creates a pair of connected sockets, for the `socketpair` system call.
+pub fn pair(a: &Arc<Socket>, b: &Arc<Socket>) -> AllocResult<()> {
+	open(a)?;
+	open(b)?;
+	let creds = Credentials::current();
+	link(a, b, creds, creds);
+	Ok(())
+}
+
+/// Sends the SIGPIPE signal to the current process and returns [`errno::EPIPE`].
+fn broken_pipe<T>() -> EResult<T> {
+	Process::current().kill(Signal::SIGPIPE);
+	Err(errno!(EPIPE))
+}
+
+/// Tells whether the credentials of the sender are to be attached to data sent on `sock`.
+fn passcred(sock: &Socket) -> bool {
+	match &*sock.state().lock() {
+		SocketState::Unix(state) => state.passcred,
+		_ => false,
+	}
+}
+
+/// Attaches the credentials of the current process to `anc` if `sock` or `dest` requires them
+/// and if the sender did not specify any.
+fn attach_creds(sock: &Socket, dest: &Socket, mut anc: Ancillary) -> Option<Ancillary> {
+	if anc.creds.is_none() && (passcred(sock) || passcred(dest)) {
+		anc.creds = Some(Credentials::current());
+	}
+	(!anc.is_empty()).then_some(anc)
+}
+
+/// Tells whether the datagram socket `sock` may send to `dest`.
+///
+/// A connected datagram socket only receives datagrams from its peer.
+fn may_send(sock: &Socket, dest: &Socket) -> bool {
+	match &*dest.state().lock() {
+		SocketState::Unix(state) => state
+			.peer
+			.as_ref()
+			.is_none_or(|peer| ptr::eq(Arc::as_ptr(peer), sock)),
+		_ => false,
+	}
+}
+
+/// Sends data on the socket `sock`.
+///
+/// Arguments:
+/// - `buf` is the data to send
+/// - `dest` is the destination socket. If `None`, the peer socket is used
+/// - `anc` is the ancillary data attached to the data
+/// - `nonblock` tells whether the function returns [`errno::EAGAIN`] instead of waiting for room
+///   in the receive buffer of the destination
+///
+/// On success, the function returns the number of bytes sent.
+pub fn send(
+	sock: &Socket,
+	buf: &[u8],
+	dest: Option<Arc<Socket>>,
+	anc: Ancillary,
+	nonblock: bool,
+) -> EResult<usize> {
+	if sock.tx_buff().lock().is_none() {
+		return broken_pipe();
+	}
+	let (peer, connected) = match &*sock.state().lock() {
+		SocketState::Unix(state) => (state.peer.clone(), state.connected),
+		_ => unreachable!(),
+	};
+	match sock.desc().type_ {
+		SocketType::SockDgram => {
+			let dest = dest.or(peer).ok_or_else(|| errno!(ENOTCONN))?;
+			if dest.desc().type_ != SocketType::SockDgram {
+				return Err(errno!(EPROTOTYPE));
+			}
+			if !may_send(sock, &dest) {
+				return Err(errno!(EPERM));
+			}
+			send_msg(sock, &dest, buf, anc, nonblock)
+		}
+		type_ => {
+			if dest.is_some() {
+				return Err(if connected {
+					errno!(EISCONN)
+				} else {
+					errno!(EOPNOTSUPP)
+				});
+			}
+			if !connected {
+				return Err(errno!(ENOTCONN));
+			}
+			let Some(peer) = peer else {
+				return broken_pipe();
+			};
+			if type_ == SocketType::SockSeqpacket {
+				return send_msg(sock, &peer, buf, anc, nonblock);
+			}
+			// The ancillary data is attached to the first byte written
+			let mut anc = attach_creds(sock, &peer, anc);
+			let timeout = sock.snd_timeout(nonblock);
+			let mut off = 0;
+			while off < buf.len() {
+				let res = peer.tx_queue().wait_until_timeout(timeout, || {
+					let mut rx_buff = peer.rx_buff().lock();
+					let Some(rx_buff) = rx_buff.as_mut() else {
+						return Some(broken_pipe());
+					};
+					if rx_buff.get_available_len() == 0 {
+						return None;
+					}
+					if let Some(anc) = anc.take() {
+						if let Err(e) = peer.ancillary().lock().push(rx_buff, anc) {
+							return Some(Err(e.into()));
+						}
+					}
+					Some(Ok(rx_buff.write(&buf[off..])))
+				});
+				off += match res {
+					// Return the length of the data sent before blocking
+					Err(e) if off > 0 && matches!(e.as_int(), errno::EAGAIN | errno::EINTR) => {
+						break
+					}
+					res => res??,
+				};
+				peer.rx_queue().wake_all();
+			}
+			Ok(off)
+		}
+	}
+}
+
+/// Sends the message `buf` atomically from `sock` to `dest`, along with the ancillary data `anc`.
+fn send_msg(
+	sock: &Socket,
+	dest: &Socket,
+	buf: &[u8],
+	anc: Ancillary,
+	nonblock: bool,
+) -> EResult<usize> {
+	let addr = sock.get_sockname().lock().try_clone()?;
+	let len = buf.len() + addr.len() + MSG_HDR_SIZE;
+	let capacity = dest.rx_buff().lock().as_ref().map(|b| b.get_size() - 1);
+	if len > capacity.unwrap_or(0) {
+		return Err(errno!(EMSGSIZE));
+	}
+	let mut anc = attach_creds(sock, dest, anc);
+	dest.tx_queue()
+		.wait_until_timeout(sock.snd_timeout(nonblock), || {
+			let mut rx_buff = dest.rx_buff().lock();
+			let Some(rx_buff) = rx_buff.as_mut() else {
+				return Some(if sock.desc().type_ == SocketType::SockDgram {
+					Err(errno!(ECONNREFUSED))
+				} else {
+					broken_pipe()
+				});
+			};
+			if rx_buff.get_available_len() < len {
+				return None;
+			}
+			if let Some(anc) = anc.take() {
+				if let Err(e) = dest.ancillary().lock().push(rx_buff, anc) {
+					return Some(Err(e.into()));
+				}
+			}
+			push_msg(rx_buff, &addr, buf).then_some(Ok(()))
+		})??;
+	dest.rx_queue().wake_all();
+	Ok(buf.len())
+}
+
+/// Releases the resources of the local socket `sock`, whose state is `state`.
+pub fn close(sock: &Socket, state: UnixState) {
+	SOCKETS.lock().retain(|s| !ptr::eq(Arc::as_ptr(s), sock));
+	// Unregister name
+	match state.name {
+		Some(Name::Path(loc)) => {
+			PATHS.lock().remove(&loc);
+		}
+		Some(Name::Abstract(name)) => {
+			ABSTRACT.lock().remove(&name);
+		}
+		None => {}
+	}
+	// Disconnect from the peer
+	let Some(peer) = state.peer else {
+		return;
+	};
+	if let SocketState::Unix(peer_state) = &mut *peer.state().lock() {
+		let linked = peer_state
+			.peer
+			.as_ref()
+			.is_some_and(|p| ptr::eq(Arc::as_ptr(p), sock));
+		if linked {
+			peer_state.peer = None;
+		}
+	}
+	peer.rx_queue().wake_all();
+	peer.tx_queue().wake_all();
+}
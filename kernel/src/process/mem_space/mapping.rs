@@ -24,19 +24,17 @@
 use super::gap::MemGap;
 use crate::{
 	arch::x86::paging,
-	memory::{
-		vmem,
-		vmem::{VMem, VMemTransaction},
-		VirtAddr,
-	},
+	file::{page_cache, File},
+	memory::{vmem, vmem::VMemTransaction, VirtAddr},
 	process::mem_space::{
 		residence::{MapResidence, Page, ResidencePage},
 		COPY_BUFFER,
 	},
 };
-use core::{alloc::AllocError, num::NonZeroUsize, ops::Range, slice};
+use core::{alloc::AllocError, num::NonZeroUsize, ops::Range};
 use utils::{
 	collections::vec::Vec,
+	errno,
 	errno::{AllocResult, EResult},
 	limits::PAGE_SIZE,
 	ptr::arc::Arc,
@@ -104,6 +102,11 @@ impl MemMapping {
 		self.flags
 	}
 
+	/// Returns the mapping's residence.
+	pub fn get_residence(&self) -> &MapResidence {
+		&self.residence
+	}
+
 	/// Tells whether the given `page` is in COW mode.
 	///
 	/// An offset is in COW mode if the mapping is not shared, and the number of references to the
@@ -154,30 +157,39 @@ impl MemMapping {
 	///
 	/// The function also applies the mapping of the page to the given `vmem_transaction`
 	/// (regardless of whether the page was effectively in COW mode).
+	///
+	/// If the page has to be read from a file first, the function returns [`errno::EAGAIN`]. See
+	/// [`MapResidence::acquire_page`].
 	pub(super) fn alloc(
 		&mut self,
 		offset: usize,
 		vmem_transaction: &mut VMemTransaction<false>,
-	) -> AllocResult<()> {
+	) -> EResult<()> {
 		let virtaddr = VirtAddr::from(self.begin) + offset * PAGE_SIZE;
 		// Get previous page
 		let previous = self
 			.phys_pages
 			// Bound check
 			.get(offset)
-			.ok_or(AllocError)?;
+			.ok_or_else(|| errno!(EINVAL))?;
 		match previous {
 			// If not pending for an allocation: map and stop here
 			Some(physaddr) if !Self::is_cow(physaddr, self.flags) => {
 				let flags = self.get_vmem_flags(true);
-				return vmem_transaction.map(physaddr.get(), virtaddr, flags);
+				vmem_transaction.map(physaddr.get(), virtaddr, flags)?;
+				return Ok(());
 			}
 			_ => {}
 		}
-		// Allocate and map new page
-		let new = self.residence.acquire_page(offset)?;
-		// Tells initializing the new page is necessary
-		let init = self.residence.is_normal();
+		// Allocate and map new page. If a page is already present, it is in COW mode and has to be
+		// copied to a new page
+		let (new, init) = match previous {
+			Some(_) => (MapResidence::Normal.acquire_page(offset)?, true),
+			None => (
+				self.residence.acquire_page(offset)?,
+				self.residence.is_normal(),
+			),
+		};
 		// Tells whether a copy from the previous page is necessary
 		let copy = previous.is_some();
 		if init {
@@ -189,10 +201,12 @@ impl MemMapping {
 		// Map new page
 		let new_physaddr = new.get();
 		// If the page has to be initialized, do not allow writing during initialization to avoid
-		// concurrency issues
-		let flags = self.get_vmem_flags(!init);
+		// concurrency issues. A page shared with the residence is writable only if the mapping
+		// is shared
+		let flags = self.get_vmem_flags(!init && !Self::is_cow(&new, self.flags));
 		vmem_transaction.map(new_physaddr, virtaddr, flags)?;
 		if !init {
+			self.phys_pages[offset] = Some(new);
 			return Ok(());
 		}
 		// Initialize the new page
@@ -241,7 +255,13 @@ impl MemMapping {
 			}
 		} else {
 			for i in 0..self.size.get() {
-				self.alloc(i, vmem_transaction)?;
+				match self.alloc(i, vmem_transaction) {
+					Ok(()) => {}
+					// Pages that are not in cache are read on the first access
+					Err(e) if e.as_int() == errno::EAGAIN => {}
+					// Other residences can only fail on allocation
+					Err(_) => return Err(AllocError),
+				}
 			}
 		}
 		Ok(())
@@ -304,43 +324,55 @@ impl MemMapping {
 		Ok((prev, gap, next))
 	}
 
-	/// Synchronizes the data on the memory mapping back to the filesystem.
+	/// Returns the file the mapping is shared with, if any.
 	///
-	/// `vmem` is the virtual memory context to read from.
+	/// Modifications to a private mapping are not carried to the file, so the function returns
+	/// `None` for them.
+	pub fn get_shared_file(&self) -> Option<&Arc<File>> {
+		if self.flags & super::MAPPING_FLAG_SHARED == 0 {
+			return None;
+		}
+		match &self.residence {
+			MapResidence::File {
+				file, ..
+			} => Some(file),
+			_ => None,
+		}
+	}
+
+	/// Schedules the synchronization of the data on the memory mapping back to the filesystem.
+	///
+	/// The pages of the mapping are shared with the page cache. They are marked as dirty, to be
+	/// written back by the writeback thread or when the file is synchronized.
 	///
 	/// The function does nothing if:
 	/// - The mapping is not shared
 	/// - The mapping is not associated with a file
-	/// - The associated file has been removed or cannot be accessed
+	/// - The mapping is not writable
 	///
 	/// If the mapping is lock, the function returns [`crate::errno::EBUSY`].
-	pub fn fs_sync(&self, vmem: &VMem) -> EResult<()> {
-		if self.flags & super::MAPPING_FLAG_SHARED == 0 {
+	pub fn fs_sync(&self) -> EResult<()> {
+		if self.flags & super::MAPPING_FLAG_WRITE == 0 {
 			return Ok(());
 		}
 		// TODO if locked, EBUSY
-		// Get file
+		let Some(file) = self.get_shared_file() else {
+			return Ok(());
+		};
+		let Some(entry) = &file.vfs_entry else {
+			return Ok(());
+		};
 		let MapResidence::File {
-			file,
-			off,
+			off, ..
 		} = &self.residence
 		else {
 			return Ok(());
 		};
-		// Sync
-		unsafe {
-			vmem::switch(vmem, || {
-				// TODO Make use of dirty flag if present on the current architecture to update
-				// only pages that have been modified
-				let slice = slice::from_raw_parts(self.begin, self.size.get() * PAGE_SIZE);
-				let mut i = 0;
-				while i < slice.len() {
-					let l = file.ops.write(file, *off, &slice[i..])?;
-					i += l;
-				}
-				Ok(())
-			})
-		}
+		// TODO Make use of dirty flag if present on the current architecture to write back only
+		// pages that have been modified
+		let start = *off / PAGE_SIZE as u64;
+		page_cache::mark_file_dirty(&entry.node().location, start, self.size.get() as u64);
+		Ok(())
 	}
 
 	/// Unmaps the mapping using the given `vmem_transaction`.
@@ -348,7 +380,8 @@ impl MemMapping {
 	/// `range` is the range of pages affect by the unmap. Pages outside of this range are left
 	/// untouched.
 	///
-	/// If applicable, the function synchronizes the data on the pages to be unmapped to the disk.
+	/// If applicable, the function schedules the synchronization of the data on the pages to be
+	/// unmapped to the disk.
 	///
 	/// This function doesn't flush the virtual memory context.
 	///
@@ -358,7 +391,7 @@ impl MemMapping {
 		pages_range: Range<usize>,
 		vmem_transaction: &mut VMemTransaction<false>,
 	) -> EResult<()> {
-		self.fs_sync(vmem_transaction.vmem)?;
+		self.fs_sync()?;
 		let begin = VirtAddr::from(self.begin) + pages_range.start * PAGE_SIZE;
 		let len = pages_range.end - pages_range.start;
 		vmem_transaction.unmap_range(begin, len)?;
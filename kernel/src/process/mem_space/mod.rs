@@ -50,7 +50,8 @@ use residence::MapResidence;
 use transaction::MemSpaceTransaction;
 use utils::{
 	collections::{btreemap::BTreeMap, vec::Vec},
-	errno::{AllocResult, CollectResult, EResult},
+	errno,
+	errno::{AllocResult, CollectResult, EResult, Errno},
 	limits::PAGE_SIZE,
 	ptr::arc::Arc,
 	TryClone,
@@ -115,6 +116,24 @@ impl MapConstraint {
 	}
 }
 
+/// The outcome of [`MemSpace::handle_page_fault`].
+#[derive(Debug)]
+pub enum PageFault {
+	/// The fault has been resolved and the execution can continue.
+	Resolved,
+	/// The access is invalid.
+	Invalid,
+	/// The page at the given offset of the residence has to be read with
+	/// [`MapResidence::fill_page`] before the fault can be resolved.
+	///
+	/// Since reading may sleep, this must be done without holding the lock on the memory space.
+	/// The fault then has to be handled again, since the memory space may have changed in the
+	/// meantime.
+	Fill(MapResidence, usize),
+	/// The page could not be mapped.
+	Error(Errno),
+}
+
 /// Removes gaps in `on` in the given range, using `transaction`.
 ///
 /// `start` is the start address of the range and `size` is the size of the range in pages.
@@ -558,6 +577,9 @@ impl MemSpace {
 	///
 	/// On error, allocations that have been made are not freed as it does not affect the behaviour
 	/// from the user's point of view.
+	///
+	/// Pages of files that are not in cache are skipped, since they cannot be read while the
+	/// memory space is locked. They are read on the first access instead.
 	pub fn alloc(&mut self, addr: VirtAddr, len: usize) -> AllocResult<()> {
 		let mut transaction = self.vmem.transaction();
 		let mut off = 0;
@@ -565,7 +587,11 @@ impl MemSpace {
 			let addr = addr + off;
 			if let Some(mapping) = self.state.get_mut_mapping_for_addr(addr) {
 				let page_offset = (addr.0 - mapping.get_begin() as usize) / PAGE_SIZE;
-				mapping.alloc(page_offset, &mut transaction)?;
+				match mapping.alloc(page_offset, &mut transaction) {
+					Ok(()) => {}
+					Err(e) if e.as_int() == errno::EAGAIN => {}
+					Err(_) => return Err(AllocError),
+				}
 			}
 			off += PAGE_SIZE;
 		}
@@ -666,35 +692,44 @@ impl MemSpace {
 	/// - `addr` is the virtual address of the wrong memory access that caused the fault.
 	/// - `code` is the error code given along with the error.
 	///
-	/// If the process should continue, the function returns `true`, else `false`.
-	pub fn handle_page_fault(&mut self, addr: VirtAddr, code: u32) -> bool {
-		if code & PAGE_FAULT_PRESENT == 0 {
-			return false;
-		}
+	/// If the page has to be read from a file, the function returns [`PageFault::Fill`] and the
+	/// caller has to read the page without holding the lock on the memory space, then call this
+	/// function again.
+	pub fn handle_page_fault(&mut self, addr: VirtAddr, code: u32) -> PageFault {
 		let Some(mapping) = self.state.get_mut_mapping_for_addr(addr) else {
-			return false;
+			return PageFault::Invalid;
 		};
+		// Only pages of files may be absent, until they are read
+		let present = code & PAGE_FAULT_PRESENT != 0;
+		if !present && mapping.get_residence().get_default_page().is_some() {
+			return PageFault::Invalid;
+		}
 		// Check permissions
 		let code_write = code & PAGE_FAULT_WRITE != 0;
 		let mapping_write = mapping.get_flags() & MAPPING_FLAG_WRITE != 0;
 		if code_write && !mapping_write {
-			return false;
+			return PageFault::Invalid;
 		}
 		// TODO check exec
 		let code_userspace = code & PAGE_FAULT_USER != 0;
 		let mapping_userspace = mapping.get_flags() & MAPPING_FLAG_USER != 0;
 		if code_userspace && !mapping_userspace {
-			return false;
+			return PageFault::Invalid;
 		}
 		// Map the accessed page
 		let page_offset = (addr.0 - mapping.get_begin() as usize) / PAGE_SIZE;
 		let mut transaction = self.vmem.transaction();
-		// TODO use OOM killer
-		mapping
-			.alloc(page_offset, &mut transaction)
-			.expect("Out of memory!");
-		transaction.commit();
-		true
+		match mapping.alloc(page_offset, &mut transaction) {
+			Ok(()) => {
+				transaction.commit();
+				PageFault::Resolved
+			}
+			Err(e) if e.as_int() == errno::EAGAIN => {
+				// Cloning a residence does not allocate memory
+				PageFault::Fill(mapping.get_residence().clone(), page_offset)
+			}
+			Err(e) => PageFault::Error(e),
+		}
 	}
 }
 
@@ -710,7 +745,7 @@ impl Drop for MemSpace {
 		let mappings = mem::take(&mut self.state.mappings);
 		for (_, m) in mappings {
 			// Ignore I/O errors
-			let _ = m.fs_sync(&self.vmem);
+			let _ = m.fs_sync();
 		}
 	}
 }
@@ -19,11 +19,10 @@
 //! A map residence provides information about how to populate a memory mapping.
 
 use crate::{
-	file::File,
+	file::{page_cache, vfs::node::Node, File},
 	memory::{buddy, PhysAddr, VirtAddr},
 };
-use core::alloc::AllocError;
-use utils::{collections::vec::Vec, errno::AllocResult, limits::PAGE_SIZE, ptr::arc::Arc};
+use utils::{collections::vec::Vec, errno, errno::EResult, limits::PAGE_SIZE, ptr::arc::Arc};
 
 /// Type representing a memory page.
 pub type Page = [u8; PAGE_SIZE];
@@ -68,6 +67,18 @@ impl Drop for ResidencePage {
 	}
 }
 
+/// Returns the node of `file` and the index of the page at `offset` in a mapping of it, where
+/// `off` is the offset of the mapping in the file.
+fn file_page(file: &File, off: u64, offset: usize) -> EResult<(&Arc<Node>, u64)> {
+	let node = file
+		.vfs_entry
+		.as_ref()
+		.ok_or_else(|| errno!(ENODEV))?
+		.node();
+	let index = off / PAGE_SIZE as u64 + offset as u64;
+	Ok((node, index))
+}
+
 // TODO when reaching the last reference to the open file, close it on unmap
 /// A map residence is the source of the data on a physical page used by a mapping. It is also the
 /// location to which the data is to be synchronized when modified.
@@ -127,22 +138,44 @@ impl MapResidence {
 	///
 	/// The returned page is already populated with the necessary data. It is released when
 	/// [`ResidencePage`] is dropped.
-	pub fn acquire_page(&self, offset: usize) -> AllocResult<Arc<ResidencePage>> {
+	///
+	/// This function never performs I/O. If the page of a file is not in cache, the function
+	/// returns [`errno::EAGAIN`] and the page has to be read first using [`Self::fill_page`].
+	pub fn acquire_page(&self, offset: usize) -> EResult<Arc<ResidencePage>> {
 		match self {
 			MapResidence::Normal => {
 				let page = buddy::alloc(0, buddy::FLAG_ZONE_TYPE_USER)?;
-				Arc::new(ResidencePage::new(page))
+				Ok(Arc::new(ResidencePage::new(page))?)
 			}
 			MapResidence::Static {
 				pages,
-			} => pages.get(offset).cloned().ok_or(AllocError),
+			} => pages.get(offset).cloned().ok_or_else(|| errno!(EINVAL)),
 			MapResidence::File {
-				file: _,
-				off: _,
+				file,
+				off,
 			} => {
-				// TODO get physical page for this offset
-				todo!();
+				let (node, index) = file_page(file, *off, offset)?;
+				page_cache::cached_file_page(&node.location, index).ok_or_else(|| errno!(EAGAIN))
 			}
 		}
 	}
+
+	/// Reads the page at `offset` of a file residence into the page cache.
+	///
+	/// This function may sleep, so it must not be called while holding a spinlock. The returned
+	/// page must be kept alive until it is acquired with [`Self::acquire_page`], so that it
+	/// cannot be evicted from the cache in between.
+	///
+	/// If the residence is not a file, the function returns `None`.
+	pub fn fill_page(&self, offset: usize) -> EResult<Option<Arc<ResidencePage>>> {
+		let MapResidence::File {
+			file,
+			off,
+		} = self
+		else {
+			return Ok(None);
+		};
+		let (node, index) = file_page(file, *off, offset)?;
+		page_cache::map_file_page(node, index).map(Some)
+	}
 }
@@ -45,7 +45,7 @@ use crate::{
 	},
 	memory::{buddy, buddy::FrameOrder, VirtAddr},
 	process::{
-		mem_space::{copy, copy::SyscallPtr, MAPPING_FLAG_USER, MAPPING_FLAG_WRITE},
+		mem_space::{copy, copy::SyscallPtr, PageFault, MAPPING_FLAG_USER, MAPPING_FLAG_WRITE},
 		pid::{PidHandle, IDLE_PID, INIT_PID},
 		rusage::Rusage,
 		scheduler::{switch, Scheduler, SCHEDULER},
@@ -65,7 +65,7 @@ use core::{
 	mem::ManuallyDrop,
 	ptr::NonNull,
 	sync::atomic::{
-		AtomicBool, AtomicPtr, AtomicU32, AtomicU8,
+		AtomicBool, AtomicPtr, AtomicU32, AtomicU8, AtomicUsize,
 		Ordering::{Acquire, Relaxed, Release, SeqCst},
 	},
 };
@@ -323,6 +323,8 @@ pub struct Process {
 	state: AtomicU8,
 	/// If `true`, the parent can resume after a `vfork`.
 	pub vfork_done: AtomicBool,
+	/// Tells whether the process is a kernel thread.
+	kthread: bool,
 	/// The links to other processes.
 	pub links: Mutex<ProcessLinks>,
 
@@ -330,6 +332,9 @@ pub struct Process {
 	kernel_stack: KernelStack,
 	/// Kernel stack pointer of saved context.
 	kernel_sp: AtomicPtr<u8>,
+	/// The number of atomic sections of the saved context. See
+	/// [`crate::sync::mutex::in_atomic`].
+	atomic_depth: AtomicUsize,
 	/// The process's FPU state.
 	fpu: Mutex<FxState>,
 	/// TLS entries.
@@ -400,26 +405,40 @@ pub(crate) fn init() -> EResult<()> {
 		if unlikely(proc.is_idle_task()) {
 			return CallbackResult::Panic;
 		}
-		// Check access
-		let success = {
-			let Some(mem_space_mutex) = proc.mem_space.as_ref() else {
-				return CallbackResult::Panic;
+		let Some(mem_space_mutex) = proc.mem_space.as_ref() else {
+			return CallbackResult::Panic;
+		};
+		// The page read from a file, kept until the fault is handled again so that it remains in
+		// cache
+		let mut _page = None;
+		let fault = loop {
+			let fault = mem_space_mutex
+				.lock()
+				.handle_page_fault(accessed_addr, code);
+			let PageFault::Fill(residence, offset) = fault else {
+				break fault;
 			};
-			let mut mem_space = mem_space_mutex.lock();
-			mem_space.handle_page_fault(accessed_addr, code)
+			// The lock is released since reading may sleep
+			match residence.fill_page(offset) {
+				Ok(page) => _page = page,
+				Err(e) => break PageFault::Error(e),
+			}
 		};
-		if !success {
-			if ring < 3 {
-				// Check if the fault was caused by a user <-> kernel copy
-				if (copy::raw_copy as usize..copy::copy_fault as usize).contains(&pc) {
-					// Jump to `copy_fault`
-					frame.set_program_counter(copy::copy_fault as usize);
-				} else {
-					return CallbackResult::Panic;
-				}
+		let signal = match fault {
+			PageFault::Resolved | PageFault::Fill(..) => return CallbackResult::Continue,
+			PageFault::Invalid => Signal::SIGSEGV,
+			PageFault::Error(_) => Signal::SIGBUS,
+		};
+		if ring < 3 {
+			// Check if the fault was caused by a user <-> kernel copy
+			if (copy::raw_copy as usize..copy::copy_fault as usize).contains(&pc) {
+				// Jump to `copy_fault`
+				frame.set_program_counter(copy::copy_fault as usize);
 			} else {
-				proc.kill(Signal::SIGSEGV);
+				return CallbackResult::Panic;
 			}
+		} else {
+			proc.kill(signal);
 		}
 		CallbackResult::Continue
 	};
@@ -467,10 +486,12 @@ impl Process {
 
 			state: AtomicU8::new(State::Running as _),
 			vfork_done: AtomicBool::new(false),
+			kthread: false,
 			links: Default::default(),
 
 			kernel_stack,
 			kernel_sp: AtomicPtr::new(kernel_sp),
+			atomic_depth: AtomicUsize::new(0),
 			fpu: Mutex::new(FxState([0; 512])),
 			tls: Default::default(),
 
@@ -490,6 +511,46 @@ impl Process {
 		})
 	}
 
+	/// Creates a kernel thread executing `entry` and places it into the scheduler's queue.
+	///
+	/// A kernel thread runs only in kernelspace, without a memory space. It does not handle
+	/// signals.
+	pub fn kernel_thread(entry: fn() -> !) -> EResult<Arc<Self>> {
+		let pid = PidHandle::unique()?;
+		let tid = pid.get();
+		let kernel_stack = KernelStack::new()?;
+		let kernel_sp = unsafe { switch::init_kthread(kernel_stack.top(), entry) };
+		let process = Self {
+			pid,
+			tid,
+
+			state: AtomicU8::new(State::Running as _),
+			vfork_done: AtomicBool::new(false),
+			kthread: true,
+			links: Default::default(),
+
+			kernel_stack,
+			kernel_sp: AtomicPtr::new(kernel_sp),
+			atomic_depth: AtomicUsize::new(0),
+			fpu: Mutex::new(FxState([0; 512])),
+			tls: Default::default(),
+
+			mem_space: Default::default(),
+			fs: Mutex::new(ProcessFs {
+				access_profile: AccessProfile::KERNEL,
+				umask: Default::default(),
+				cwd: vfs::root(),
+				chroot: vfs::root(),
+			}),
+			file_descriptors: Default::default(),
+			timer_manager: Arc::new(Mutex::new(TimerManager::new(tid)?))?,
+			signal: Mutex::new(ProcessSignal::new()?),
+
+			rusage: Default::default(),
+		};
+		Ok(SCHEDULER.get().lock().add_process(process)?)
+	}
+
 	/// Creates the init process and places it into the scheduler's queue.
 	///
 	/// The process is set to state [`State::Running`] by default and has user root.
@@ -523,10 +584,12 @@ impl Process {
 
 			state: AtomicU8::new(State::Running as _),
 			vfork_done: AtomicBool::new(false),
+			kthread: false,
 			links: Mutex::new(ProcessLinks::default()),
 
 			kernel_stack: KernelStack::new()?,
 			kernel_sp: AtomicPtr::default(),
+			atomic_depth: AtomicUsize::new(0),
 			fpu: Mutex::new(FxState([0; 512])),
 			tls: Default::default(),
 
@@ -564,6 +627,11 @@ impl Process {
 		self.pid.get() == IDLE_PID
 	}
 
+	/// Tells whether the process is a kernel thread.
+	pub fn is_kernel_thread(&self) -> bool {
+		self.kthread
+	}
+
 	/// Tells whether the process is the init process.
 	#[inline(always)]
 	pub fn is_init(&self) -> bool {
@@ -821,6 +889,7 @@ impl Process {
 
 			state: AtomicU8::new(State::Running as _),
 			vfork_done: AtomicBool::new(false),
+			kthread: false,
 			links: Mutex::new(ProcessLinks {
 				parent: Some(this.clone()),
 				group_leader: this.links.lock().group_leader.clone(),
@@ -829,6 +898,7 @@ impl Process {
 
 			kernel_stack: KernelStack::new()?,
 			kernel_sp: AtomicPtr::default(),
+			atomic_depth: AtomicUsize::new(0),
 			fpu: Mutex::new(this.fpu.lock().clone()),
 			tls: Mutex::new(*this.tls.lock()),
 
@@ -857,6 +927,9 @@ impl Process {
 	/// If the process doesn't have a signal handler, the default action for the signal is
 	/// executed.
 	pub fn kill(&self, sig: Signal) {
+		if self.is_kernel_thread() {
+			return;
+		}
 		let mut signal_manager = self.signal.lock();
 		// Ignore blocked signals
 		if sig.can_catch() && signal_manager.sigmask.is_set(sig as _) {
@@ -976,3 +1049,35 @@ pub fn yield_current(ring: u8, frame: &mut IntFrame) {
 		Scheduler::tick();
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{selftest, selftest::Subsystem};
+	use core::sync::atomic::AtomicBool;
+
+	/// Tells whether the kernel thread of the test ran.
+	static KTHREAD_RAN: AtomicBool = AtomicBool::new(false);
+
+	fn kthread() -> ! {
+		KTHREAD_RAN.store(true, Release);
+		// Give the CPU back to the self-tests for good
+		Process::current().set_state(State::Sleeping);
+		loop {
+			Scheduler::tick();
+		}
+	}
+
+	#[test_case]
+	fn process_kernel_thread() {
+		selftest::require(Subsystem::Processes);
+		let thread = Process::kernel_thread(kthread).unwrap();
+		assert!(thread.is_kernel_thread());
+		// The self-tests run in place of the idle task, to which the scheduler switches back once
+		// the thread sleeps
+		Scheduler::tick();
+		assert!(KTHREAD_RAN.load(Acquire));
+		assert_eq!(thread.get_state(), State::Sleeping);
+		SCHEDULER.get().lock().remove_process(thread.get_pid());
+	}
+}
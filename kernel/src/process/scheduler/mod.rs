@@ -24,18 +24,21 @@
 pub mod switch;
 
 use crate::{
-	arch::x86::{cli, idt::IntFrame, pic},
+	arch::x86::{cli, idt::IntFrame, is_interrupt_enabled, pic, sti},
 	event,
 	event::{CallbackHook, CallbackResult},
 	process::{pid::Pid, scheduler::switch::switch, Process, State},
-	sync::{atomic::AtomicU64, mutex::IntMutex, once::OnceInit},
+	sync::{atomic::AtomicU64, mutex, mutex::IntMutex, once::OnceInit},
 	time,
 };
 use core::{
 	mem,
 	sync::{
 		atomic,
-		atomic::{AtomicUsize, Ordering::Release},
+		atomic::{
+			AtomicBool, AtomicUsize,
+			Ordering::{Acquire, Release},
+		},
 	},
 };
 use utils::{
@@ -47,6 +50,8 @@ use utils::{
 
 /// The process scheduler.
 pub static SCHEDULER: OnceInit<IntMutex<Scheduler>> = unsafe { OnceInit::new() };
+/// Tells whether [`SCHEDULER`] has been initialized.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 /// Initializes schedulers.
 pub fn init() -> AllocResult<()> {
@@ -54,9 +59,19 @@ pub fn init() -> AllocResult<()> {
 		SCHEDULER.init(IntMutex::new(Scheduler::new()?));
 	}
 	SCHEDULER.get().lock().setup_gs_base();
+	INITIALIZED.store(true, Release);
 	Ok(())
 }
 
+/// Tells whether the current context is allowed to sleep.
+///
+/// This is not the case in the following situations, in which drivers have to busy-wait instead:
+/// - at boot, before the first process is running
+/// - in an interrupt handler, or while holding an [`IntMutex`]. See [`mutex::in_atomic`]
+pub fn can_sleep() -> bool {
+	INITIALIZED.load(Acquire) && !mutex::in_atomic() && !Process::current().is_idle_task()
+}
+
 /// Kernel CPU local storage.
 #[derive(Default)]
 #[repr(C)]
@@ -245,9 +260,18 @@ impl Scheduler {
 	///
 	/// If no process is ready to run, the scheduler halts the current core until a process becomes
 	/// runnable.
+	///
+	/// When the current context is resumed, interrupts are restored to their state at the time of
+	/// the call.
 	pub fn tick() {
+		let int_state = is_interrupt_enabled();
 		// Disable interrupts so that no interrupt can occur before switching to the next process
 		cli();
+		let restore = || {
+			if int_state {
+				sti();
+			}
+		};
 		let (prev, next) = {
 			let mut sched = SCHEDULER.get().lock();
 			sched.total_ticks.fetch_add(1, atomic::Ordering::Relaxed);
@@ -255,6 +279,8 @@ impl Scheduler {
 			let next = sched.get_next_process().unwrap_or(sched.idle_task.clone());
 			// If the process to run is the current, do nothing
 			if next.get_pid() == sched.curr_proc.get_pid() {
+				drop(sched);
+				restore();
 				return;
 			}
 			// Swap current running process. We use pointers to avoid cloning the Arc
@@ -267,5 +293,7 @@ impl Scheduler {
 		unsafe {
 			switch(prev, next);
 		}
+		// The stack is the one of the resumed context, and so is `int_state`
+		restore();
 	}
 }
@@ -19,11 +19,12 @@
 //! Context switching utilities.
 
 use crate::{
-	arch::x86::{fxrstor, fxsave, gdt, idt::IntFrame, tss},
+	arch::x86::{fxrstor, fxsave, gdt, idt::IntFrame, sti, tss},
 	memory::vmem,
 	process::Process,
+	sync::mutex,
 };
-use core::{arch::global_asm, mem::offset_of, ptr::NonNull};
+use core::{arch::global_asm, mem::offset_of, ptr::NonNull, sync::atomic::Ordering::Relaxed};
 
 /// Stashes current segment values during execution of `f`, restoring them after.
 pub fn stash_segments<F: FnOnce() -> T, T>(f: F) -> T {
@@ -104,6 +105,8 @@ extern "C" {
 
 	/// The idle task code.
 	pub fn idle_task() -> !;
+	/// The code starting a kernel thread, calling [`kthread_main`] with the entry point.
+	fn kthread_start() -> !;
 }
 
 #[cfg(target_arch = "x86")]
@@ -204,6 +207,9 @@ switch_asm:
 /// This function is jumped to from [`switch`].
 #[export_name = "switch_finish"]
 pub extern "C" fn finish(prev: &Process, next: &Process) {
+	// Swap atomic sections, which belong to the context
+	let depth = mutex::swap_atomic_depth(next.atomic_depth.load(Relaxed));
+	prev.atomic_depth.store(depth, Relaxed);
 	// Bind the memory space
 	match next.mem_space.as_ref() {
 		Some(mem_space) => mem_space.lock().bind(),
@@ -274,3 +280,65 @@ pub unsafe fn init_idle(stack: NonNull<u8>) -> *mut u8 {
 	stack.write(frame);
 	stack.cast().as_ptr()
 }
+
+/// Initialization frame for a kernel thread.
+#[cfg(target_arch = "x86")]
+#[repr(C, packed)]
+struct KThreadInit {
+	/// Padding for unused registers pop.
+	pad: [u32; 2],
+	/// The entry point, popped into `ebx`.
+	entry: u32,
+	/// Padding for unused registers pop.
+	pad2: u32,
+	/// Program counter.
+	rip: u32,
+	/// Space for the arguments to [`switch`].
+	args: [u32; 2],
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C, packed)]
+struct KThreadInit {
+	/// Padding for unused registers pop.
+	pad: [u64; 4],
+	/// The entry point, popped into `rbx`.
+	entry: u64,
+	/// Padding for unused registers pop.
+	pad2: u64,
+	/// Program counter.
+	rip: u64,
+}
+
+/// Writes an initialization frame for a kernel thread on `stack`.
+///
+/// When first scheduled, the thread starts executing `entry`.
+///
+/// The function returns the new stack pointer with the frame on top.
+///
+/// # Safety
+///
+/// `stack` must be the top of a valid stack.
+pub unsafe fn init_kthread(stack: NonNull<u8>, entry: fn() -> !) -> *mut u8 {
+	let frame = KThreadInit {
+		pad: Default::default(),
+		entry: entry as usize as _,
+		pad2: 0,
+		rip: kthread_start as usize as _,
+		// this will get written on by the function `stack`
+		#[cfg(target_arch = "x86")]
+		args: [0; 2],
+	};
+	let stack = stack.cast().sub(1);
+	stack.write(frame);
+	stack.cast().as_ptr()
+}
+
+/// Entry point of kernel threads, called from [`kthread_start`].
+#[no_mangle]
+#[allow(improper_ctypes_definitions)]
+extern "C" fn kthread_main(entry: fn() -> !) -> ! {
+	// Interrupts are disabled when switching context
+	sti();
+	entry()
+}
@@ -75,3 +75,47 @@ pub fn runner(tests: &[&dyn Testable]) {
 pub fn is_running() -> bool {
 	RUNNING.load(atomic::Ordering::Relaxed)
 }
+
+/// A subsystem self-tests may require, in initialization order.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Subsystem {
+	/// Time management.
+	Time,
+	/// The network stack, with the loopback interface.
+	Net,
+	/// Files management, with an empty root filesystem.
+	Files,
+	/// Processes management.
+	Processes,
+}
+
+/// Initializes `subsystem` along with the subsystems preceding it, unless already done.
+///
+/// Self-tests run before most subsystems are initialized. Since the kernel halts once they are
+/// done, tests initialize the subsystems they require on demand.
+#[cfg(test)]
+pub fn require(subsystem: Subsystem) {
+	use crate::{file, net, process, sync::mutex::Mutex, time};
+	use utils::errno::EResult;
+
+	/// The last initialized subsystem.
+	static INITIALIZED: Mutex<Option<Subsystem>> = Mutex::new(None);
+	let steps = [
+		(Subsystem::Time, time::init as fn() -> EResult<()>),
+		(Subsystem::Net, net::init),
+		(Subsystem::Files, || file::init(None)),
+		(Subsystem::Processes, process::init),
+	];
+	let mut initialized = INITIALIZED.lock();
+	for (s, init) in steps {
+		if s > subsystem {
+			break;
+		}
+		if initialized.is_some_and(|i| s <= i) {
+			continue;
+		}
+		init().unwrap_or_else(|e| panic!("Cannot initialize {s:?} for self-tests! ({e})"));
+		*initialized = Some(s);
+	}
+}
@@ -41,8 +41,46 @@ use core::{
 	cell::UnsafeCell,
 	fmt::{self, Formatter},
 	ops::{Deref, DerefMut},
+	sync::atomic::{AtomicUsize, Ordering::Relaxed},
 };
 
+/// The number of atomic sections the running context is in.
+///
+/// An atomic section is either a held [`IntMutex`] or the execution of an interrupt handler.
+/// The value belongs to the running context and is swapped on context switch.
+static ATOMIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Enters an atomic section. See [`in_atomic`].
+#[inline]
+pub fn enter_atomic() {
+	ATOMIC_DEPTH.fetch_add(1, Relaxed);
+}
+
+/// Leaves an atomic section entered with [`enter_atomic`].
+#[inline]
+pub fn leave_atomic() {
+	ATOMIC_DEPTH.fetch_sub(1, Relaxed);
+}
+
+/// Tells whether the running context is in an atomic section, in which it must not sleep.
+///
+/// Masked interrupts alone do not make an atomic section, since system calls and exceptions
+/// are entered with interrupts masked and are allowed to sleep. However, sleeping while
+/// holding an [`IntMutex`] or in an interrupt handler would deadlock as soon as another
+/// context attempts to take the same lock or expects the handler to return.
+#[inline]
+pub fn in_atomic() -> bool {
+	ATOMIC_DEPTH.load(Relaxed) != 0
+}
+
+/// Replaces the atomic depth of the running context with `depth`, returning the previous one.
+///
+/// This function is meant to be used on context switch only.
+#[inline]
+pub fn swap_atomic_depth(depth: usize) -> usize {
+	ATOMIC_DEPTH.swap(depth, Relaxed)
+}
+
 /// Type used to declare a guard meant to unlock the associated `Mutex` at the
 /// moment the execution gets out of the scope of its declaration.
 pub struct MutexGuard<'m, T: ?Sized, const INT: bool> {
@@ -130,6 +168,7 @@ impl<T: ?Sized, const INT: bool> Mutex<T, INT> {
 		let int_state = if !INT {
 			let enabled = x86::is_interrupt_enabled();
 			cli();
+			enter_atomic();
 			enabled
 		} else {
 			// In this case, this value does not matter
@@ -144,6 +183,36 @@ impl<T: ?Sized, const INT: bool> Mutex<T, INT> {
 		}
 	}
 
+	/// Attempts to lock the mutex without waiting.
+	///
+	/// If the mutex is already locked, the function returns `None`.
+	pub fn try_lock(&self) -> Option<MutexGuard<T, INT>> {
+		let int_state = if !INT {
+			let enabled = x86::is_interrupt_enabled();
+			cli();
+			enter_atomic();
+			enabled
+		} else {
+			// In this case, this value does not matter
+			false
+		};
+		// Safe because using the spinlock
+		let inner = unsafe { &mut *self.inner.get() };
+		if !inner.spin.try_lock() {
+			if !INT {
+				leave_atomic();
+				if int_state {
+					sti();
+				}
+			}
+			return None;
+		}
+		Some(MutexGuard {
+			mutex: self,
+			int_state,
+		})
+	}
+
 	/// Unlocks the mutex. This function should not be used directly since it is called when the
 	/// mutex guard is dropped.
 	///
@@ -157,8 +226,11 @@ impl<T: ?Sized, const INT: bool> Mutex<T, INT> {
 	pub unsafe fn unlock(&self, int_state: bool) {
 		let inner = &mut (*self.inner.get());
 		inner.spin.unlock();
-		if !INT && int_state {
-			sti();
+		if !INT {
+			leave_atomic();
+			if int_state {
+				sti();
+			}
 		}
 	}
 }
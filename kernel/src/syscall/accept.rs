@@ -0,0 +1,92 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `accept` system call accepts a connection on a listening socket.
+
+use crate::{
+	file,
+	file::{
+		fd::{FileDescriptorTable, FD_CLOEXEC},
+		socket::Socket,
+		File,
+	},
+	net::{SOCK_CLOEXEC, SOCK_NONBLOCK},
+	process::{
+		mem_space::copy::{SyscallPtr, SyscallSlice},
+		Process,
+	},
+	sync::mutex::Mutex,
+	syscall::{util::socket::copy_sockaddr_to_user, Args},
+};
+use core::{any::Any, ffi::c_int};
+use utils::{
+	errno,
+	errno::{EResult, Errno},
+	ptr::arc::Arc,
+};
+
+/// Performs the `accept4` operation.
+///
+/// Arguments:
+/// - `sockfd` is the file descriptor of the listening socket
+/// - `addr` is the buffer to write the address of the peer to. If null, the address is not written
+/// - `addrlen` is the length of `addr`, updated with the length of the address
+/// - `flags` is the set of flags for the new file descriptor
+/// - `fds` is the file descriptors table
+pub fn do_accept(
+	sockfd: c_int,
+	addr: SyscallSlice<u8>,
+	addrlen: SyscallPtr<c_int>,
+	flags: c_int,
+	fds: Arc<Mutex<FileDescriptorTable>>,
+) -> EResult<usize> {
+	if flags & !(SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+		return Err(errno!(EINVAL));
+	}
+	let file = fds.lock().get_fd(sockfd)?.get_file().clone();
+	let sock = file
+		.get_buffer_arc::<Socket>()
+		.ok_or_else(|| errno!(ENOTSOCK))?;
+	if !sock.desc().type_.is_stream() {
+		return Err(errno!(EOPNOTSUPP));
+	}
+	let new_sock = sock.accept(file.get_flags() & file::O_NONBLOCK != 0)?;
+	let new_file = File::open_floating(new_sock.clone(), file::O_RDWR | (flags & SOCK_NONBLOCK))?;
+	if addr.0.is_some() {
+		// The peer might have closed the connection already
+		let peer = match new_sock.get_peername() {
+			Err(e) if e.as_int() == errno::ENOTCONN => Default::default(),
+			res => res?,
+		};
+		copy_sockaddr_to_user(&peer, &addr, &addrlen)?;
+	}
+	let fd_flags = if flags & SOCK_CLOEXEC != 0 {
+		FD_CLOEXEC
+	} else {
+		0
+	};
+	let (fd, _) = fds.lock().create_fd(fd_flags, new_file)?;
+	Ok(fd as _)
+}
+
+pub fn accept(
+	Args((sockfd, addr, addrlen)): Args<(c_int, SyscallSlice<u8>, SyscallPtr<c_int>)>,
+	fds: Arc<Mutex<FileDescriptorTable>>,
+) -> EResult<usize> {
+	do_accept(sockfd, addr, addrlen, 0, fds)
+}
@@ -0,0 +1,41 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `accept4` system call is the same as `accept`, with flags for the new file descriptor.
+
+use crate::{
+	file::fd::FileDescriptorTable,
+	process::mem_space::copy::{SyscallPtr, SyscallSlice},
+	sync::mutex::Mutex,
+	syscall::Args,
+};
+use core::ffi::c_int;
+use utils::{errno::EResult, ptr::arc::Arc};
+
+#[allow(clippy::type_complexity)]
+pub fn accept4(
+	Args((sockfd, addr, addrlen, flags)): Args<(
+		c_int,
+		SyscallSlice<u8>,
+		SyscallPtr<c_int>,
+		c_int,
+	)>,
+	fds: Arc<Mutex<FileDescriptorTable>>,
+) -> EResult<usize> {
+	super::accept::do_accept(sockfd, addr, addrlen, flags, fds)
+}
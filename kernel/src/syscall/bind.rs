@@ -19,10 +19,10 @@
 //! The `bind` system call binds a name to a socket.
 
 use crate::{
-	file::{fd::FileDescriptorTable, socket::Socket},
+	file::{fd::FileDescriptorTable, socket::Socket, vfs::ResolutionSettings},
 	process::{mem_space::copy::SyscallSlice, Process},
 	sync::mutex::Mutex,
-	syscall::Args,
+	syscall::{Args, Umask},
 };
 use core::{any::Any, ffi::c_int};
 use utils::{
@@ -33,6 +33,8 @@ use utils::{
 
 pub fn bind(
 	Args((sockfd, addr, addrlen)): Args<(c_int, SyscallSlice<u8>, isize)>,
+	rs: ResolutionSettings,
+	umask: Umask,
 	fds: Arc<Mutex<FileDescriptorTable>>,
 ) -> EResult<usize> {
 	// Validation
@@ -41,10 +43,10 @@ pub fn bind(
 	}
 	// Get socket
 	let file = fds.lock().get_fd(sockfd)?.get_file().clone();
-	let sock: &Socket = file.get_buffer().ok_or_else(|| errno!(ENOTSOCK))?;
+	let sock: Arc<Socket> = file.get_buffer_arc().ok_or_else(|| errno!(ENOTSOCK))?;
 	let buf = addr
 		.copy_from_user_vec(0, addrlen as usize)?
 		.ok_or_else(|| errno!(EFAULT))?;
-	sock.bind(&buf)?;
+	Socket::bind(&sock, &buf, &rs, umask.0)?;
 	Ok(0)
 }
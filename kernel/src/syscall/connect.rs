@@ -19,7 +19,7 @@
 //! The `connect` system call connects a socket to a distant host.
 
 use crate::{
-	file::{fd::FileDescriptorTable, socket::Socket},
+	file::{fd::FileDescriptorTable, socket::Socket, vfs::ResolutionSettings, O_NONBLOCK},
 	process::{mem_space::copy::SyscallSlice, Process},
 	sync::mutex::Mutex,
 	syscall::Args,
@@ -34,6 +34,7 @@ use utils::{
 /// The implementation of the `connect` syscall.
 pub fn connect(
 	Args((sockfd, addr, addrlen)): Args<(c_int, SyscallSlice<u8>, isize)>,
+	rs: ResolutionSettings,
 	fds: Arc<Mutex<FileDescriptorTable>>,
 ) -> EResult<usize> {
 	// Validation
@@ -42,10 +43,10 @@ pub fn connect(
 	}
 	// Get socket
 	let file = fds.lock().get_fd(sockfd)?.get_file().clone();
-	let _sock: &Socket = file.get_buffer().ok_or_else(|| errno!(ENOTSOCK))?;
-	let _addr = addr
+	let sock: Arc<Socket> = file.get_buffer_arc().ok_or_else(|| errno!(ENOTSOCK))?;
+	let addr = addr
 		.copy_from_user_vec(0, addrlen as usize)?
 		.ok_or_else(|| errno!(EFAULT))?;
-	// TODO connect socket
-	todo!()
+	Socket::connect(&sock, &addr, &rs, file.get_flags() & O_NONBLOCK != 0)?;
+	Ok(0)
 }
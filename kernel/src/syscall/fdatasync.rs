@@ -0,0 +1,33 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `fdatasync` system call synchronizes the data of a file to storage.
+
+use crate::{file::fd::FileDescriptorTable, sync::mutex::Mutex, syscall::Args};
+use core::ffi::c_int;
+use utils::{
+	errno::{EResult, Errno},
+	ptr::arc::Arc,
+};
+
+pub fn fdatasync(Args(fd): Args<c_int>, fds: Arc<Mutex<FileDescriptorTable>>) -> EResult<usize> {
+	// Metadata is written along with data, so this is the same as `fsync`
+	let file = fds.lock().get_fd(fd)?.get_file().clone();
+	file.sync()?;
+	Ok(0)
+}
@@ -18,15 +18,15 @@
 
 //! The `fsync` system call synchronizes the state of a file to storage.
 
-use crate::{file::fd::FileDescriptorTable, process::Process, sync::mutex::Mutex, syscall::Args};
+use crate::{file::fd::FileDescriptorTable, sync::mutex::Mutex, syscall::Args};
 use core::ffi::c_int;
 use utils::{
-	errno,
 	errno::{EResult, Errno},
 	ptr::arc::Arc,
 };
 
-pub fn fsync(Args(_fd): Args<c_int>, _fds: Arc<Mutex<FileDescriptorTable>>) -> EResult<usize> {
-	// TODO
+pub fn fsync(Args(fd): Args<c_int>, fds: Arc<Mutex<FileDescriptorTable>>) -> EResult<usize> {
+	let file = fds.lock().get_fd(fd)?.get_file().clone();
+	file.sync()?;
 	Ok(0)
 }
@@ -64,6 +64,33 @@ pub const TIOCSWINSZ: c_ulong = 0x00005414;
 /// ioctl request: Returns the number of bytes available on the file descriptor.
 pub const FIONREAD: c_ulong = 0x0000541b;
 
+// ioctl requests: network
+
+/// ioctl request: Adds a route to the routing table.
+pub const SIOCADDRT: c_ulong = 0x0000890b;
+/// ioctl request: Removes a route from the routing table.
+pub const SIOCDELRT: c_ulong = 0x0000890c;
+/// ioctl request: Returns the list of addresses of the network interfaces.
+pub const SIOCGIFCONF: c_ulong = 0x00008912;
+/// ioctl request: Returns the flags of a network interface.
+pub const SIOCGIFFLAGS: c_ulong = 0x00008913;
+/// ioctl request: Sets the flags of a network interface.
+pub const SIOCSIFFLAGS: c_ulong = 0x00008914;
+/// ioctl request: Returns the address of a network interface.
+pub const SIOCGIFADDR: c_ulong = 0x00008915;
+/// ioctl request: Sets the address of a network interface.
+pub const SIOCSIFADDR: c_ulong = 0x00008916;
+/// ioctl request: Returns the netmask of a network interface.
+pub const SIOCGIFNETMASK: c_ulong = 0x0000891b;
+/// ioctl request: Sets the netmask of a network interface.
+pub const SIOCSIFNETMASK: c_ulong = 0x0000891c;
+/// ioctl request: Returns the MTU of a network interface.
+pub const SIOCGIFMTU: c_ulong = 0x00008921;
+/// ioctl request: Returns the hardware address of a network interface.
+pub const SIOCGIFHWADDR: c_ulong = 0x00008927;
+/// ioctl request: Returns the index of a network interface.
+pub const SIOCGIFINDEX: c_ulong = 0x00008933;
+
 /// IO directions for ioctl requests.
 #[derive(Eq, PartialEq)]
 pub enum Direction {
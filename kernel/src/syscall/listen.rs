@@ -0,0 +1,49 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `listen` system call marks a socket as accepting connections.
+
+use crate::{
+	file::{fd::FileDescriptorTable, socket::Socket},
+	process::Process,
+	sync::mutex::Mutex,
+	syscall::Args,
+};
+use core::ffi::c_int;
+use utils::{
+	errno,
+	errno::{EResult, Errno},
+	ptr::arc::Arc,
+};
+
+/// The maximum length of the queue of pending connections.
+const SOMAXCONN: usize = 4096;
+
+pub fn listen(
+	Args((sockfd, backlog)): Args<(c_int, c_int)>,
+	fds: Arc<Mutex<FileDescriptorTable>>,
+) -> EResult<usize> {
+	let file = fds.lock().get_fd(sockfd)?.get_file().clone();
+	let sock = file
+		.get_buffer_arc::<Socket>()
+		.ok_or_else(|| errno!(ENOTSOCK))?;
+	// Like Linux, a negative value is interpreted as unsigned, and thus clamped
+	let backlog = (backlog as u32 as usize).min(SOMAXCONN);
+	Socket::listen(&sock, backlog)?;
+	Ok(0)
+}
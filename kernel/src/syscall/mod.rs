@@ -26,6 +26,8 @@
 mod _exit;
 mod _llseek;
 mod _newselect;
+mod accept;
+mod accept4;
 mod access;
 mod arch_prctl;
 mod bind;
@@ -54,6 +56,7 @@ mod fchmod;
 mod fchmodat;
 mod fcntl;
 mod fcntl64;
+mod fdatasync;
 mod finit_module;
 mod fork;
 mod fstatfs;
@@ -65,6 +68,7 @@ mod getdents64;
 mod getegid;
 mod geteuid;
 mod getgid;
+mod getpeername;
 mod getpgid;
 mod getpid;
 mod getppid;
@@ -82,6 +86,7 @@ mod kill;
 mod lchown;
 mod link;
 mod linkat;
+mod listen;
 mod madvise;
 mod mkdir;
 mod mknod;
@@ -107,6 +112,8 @@ mod read;
 mod readlink;
 mod readv;
 mod reboot;
+mod recvfrom;
+mod recvmsg;
 mod rename;
 mod renameat2;
 mod rmdir;
@@ -114,6 +121,7 @@ mod rt_sigaction;
 mod rt_sigprocmask;
 mod sched_yield;
 mod select;
+mod sendmsg;
 mod sendto;
 mod set_thread_area;
 mod set_tid_address;
@@ -130,12 +138,14 @@ mod shutdown;
 mod signal;
 mod sigreturn;
 mod socket;
+mod socketcall;
 mod socketpair;
 mod stat;
 mod statfs;
 mod statfs64;
 mod symlink;
 mod symlinkat;
+mod sync;
 mod syncfs;
 mod time;
 mod timer_create;
@@ -169,6 +179,8 @@ use crate::{
 use _exit::_exit;
 use _llseek::{_llseek, lseek};
 use _newselect::_newselect;
+use accept::accept;
+use accept4::accept4;
 use access::access;
 use arch_prctl::arch_prctl;
 use bind::bind;
@@ -197,6 +209,7 @@ use fchmod::fchmod;
 use fchmodat::fchmodat;
 use fcntl::fcntl;
 use fcntl64::fcntl64;
+use fdatasync::fdatasync;
 use finit_module::finit_module;
 use fork::fork;
 use fstatfs::fstatfs;
@@ -208,6 +221,7 @@ use getdents64::getdents64;
 use getegid::getegid;
 use geteuid::geteuid;
 use getgid::getgid;
+use getpeername::getpeername;
 use getpgid::getpgid;
 use getpid::getpid;
 use getppid::getppid;
@@ -225,6 +239,7 @@ use kill::kill;
 use lchown::lchown;
 use link::link;
 use linkat::linkat;
+use listen::listen;
 use madvise::madvise;
 use mkdir::mkdir;
 use mknod::mknod;
@@ -251,6 +266,8 @@ use read::read;
 use readlink::readlink;
 use readv::readv;
 use reboot::reboot;
+use recvfrom::recvfrom;
+use recvmsg::recvmsg;
 use rename::rename;
 use renameat2::renameat2;
 use rmdir::rmdir;
@@ -258,6 +275,7 @@ use rt_sigaction::{compat_rt_sigaction, rt_sigaction};
 use rt_sigprocmask::rt_sigprocmask;
 use sched_yield::sched_yield;
 use select::select;
+use sendmsg::sendmsg;
 use sendto::sendto;
 use set_thread_area::set_thread_area;
 use set_tid_address::set_tid_address;
@@ -274,12 +292,14 @@ use shutdown::shutdown;
 use signal::signal;
 use sigreturn::{rt_sigreturn, sigreturn};
 use socket::socket;
+use socketcall::socketcall;
 use socketpair::socketpair;
 use stat::{fstat, fstat64, lstat, lstat64, stat, stat64, statx};
 use statfs::statfs;
 use statfs64::statfs64;
 use symlink::symlink;
 use symlinkat::symlinkat;
+use sync::sync;
 use syncfs::syncfs;
 use time::time;
 use timer_create::timer_create;
@@ -567,7 +587,7 @@ fn do_syscall32(id: usize, frame: &mut IntFrame) -> Option<EResult<usize>> {
 		0x021 => syscall!(access, frame),
 		// TODO 0x022 => syscall!(nice, frame),
 		// TODO 0x023 => syscall!(ftime, frame),
-		// TODO 0x024 => syscall!(sync, frame),
+		0x024 => syscall!(sync, frame),
 		0x025 => syscall!(kill, frame),
 		0x026 => syscall!(rename, frame),
 		0x027 => syscall!(mkdir, frame),
@@ -633,7 +653,7 @@ fn do_syscall32(id: usize, frame: &mut IntFrame) -> Option<EResult<usize>> {
 		0x063 => syscall!(statfs, frame),
 		0x064 => syscall!(fstatfs, frame),
 		// TODO 0x065 => syscall!(ioperm, frame),
-		// TODO 0x066 => syscall!(socketcall, frame),
+		0x066 => syscall!(socketcall, frame),
 		// TODO 0x067 => syscall!(syslog, frame),
 		// TODO 0x068 => syscall!(setitimer, frame),
 		// TODO 0x069 => syscall!(getitimer, frame),
@@ -677,7 +697,7 @@ fn do_syscall32(id: usize, frame: &mut IntFrame) -> Option<EResult<usize>> {
 		0x091 => syscall!(readv, frame),
 		0x092 => syscall!(writev, frame),
 		// TODO 0x093 => syscall!(getsid, frame),
-		// TODO 0x094 => syscall!(fdatasync, frame),
+		0x094 => syscall!(fdatasync, frame),
 		// TODO 0x095 => syscall!(_sysctl, frame),
 		// TODO 0x096 => syscall!(mlock, frame),
 		// TODO 0x097 => syscall!(munlock, frame),
@@ -888,16 +908,16 @@ fn do_syscall32(id: usize, frame: &mut IntFrame) -> Option<EResult<usize>> {
 		0x168 => syscall!(socketpair, frame),
 		0x169 => syscall!(bind, frame),
 		0x16a => syscall!(connect, frame),
-		// TODO 0x16b => syscall!(listen, frame),
-		// TODO 0x16c => syscall!(accept4, frame),
+		0x16b => syscall!(listen, frame),
+		0x16c => syscall!(accept4, frame),
 		0x16d => syscall!(getsockopt, frame),
 		0x16e => syscall!(setsockopt, frame),
 		0x16f => syscall!(getsockname, frame),
-		// TODO 0x170 => syscall!(getpeername, frame),
+		0x170 => syscall!(getpeername, frame),
 		0x171 => syscall!(sendto, frame),
-		// TODO 0x172 => syscall!(sendmsg, frame),
-		// TODO 0x173 => syscall!(recvfrom, frame),
-		// TODO 0x174 => syscall!(recvmsg, frame),
+		0x172 => syscall!(sendmsg, frame),
+		0x173 => syscall!(recvfrom, frame),
+		0x174 => syscall!(recvmsg, frame),
 		0x175 => syscall!(shutdown, frame),
 		// TODO 0x176 => syscall!(userfaultfd, frame),
 		// TODO 0x177 => syscall!(membarrier, frame),
@@ -1020,16 +1040,16 @@ fn do_syscall64(id: usize, frame: &mut IntFrame) -> Option<EResult<usize>> {
 		// TODO 0x028 => syscall!(sendfile, frame),
 		0x029 => syscall!(socket, frame),
 		0x02a => syscall!(connect, frame),
-		// TODO 0x02b => syscall!(accept, frame),
+		0x02b => syscall!(accept, frame),
 		0x02c => syscall!(sendto, frame),
-		// TODO 0x02d => syscall!(recvfrom, frame),
-		// TODO 0x02e => syscall!(sendmsg, frame),
-		// TODO 0x02f => syscall!(recvmsg, frame),
+		0x02d => syscall!(recvfrom, frame),
+		0x02e => syscall!(sendmsg, frame),
+		0x02f => syscall!(recvmsg, frame),
 		0x030 => syscall!(shutdown, frame),
 		0x031 => syscall!(bind, frame),
-		// TODO 0x032 => syscall!(listen, frame),
+		0x032 => syscall!(listen, frame),
 		0x033 => syscall!(getsockname, frame),
-		// TODO 0x034 => syscall!(getpeername, frame),
+		0x034 => syscall!(getpeername, frame),
 		0x035 => syscall!(socketpair, frame),
 		0x036 => syscall!(setsockopt, frame),
 		0x037 => syscall!(getsockopt, frame),
@@ -1052,7 +1072,7 @@ fn do_syscall64(id: usize, frame: &mut IntFrame) -> Option<EResult<usize>> {
 		0x048 => syscall!(fcntl, frame),
 		// TODO 0x049 => syscall!(flock, frame),
 		0x04a => syscall!(fsync, frame),
-		// TODO 0x04b => syscall!(fdatasync, frame),
+		0x04b => syscall!(fdatasync, frame),
 		0x04c => syscall!(truncate, frame),
 		// TODO 0x04d => syscall!(ftruncate, frame),
 		0x04e => syscall!(getdents, frame),
@@ -1139,7 +1159,7 @@ fn do_syscall64(id: usize, frame: &mut IntFrame) -> Option<EResult<usize>> {
 		// TODO 0x09f => syscall!(adjtimex, frame),
 		// TODO 0x0a0 => syscall!(setrlimit, frame),
 		0x0a1 => syscall!(chroot, frame),
-		// TODO 0x0a2 => syscall!(sync, frame),
+		0x0a2 => syscall!(sync, frame),
 		// TODO 0x0a3 => syscall!(acct, frame),
 		// TODO 0x0a4 => syscall!(settimeofday, frame),
 		0x0a5 => syscall!(mount, frame),
@@ -1265,7 +1285,7 @@ fn do_syscall64(id: usize, frame: &mut IntFrame) -> Option<EResult<usize>> {
 		// TODO 0x11d => syscall!(fallocate, frame),
 		// TODO 0x11e => syscall!(timerfd_settime, frame),
 		// TODO 0x11f => syscall!(timerfd_gettime, frame),
-		// TODO 0x120 => syscall!(accept4, frame),
+		0x120 => syscall!(accept4, frame),
 		// TODO 0x121 => syscall!(signalfd4, frame),
 		// TODO 0x122 => syscall!(eventfd2, frame),
 		// TODO 0x123 => syscall!(epoll_create1, frame),
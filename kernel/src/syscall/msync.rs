@@ -28,6 +28,7 @@ use crate::{
 };
 use core::ffi::{c_int, c_void};
 use utils::{
+	collections::vec::Vec,
 	errno,
 	errno::{EResult, Errno},
 	limits::PAGE_SIZE,
@@ -54,13 +55,25 @@ pub fn msync(
 		return Err(errno!(EINVAL));
 	}
 	// Iterate over mappings
-	let mem_space = mem_space.lock();
-	let mut i = 0;
-	let pages = length.div_ceil(PAGE_SIZE);
-	while i < pages {
-		let mapping = mem_space.get_mapping_for_addr(addr).ok_or(errno!(ENOMEM))?;
-		mapping.fs_sync(&mem_space.vmem)?; // TODO Use flags
-		i += mapping.get_size().get();
+	let mut files = Vec::new();
+	{
+		let mem_space = mem_space.lock();
+		let end = addr + length.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+		let mut addr = addr;
+		while addr < end {
+			let mapping = mem_space.get_mapping_for_addr(addr).ok_or(errno!(ENOMEM))?;
+			mapping.fs_sync()?;
+			if flags & MS_SYNC != 0 {
+				if let Some(file) = mapping.get_shared_file() {
+					files.push(file.clone())?;
+				}
+			}
+			addr = VirtAddr::from(mapping.get_begin()) + mapping.get_size().get() * PAGE_SIZE;
+		}
+	}
+	// Wait for the data to be written, without holding the memory space
+	for file in files {
+		file.sync()?;
 	}
 	Ok(0)
 }
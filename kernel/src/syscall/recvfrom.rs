@@ -0,0 +1,78 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `recvfrom` system call receives a message from a socket, along with the address of the
+//! sender.
+
+use crate::{
+	file::{fd::FileDescriptorTable, socket::Socket},
+	process::{
+		mem_space::copy::{SyscallPtr, SyscallSlice},
+		Process,
+	},
+	sync::mutex::Mutex,
+	syscall::{
+		util::socket::{copy_sockaddr_to_user, is_nonblock, MSG_TRUNC},
+		Args,
+	},
+};
+use core::{any::Any, cmp::min, ffi::c_int};
+use utils::{
+	collections::vec::Vec,
+	errno,
+	errno::{EResult, Errno},
+	ptr::arc::Arc,
+	vec,
+};
+
+// TODO implement MSG_PEEK and MSG_WAITALL
+
+#[allow(clippy::type_complexity)]
+pub fn recvfrom(
+	Args((sockfd, buf, len, flags, src_addr, addrlen)): Args<(
+		c_int,
+		SyscallSlice<u8>,
+		usize,
+		c_int,
+		SyscallSlice<u8>,
+		SyscallPtr<c_int>,
+	)>,
+	fds: Arc<Mutex<FileDescriptorTable>>,
+) -> EResult<usize> {
+	// Get socket
+	let file = fds.lock().get_fd(sockfd)?.get_file().clone();
+	let sock: &Socket = file.get_buffer().ok_or_else(|| errno!(ENOTSOCK))?;
+	// TODO perf: a buffer is not necessarily required
+	let mut buffer = vec![0u8; min(len, i32::MAX as usize)]?;
+	let mut addr = Vec::new();
+	let (len, data_len) = sock.recv_msg(
+		&mut buffer,
+		Some(&mut addr),
+		None,
+		is_nonblock(&file, flags),
+	)?;
+	buf.copy_to_user(0, &buffer[..len])?;
+	if src_addr.0.is_some() {
+		copy_sockaddr_to_user(&addr, &src_addr, &addrlen)?;
+	}
+	if flags & MSG_TRUNC != 0 {
+		Ok(data_len)
+	} else {
+		Ok(len)
+	}
+}
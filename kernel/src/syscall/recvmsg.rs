@@ -0,0 +1,144 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `recvmsg` system call receives a message from a socket, along with control messages.
+
+use crate::{
+	file::{
+		fd::{FileDescriptorTable, FD_CLOEXEC},
+		socket::{Socket, SocketState},
+	},
+	net::{sockopt::SOL_SOCKET, unix::Ancillary},
+	process::Process,
+	sync::mutex::Mutex,
+	syscall::{
+		util::socket::{
+			is_nonblock, CmsgWriter, SyscallMsgHdr, MSG_CMSG_CLOEXEC, MSG_CTRUNC, MSG_TRUNC,
+			SCM_CREDENTIALS, SCM_RIGHTS,
+		},
+		Args,
+	},
+};
+use core::{any::Any, cmp::min, ffi::c_int, mem::size_of};
+use utils::{
+	bytes::as_bytes,
+	collections::vec::Vec,
+	errno,
+	errno::{EResult, Errno},
+	ptr::arc::Arc,
+	vec,
+};
+
+/// Writes the ancillary data `anc` as control messages into `cmsgs`.
+///
+/// Arguments:
+/// - `sock` is the socket on which the data has been received
+/// - `flags` is the set of flags given to the system call
+/// - `fds` is the file descriptors table in which received files are installed
+///
+/// Files that do not fit in the control messages buffer are closed.
+fn write_ancillary(
+	cmsgs: &mut CmsgWriter,
+	sock: &Socket,
+	anc: &Ancillary,
+	flags: c_int,
+	fds: &Mutex<FileDescriptorTable>,
+) -> EResult<()> {
+	let passcred = match &*sock.state().lock() {
+		SocketState::Unix(state) => state.passcred,
+		_ => false,
+	};
+	if let Some(creds) = anc.creds.filter(|_| passcred) {
+		cmsgs.push(SOL_SOCKET, SCM_CREDENTIALS, as_bytes(&creds))?;
+	}
+	if anc.files.is_empty() {
+		return Ok(());
+	}
+	let fd_flags = if flags & MSG_CMSG_CLOEXEC != 0 {
+		FD_CLOEXEC
+	} else {
+		0
+	};
+	let max = min(anc.files.len(), cmsgs.available() / size_of::<c_int>());
+	let mut ids = Vec::with_capacity(max)?;
+	{
+		let mut fds = fds.lock();
+		for file in &anc.files[..max] {
+			// Like Linux, stop at the first error and report the truncation
+			let Ok((id, _)) = fds.create_fd(fd_flags, file.clone()) else {
+				break;
+			};
+			ids.extend_from_slice(&(id as c_int).to_ne_bytes())?;
+		}
+	}
+	if ids.len() / size_of::<c_int>() < anc.files.len() {
+		cmsgs.truncate();
+	}
+	if !ids.is_empty() {
+		cmsgs.push(SOL_SOCKET, SCM_RIGHTS, &ids)?;
+	}
+	Ok(())
+}
+
+// TODO implement MSG_PEEK and MSG_WAITALL
+
+pub fn recvmsg(
+	Args((sockfd, msg, flags)): Args<(c_int, SyscallMsgHdr, c_int)>,
+	fds: Arc<Mutex<FileDescriptorTable>>,
+) -> EResult<usize> {
+	// Get socket
+	let file = fds.lock().get_fd(sockfd)?.get_file().clone();
+	let sock: &Socket = file.get_buffer().ok_or_else(|| errno!(ENOTSOCK))?;
+	let hdr = msg.copy_from_user()?;
+	// TODO perf: a buffer is not necessarily required
+	let mut buf = vec![0u8; hdr.data_len()?]?;
+	let mut addr = Vec::new();
+	let mut anc = Ancillary::default();
+	let (len, data_len) = sock.recv_msg(
+		&mut buf,
+		Some(&mut addr),
+		Some(&mut anc),
+		is_nonblock(&file, flags),
+	)?;
+	hdr.write_data(&buf[..len])?;
+	// Address of the sender
+	let namelen = if hdr.name.0.is_some() {
+		let l = min(addr.len(), hdr.namelen as usize);
+		hdr.name.copy_to_user(0, &addr[..l])?;
+		addr.len() as u32
+	} else {
+		0
+	};
+	// Control messages
+	let mut cmsgs = CmsgWriter::new(&hdr);
+	write_ancillary(&mut cmsgs, sock, &anc, flags, &fds)?;
+	hdr.control.copy_to_user(0, cmsgs.as_slice())?;
+	let mut msg_flags = 0;
+	if data_len > len {
+		msg_flags |= MSG_TRUNC;
+	}
+	if cmsgs.is_truncated() {
+		msg_flags |= MSG_CTRUNC;
+	}
+	msg.copy_result_to_user(namelen, cmsgs.as_slice().len(), msg_flags)?;
+	if flags & MSG_TRUNC != 0 {
+		Ok(data_len)
+	} else {
+		Ok(len)
+	}
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `sendmsg` system call sends a message on a socket, along with control messages.
+
+use crate::{
+	file::{
+		fd::FileDescriptorTable, perm::AccessProfile, socket::Socket, vfs::ResolutionSettings,
+	},
+	net::{
+		sockopt::SOL_SOCKET,
+		unix::{Ancillary, Credentials},
+	},
+	process::Process,
+	sync::mutex::Mutex,
+	syscall::{
+		util::socket::{is_nonblock, Cmsgs, MsgHdr, SyscallMsgHdr, SCM_CREDENTIALS, SCM_RIGHTS},
+		Args,
+	},
+};
+use core::{ffi::c_int, mem::size_of};
+use utils::{
+	bytes::from_bytes,
+	errno,
+	errno::{EResult, Errno},
+	ptr::arc::Arc,
+};
+
+/// The maximum number of file descriptors passed in a single message.
+const SCM_MAX_FD: usize = 253;
+
+/// Parses the control messages of `hdr` into ancillary data.
+///
+/// Arguments:
+/// - `compat` tells whether userspace is in compatibility mode
+/// - `ap` is the access profile of the sender, used to check the credentials it sends
+/// - `fds` is the file descriptors table of the sender, used for `SCM_RIGHTS`
+fn parse_ancillary(
+	hdr: &MsgHdr,
+	ap: &AccessProfile,
+	fds: &Mutex<FileDescriptorTable>,
+) -> EResult<Ancillary> {
+	let control = hdr.read_control()?;
+	let mut anc = Ancillary::default();
+	for cmsg in Cmsgs::new(&control, hdr.is_compat()) {
+		let cmsg = cmsg?;
+		// Control messages of other levels are specific to other protocols
+		if cmsg.level != SOL_SOCKET {
+			continue;
+		}
+		match cmsg.type_ {
+			SCM_RIGHTS => {
+				let count = cmsg.data.len() / size_of::<c_int>();
+				if anc.files.len() + count > SCM_MAX_FD {
+					return Err(errno!(EINVAL));
+				}
+				let fds = fds.lock();
+				for fd in cmsg.data.chunks_exact(size_of::<c_int>()) {
+					let fd = c_int::from_ne_bytes(fd.try_into().unwrap());
+					let file = fds.get_fd(fd)?.get_file().clone();
+					anc.files.push(file)?;
+				}
+			}
+			SCM_CREDENTIALS => {
+				if cmsg.data.len() != size_of::<Credentials>() {
+					return Err(errno!(EINVAL));
+				}
+				let creds = *from_bytes::<Credentials>(cmsg.data).ok_or_else(|| errno!(EINVAL))?;
+				creds.check(Process::current().get_pid() as _, ap)?;
+				anc.creds = Some(creds);
+			}
+			_ => return Err(errno!(EINVAL)),
+		}
+	}
+	Ok(anc)
+}
+
+// TODO implement the other flags
+
+pub fn sendmsg(
+	Args((sockfd, msg, flags)): Args<(c_int, SyscallMsgHdr, c_int)>,
+	rs: ResolutionSettings,
+	fds: Arc<Mutex<FileDescriptorTable>>,
+) -> EResult<usize> {
+	// Get socket
+	let file = fds.lock().get_fd(sockfd)?.get_file().clone();
+	let sock = file
+		.get_buffer_arc::<Socket>()
+		.ok_or_else(|| errno!(ENOTSOCK))?;
+	let hdr = msg.copy_from_user()?;
+	let data = hdr.read_data()?;
+	let addr = hdr.name.copy_from_user_vec(0, hdr.namelen as usize)?;
+	let anc = parse_ancillary(&hdr, &rs.access_profile, &fds)?;
+	let nonblock = is_nonblock(&file, flags);
+	Socket::send_msg(&sock, &data, addr.as_deref(), anc, &rs, nonblock)
+}
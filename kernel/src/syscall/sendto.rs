@@ -19,10 +19,10 @@
 //! The `sendto` system call sends a message on a socket.
 
 use crate::{
-	file::{fd::FileDescriptorTable, socket::Socket},
+	file::{fd::FileDescriptorTable, socket::Socket, vfs::ResolutionSettings},
 	process::{mem_space::copy::SyscallSlice, Process},
 	sync::mutex::Mutex,
-	syscall::Args,
+	syscall::{util::socket::is_nonblock, Args},
 };
 use core::{any::Any, ffi::c_int, intrinsics::unlikely};
 use utils::{
@@ -30,11 +30,11 @@ use utils::{
 	errno::{EResult, Errno},
 	ptr::arc::Arc,
 };
-// TODO implement flags
+// TODO implement the other flags
 
 #[allow(clippy::type_complexity)]
 pub fn sendto(
-	Args((sockfd, buf, len, _flags, dest_addr, addrlen)): Args<(
+	Args((sockfd, buf, len, flags, dest_addr, addrlen)): Args<(
 		c_int,
 		SyscallSlice<u8>,
 		usize,
@@ -42,6 +42,7 @@ pub fn sendto(
 		SyscallSlice<u8>,
 		isize,
 	)>,
+	rs: ResolutionSettings,
 	fds: Arc<Mutex<FileDescriptorTable>>,
 ) -> EResult<usize> {
 	// Validation
@@ -50,11 +51,17 @@ pub fn sendto(
 	}
 	// Get socket
 	let file = fds.lock().get_fd(sockfd)?.get_file().clone();
-	let _sock: &Socket = file.get_buffer().ok_or_else(|| errno!(ENOTSOCK))?;
+	let sock = file
+		.get_buffer_arc::<Socket>()
+		.ok_or_else(|| errno!(ENOTSOCK))?;
 	// Get slices
-	let _buf_slice = buf.copy_from_user_vec(0, len)?.ok_or(errno!(EFAULT))?;
-	let _dest_addr_slice = dest_addr
-		.copy_from_user_vec(0, addrlen as usize)?
-		.ok_or(errno!(EFAULT))?;
-	todo!()
+	let buf_slice = buf.copy_from_user_vec(0, len)?.ok_or(errno!(EFAULT))?;
+	let nonblock = is_nonblock(&file, flags);
+	// If no destination address is given, use the connected peer
+	match dest_addr.copy_from_user_vec(0, addrlen as usize)? {
+		Some(dest_addr_slice) => {
+			Socket::send_to(&sock, &buf_slice, &dest_addr_slice, &rs, nonblock)
+		}
+		None => Socket::send(&sock, &buf_slice, nonblock),
+	}
 }
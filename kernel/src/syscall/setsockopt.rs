@@ -48,6 +48,6 @@ pub fn setsockopt(
 	let optval_slice = optval
 		.copy_from_user_vec(0, optlen)?
 		.ok_or(errno!(EFAULT))?;
-	sock.set_opt(level, optname, &optval_slice)
-		.map(|opt| opt as _)
+	sock.set_opt(level, optname, &optval_slice)?;
+	Ok(0)
 }
@@ -20,8 +20,13 @@
 
 use crate::{
 	file,
-	file::{fd::FileDescriptorTable, perm::AccessProfile, socket::Socket, vfs, File},
-	net::{SocketDesc, SocketDomain, SocketType},
+	file::{
+		fd::{FileDescriptorTable, FD_CLOEXEC},
+		perm::AccessProfile,
+		socket::Socket,
+		vfs, File,
+	},
+	net::{osi, unix, SocketDesc, SocketDomain, SocketType, SOCK_CLOEXEC, SOCK_NONBLOCK},
 	process::Process,
 	sync::mutex::Mutex,
 	syscall::Args,
@@ -40,19 +45,41 @@ pub fn socket(
 	fds: Arc<Mutex<FileDescriptorTable>>,
 ) -> EResult<usize> {
 	let sock_domain = SocketDomain::try_from(domain as u32)?;
-	let sock_type = SocketType::try_from(r#type as u32)?;
-	// Check permissions
-	if !ap.can_use_sock_domain(&sock_domain) || !ap.can_use_sock_type(&sock_type) {
+	// The type may be combined with flags for the file descriptor
+	let flags = r#type & (SOCK_NONBLOCK | SOCK_CLOEXEC);
+	let sock_type = SocketType::try_from((r#type & !flags) as u32)?;
+	// Check permissions. Raw netlink sockets do not give access to the network
+	let netlink = sock_domain == SocketDomain::AfNetlink;
+	if !ap.can_use_sock_domain(&sock_domain) || !(netlink || ap.can_use_sock_type(&sock_type)) {
 		return Err(errno!(EACCES));
 	}
+	// Packet sockets handle either whole frames or their payload
+	if sock_domain == SocketDomain::AfPacket
+		&& !matches!(sock_type, SocketType::SockRaw | SocketType::SockDgram)
+	{
+		return Err(errno!(ESOCKTNOSUPPORT));
+	}
 	let desc = SocketDesc {
 		domain: sock_domain,
 		type_: sock_type,
 		protocol,
 	};
+	match sock_domain {
+		SocketDomain::AfUnix => unix::check_desc(&desc)?,
+		SocketDomain::AfInet | SocketDomain::AfInet6 | SocketDomain::AfNetlink => {
+			osi::get_protocol(&desc)?;
+		}
+		_ => {}
+	}
 	// Create socket
 	let sock = Arc::new(Socket::new(desc)?)?;
-	let file = File::open_floating(sock, file::O_RDWR)?;
-	let (sock_fd_id, _) = fds.lock().create_fd(0, file)?;
+	Socket::open(&sock, &ap)?;
+	let file = File::open_floating(sock, file::O_RDWR | (flags & SOCK_NONBLOCK))?;
+	let fd_flags = if flags & SOCK_CLOEXEC != 0 {
+		FD_CLOEXEC
+	} else {
+		0
+	};
+	let (sock_fd_id, _) = fds.lock().create_fd(fd_flags, file)?;
 	Ok(sock_fd_id as _)
 }
@@ -0,0 +1,110 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `socketcall` system call is the entry point for socket system calls on x86, for
+//! userspace that does not use the dedicated system calls.
+
+use super::{
+	accept, accept4, bind, connect, getpeername, getsockname, getsockopt, listen, recvfrom,
+	recvmsg, sendmsg, sendto, setsockopt, shutdown, socket, socketpair, SyscallHandler,
+};
+use crate::{
+	arch::x86::idt::IntFrame,
+	process::{mem_space::copy::SyscallSlice, Process},
+	syscall::Args,
+};
+use core::ffi::c_int;
+use utils::{
+	errno,
+	errno::{EResult, Errno},
+};
+
+/// Returns the number of arguments of the given call, or `None` if the call does not exist.
+fn args_count(call: c_int) -> Option<usize> {
+	let count = match call {
+		// socket, bind, connect
+		1..=3 => 3,
+		// listen
+		4 => 2,
+		// accept, getsockname, getpeername
+		5..=7 => 3,
+		// socketpair, send, recv
+		8..=10 => 4,
+		// sendto, recvfrom
+		11 | 12 => 6,
+		// shutdown
+		13 => 2,
+		// setsockopt, getsockopt
+		14 | 15 => 5,
+		// sendmsg, recvmsg
+		16 | 17 => 3,
+		// accept4
+		18 => 4,
+		_ => return None,
+	};
+	Some(count)
+}
+
+pub fn socketcall(
+	Args((call, args)): Args<(c_int, SyscallSlice<u32>)>,
+	frame: &mut IntFrame,
+) -> EResult<usize> {
+	let count = args_count(call).ok_or_else(|| errno!(EINVAL))?;
+	let mut a = [0u32; 6];
+	if !args.copy_from_user(0, &mut a[..count])? {
+		return Err(errno!(EFAULT));
+	}
+	// Place the arguments where the handlers expect them, as for a regular system call
+	let mut f = frame.clone();
+	f.rbx = a[0] as _;
+	f.rcx = a[1] as _;
+	f.rdx = a[2] as _;
+	f.rsi = a[3] as _;
+	f.rdi = a[4] as _;
+	f.rbp = a[5] as _;
+	match call {
+		1 => socket.call("socket", &mut f),
+		2 => bind.call("bind", &mut f),
+		3 => connect.call("connect", &mut f),
+		4 => listen.call("listen", &mut f),
+		5 => accept.call("accept", &mut f),
+		6 => getsockname.call("getsockname", &mut f),
+		7 => getpeername.call("getpeername", &mut f),
+		8 => socketpair.call("socketpair", &mut f),
+		// `send` and `recv` are `sendto` and `recvfrom` without address
+		9 => {
+			f.rdi = 0;
+			f.rbp = 0;
+			sendto.call("send", &mut f)
+		}
+		10 => {
+			f.rdi = 0;
+			f.rbp = 0;
+			recvfrom.call("recv", &mut f)
+		}
+		11 => sendto.call("sendto", &mut f),
+		12 => recvfrom.call("recvfrom", &mut f),
+		13 => shutdown.call("shutdown", &mut f),
+		14 => setsockopt.call("setsockopt", &mut f),
+		15 => getsockopt.call("getsockopt", &mut f),
+		16 => sendmsg.call("sendmsg", &mut f),
+		17 => recvmsg.call("recvmsg", &mut f),
+		18 => accept4.call("accept4", &mut f),
+		_ => unreachable!(),
+	}
+}
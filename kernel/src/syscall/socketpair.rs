@@ -21,8 +21,13 @@
 
 use crate::{
 	file,
-	file::{fd::FileDescriptorTable, perm::AccessProfile, socket::Socket, vfs, File},
-	net::{SocketDesc, SocketDomain, SocketType},
+	file::{
+		fd::{FileDescriptorTable, FD_CLOEXEC},
+		perm::AccessProfile,
+		socket::Socket,
+		vfs, File,
+	},
+	net::{unix, SocketDesc, SocketDomain, SocketType, SOCK_CLOEXEC, SOCK_NONBLOCK},
 	process::{mem_space::copy::SyscallPtr, Process},
 	sync::mutex::Mutex,
 	syscall::Args,
@@ -41,7 +46,9 @@ pub fn socketpair(
 	fds: Arc<Mutex<FileDescriptorTable>>,
 ) -> EResult<usize> {
 	let sock_domain = SocketDomain::try_from(domain as u32)?;
-	let sock_type = SocketType::try_from(r#type as u32)?;
+	// The type may be combined with flags for the file descriptors
+	let flags = r#type & (SOCK_NONBLOCK | SOCK_CLOEXEC);
+	let sock_type = SocketType::try_from((r#type & !flags) as u32)?;
 	// Check permissions
 	if !ap.can_use_sock_domain(&sock_domain) || !ap.can_use_sock_type(&sock_type) {
 		return Err(errno!(EACCES));
@@ -51,12 +58,27 @@ pub fn socketpair(
 		type_: sock_type,
 		protocol,
 	};
-	// Create socket
-	let sock = Arc::new(Socket::new(desc)?)?;
-	let file0 = File::open_floating(sock.clone(), file::O_RDWR)?;
-	let file1 = File::open_floating(sock, file::O_RDWR)?;
+	// Only local sockets can be connected to each other
+	if sock_domain != SocketDomain::AfUnix {
+		return Err(errno!(EOPNOTSUPP));
+	}
+	unix::check_desc(&desc)?;
+	// Create sockets
+	let sock0 = Arc::new(Socket::new(desc.clone())?)?;
+	let sock1 = Arc::new(Socket::new(desc)?)?;
+	unix::pair(&sock0, &sock1)?;
+	let file0 = File::open_floating(sock0, file::O_RDWR | (flags & SOCK_NONBLOCK))?;
+	let file1 = File::open_floating(sock1, file::O_RDWR | (flags & SOCK_NONBLOCK))?;
 	// Create file descriptors
-	let (fd0_id, fd1_id) = fds.lock().create_fd_pair(file0, file1)?;
+	let (fd0_id, fd1_id) = {
+		let mut fds = fds.lock();
+		let (fd0_id, fd1_id) = fds.create_fd_pair(file0, file1)?;
+		if flags & SOCK_CLOEXEC != 0 {
+			fds.get_fd_mut(fd0_id as _)?.flags = FD_CLOEXEC;
+			fds.get_fd_mut(fd1_id as _)?.flags = FD_CLOEXEC;
+		}
+		(fd0_id, fd1_id)
+	};
 	sv.copy_to_user(&[fd0_id as _, fd1_id as _])?;
 	Ok(0)
 }
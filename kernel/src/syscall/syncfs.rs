@@ -19,21 +19,25 @@
 //! The `syncfs` system call allows to synchronize the filesystem containing the
 //! file pointed by the given file descriptor.
 
-use crate::{file::fd::FileDescriptorTable, process::Process, sync::mutex::Mutex, syscall::Args};
+use crate::{
+	file::{fd::FileDescriptorTable, page_cache},
+	sync::mutex::Mutex,
+	syscall::Args,
+};
 use core::ffi::c_int;
 use utils::{
-	errno,
 	errno::{EResult, Errno},
 	ptr::arc::Arc,
 };
 
 pub fn syncfs(Args(fd): Args<c_int>, fds: Arc<Mutex<FileDescriptorTable>>) -> EResult<usize> {
-	let fds = fds.lock();
-	let file = fds.get_fd(fd)?.get_file();
+	let file = fds.lock().get_fd(fd)?.get_file().clone();
 	let Some(ent) = &file.vfs_entry else {
 		return Ok(0);
 	};
-	let _mountpoint = ent.node().location.get_mountpoint();
-	// TODO Sync all files on mountpoint
+	let Some(mountpoint) = ent.node().location.get_mountpoint() else {
+		return Ok(0);
+	};
+	page_cache::sync_mountpoint(&mountpoint)?;
 	Ok(0)
 }
@@ -19,3 +19,4 @@
 //! Utility functions for system calls.
 
 pub mod at;
+pub mod socket;
@@ -0,0 +1,502 @@
+/*
+ * Copyright 2024 Luc Lenôtre
+ *
+ * This file is part of Maestro.
+ *
+ * Maestro is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * Maestro is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR
+ * A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Maestro. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Utility functions for socket system calls.
+//!
+//! This module implements the copy of socket addresses to userspace and the handling of the
+//! `msghdr` structure used by `sendmsg` and `recvmsg`, along with the control messages it
+//! carries.
+
+use crate::{
+	file::{File, O_NONBLOCK},
+	process::mem_space::copy::{SyscallIOVec, SyscallPtr, SyscallSlice},
+	syscall::FromSyscallArg,
+};
+use core::{
+	cmp::min,
+	ffi::c_int,
+	fmt,
+	mem::{offset_of, size_of},
+};
+use utils::{
+	collections::vec::Vec,
+	errno,
+	errno::{AllocResult, EResult},
+	limits::IOV_MAX,
+};
+
+/// Control message type: File descriptors
+pub const SCM_RIGHTS: c_int = 1;
+/// Control message type: Process credentials
+pub const SCM_CREDENTIALS: c_int = 2;
+
+/// Message flag: The control data has been truncated
+pub const MSG_CTRUNC: c_int = 0x8;
+/// Message flag: The message has been truncated. As an input flag, tells to return the real
+/// length of the message
+pub const MSG_TRUNC: c_int = 0x20;
+/// Message flag: Do not wait, like on a file descriptor with `O_NONBLOCK`
+pub const MSG_DONTWAIT: c_int = 0x40;
+/// Message flag: Set the close-on-exec flag on file descriptors received with `SCM_RIGHTS`
+pub const MSG_CMSG_CLOEXEC: c_int = 0x40000000;
+
+/// Tells whether an operation on the socket `file` with the message flags `flags` must return
+/// instead of waiting.
+pub fn is_nonblock(file: &File, flags: c_int) -> bool {
+	file.get_flags() & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0
+}
+
+/// The maximum size of the control data of a message, like Linux's default `optmem_max`.
+const CONTROL_MAX: usize = 20480;
+
+/// Copies the socket address `addr` to userspace, like Linux's `move_addr_to_user`.
+///
+/// Arguments:
+/// - `buf` is the buffer to write the address to
+/// - `len` is the length of `buf`. It is updated with the real length of the address, which might
+///   be greater if the address has been truncated
+pub fn copy_sockaddr_to_user(
+	addr: &[u8],
+	buf: &SyscallSlice<u8>,
+	len: &SyscallPtr<c_int>,
+) -> EResult<()> {
+	let buf_len = len.copy_from_user()?.ok_or_else(|| errno!(EFAULT))?;
+	if buf_len < 0 {
+		return Err(errno!(EINVAL));
+	}
+	let l = min(addr.len(), buf_len as usize);
+	buf.copy_to_user(0, &addr[..l])?;
+	len.copy_to_user(&(addr.len() as _))
+}
+
+/// The native `msghdr` structure.
+#[repr(C)]
+#[derive(Debug)]
+struct MsgHdrNative {
+	msg_name: usize,
+	msg_namelen: u32,
+	msg_iov: usize,
+	msg_iovlen: usize,
+	msg_control: usize,
+	msg_controllen: usize,
+	msg_flags: c_int,
+}
+
+/// A [`MsgHdrNative`] for compatibility mode.
+#[repr(C)]
+#[derive(Debug)]
+struct MsgHdrCompat {
+	msg_name: u32,
+	msg_namelen: u32,
+	msg_iov: u32,
+	msg_iovlen: u32,
+	msg_control: u32,
+	msg_controllen: u32,
+	msg_flags: c_int,
+}
+
+/// A `msghdr` structure, copied from userspace.
+#[derive(Debug)]
+pub struct MsgHdr {
+	/// The address of the peer.
+	pub name: SyscallSlice<u8>,
+	/// The length of the address of the peer.
+	pub namelen: u32,
+	/// The IO vector containing the data.
+	pub iov: SyscallIOVec,
+	/// The number of elements in `iov`.
+	pub iovlen: usize,
+	/// The buffer of control messages.
+	pub control: SyscallSlice<u8>,
+	/// The length of `control`.
+	pub controllen: usize,
+	/// Tells whether userspace is in compatibility mode.
+	compat: bool,
+}
+
+impl MsgHdr {
+	/// Tells whether userspace is in compatibility mode.
+	pub fn is_compat(&self) -> bool {
+		self.compat
+	}
+
+	/// Returns an iterator over the IO vector, checking its length.
+	fn iov_iter(&self) -> EResult<impl Iterator<Item = EResult<(SyscallSlice<u8>, usize)>> + '_> {
+		if self.iovlen > IOV_MAX {
+			return Err(errno!(EMSGSIZE));
+		}
+		Ok(self
+			.iov
+			.iter(self.iovlen)
+			.map(|i| i.map(|i| (SyscallSlice::<u8>::from_ptr(i.iov_base as usize), i.iov_len))))
+	}
+
+	/// Returns the total length of the buffers of the IO vector.
+	pub fn data_len(&self) -> EResult<usize> {
+		let mut len = 0usize;
+		for i in self.iov_iter()? {
+			let (_, l) = i?;
+			len = len.saturating_add(l);
+		}
+		// Limited like the `read` and `write` system calls
+		Ok(min(len, i32::MAX as usize))
+	}
+
+	/// Copies the data from the IO vector.
+	pub fn read_data(&self) -> EResult<Vec<u8>> {
+		let mut data = Vec::new();
+		for i in self.iov_iter()? {
+			let (ptr, l) = i?;
+			let l = min(l, i32::MAX as usize - data.len());
+			if l == 0 {
+				continue;
+			}
+			let buf = ptr
+				.copy_from_user_vec(0, l)?
+				.ok_or_else(|| errno!(EFAULT))?;
+			data.extend_from_slice(&buf)?;
+		}
+		Ok(data)
+	}
+
+	/// Writes `data` into the buffers of the IO vector.
+	pub fn write_data(&self, mut data: &[u8]) -> EResult<()> {
+		for i in self.iov_iter()? {
+			if data.is_empty() {
+				break;
+			}
+			let (ptr, l) = i?;
+			let l = min(l, data.len());
+			ptr.copy_to_user(0, &data[..l])?;
+			data = &data[l..];
+		}
+		Ok(())
+	}
+
+	/// Copies the buffer of control messages from userspace.
+	///
+	/// If the buffer is larger than the limit, the function returns [`errno::ENOBUFS`].
+	pub fn read_control(&self) -> EResult<Vec<u8>> {
+		if self.controllen > CONTROL_MAX {
+			return Err(errno!(ENOBUFS));
+		}
+		if self.controllen < cmsg_hdr_len(self.compat) {
+			return Ok(Vec::new());
+		}
+		self.control
+			.copy_from_user_vec(0, self.controllen)?
+			.ok_or_else(|| errno!(EFAULT))
+	}
+}
+
+/// A pointer to a `msghdr` structure, as a system call argument.
+pub struct SyscallMsgHdr {
+	/// The pointer to the structure.
+	ptr: SyscallPtr<u8>,
+	/// Tells whether the userspace is in compatibility mode.
+	compat: bool,
+}
+
+impl FromSyscallArg for SyscallMsgHdr {
+	fn from_syscall_arg(ptr: usize, compat: bool) -> Self {
+		Self {
+			ptr: SyscallPtr::from_ptr(ptr),
+			compat,
+		}
+	}
+}
+
+impl fmt::Debug for SyscallMsgHdr {
+	fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.ptr.fmt(fmt)
+	}
+}
+
+impl SyscallMsgHdr {
+	/// Copies the structure from userspace.
+	///
+	/// If the pointer is null, the function returns [`errno::EFAULT`].
+	pub fn copy_from_user(&self) -> EResult<MsgHdr> {
+		let ptr = self.ptr.as_ptr() as usize;
+		let hdr = if self.compat {
+			let hdr = SyscallPtr::<MsgHdrCompat>::from_ptr(ptr)
+				.copy_from_user()?
+				.ok_or_else(|| errno!(EFAULT))?;
+			MsgHdrNative {
+				msg_name: hdr.msg_name as _,
+				msg_namelen: hdr.msg_namelen,
+				msg_iov: hdr.msg_iov as _,
+				msg_iovlen: hdr.msg_iovlen as _,
+				msg_control: hdr.msg_control as _,
+				msg_controllen: hdr.msg_controllen as _,
+				msg_flags: hdr.msg_flags,
+			}
+		} else {
+			SyscallPtr::<MsgHdrNative>::from_ptr(ptr)
+				.copy_from_user()?
+				.ok_or_else(|| errno!(EFAULT))?
+		};
+		Ok(MsgHdr {
+			name: SyscallSlice::from_ptr(hdr.msg_name),
+			namelen: hdr.msg_namelen,
+			iov: SyscallIOVec::from_syscall_arg(hdr.msg_iov, self.compat),
+			iovlen: hdr.msg_iovlen,
+			control: SyscallSlice::from_ptr(hdr.msg_control),
+			controllen: hdr.msg_controllen,
+			compat: self.compat,
+		})
+	}
+
+	/// Writes the fields of the structure that are returned by `recvmsg`.
+	///
+	/// Arguments:
+	/// - `namelen` is the length of the address of the peer
+	/// - `controllen` is the length of the control messages
+	/// - `flags` is the set of flags of the received message
+	pub fn copy_result_to_user(
+		&self,
+		namelen: u32,
+		controllen: usize,
+		flags: c_int,
+	) -> EResult<()> {
+		let ptr = self.ptr.as_ptr() as usize;
+		if self.compat {
+			SyscallPtr::<u32>::from_ptr(ptr + offset_of!(MsgHdrCompat, msg_namelen))
+				.copy_to_user(&namelen)?;
+			SyscallPtr::<u32>::from_ptr(ptr + offset_of!(MsgHdrCompat, msg_controllen))
+				.copy_to_user(&(controllen as _))?;
+			SyscallPtr::<c_int>::from_ptr(ptr + offset_of!(MsgHdrCompat, msg_flags))
+				.copy_to_user(&flags)
+		} else {
+			SyscallPtr::<u32>::from_ptr(ptr + offset_of!(MsgHdrNative, msg_namelen))
+				.copy_to_user(&namelen)?;
+			SyscallPtr::<usize>::from_ptr(ptr + offset_of!(MsgHdrNative, msg_controllen))
+				.copy_to_user(&controllen)?;
+			SyscallPtr::<c_int>::from_ptr(ptr + offset_of!(MsgHdrNative, msg_flags))
+				.copy_to_user(&flags)
+		}
+	}
+}
+
+/// Returns the size of the header of a control message (`cmsghdr`).
+fn cmsg_hdr_len(compat: bool) -> usize {
+	if compat {
+		size_of::<u32>() + size_of::<c_int>() * 2
+	} else {
+		size_of::<usize>() + size_of::<c_int>() * 2
+	}
+}
+
+/// Aligns `len` to the alignment of control messages.
+fn cmsg_align(len: usize, compat: bool) -> usize {
+	let align = if compat {
+		size_of::<u32>()
+	} else {
+		size_of::<usize>()
+	};
+	len.next_multiple_of(align)
+}
+
+/// A control message.
+#[derive(Debug)]
+pub struct Cmsg<'b> {
+	/// The level of the message.
+	pub level: c_int,
+	/// The type of the message.
+	pub type_: c_int,
+	/// The data of the message.
+	pub data: &'b [u8],
+}
+
+/// Iterator over the control messages of a buffer.
+pub struct Cmsgs<'b> {
+	/// The remaining part of the buffer.
+	buf: &'b [u8],
+	/// Tells whether userspace is in compatibility mode.
+	compat: bool,
+}
+
+impl<'b> Cmsgs<'b> {
+	/// Creates an iterator over the control messages in `buf`.
+	pub fn new(buf: &'b [u8], compat: bool) -> Self {
+		Self {
+			buf,
+			compat,
+		}
+	}
+}
+
+impl<'b> Iterator for Cmsgs<'b> {
+	type Item = EResult<Cmsg<'b>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let hdr_len = cmsg_hdr_len(self.compat);
+		// The remaining bytes are ignored if too short for a header
+		if self.buf.len() < hdr_len {
+			return None;
+		}
+		let len_size = hdr_len - size_of::<c_int>() * 2;
+		let len = if self.compat {
+			u32::from_ne_bytes(self.buf[..4].try_into().unwrap()) as usize
+		} else {
+			usize::from_ne_bytes(self.buf[..len_size].try_into().unwrap())
+		};
+		if len < hdr_len || len > self.buf.len() {
+			self.buf = &[];
+			return Some(Err(errno!(EINVAL)));
+		}
+		let level = c_int::from_ne_bytes(self.buf[len_size..len_size + 4].try_into().unwrap());
+		let type_ = c_int::from_ne_bytes(self.buf[len_size + 4..hdr_len].try_into().unwrap());
+		let data = &self.buf[hdr_len..len];
+		let next = min(cmsg_align(len, self.compat), self.buf.len());
+		self.buf = &self.buf[next..];
+		Some(Ok(Cmsg {
+			level,
+			type_,
+			data,
+		}))
+	}
+}
+
+/// Builder for the buffer of control messages returned by `recvmsg`.
+pub struct CmsgWriter {
+	/// The control messages.
+	buf: Vec<u8>,
+	/// The size of the buffer provided by userspace.
+	capacity: usize,
+	/// Tells whether userspace is in compatibility mode.
+	compat: bool,
+	/// Tells whether control data has been truncated for lack of room.
+	truncated: bool,
+}
+
+impl CmsgWriter {
+	/// Creates a new instance for the buffer of the given message.
+	pub fn new(hdr: &MsgHdr) -> Self {
+		Self {
+			buf: Vec::new(),
+			capacity: min(hdr.controllen, CONTROL_MAX),
+			compat: hdr.compat,
+			truncated: false,
+		}
+	}
+
+	/// Returns the space available for the data of the next control message.
+	pub fn available(&self) -> usize {
+		(self.capacity - self.buf.len()).saturating_sub(cmsg_hdr_len(self.compat))
+	}
+
+	/// Marks the control data as truncated.
+	pub fn truncate(&mut self) {
+		self.truncated = true;
+	}
+
+	/// Tells whether control data has been truncated.
+	pub fn is_truncated(&self) -> bool {
+		self.truncated
+	}
+
+	/// Returns the control messages.
+	pub fn as_slice(&self) -> &[u8] {
+		&self.buf
+	}
+
+	/// Appends a control message.
+	///
+	/// If the message does not fit, it is truncated, like on Linux.
+	pub fn push(&mut self, level: c_int, type_: c_int, data: &[u8]) -> AllocResult<()> {
+		let hdr_len = cmsg_hdr_len(self.compat);
+		let remain = self.capacity - self.buf.len();
+		if remain < hdr_len {
+			self.truncated = true;
+			return Ok(());
+		}
+		let mut len = hdr_len + data.len();
+		if len > remain {
+			self.truncated = true;
+			len = remain;
+		}
+		if self.compat {
+			self.buf.extend_from_slice(&(len as u32).to_ne_bytes())?;
+		} else {
+			self.buf.extend_from_slice(&len.to_ne_bytes())?;
+		}
+		self.buf.extend_from_slice(&level.to_ne_bytes())?;
+		self.buf.extend_from_slice(&type_.to_ne_bytes())?;
+		self.buf.extend_from_slice(&data[..len - hdr_len])?;
+		// Padding
+		let end = min(
+			self.buf.len() - len + cmsg_align(len, self.compat),
+			self.capacity,
+		);
+		self.buf.resize(end, 0)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test_case]
+	fn cmsg_roundtrip() {
+		let hdr = MsgHdr {
+			name: SyscallSlice::from_ptr(0),
+			namelen: 0,
+			iov: SyscallIOVec::from_syscall_arg(0, false),
+			iovlen: 0,
+			control: SyscallSlice::from_ptr(0),
+			controllen: 64,
+			compat: false,
+		};
+		let mut w = CmsgWriter::new(&hdr);
+		w.push(1, SCM_RIGHTS, &[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0])
+			.unwrap();
+		w.push(1, SCM_CREDENTIALS, &[0; 12]).unwrap();
+		assert!(!w.is_truncated());
+		let mut msgs = Cmsgs::new(w.as_slice(), false);
+		let msg = msgs.next().unwrap().unwrap();
+		assert_eq!((msg.level, msg.type_, msg.data.len()), (1, SCM_RIGHTS, 12));
+		assert_eq!(msg.data[4], 2);
+		let msg = msgs.next().unwrap().unwrap();
+		assert_eq!(
+			(msg.level, msg.type_, msg.data.len()),
+			(1, SCM_CREDENTIALS, 12)
+		);
+		assert!(msgs.next().is_none());
+		// Not enough room for the second message
+		let mut w = CmsgWriter::new(&hdr);
+		w.push(1, SCM_RIGHTS, &[0; 40]).unwrap();
+		w.push(1, SCM_CREDENTIALS, &[0; 12]).unwrap();
+		assert!(w.is_truncated());
+		assert_eq!(w.as_slice().len(), 56);
+	}
+
+	#[test_case]
+	fn cmsg_invalid() {
+		// Length shorter than the header
+		let mut buf = [0u8; 16];
+		buf[..8].copy_from_slice(&4usize.to_ne_bytes());
+		assert!(Cmsgs::new(&buf, false).next().unwrap().is_err());
+		// Compatibility layout
+		let mut buf = [0u8; 16];
+		buf[..4].copy_from_slice(&16u32.to_ne_bytes());
+		buf[4..8].copy_from_slice(&1i32.to_ne_bytes());
+		let msg = Cmsgs::new(&buf, true).next().unwrap().unwrap();
+		assert_eq!((msg.level, msg.data.len()), (1, 4));
+	}
+}
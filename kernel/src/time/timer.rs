@@ -20,7 +20,8 @@
 
 use super::{
 	clock,
-	unit::{ClockIdT, ITimerspec32, TimeUnit, TimerT, Timespec, TimestampScale},
+	clock::CLOCK_MONOTONIC,
+	unit::{ClockIdT, ITimerspec32, TimeUnit, TimerT, Timespec, Timestamp, TimestampScale},
 };
 use crate::{
 	process::{
@@ -33,7 +34,7 @@ use crate::{
 	time::unit::Timespec32,
 };
 use utils::{
-	collections::{btreemap::BTreeMap, hashmap::HashMap, id_allocator::IDAllocator},
+	collections::{btreemap::BTreeMap, hashmap::HashMap, id_allocator::IDAllocator, vec::Vec},
 	errno,
 	errno::{AllocResult, EResult},
 	limits::TIMER_MAX,
@@ -288,8 +289,104 @@ impl Drop for TimerManager {
 static TIMERS_QUEUE: IntMutex<BTreeMap<(Timespec, Pid, TimerT), ()>> =
 	IntMutex::new(BTreeMap::new());
 
+/// A periodic timer executing a callback in kernel space.
+struct KernelTimer {
+	/// The interval between two executions, in milliseconds.
+	interval: Timestamp,
+	/// The timestamp of the next execution, in milliseconds.
+	next: Timestamp,
+	/// The callback to execute.
+	callback: fn(),
+}
+
+/// The list of kernel timers.
+static KERNEL_TIMERS: IntMutex<Vec<KernelTimer>> = IntMutex::new(Vec::new());
+
+/// Registers a kernel timer executing `callback` every `interval` milliseconds.
+///
+/// The callback is executed in interrupt context: it must not sleep and must only use resources
+/// protected by locks masking interrupts.
+pub fn register_kernel_timer(interval: Timestamp, callback: fn()) -> EResult<()> {
+	let ts = clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond)?;
+	KERNEL_TIMERS.lock().push(KernelTimer {
+		interval,
+		next: ts + interval,
+		callback,
+	})?;
+	Ok(())
+}
+
+/// Executes kernel timers that have expired.
+fn tick_kernel() {
+	let Ok(ts) = clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond) else {
+		return;
+	};
+	let mut expired: [Option<fn()>; 8] = Default::default();
+	let mut i = 0;
+	{
+		let mut timers = KERNEL_TIMERS.lock();
+		// Remaining timers are executed on the next tick
+		for timer in timers
+			.iter_mut()
+			.filter(|t| ts >= t.next)
+			.take(expired.len())
+		{
+			timer.next = ts + timer.interval;
+			expired[i] = Some(timer.callback);
+			i += 1;
+		}
+	}
+	// Execute callbacks without holding the lock, allowing them to register timers
+	for callback in expired.into_iter().flatten() {
+		callback();
+	}
+}
+
+/// Processes to be woken up at a given time, for waits with a timeout.
+///
+/// The key has the following elements:
+/// - the monotonic timestamp at which the process is to be woken up, in milliseconds
+/// - the PID of the process
+static WAKEUPS: IntMutex<BTreeMap<(Timestamp, Pid), ()>> = IntMutex::new(BTreeMap::new());
+
+/// Schedules waking up the process with PID `pid` at the monotonic timestamp `ts`, in
+/// milliseconds.
+///
+/// The wakeup must be cancelled with [`cancel_wakeup`] once the wait is over.
+pub fn schedule_wakeup(ts: Timestamp, pid: Pid) -> AllocResult<()> {
+	WAKEUPS.lock().insert((ts, pid), ())?;
+	Ok(())
+}
+
+/// Cancels a wakeup scheduled with [`schedule_wakeup`]. If the wakeup already happened, the
+/// function does nothing.
+pub fn cancel_wakeup(ts: Timestamp, pid: Pid) {
+	WAKEUPS.lock().remove(&(ts, pid));
+}
+
+/// Wakes up processes whose wakeup time has been reached.
+fn tick_wakeups() {
+	let Ok(ts) = clock::current_time(CLOCK_MONOTONIC, TimestampScale::Millisecond) else {
+		return;
+	};
+	let mut wakeups = WAKEUPS.lock();
+	while let Some(((next, pid), _)) = wakeups.first_key_value() {
+		if *next > ts {
+			break;
+		}
+		let pid = *pid;
+		wakeups.pop_first();
+		if let Some(proc) = Process::get_by_pid(pid) {
+			proc.wake();
+		}
+	}
+}
+
 /// Ticks active timers and triggers them if necessary.
 pub(super) fn tick() {
+	tick_kernel();
+	tick_wakeups();
+
 	let mut times: [Option<Timespec>; 12] = Default::default();
 	let mut queue = TIMERS_QUEUE.lock();
 
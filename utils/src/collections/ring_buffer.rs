@@ -109,9 +109,16 @@ impl<T: Default + Copy, B: AsRef<[T]> + AsMut<[T]>> RingBuffer<T, B> {
 	///
 	/// The function returns the number of elements read.
 	pub fn peek(&mut self, buf: &mut [T]) -> usize {
-		let cursor = self.read_cursor;
-		let len = min(buf.len(), self.get_data_len());
+		self.peek_at(0, buf)
+	}
+
+	/// Same as [`Self::peek`], but starts reading `off` elements after the read cursor.
+	///
+	/// If `off` is greater than the length of the data, the function returns zero.
+	pub fn peek_at(&mut self, off: usize, buf: &mut [T]) -> usize {
 		let buffer_size = self.get_size();
+		let cursor = (self.read_cursor + off) % buffer_size;
+		let len = min(buf.len(), self.get_data_len().saturating_sub(off));
 		let buffer = self.get_buffer();
 
 		// The length of the first read, before going back to the beginning of the
@@ -137,6 +144,17 @@ impl<T: Default + Copy, B: AsRef<[T]> + AsMut<[T]>> RingBuffer<T, B> {
 		len
 	}
 
+	/// Consumes at most `len` elements from the buffer without reading them.
+	///
+	/// The function returns the number of elements consumed.
+	pub fn skip(&mut self, len: usize) -> usize {
+		let len = min(len, self.get_data_len());
+		let buffer_size = self.get_size();
+
+		self.read_cursor = (self.read_cursor + len) % buffer_size;
+		len
+	}
+
 	/// Writes data in `buf` to the buffer.
 	///
 	/// The function returns the number of elements written.
@@ -207,5 +225,22 @@ mod test {
 		}
 	}
 
-	// TODO peek
+	#[test]
+	fn ring_buffer_peek() {
+		let mut rb = RingBuffer::new([0u8; 10]);
+		assert_eq!(rb.write(&[1, 2, 3, 4, 5, 6, 7]), 7);
+		assert_eq!(rb.skip(5), 5);
+		assert_eq!(rb.write(&[8, 9, 10, 11]), 4);
+
+		let mut buf = [0u8; 4];
+		assert_eq!(rb.peek_at(2, &mut buf), 4);
+		assert_eq!(buf, [8, 9, 10, 11]);
+		assert_eq!(rb.peek_at(4, &mut buf), 2);
+		assert_eq!(buf[..2], [10, 11]);
+		assert_eq!(rb.peek_at(7, &mut buf), 0);
+		assert_eq!(rb.get_data_len(), 6);
+
+		assert_eq!(rb.skip(10), 6);
+		assert!(rb.is_empty());
+	}
 }
@@ -22,6 +22,7 @@
 use crate::{__alloc, __dealloc, boxed::Box, errno::AllocResult};
 use core::{
 	alloc::{AllocError, Layout},
+	any::Any,
 	borrow::Borrow,
 	fmt,
 	hash::{Hash, Hasher},
@@ -159,6 +160,22 @@ impl<T: ?Sized> Arc<T> {
 	}
 }
 
+impl Arc<dyn Any> {
+	/// Attempts to downcast the `Arc` to a concrete type.
+	///
+	/// If the inner object is not of type `T`, the function returns the `Arc` unchanged.
+	pub fn downcast<T: Any>(self) -> Result<Arc<T>, Self> {
+		if !(*self).is::<T>() {
+			return Err(self);
+		}
+		// Do not decrement the reference counter since the new `Arc` inherits the reference
+		let this = ManuallyDrop::new(self);
+		Ok(Arc {
+			inner: this.inner.cast(),
+		})
+	}
+}
+
 impl<T: ?Sized> AsRef<T> for Arc<T> {
 	fn as_ref(&self) -> &T {
 		&self.inner().obj